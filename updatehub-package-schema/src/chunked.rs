@@ -0,0 +1,59 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::definitions::{ChunkSize, TargetType};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Block image described by an index of content-defined chunks. The object
+/// itself is the chunk index, the chunks are fetched separately.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Chunked {
+    pub filename: String,
+    pub size: u64,
+    pub sha256sum: String,
+    #[serde(flatten)]
+    pub target_type: TargetType,
+
+    pub image_size: u64,
+    /// Device holding the active partition, used as a seed for the chunks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<PathBuf>,
+    #[serde(default)]
+    pub chunk_size: ChunkSize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
+}
+
+#[test]
+fn deserialize() {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    assert_eq!(
+        Chunked {
+            filename: "rootfs.ext4.index".to_string(),
+            size: 1024,
+            sha256sum: "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722"
+                .to_string(),
+            target_type: TargetType::Device(PathBuf::from("/dev/mmcblk0p3")),
+
+            image_size: 419_430_400,
+            seed: Some(PathBuf::from("/dev/mmcblk0p2")),
+            chunk_size: ChunkSize::default(),
+            install_if: None,
+        },
+        serde_json::from_value::<Chunked>(json!({
+            "filename": "rootfs.ext4.index",
+            "size": 1024,
+            "sha256sum": "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722",
+            "target-type": "device",
+            "target": "/dev/mmcblk0p3",
+            "image-size": 419_430_400,
+            "seed": "/dev/mmcblk0p2",
+        }))
+        .unwrap()
+    );
+}
@@ -3,12 +3,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::definitions::{
-    Filesystem, InstallIfDifferent, TargetFormat, TargetPermissions, TargetType,
+    Filesystem, InstallIfDifferent, TargetAttributes, TargetFormat, TargetPermissions, TargetType,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Copy {
     pub filename: String,
@@ -19,9 +19,12 @@ pub struct Copy {
     pub target_type: TargetType,
     pub target_path: PathBuf,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub install_if_different: Option<InstallIfDifferent>,
     #[serde(flatten)]
     pub target_permissions: TargetPermissions,
+    #[serde(flatten)]
+    pub target_attributes: TargetAttributes,
     #[serde(default)]
     pub compressed: bool,
     #[serde(default)]
@@ -30,6 +33,8 @@ pub struct Copy {
     pub target_format: TargetFormat,
     #[serde(default)]
     pub mount_options: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
 }
 
 #[test]
@@ -49,10 +54,12 @@ fn deserialize() {
 
             install_if_different: Some(InstallIfDifferent::CheckSum),
             target_permissions: TargetPermissions::default(),
+            target_attributes: TargetAttributes::default(),
             compressed: false,
             required_uncompressed_size: 0,
             target_format: TargetFormat::default(),
             mount_options: String::default(),
+            install_if: None,
         },
         serde_json::from_value::<Copy>(json!({
             "filename": "etc/passwd",
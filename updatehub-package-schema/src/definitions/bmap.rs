@@ -0,0 +1,95 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fmt};
+
+/// Block map of a sparse image, as generated by `bmaptool create`, listing
+/// the blocks which hold data. The remaining blocks are holes which don't
+/// need to be written.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Bmap {
+    pub image_size: u64,
+    pub block_size: u64,
+    pub mapped_blocks: Vec<BlockRange>,
+}
+
+/// Inclusive range of blocks, written as `first-last` or as a single block
+/// number like in the `Range` elements of the bmap file.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Copy, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct BlockRange {
+    pub first: u64,
+    pub last: u64,
+}
+
+impl Bmap {
+    /// Mapped byte ranges of the image, the last one is clamped to the image
+    /// size as it might not be block aligned.
+    pub fn mapped_bytes(&self) -> impl Iterator<Item = std::ops::Range<u64>> + '_ {
+        self.mapped_blocks.iter().map(move |r| {
+            let start = r.first.saturating_mul(self.block_size).min(self.image_size);
+            let end = r.last.saturating_add(1).saturating_mul(self.block_size).min(self.image_size);
+            start..end
+        })
+    }
+}
+
+impl TryFrom<String> for BlockRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let parse = |n: &str| {
+            n.trim().parse::<u64>().map_err(|_| format!("'{}' is not a valid block range", s))
+        };
+        let (first, last) = match s.find('-') {
+            Some(i) => (parse(&s[..i])?, parse(&s[i + 1..])?),
+            None => (parse(&s)?, parse(&s)?),
+        };
+        if first > last {
+            return Err(format!("'{}' is not a valid block range", s));
+        }
+
+        Ok(BlockRange { first, last })
+    }
+}
+
+impl From<BlockRange> for String {
+    fn from(range: BlockRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for BlockRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+#[test]
+fn deserialize() {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    assert_eq!(
+        Bmap {
+            image_size: 8192,
+            block_size: 1024,
+            mapped_blocks: vec![BlockRange { first: 0, last: 1 }, BlockRange { first: 5, last: 5 }],
+        },
+        serde_json::from_value::<Bmap>(json!({
+            "image-size": 8192,
+            "block-size": 1024,
+            "mapped-blocks": ["0-1", "5"]
+        }))
+        .unwrap()
+    );
+    assert!(serde_json::from_value::<BlockRange>(json!("5-1")).is_err());
+    assert!(serde_json::from_value::<BlockRange>(json!("a-b")).is_err());
+}
@@ -2,11 +2,11 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::{de, Deserialize, Deserializer};
+use serde::{de, Deserialize, Deserializer, Serialize};
 
 /// The size of the buffers (in bytes) used to read and write,
 /// default is the 128KiB.
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Serialize)]
 pub struct ChunkSize(pub usize);
 
 impl Default for ChunkSize {
@@ -2,7 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::{de, Deserialize, Deserializer};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 /// How many `ChunkSize` blocks must be copied from the source file to
 /// the target. The default value of -1 means all possible bytes
@@ -32,6 +32,18 @@ impl<'de> Deserialize<'de> for Count {
     }
 }
 
+impl Serialize for Count {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Count::All => serializer.serialize_i64(-1),
+            Count::Limited(n) => serializer.serialize_i64(*n as i64),
+        }
+    }
+}
+
 impl std::iter::Iterator for Count {
     type Item = isize;
 
@@ -55,7 +67,7 @@ mod test {
     use pretty_assertions::assert_eq;
     use serde_json::json;
 
-    #[derive(Debug, PartialEq, Deserialize)]
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
     struct Payload {
         #[serde(default)]
         count: Count,
@@ -77,6 +89,18 @@ mod test {
         );
     }
 
+    #[test]
+    fn serialize() {
+        assert_eq!(
+            serde_json::to_value(Payload { count: Count::All }).unwrap(),
+            json!({ "count": -1 })
+        );
+        assert_eq!(
+            serde_json::to_value(Payload { count: Count::Limited(3) }).unwrap(),
+            json!({ "count": 3 })
+        );
+    }
+
     #[test]
     fn validation_of_minimal() {
         assert!(serde_json::from_value::<Payload>(json!({ "count": -2 })).is_err());
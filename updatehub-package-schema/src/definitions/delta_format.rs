@@ -0,0 +1,31 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+
+/// Encoding used by a binary delta.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum DeltaFormat {
+    /// Classic `bsdiff` patch (`BSDIFF40` header with bzip2 compressed blocks).
+    Bsdiff,
+    /// RFC 3284 VCDIFF patch, as produced by `xdelta3`.
+    Vcdiff,
+}
+
+#[test]
+fn deserialize() {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    assert_eq!(
+        DeltaFormat::Bsdiff,
+        serde_json::from_value::<DeltaFormat>(json!("bsdiff")).unwrap()
+    );
+    assert_eq!(
+        DeltaFormat::Vcdiff,
+        serde_json::from_value::<DeltaFormat>(json!("vcdiff")).unwrap()
+    );
+    assert!(serde_json::from_value::<DeltaFormat>(json!("xdelta")).is_err());
+}
@@ -2,11 +2,11 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 /// Filesystem type that must be used to mount device.
-#[derive(Deserialize, PartialEq, Debug, Copy, Clone)]
+#[derive(Deserialize, Serialize, PartialEq, Debug, Copy, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum Filesystem {
     Btrfs,
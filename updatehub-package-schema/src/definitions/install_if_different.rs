@@ -3,13 +3,13 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use derive_more::Display;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Handles when an object should be installed on target.
-#[derive(PartialEq, Debug, Deserialize, Display)]
+#[derive(PartialEq, Debug, Deserialize, Serialize, Display)]
 #[serde(untagged)]
 pub enum InstallIfDifferent {
-    #[serde(deserialize_with = "deserialize_checksum")]
+    #[serde(deserialize_with = "deserialize_checksum", serialize_with = "serialize_checksum")]
     /// Use checksum to check.
     #[display(fmt = "checksum")]
     CheckSum,
@@ -23,7 +23,7 @@ pub enum InstallIfDifferent {
 
 /// Known patterns to be used with
 /// [`InstallIfDifferent`](InstallIfDifferent::KnownPattern)
-#[derive(PartialEq, Debug, Deserialize, Display)]
+#[derive(PartialEq, Debug, Deserialize, Serialize, Display)]
 #[serde(rename_all = "kebab-case")]
 pub enum KnownPatternKind {
     /// Linux Kernel pattern.
@@ -36,7 +36,7 @@ pub enum KnownPatternKind {
 
 /// Custom pattern to use with
 /// [`InstallIfDifferent`](InstallIfDifferent::CustomPattern)
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Pattern {
     pub regexp: String,
@@ -55,6 +55,10 @@ where
     }
 }
 
+fn serialize_checksum<S: serde::Serializer>(serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("sha256sum")
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -94,4 +98,24 @@ mod test {
             .unwrap()
         )
     }
+
+    #[test]
+    fn serialize() {
+        for value in &[
+            json!("sha256sum"),
+            json!({ "version": "2.0", "pattern": "u-boot" }),
+            json!({
+                "version": "2.0",
+                "pattern": { "regexp": "[0-9.]+", "seek": 1024, "buffer-size": 2048 }
+            }),
+        ] {
+            assert_eq!(
+                *value,
+                serde_json::to_value(
+                    serde_json::from_value::<InstallIfDifferent>(value.clone()).unwrap()
+                )
+                .unwrap()
+            );
+        }
+    }
 }
@@ -2,22 +2,30 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+mod bmap;
 mod chunk_size;
 mod count;
+mod delta_format;
 mod filesystem;
 pub mod install_if_different;
 mod skip;
+mod target_attributes;
 mod target_format;
 pub mod target_permissions;
 mod target_type;
 mod truncate;
+mod ubi_volume;
 
+pub use bmap::{BlockRange, Bmap};
 pub use chunk_size::ChunkSize;
 pub use count::Count;
+pub use delta_format::DeltaFormat;
 pub use filesystem::Filesystem;
 pub use install_if_different::InstallIfDifferent;
 pub use skip::Skip;
+pub use target_attributes::{Acl, AclEntry, AclTag, TargetAttributes};
 pub use target_format::TargetFormat;
 pub use target_permissions::TargetPermissions;
 pub use target_type::TargetType;
 pub use truncate::Truncate;
+pub use ubi_volume::{UbiVolume, UbiVolumeType};
@@ -2,10 +2,10 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// How many chunk-size blocks must be skipped in the source file
-#[derive(PartialEq, Debug, Default, Deserialize)]
+#[derive(PartialEq, Debug, Default, Deserialize, Serialize)]
 pub struct Skip(pub u64);
 
 #[cfg(test)]
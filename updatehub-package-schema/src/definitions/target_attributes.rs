@@ -0,0 +1,187 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, convert::TryFrom, fmt};
+
+/// Extended attributes to set after installing on target, on top of the
+/// ones kept from the file being replaced.
+#[derive(PartialEq, Debug, Deserialize, Serialize, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+#[serde(default)]
+pub struct TargetAttributes {
+    /// Extended attributes by their full name (e.g. `user.checksum`).
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub target_xattrs: BTreeMap<String, String>,
+    /// SELinux context, stored as the `security.selinux` attribute.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_selinux_context: Option<String>,
+    /// POSIX access ACL, stored as the `system.posix_acl_access` attribute.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_acl: Option<Acl>,
+}
+
+/// POSIX ACL in the short text form used by `setfacl`, like
+/// `user::rw-,user:1000:r--,group::r--,mask::r--,other::---`. Named
+/// entries take numeric ids as names can't be resolved for the target
+/// when the package is built.
+#[derive(PartialEq, Debug, Deserialize, Serialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct Acl(pub Vec<AclEntry>);
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    /// Permission bits, `4` for read, `2` for write and `1` for execute.
+    pub perm: u8,
+}
+
+/// Kind of an ACL entry, ordered as the kernel expects them.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone)]
+pub enum AclTag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    Mask,
+    Other,
+}
+
+impl TryFrom<String> for Acl {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut entries = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|e| !e.is_empty())
+            .map(AclEntry::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.tag);
+
+        if entries.windows(2).any(|w| w[0].tag == w[1].tag) {
+            return Err(format!("'{}' has duplicated entries", s));
+        }
+        let has = |tag| entries.iter().any(|e| e.tag == tag);
+        if !has(AclTag::UserObj) || !has(AclTag::GroupObj) || !has(AclTag::Other) {
+            return Err(format!("'{}' must have the user, group and other entries", s));
+        }
+        let named = entries.iter().any(|e| matches!(e.tag, AclTag::User(_) | AclTag::Group(_)));
+        if named && !has(AclTag::Mask) {
+            return Err(format!("'{}' must have a mask entry as it has named entries", s));
+        }
+
+        Ok(Acl(entries))
+    }
+}
+
+impl From<Acl> for String {
+    fn from(acl: Acl) -> Self {
+        acl.to_string()
+    }
+}
+
+impl fmt::Display for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, entry) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(",")?;
+            }
+            fmt::Display::fmt(entry, f)?;
+        }
+        Ok(())
+    }
+}
+
+impl AclEntry {
+    fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("'{}' is not a valid ACL entry", s);
+        let fields = s.split(':').collect::<Vec<_>>();
+        let (tag, qualifier, perm) = match fields.as_slice() {
+            [tag, qualifier, perm] => (*tag, *qualifier, *perm),
+            _ => return Err(invalid()),
+        };
+        let id = || qualifier.parse::<u32>().map_err(|_| invalid());
+
+        let tag = match (tag, qualifier.is_empty()) {
+            ("u", true) | ("user", true) => AclTag::UserObj,
+            ("u", false) | ("user", false) => AclTag::User(id()?),
+            ("g", true) | ("group", true) => AclTag::GroupObj,
+            ("g", false) | ("group", false) => AclTag::Group(id()?),
+            ("m", true) | ("mask", true) => AclTag::Mask,
+            ("o", true) | ("other", true) => AclTag::Other,
+            _ => return Err(invalid()),
+        };
+
+        if perm.len() != 3 {
+            return Err(invalid());
+        }
+        let mut bits = 0;
+        for (c, (set, bit)) in perm.chars().zip([('r', 4), ('w', 2), ('x', 1)].iter()) {
+            match c {
+                '-' => {}
+                c if c == *set => bits |= bit,
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(AclEntry { tag, perm: bits })
+    }
+}
+
+impl fmt::Display for AclEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.tag {
+            AclTag::UserObj => write!(f, "user::")?,
+            AclTag::User(id) => write!(f, "user:{}:", id)?,
+            AclTag::GroupObj => write!(f, "group::")?,
+            AclTag::Group(id) => write!(f, "group:{}:", id)?,
+            AclTag::Mask => write!(f, "mask::")?,
+            AclTag::Other => write!(f, "other::")?,
+        }
+        for (bit, set) in [(4, 'r'), (2, 'w'), (1, 'x')].iter() {
+            write!(f, "{}", if self.perm & bit != 0 { *set } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn deserialize() {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    assert_eq!(
+        TargetAttributes {
+            target_xattrs: vec![("user.origin".to_string(), "updatehub".to_string())]
+                .into_iter()
+                .collect(),
+            target_selinux_context: Some("system_u:object_r:etc_t:s0".to_string()),
+            target_acl: Some(Acl(vec![
+                AclEntry { tag: AclTag::UserObj, perm: 6 },
+                AclEntry { tag: AclTag::User(1000), perm: 4 },
+                AclEntry { tag: AclTag::GroupObj, perm: 4 },
+                AclEntry { tag: AclTag::Mask, perm: 4 },
+                AclEntry { tag: AclTag::Other, perm: 0 },
+            ])),
+        },
+        serde_json::from_value::<TargetAttributes>(json!({
+            "target-xattrs": { "user.origin": "updatehub" },
+            "target-selinux-context": "system_u:object_r:etc_t:s0",
+            "target-acl": "u::rw-,o::---,g::r--,u:1000:r--,m::r--"
+        }))
+        .unwrap()
+    );
+
+    assert_eq!(json!({}), serde_json::to_value(TargetAttributes::default()).unwrap());
+    assert_eq!(
+        json!("user::rw-,group::r--,other::r--"),
+        serde_json::to_value(Acl::try_from("user::rw- group::r-- other::r--".to_string()).unwrap())
+            .unwrap()
+    );
+    for invalid in &["user::rw-", "user::rw-,user::r--,group::r--,other::---", "u:x:rw-", "u::rwz"]
+    {
+        assert!(serde_json::from_value::<Acl>(json!(invalid)).is_err(), "{}", invalid);
+    }
+    assert!(serde_json::from_value::<Acl>(json!("u::rw-,u:1:r--,g::r--,o::---")).is_err());
+}
@@ -2,14 +2,15 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Information about formatting the partition before installing.
-#[derive(PartialEq, Debug, Deserialize, Default)]
+#[derive(PartialEq, Debug, Deserialize, Serialize, Default)]
 #[serde(rename_all = "kebab-case")]
 pub struct TargetFormat {
     #[serde(rename = "format?", default)]
     pub should_format: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub format_options: Option<String>,
 }
 
@@ -2,20 +2,26 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::{de, Deserialize, Deserializer};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 /// Options to set permissions after installing on target.
-#[derive(PartialEq, Debug, Deserialize, Default)]
+#[derive(PartialEq, Debug, Deserialize, Serialize, Default)]
 #[serde(rename_all = "kebab-case")]
 #[serde(default)]
 pub struct TargetPermissions {
-    #[serde(deserialize_with = "optional_octal_from_str")]
+    #[serde(
+        deserialize_with = "optional_octal_from_str",
+        serialize_with = "optional_octal_to_str",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub target_mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub target_gid: Option<Gid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub target_uid: Option<Uid>,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Gid {
     /// Group name.
@@ -25,7 +31,7 @@ pub enum Gid {
     Number(u32),
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Uid {
     /// User name.
@@ -44,6 +50,13 @@ where
     })
 }
 
+fn optional_octal_to_str<S>(mode: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    mode.map(|m| format!("{:04o}", m)).serialize(serializer)
+}
+
 #[test]
 fn deserialize() {
     use pretty_assertions::assert_eq;
@@ -76,3 +89,21 @@ fn deserialize() {
         .unwrap()
     );
 }
+
+#[test]
+fn serialize() {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    let value = json!({
+        "target-mode": "0644",
+        "target-uid": "user",
+        "target-gid": 1000
+    });
+    assert_eq!(
+        value,
+        serde_json::to_value(serde_json::from_value::<TargetPermissions>(value.clone()).unwrap())
+            .unwrap()
+    );
+    assert_eq!(json!({}), serde_json::to_value(TargetPermissions::default()).unwrap());
+}
@@ -2,16 +2,46 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::Deserialize;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::PathBuf};
 
 /// The type the device that will receive the update.
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "lowercase", tag = "target-type", content = "target")]
 pub enum TargetType {
     Device(PathBuf),
     UBIVolume(String),
     MTDName(String),
+    /// Partition by its GPT partition name, as in `/dev/disk/by-partlabel`.
+    PartLabel(String),
+    /// Partition by its GPT unique partition GUID, or MBR disk signature
+    /// and partition number as in `1234abcd-02`, as in
+    /// `/dev/disk/by-partuuid`.
+    PartUUID(String),
+    /// Partition by the label of its filesystem, as in `/dev/disk/by-label`.
+    FsLabel(String),
+    /// Partition by its GPT partition name, always read from the partition
+    /// tables themselves.
+    #[serde(rename = "gpt-partition-name")]
+    GptPartitionName(String),
+    /// Filesystem image stored as a regular file, attached to a loop
+    /// device when it needs to be mounted.
+    ImageFile(PathBuf),
+}
+
+impl fmt::Display for TargetType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetType::Device(path) => write!(f, "device {}", path.display()),
+            TargetType::UBIVolume(name) => write!(f, "ubivolume {}", name),
+            TargetType::MTDName(name) => write!(f, "mtdname {}", name),
+            TargetType::PartLabel(name) => write!(f, "partlabel {}", name),
+            TargetType::PartUUID(uuid) => write!(f, "partuuid {}", uuid),
+            TargetType::FsLabel(label) => write!(f, "fslabel {}", label),
+            TargetType::GptPartitionName(name) => write!(f, "gpt-partition-name {}", name),
+            TargetType::ImageFile(path) => write!(f, "imagefile {}", path.display()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -38,5 +68,38 @@ mod test {
             }))
             .unwrap()
         );
+        assert_eq!(
+            TargetType::PartUUID("1234abcd-02".to_string()),
+            serde_json::from_value::<TargetType>(json!({
+                "target-type": "partuuid",
+                "target": "1234abcd-02",
+            }))
+            .unwrap()
+        );
+        assert_eq!(
+            TargetType::GptPartitionName("rootfs-a".to_string()),
+            serde_json::from_value::<TargetType>(json!({
+                "target-type": "gpt-partition-name",
+                "target": "rootfs-a",
+            }))
+            .unwrap()
+        );
+        assert_eq!(
+            TargetType::ImageFile(PathBuf::from("/data/apps/app.img")),
+            serde_json::from_value::<TargetType>(json!({
+                "target-type": "imagefile",
+                "target": "/data/apps/app.img",
+            }))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(TargetType::Device(PathBuf::from("/dev/sdb")).to_string(), "device /dev/sdb");
+        assert_eq!(
+            TargetType::GptPartitionName("rootfs-a".to_string()).to_string(),
+            "gpt-partition-name rootfs-a"
+        );
     }
 }
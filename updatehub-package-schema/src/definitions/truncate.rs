@@ -2,11 +2,11 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// True if the file pointed to by the `target_path` should be open in
 /// truncate mode (erase content before writing).
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Deserialize, Serialize)]
 pub struct Truncate(pub bool);
 
 impl Default for Truncate {
@@ -0,0 +1,87 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// UBI volume the agent creates, resizes, renames or removes before the
+/// objects are installed. The `name` and `rename-from` fields may have the
+/// same placeholders as `objects-template`, so a single declaration covers
+/// the volume of each installation set.
+#[derive(PartialEq, Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct UbiVolume {
+    pub name: String,
+    /// UBI device holding the volume, by default the one already holding it
+    /// or `/dev/ubi0` when it is created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<PathBuf>,
+    /// Size in bytes, rounded up to whole logical erase blocks.
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub volume_type: UbiVolumeType,
+    /// Existing volume renamed to `name` when there is no volume with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rename_from: Option<String>,
+    /// Removes the volume, when present, instead.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub remove: bool,
+}
+
+/// Static volumes hold read-only data whose size is tracked by UBI, a
+/// volume is recreated when its type changes.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum UbiVolumeType {
+    Dynamic,
+    Static,
+}
+
+impl Default for UbiVolumeType {
+    fn default() -> Self {
+        UbiVolumeType::Dynamic
+    }
+}
+
+#[test]
+fn deserialize() {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    assert_eq!(
+        UbiVolume {
+            name: "rootfs_${set}".to_string(),
+            device: None,
+            size: 64 * 1024 * 1024,
+            volume_type: UbiVolumeType::Static,
+            rename_from: Some("system_${set}".to_string()),
+            remove: false,
+        },
+        serde_json::from_value::<UbiVolume>(json!({
+            "name": "rootfs_${set}",
+            "size": 67108864,
+            "volume-type": "static",
+            "rename-from": "system_${set}"
+        }))
+        .unwrap()
+    );
+
+    assert_eq!(
+        UbiVolume {
+            name: "data".to_string(),
+            device: Some(PathBuf::from("/dev/ubi1")),
+            size: 0,
+            volume_type: UbiVolumeType::Dynamic,
+            rename_from: None,
+            remove: true,
+        },
+        serde_json::from_value::<UbiVolume>(json!({
+            "name": "data",
+            "device": "/dev/ubi1",
+            "remove": true
+        }))
+        .unwrap()
+    );
+}
@@ -0,0 +1,61 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Image, usually the bootloader, written to a hardware boot partition of
+/// an eMMC.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct EmmcBoot {
+    pub filename: String,
+    pub size: u64,
+    pub sha256sum: String,
+    /// Boot partition, as `/dev/mmcblk0boot1`, or the eMMC itself, as
+    /// `/dev/mmcblk0`, to write the boot partition the device doesn't boot
+    /// from.
+    pub target: PathBuf,
+
+    #[serde(default)]
+    pub compressed: bool,
+    #[serde(default)]
+    pub required_uncompressed_size: u64,
+    /// Makes the written partition the one the device boots from, through
+    /// the `PARTITION_CONFIG` register of the eMMC, once all the objects
+    /// are installed and it is read back.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub switch_boot_partition: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
+}
+
+#[test]
+fn deserialize() {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    assert_eq!(
+        EmmcBoot {
+            filename: "u-boot.imx".to_string(),
+            size: 1024,
+            sha256sum: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                .to_string(),
+            target: PathBuf::from("/dev/mmcblk0"),
+
+            compressed: false,
+            required_uncompressed_size: 0,
+            switch_boot_partition: true,
+            install_if: None,
+        },
+        serde_json::from_value::<EmmcBoot>(json!({
+            "filename": "u-boot.imx",
+            "size": 1024,
+            "sha256sum": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "target": "/dev/mmcblk0",
+            "switch-boot-partition": true
+        }))
+        .unwrap()
+    );
+}
@@ -0,0 +1,180 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::definitions::{DeltaFormat, Filesystem, TargetPermissions, TargetType};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Set of file changes applied to a mounted filesystem. The object is a tar
+/// archive, which may be compressed, whose first entry is `manifest.json`
+/// holding a [`FileDeltaManifest`], followed by the content of the files and
+/// patches, each stored under the path it changes.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileDelta {
+    pub filename: String,
+    pub filesystem: Filesystem,
+    pub size: u64,
+    pub sha256sum: String,
+    #[serde(flatten)]
+    pub target_type: TargetType,
+    pub target_path: PathBuf,
+
+    /// Device holding the matching filesystem of the installation set the
+    /// device runs from, which is cloned into the target before the changes
+    /// are applied. As the `source` of [`RawDelta`](crate::objects::RawDelta)
+    /// objects, it may have active set placeholders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+    #[serde(default)]
+    pub mount_options: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
+}
+
+/// Changes carried by a [`FileDelta`] object. They are applied by kind, in
+/// the order deletions, directories, files and patches, symbolic links and
+/// permissions, with paths relative to the object's `target-path`.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileDeltaManifest {
+    pub changes: Vec<FileChange>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum FileChange {
+    /// Removes a file or a whole directory.
+    Delete { path: PathBuf },
+    /// Creates a directory, along with its parents.
+    Directory {
+        path: PathBuf,
+        #[serde(flatten)]
+        permissions: TargetPermissions,
+    },
+    /// Adds or replaces a file with the content stored in the archive.
+    #[serde(rename_all = "kebab-case")]
+    File {
+        path: PathBuf,
+        sha256sum: String,
+        #[serde(flatten)]
+        permissions: TargetPermissions,
+    },
+    /// Replaces a file applying the binary delta stored in the archive to
+    /// its current content.
+    #[serde(rename_all = "kebab-case")]
+    Patch {
+        path: PathBuf,
+        delta_format: DeltaFormat,
+        source_sha256sum: String,
+        sha256sum: String,
+        #[serde(flatten)]
+        permissions: TargetPermissions,
+    },
+    /// Creates or replaces a symbolic link.
+    Symlink { path: PathBuf, target: PathBuf },
+    /// Changes the permissions of an existing entry.
+    Permissions {
+        path: PathBuf,
+        #[serde(flatten)]
+        permissions: TargetPermissions,
+    },
+}
+
+impl FileChange {
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            FileChange::Delete { path }
+            | FileChange::Directory { path, .. }
+            | FileChange::File { path, .. }
+            | FileChange::Patch { path, .. }
+            | FileChange::Symlink { path, .. }
+            | FileChange::Permissions { path, .. } => path,
+        }
+    }
+}
+
+#[test]
+fn deserialize() {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    assert_eq!(
+        FileDelta {
+            filename: "app.delta.tar".to_string(),
+            filesystem: Filesystem::Ext4,
+            size: 1024,
+            sha256sum: "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722"
+                .to_string(),
+            target_type: TargetType::Device(PathBuf::from("/dev/mmcblk0p6")),
+            target_path: PathBuf::from("/"),
+
+            source: Some(PathBuf::from("/dev/mmcblk0p5")),
+            mount_options: String::default(),
+            install_if: None,
+        },
+        serde_json::from_value::<FileDelta>(json!({
+            "filename": "app.delta.tar",
+            "filesystem": "ext4",
+            "size": 1024,
+            "sha256sum": "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722",
+            "target-type": "device",
+            "target": "/dev/mmcblk0p6",
+            "target-path": "/",
+            "source": "/dev/mmcblk0p5"
+        }))
+        .unwrap()
+    );
+
+    assert_eq!(
+        FileDeltaManifest {
+            changes: vec![
+                FileChange::Delete { path: PathBuf::from("lib/old.so") },
+                FileChange::File {
+                    path: PathBuf::from("bin/app"),
+                    sha256sum: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                        .to_string(),
+                    permissions: TargetPermissions {
+                        target_mode: Some(0o755),
+                        ..TargetPermissions::default()
+                    },
+                },
+                FileChange::Patch {
+                    path: PathBuf::from("lib/app.so"),
+                    delta_format: DeltaFormat::Bsdiff,
+                    source_sha256sum:
+                        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                            .to_string(),
+                    sha256sum: "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722"
+                        .to_string(),
+                    permissions: TargetPermissions::default(),
+                },
+                FileChange::Symlink {
+                    path: PathBuf::from("lib/app.so.1"),
+                    target: PathBuf::from("app.so"),
+                },
+            ],
+        },
+        serde_json::from_value::<FileDeltaManifest>(json!({
+            "changes": [
+                { "type": "delete", "path": "lib/old.so" },
+                {
+                    "type": "file",
+                    "path": "bin/app",
+                    "sha256sum": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                    "target-mode": "0755"
+                },
+                {
+                    "type": "patch",
+                    "path": "lib/app.so",
+                    "delta-format": "bsdiff",
+                    "source-sha256sum": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                    "sha256sum": "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722"
+                },
+                { "type": "symlink", "path": "lib/app.so.1", "target": "app.so" }
+            ]
+        }))
+        .unwrap()
+    );
+}
@@ -3,9 +3,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::definitions::{InstallIfDifferent, TargetType};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Flash {
     pub filename: String,
@@ -14,7 +14,10 @@ pub struct Flash {
     #[serde(flatten)]
     pub target: TargetType,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub install_if_different: Option<InstallIfDifferent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
 }
 
 #[test]
@@ -31,6 +34,7 @@ fn deserialize() {
             target: TargetType::Device(std::path::PathBuf::from("/dev/sda")),
 
             install_if_different: None,
+            install_if: None,
         },
         serde_json::from_value::<Flash>(json!({
             "filename": "etc/passwd",
@@ -3,25 +3,28 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::definitions::InstallIfDifferent;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct Imxkobs {
     pub filename: String,
     pub size: u64,
     pub sha256sum: String,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub install_if_different: Option<InstallIfDifferent>,
     #[serde(rename = "1k_padding")]
     #[serde(default)]
     pub padding_1k: bool,
     #[serde(default)]
     pub search_exponent: usize,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub chip_0_device_path: Option<PathBuf>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub chip_1_device_path: Option<PathBuf>,
+    #[serde(default, rename = "install-if", skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
 }
 
 #[test]
@@ -41,6 +44,7 @@ fn deserialize() {
             search_exponent: 2,
             chip_0_device_path: Some(PathBuf::from("/dev/sda1")),
             chip_1_device_path: Some(PathBuf::from("/dev/sda2")),
+            install_if: None,
         },
         serde_json::from_value::<Imxkobs>(json!({
             "filename": "imxkobs-filename",
@@ -2,42 +2,144 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+mod chunked;
 mod copy;
+mod emmc_boot;
+mod file_delta;
 mod flash;
 mod imxkobs;
 mod mender;
 mod raw;
+mod raw_delta;
 mod tarball;
 mod test;
 mod ubifs;
 mod zephyr;
 
+mod template;
 mod update_package;
+mod validation;
 
 /// Internal structures in the Objects for some type validation
 pub mod definitions;
 /// Objects representing each possible install mode
 pub mod objects {
     pub use crate::{
-        copy::Copy, flash::Flash, imxkobs::Imxkobs, raw::Raw, tarball::Tarball, test::Test,
+        chunked::Chunked,
+        copy::Copy,
+        emmc_boot::EmmcBoot,
+        file_delta::{FileChange, FileDelta, FileDeltaManifest},
+        flash::Flash,
+        imxkobs::Imxkobs,
+        mender::Mender,
+        raw::Raw,
+        raw_delta::RawDelta,
+        tarball::Tarball,
+        test::Test,
         ubifs::Ubifs,
+        zephyr::Zephyr,
     };
 }
-pub use update_package::{SupportedHardware, UpdatePackage};
+pub use template::ObjectTemplate;
+pub use update_package::{SupportedHardware, UpdatePackage, UpdatePackageBuilder};
+pub use validation::{Diagnostic, Diagnostics, ValidationContext, SCHEMA_VERSION};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Represents the install mode for the object data
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
 #[serde(tag = "mode")]
 #[serde(rename_all = "lowercase")]
 pub enum Object {
+    Chunked(Box<objects::Chunked>),
     Copy(Box<objects::Copy>),
+    #[serde(rename = "emmc-boot")]
+    EmmcBoot(Box<objects::EmmcBoot>),
+    #[serde(rename = "file-delta")]
+    FileDelta(Box<objects::FileDelta>),
     Flash(Box<objects::Flash>),
     Imxkobs(Box<objects::Imxkobs>),
+    Mender(Box<objects::Mender>),
     Raw(Box<objects::Raw>),
+    #[serde(rename = "raw-delta")]
+    RawDelta(Box<objects::RawDelta>),
     Tarball(Box<objects::Tarball>),
     Test(Box<objects::Test>),
     Ubifs(Box<objects::Ubifs>),
-    // FIXME: Add support for the missing modes: Mende Zephyr
+    Zephyr(Box<objects::Zephyr>),
 }
+
+impl Object {
+    /// Name of the install mode, as used in the `mode` field.
+    pub fn mode(&self) -> &'static str {
+        match self {
+            Object::Chunked(_) => "chunked",
+            Object::Copy(_) => "copy",
+            Object::EmmcBoot(_) => "emmc-boot",
+            Object::FileDelta(_) => "file-delta",
+            Object::Flash(_) => "flash",
+            Object::Imxkobs(_) => "imxkobs",
+            Object::Mender(_) => "mender",
+            Object::Raw(_) => "raw",
+            Object::RawDelta(_) => "raw-delta",
+            Object::Tarball(_) => "tarball",
+            Object::Test(_) => "test",
+            Object::Ubifs(_) => "ubifs",
+            Object::Zephyr(_) => "zephyr",
+        }
+    }
+
+    /// Condition on the device attributes and identity for the object to
+    /// be installed, the object is always installed when there is none.
+    pub fn install_if(&self) -> Option<&str> {
+        match self {
+            Object::Chunked(o) => o.install_if.as_deref(),
+            Object::Copy(o) => o.install_if.as_deref(),
+            Object::EmmcBoot(o) => o.install_if.as_deref(),
+            Object::FileDelta(o) => o.install_if.as_deref(),
+            Object::Flash(o) => o.install_if.as_deref(),
+            Object::Imxkobs(o) => o.install_if.as_deref(),
+            Object::Mender(o) => o.install_if.as_deref(),
+            Object::Raw(o) => o.install_if.as_deref(),
+            Object::RawDelta(o) => o.install_if.as_deref(),
+            Object::Tarball(o) => o.install_if.as_deref(),
+            Object::Test(o) => o.install_if.as_deref(),
+            Object::Ubifs(o) => o.install_if.as_deref(),
+            Object::Zephyr(o) => o.install_if.as_deref(),
+        }
+    }
+
+    /// Target the object is written to, if it is given as a target type.
+    pub fn target_type(&self) -> Option<&definitions::TargetType> {
+        match self {
+            Object::Chunked(o) => Some(&o.target_type),
+            Object::Copy(o) => Some(&o.target_type),
+            Object::FileDelta(o) => Some(&o.target_type),
+            Object::Flash(o) => Some(&o.target),
+            Object::Mender(o) => Some(&o.target_type),
+            Object::Raw(o) => Some(&o.target_type),
+            Object::RawDelta(o) => Some(&o.target_type),
+            Object::Tarball(o) => Some(&o.target),
+            Object::Ubifs(o) => Some(&o.target),
+            Object::Zephyr(o) => Some(&o.target),
+            Object::EmmcBoot(_) | Object::Imxkobs(_) | Object::Test(_) => None,
+        }
+    }
+}
+
+macro_rules! impl_from_object_types {
+    ($($kind:ident),*) => {
+        $(
+            impl From<objects::$kind> for Object {
+                fn from(obj: objects::$kind) -> Self {
+                    Object::$kind(Box::new(obj))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_object_types!(
+    Chunked, Copy, EmmcBoot, FileDelta, Flash, Imxkobs, Mender, Raw, RawDelta, Tarball, Test,
+    Ubifs, Zephyr
+);
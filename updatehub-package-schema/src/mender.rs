@@ -2,19 +2,31 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::Deserialize;
+use crate::definitions::{ChunkSize, TargetType};
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub struct Mender {
     pub filename: String,
     pub size: u64,
     pub sha256sum: String,
+    #[serde(flatten)]
+    pub target_type: TargetType,
+
+    #[serde(default)]
+    pub required_uncompressed_size: u64,
+    #[serde(default)]
+    pub chunk_size: ChunkSize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
 }
 
 #[test]
 fn deserialize() {
     use pretty_assertions::assert_eq;
     use serde_json::json;
+    use std::path::PathBuf;
 
     assert_eq!(
         Mender {
@@ -22,11 +34,19 @@ fn deserialize() {
             size: 1024,
             sha256sum: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
                 .to_string(),
+            target_type: TargetType::Device(PathBuf::from("/dev/mmcblk0p2")),
+
+            required_uncompressed_size: 2048,
+            chunk_size: ChunkSize::default(),
+            install_if: None,
         },
         serde_json::from_value::<Mender>(json!({
             "filename": "artifact.mender",
             "size": 1024,
             "sha256sum": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "target-type": "device",
+            "target": "/dev/mmcblk0p2",
+            "required-uncompressed-size": 2048
         }))
         .unwrap()
     );
@@ -2,10 +2,10 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::definitions::{ChunkSize, Count, InstallIfDifferent, Skip, TargetType, Truncate};
-use serde::Deserialize;
+use crate::definitions::{Bmap, ChunkSize, Count, InstallIfDifferent, Skip, TargetType, Truncate};
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Raw {
     pub filename: String,
@@ -14,6 +14,7 @@ pub struct Raw {
     #[serde(flatten)]
     pub target_type: TargetType,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub install_if_different: Option<InstallIfDifferent>,
     #[serde(default)]
     pub compressed: bool,
@@ -29,10 +30,24 @@ pub struct Raw {
     pub count: Count,
     #[serde(default)]
     pub truncate: Truncate,
+    /// Block map of the image, only its mapped blocks are written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bmap: Option<Bmap>,
+    /// Discards the holes of sparse images, so the storage can reclaim
+    /// them.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub discard_holes: bool,
+    /// Reads back each chunk from the target, only writing the ones which
+    /// are different.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip_unchanged: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
 }
 
 #[test]
 fn deserialize() {
+    use crate::definitions::BlockRange;
     use pretty_assertions::assert_eq;
     use serde_json::json;
     use std::path::PathBuf;
@@ -53,6 +68,14 @@ fn deserialize() {
             seek: u64::default(),
             count: Count::default(),
             truncate: Truncate::default(),
+            bmap: Some(Bmap {
+                image_size: 4096,
+                block_size: 1024,
+                mapped_blocks: vec![BlockRange { first: 0, last: 1 }],
+            }),
+            discard_holes: true,
+            skip_unchanged: true,
+            install_if: None,
         },
         serde_json::from_value::<Raw>(json!({
             "filename": "etc/passwd",
@@ -62,7 +85,10 @@ fn deserialize() {
             "target-type": "device",
             "target": "/dev/sdb",
             "compressed": true,
-            "required-uncompressed-size": 2048
+            "required-uncompressed-size": 2048,
+            "bmap": { "image-size": 4096, "block-size": 1024, "mapped-blocks": ["0-1"] },
+            "discard-holes": true,
+            "skip-unchanged": true
         }))
         .unwrap()
     );
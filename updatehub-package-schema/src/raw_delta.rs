@@ -0,0 +1,79 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::definitions::{ChunkSize, DeltaFormat, TargetType};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct RawDelta {
+    pub filename: String,
+    pub size: u64,
+    pub sha256sum: String,
+    #[serde(flatten)]
+    pub target_type: TargetType,
+
+    pub delta_format: DeltaFormat,
+    /// Device holding the matching partition of the installation set the
+    /// device runs from, usually given through the `${active_set}` and
+    /// `${active_set_index}` placeholders, as `/dev/mmcblk0p${active_set_index+2}`,
+    /// which are expanded at install time. A fixed device is only right for
+    /// devices with two installation sets, where it is the other set.
+    pub source: PathBuf,
+    pub source_size: u64,
+    pub source_sha256sum: String,
+    pub result_size: u64,
+    pub result_sha256sum: String,
+
+    #[serde(default)]
+    pub chunk_size: ChunkSize,
+    #[serde(default)]
+    pub seek: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
+}
+
+#[test]
+fn deserialize() {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    assert_eq!(
+        RawDelta {
+            filename: "rootfs.ext4.bsdiff".to_string(),
+            size: 1024,
+            sha256sum: "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722"
+                .to_string(),
+            target_type: TargetType::Device(PathBuf::from("/dev/mmcblk0p3")),
+
+            delta_format: DeltaFormat::Bsdiff,
+            source: PathBuf::from("/dev/mmcblk0p2"),
+            source_size: 4096,
+            source_sha256sum: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                .to_string(),
+            result_size: 8192,
+            result_sha256sum: "5994471abb01112afcc18159f6cc74b4f511b99806da59b3caf5a9c173cacfc5"
+                .to_string(),
+
+            chunk_size: ChunkSize::default(),
+            seek: 0,
+            install_if: None,
+        },
+        serde_json::from_value::<RawDelta>(json!({
+            "filename": "rootfs.ext4.bsdiff",
+            "size": 1024,
+            "sha256sum": "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722",
+            "target-type": "device",
+            "target": "/dev/mmcblk0p3",
+            "delta-format": "bsdiff",
+            "source": "/dev/mmcblk0p2",
+            "source-size": 4096,
+            "source-sha256sum": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "result-size": 8192,
+            "result-sha256sum": "5994471abb01112afcc18159f6cc74b4f511b99806da59b3caf5a9c173cacfc5",
+        }))
+        .unwrap()
+    );
+}
@@ -2,11 +2,13 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::definitions::{Filesystem, TargetFormat, TargetType};
-use serde::Deserialize;
+use crate::definitions::{
+    Filesystem, InstallIfDifferent, TargetFormat, TargetPermissions, TargetType,
+};
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Tarball {
     pub filename: String,
@@ -17,6 +19,18 @@ pub struct Tarball {
     pub target: TargetType,
     pub target_path: PathBuf,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub install_if_different: Option<InstallIfDifferent>,
+    /// File, relative to `target-path`, checked by `install-if-different`.
+    /// It holds the object's checksum, written after each installation,
+    /// or the version matched by a pattern.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if_different_file: Option<PathBuf>,
+    #[serde(flatten)]
+    pub target_permissions: TargetPermissions,
+    /// Remove the content of `target-path` before extracting the archive.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub wipe_target: bool,
     #[serde(default)]
     pub compressed: bool,
     #[serde(default)]
@@ -25,6 +39,8 @@ pub struct Tarball {
     pub target_format: TargetFormat,
     #[serde(default)]
     pub mount_options: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
 }
 
 #[test]
@@ -42,10 +58,15 @@ fn deserialize() {
             target: TargetType::Device(std::path::PathBuf::from("/dev/sda")),
             target_path: PathBuf::from("/"),
 
+            install_if_different: Some(InstallIfDifferent::CheckSum),
+            install_if_different_file: Some(PathBuf::from("etc/rootfs.sha256sum")),
+            target_permissions: TargetPermissions::default(),
+            wipe_target: true,
             compressed: false,
             required_uncompressed_size: 0,
             target_format: TargetFormat::default(),
             mount_options: String::default(),
+            install_if: None,
         },
         serde_json::from_value::<Tarball>(json!({
             "filename": "etc/passwd",
@@ -54,7 +75,10 @@ fn deserialize() {
             "target-type": "device",
             "target": "/dev/sda",
             "filesystem": "ext4",
-            "target-path": "/"
+            "target-path": "/",
+            "install-if-different": "sha256sum",
+            "install-if-different-file": "etc/rootfs.sha256sum",
+            "wipe-target": true
         }))
         .unwrap()
     );
@@ -0,0 +1,436 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{definitions::UbiVolume, Diagnostic, Diagnostics, Object, UpdatePackage};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+
+/// Number of installation sets templated packages are validated for, as
+/// only the agent knows how many sets the device has.
+pub(crate) const TEMPLATE_INSTALLATION_SETS: usize = 2;
+
+/// Fields of the templated objects which may have placeholders.
+const TEMPLATED_FIELDS: &[&str] = &["target", "target-path", "seek"];
+
+/// Object of `objects-template`, whose `target`, `target-path` and `seek`
+/// fields may have placeholders. These are parsed along with the package,
+/// so malformed placeholders and objects are refused upfront.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ObjectTemplate {
+    /// Fields which are the same for every installation set.
+    fields: Map<String, Value>,
+    templated: Vec<(&'static str, Template)>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+/// Placeholders refer either to the set the object is expanded for or,
+/// when `active`, to the set the device runs from.
+#[derive(Debug, PartialEq, Clone)]
+enum Segment {
+    Text(String),
+    SetName { active: bool },
+    SetIndex { active: bool, factor: i64, offset: i64 },
+}
+
+impl ObjectTemplate {
+    /// Expands the placeholders for the set, the returned diagnostic path
+    /// is relative to the object.
+    pub fn expand(&self, set: usize) -> Result<Object, Diagnostic> {
+        let mut fields = self.fields.clone();
+        for (key, template) in &self.templated {
+            let expanded = template
+                .expand(set)
+                .map_err(|message| Diagnostic { path: format!(".{}", key), message })?;
+            let value = if *key == "seek" {
+                Value::from(expanded.parse::<u64>().map_err(|_| Diagnostic {
+                    path: ".seek".to_string(),
+                    message: format!("'{}' is not a valid offset", expanded),
+                })?)
+            } else {
+                Value::from(expanded)
+            };
+            fields.insert((*key).to_string(), value);
+        }
+
+        serde_json::from_value(Value::Object(fields))
+            .map_err(|e| Diagnostic { path: String::default(), message: e.to_string() })
+    }
+}
+
+impl<'de> Deserialize<'de> for ObjectTemplate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut fields = Map::deserialize(deserializer)?;
+        let mut templated = Vec::default();
+        for key in TEMPLATED_FIELDS {
+            if let Some(Value::String(field)) = fields.get(*key) {
+                let template = Template::parse(field)
+                    .map_err(|message| de::Error::custom(format!("{}: {}", key, message)))?;
+                fields.remove(*key);
+                templated.push((*key, template));
+            }
+        }
+
+        let template = ObjectTemplate { fields, templated };
+        template.expand(0).map_err(|d| de::Error::custom(format!("{}: {}", d.path, d.message)))?;
+
+        Ok(template)
+    }
+}
+
+impl Serialize for ObjectTemplate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut fields = self.fields.clone();
+        for (key, template) in &self.templated {
+            fields.insert((*key).to_string(), Value::from(template.source.clone()));
+        }
+        fields.serialize(serializer)
+    }
+}
+
+impl Template {
+    /// Parses the `${set}` and `${set_index}` placeholders, the latter can
+    /// be scaled and shifted as in `${set_index*1024+2}`.
+    fn parse(field: &str) -> Result<Self, String> {
+        let mut segments = Vec::default();
+        let mut rest = field;
+
+        while let Some(start) = rest.find("${") {
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| format!("unterminated placeholder in '{}'", field))?;
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            segments.push(Segment::parse(&rest[start + 2..end])?);
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+
+        Ok(Template { source: field.to_string(), segments })
+    }
+
+    fn expand(&self, set: usize) -> Result<String, String> {
+        self.expand_for(set, false)
+    }
+
+    /// Expands the placeholders of either the set the object is for or the
+    /// active one, refusing the other kind.
+    fn expand_for(&self, set: usize, active: bool) -> Result<String, String> {
+        let mut expanded = String::default();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => expanded.push_str(text),
+                Segment::SetName { active: a } | Segment::SetIndex { active: a, .. }
+                    if *a != active =>
+                {
+                    return Err(if active {
+                        format!("only active set placeholders are allowed in '{}'", self.source)
+                    } else {
+                        format!("active set placeholders are not allowed in '{}'", self.source)
+                    })
+                }
+                Segment::SetName { .. } => expanded.push((b'a' + set as u8) as char),
+                Segment::SetIndex { factor, offset, .. } => expanded.push_str(
+                    &(set as i64)
+                        .checked_mul(*factor)
+                        .and_then(|v| v.checked_add(*offset))
+                        .filter(|v| *v >= 0)
+                        .map(|v| v.to_string())
+                        .ok_or_else(|| {
+                            format!("placeholder in '{}' is out of range", self.source)
+                        })?,
+                ),
+            }
+        }
+
+        Ok(expanded)
+    }
+}
+
+impl Segment {
+    fn parse(placeholder: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid placeholder '${{{}}}'", placeholder);
+        let expression: String = placeholder.chars().filter(|c| !c.is_whitespace()).collect();
+        let (active, expression) = match expression.strip_prefix("active_") {
+            Some(expression) => (true, expression),
+            None => (false, expression.as_str()),
+        };
+
+        if expression == "set" {
+            return Ok(Segment::SetName { active });
+        }
+
+        let rest = expression.strip_prefix("set_index").ok_or_else(invalid)?;
+        let (factor, rest) = match rest.strip_prefix('*') {
+            Some(rest) => {
+                let end = rest.find(&['+', '-'][..]).unwrap_or(rest.len());
+                (rest[..end].parse::<i64>().map_err(|_| invalid())?, &rest[end..])
+            }
+            None => (1, rest),
+        };
+        let offset = match rest.chars().next() {
+            None => 0,
+            Some('+') => rest[1..].parse::<i64>().map_err(|_| invalid())?,
+            Some('-') => -rest[1..].parse::<i64>().map_err(|_| invalid())?,
+            Some(_) => return Err(invalid()),
+        };
+
+        Ok(Segment::SetIndex { active, factor, offset })
+    }
+}
+
+impl UpdatePackage {
+    /// Expands `objects-template` into the objects of the given number of
+    /// installation sets, an empty list is returned when the package has no
+    /// template.
+    pub fn expand_objects_template(&self, sets: usize) -> Result<Vec<Vec<Object>>, Diagnostics> {
+        if self.objects_template.is_empty() {
+            return Ok(Vec::default());
+        }
+
+        let mut expanded = Vec::default();
+        let mut diagnostics = Vec::default();
+        for set in 0..sets {
+            let mut objects = Vec::default();
+            for (i, template) in self.objects_template.iter().enumerate() {
+                match template.expand(set) {
+                    Ok(object) => objects.push(object),
+                    Err(diagnostic) => {
+                        let diagnostic = Diagnostic {
+                            path: format!("$.objects-template[{}]{}", i, diagnostic.path),
+                            message: diagnostic.message,
+                        };
+                        // Most problems are the same for every set
+                        if !diagnostics.contains(&diagnostic) {
+                            diagnostics.push(diagnostic);
+                        }
+                    }
+                }
+            }
+            expanded.push(objects);
+        }
+
+        if diagnostics.is_empty() {
+            Ok(expanded)
+        } else {
+            Err(Diagnostics(diagnostics))
+        }
+    }
+
+    /// Expands the placeholders of the `ubi-volumes` names for the given
+    /// installation set.
+    pub fn expand_ubi_volumes(&self, set: usize) -> Result<Vec<UbiVolume>, Diagnostics> {
+        let mut volumes = Vec::default();
+        let mut diagnostics = Vec::default();
+        for (i, volume) in self.ubi_volumes.iter().enumerate() {
+            let mut volume = volume.clone();
+            let fields = std::iter::once(("name", Some(&mut volume.name)))
+                .chain(std::iter::once(("rename-from", volume.rename_from.as_mut())));
+            for (key, field) in fields {
+                let field = match field {
+                    Some(field) => field,
+                    None => continue,
+                };
+                match expand(field, set) {
+                    Ok(expanded) => *field = expanded,
+                    Err(message) => diagnostics.push(Diagnostic {
+                        path: format!("$.ubi-volumes[{}].{}", i, key),
+                        message,
+                    }),
+                }
+            }
+            volumes.push(volume);
+        }
+
+        if diagnostics.is_empty() {
+            Ok(volumes)
+        } else {
+            Err(Diagnostics(diagnostics))
+        }
+    }
+}
+
+impl UpdatePackage {
+    /// Expands the active set placeholders of the delta sources, of the
+    /// objects of the installation set and of the common ones.
+    pub fn expand_active_set(&mut self, set: usize, active: usize) -> Result<(), Diagnostics> {
+        let objects = self.objects.get_mut(set).into_iter().flatten().enumerate();
+        let objects =
+            objects.map(|(i, object)| (format!("$.objects[{}][{}]", set, i), object)).chain(
+                self.common
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(i, object)| (format!("$.common[{}]", i), object)),
+            );
+
+        let mut diagnostics = Vec::default();
+        for (path, object) in objects {
+            let source = match object {
+                Object::RawDelta(o) => &mut o.source,
+                Object::FileDelta(o) => match o.source {
+                    Some(ref mut source) => source,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            match expand_active_set(source, active) {
+                Ok(expanded) => *source = expanded,
+                Err(message) => {
+                    diagnostics.push(Diagnostic { path: format!("{}.source", path), message })
+                }
+            }
+        }
+
+        if diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(Diagnostics(diagnostics))
+        }
+    }
+}
+
+/// Expands the placeholders of a field for the set.
+fn expand(field: &str, set: usize) -> Result<String, String> {
+    Template::parse(field)?.expand(set)
+}
+
+/// Expands the `${active_set}` and `${active_set_index}` placeholders of
+/// the `source` of delta objects, which can be scaled and shifted as the
+/// other placeholders. The source is a partition of the set the device runs
+/// from, which is only known at install time, so it is expanded then.
+pub(crate) fn expand_active_set(field: &Path, active: usize) -> Result<PathBuf, String> {
+    let field = field.to_str().ok_or_else(|| format!("{:?} is not valid UTF-8", field))?;
+    Ok(PathBuf::from(Template::parse(field)?.expand_for(active, true)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(expand("/dev/mmcblk0p${set_index+2}", 0).unwrap(), "/dev/mmcblk0p2");
+        assert_eq!(expand("/dev/mmcblk0p${set_index+2}", 1).unwrap(), "/dev/mmcblk0p3");
+        assert_eq!(expand("rootfs-${set}", 1).unwrap(), "rootfs-b");
+        assert_eq!(expand("${ set_index * 4096 - 1 }", 1).unwrap(), "4095");
+        assert_eq!(expand("/mnt/${set}/${set_index}", 0).unwrap(), "/mnt/a/0");
+        assert_eq!(expand("/dev/sda", 1).unwrap(), "/dev/sda");
+
+        assert!(expand("${set_index-1}", 0).is_err());
+        assert!(expand("${set_index+}", 0).is_err());
+        assert!(expand("${other}", 0).is_err());
+        assert!(expand("${set", 0).is_err());
+        assert!(expand("/dev/mmcblk0p${active_set_index+2}", 0).is_err());
+    }
+
+    #[test]
+    fn active_set_placeholders() {
+        let expand = |field: &str, active| expand_active_set(Path::new(field), active);
+        assert_eq!(
+            expand("/dev/mmcblk0p${active_set_index+2}", 1).unwrap(),
+            Path::new("/dev/mmcblk0p3")
+        );
+        assert_eq!(expand("/dev/rootfs_${active_set}", 2).unwrap(), Path::new("/dev/rootfs_c"));
+        assert_eq!(expand("/dev/mmcblk0p2", 1).unwrap(), Path::new("/dev/mmcblk0p2"));
+        assert!(expand("/dev/mmcblk0p${set_index+2}", 1).is_err());
+    }
+
+    #[test]
+    fn expand_objects() {
+        let package = serde_json::from_value::<UpdatePackage>(json!({
+            "product": "0123456789",
+            "version": "1.0",
+            "objects-template": [{
+                "mode": "raw",
+                "filename": "rootfs.img",
+                "size": 4096,
+                "sha256sum": "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722",
+                "target-type": "device",
+                "target": "/dev/mmcblk0p${set_index+2}",
+                "seek": "${set_index*8}"
+            }]
+        }))
+        .unwrap();
+
+        let sets = package.expand_objects_template(3).unwrap();
+        let targets = sets
+            .iter()
+            .map(|objects| match &objects[0] {
+                Object::Raw(o) => (o.target_type.clone(), o.seek),
+                o => panic!("Unexpected object: {:?}", o),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            targets,
+            vec![
+                (crate::definitions::TargetType::Device("/dev/mmcblk0p2".into()), 0),
+                (crate::definitions::TargetType::Device("/dev/mmcblk0p3".into()), 8),
+                (crate::definitions::TargetType::Device("/dev/mmcblk0p4".into()), 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_delta_sources() {
+        let mut package = serde_json::from_value::<UpdatePackage>(json!({
+            "product": "0123456789",
+            "version": "1.0",
+            "common": [{
+                "mode": "file-delta",
+                "filename": "rootfs.delta",
+                "filesystem": "ext4",
+                "size": 1024,
+                "sha256sum": "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722",
+                "target-type": "device",
+                "target": "/dev/mmcblk0p3",
+                "target-path": "/",
+                "source": "/dev/mmcblk0p${active_set_index+2}"
+            }]
+        }))
+        .unwrap();
+
+        package.expand_active_set(1, 2).unwrap();
+        match &package.common[0] {
+            Object::FileDelta(o) => {
+                assert_eq!(o.source.as_deref(), Some(Path::new("/dev/mmcblk0p4")))
+            }
+            o => panic!("Unexpected object: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn invalid_template() {
+        let parse = |template| {
+            serde_json::from_value::<UpdatePackage>(json!({
+                "product": "0123456789",
+                "version": "1.0",
+                "objects-template": [template]
+            }))
+            .unwrap_err()
+            .to_string()
+        };
+
+        assert!(parse(json!({
+            "mode": "test", "filename": "a", "sha256sum": "", "target": "${set_index+}", "size": 1
+        }))
+        .starts_with("target: invalid placeholder '${set_index+}'"));
+        assert!(parse(json!({
+            "mode": "test", "filename": "b", "sha256sum": "", "target": "${set_index-1}", "size": 1
+        }))
+        .starts_with(".target: placeholder in '${set_index-1}' is out of range"));
+        assert!(parse(json!({ "mode": "test", "filename": "c", "sha256sum": "", "target": "t" }))
+            .contains("missing field `size`"));
+    }
+}
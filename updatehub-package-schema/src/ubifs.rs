@@ -3,9 +3,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::definitions::TargetType;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Ubifs {
     pub filename: String,
@@ -18,6 +18,8 @@ pub struct Ubifs {
     pub compressed: bool,
     #[serde(default)]
     pub required_uncompressed_size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
 }
 
 #[test]
@@ -35,6 +37,7 @@ fn deserialize() {
 
             compressed: true,
             required_uncompressed_size: 2048,
+            install_if: None,
         },
         serde_json::from_value::<Ubifs>(json!({
             "filename": "ubifs",
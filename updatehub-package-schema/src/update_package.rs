@@ -2,22 +2,196 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::Deserialize;
+use crate::{
+    definitions::UbiVolume, validation::ValidationContext, Diagnostics, Object, ObjectTemplate,
+    SCHEMA_VERSION,
+};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct UpdatePackage {
+    #[serde(default, rename = "schema-version", skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u32>,
     #[serde(rename = "product")]
     pub product_uid: String,
     pub version: String,
     #[serde(default, rename = "supported-hardware")]
     pub supported_hardware: SupportedHardware,
-    pub objects: (Vec<crate::Object>, Vec<crate::Object>),
+    /// Objects of each installation set of the device, packages for
+    /// single-bank devices have only one set which is installed in place.
+    #[serde(default)]
+    pub objects: Vec<Vec<Object>>,
+    /// Objects for every installation set of the device, in place of
+    /// `objects`, whose `target`, `target-path` and `seek` fields may have
+    /// placeholders: `${set}` is the set name (`a`, `b`, ...) and
+    /// `${set_index}` its index, which can be scaled and shifted as in
+    /// `${set_index*1024+2}`.
+    #[serde(default, rename = "objects-template", skip_serializing_if = "Vec::is_empty")]
+    pub objects_template: Vec<ObjectTemplate>,
+    /// Objects targeting single-copy storage, such as the bootloader,
+    /// installed once whatever the installation set is.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub common: Vec<Object>,
+    /// UBI volumes set up, in order, before the objects are installed.
+    #[serde(default, rename = "ubi-volumes", skip_serializing_if = "Vec::is_empty")]
+    pub ubi_volumes: Vec<UbiVolume>,
+    /// Allows the package to be installed over a newer firmware version.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub downgrade: bool,
+    /// Versions older than this are refused once the package is installed.
+    #[serde(default, rename = "minimum-security-version", skip_serializing_if = "Option::is_none")]
+    pub minimum_security_version: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+impl UpdatePackage {
+    /// Starts building a package for the given product and version.
+    pub fn builder(
+        product_uid: impl Into<String>,
+        version: impl Into<String>,
+    ) -> UpdatePackageBuilder {
+        UpdatePackageBuilder {
+            product_uid: product_uid.into(),
+            version: version.into(),
+            supported_hardware: SupportedHardware::default(),
+            objects: Vec::default(),
+            common: Vec::default(),
+            ubi_volumes: Vec::default(),
+            downgrade: false,
+            minimum_security_version: None,
+        }
+    }
+
+    /// Whether the package has a single installation set, to be installed
+    /// in place on devices without A/B sets.
+    pub fn is_single_bank(&self) -> bool {
+        self.objects.len() == 1
+    }
+
+    /// Whether the package only updates common objects, leaving the
+    /// installation sets untouched.
+    pub fn is_common_only(&self) -> bool {
+        self.objects_template.is_empty() && self.objects.iter().all(Vec::is_empty)
+    }
+}
+
+/// Builds an [`UpdatePackage`] whose serialization is a metadata document
+/// accepted by the agent.
+#[derive(Debug)]
+pub struct UpdatePackageBuilder {
+    product_uid: String,
+    version: String,
+    supported_hardware: SupportedHardware,
+    objects: Vec<Vec<Object>>,
+    common: Vec<Object>,
+    ubi_volumes: Vec<UbiVolume>,
+    downgrade: bool,
+    minimum_security_version: Option<String>,
+}
+
+impl UpdatePackageBuilder {
+    /// Restricts the package to the given hardware, by default any
+    /// hardware is supported.
+    pub fn supported_hardware<I, S>(mut self, hardware: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.supported_hardware =
+            SupportedHardware::HardwareList(hardware.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Adds an object to the package, `first` is installed when the
+    /// first installation set is the inactive one and `second` otherwise.
+    /// Further sets added by [`Self::multi_set_object`] are kept.
+    pub fn object(mut self, first: impl Into<Object>, second: impl Into<Object>) -> Self {
+        if self.objects.len() < 2 {
+            self.objects.resize_with(2, Vec::default);
+        }
+        self.objects[0].push(first.into());
+        self.objects[1].push(second.into());
+        self
+    }
+
+    /// Adds an object to the package with a copy for each installation
+    /// set, in order, for devices with more than two sets.
+    pub fn multi_set_object<I>(mut self, objects: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Object>,
+    {
+        let objects = objects.into_iter().map(Into::into).collect::<Vec<_>>();
+        self.objects.resize_with(objects.len().max(self.objects.len()), Vec::default);
+        for (set, object) in self.objects.iter_mut().zip(objects) {
+            set.push(object);
+        }
+        self
+    }
+
+    /// Adds an object to a single-bank package, which has only one
+    /// installation set. Mixing it with [`Self::object`] fails the build.
+    pub fn single_bank_object(mut self, object: impl Into<Object>) -> Self {
+        if self.objects.is_empty() {
+            self.objects.push(Vec::default());
+        }
+        self.objects[0].push(object.into());
+        self
+    }
+
+    /// Adds an object installed once whatever the installation set is.
+    pub fn common_object(mut self, object: impl Into<Object>) -> Self {
+        self.common.push(object.into());
+        self
+    }
+
+    /// Declares a UBI volume set up before the objects are installed.
+    pub fn ubi_volume(mut self, volume: UbiVolume) -> Self {
+        self.ubi_volumes.push(volume);
+        self
+    }
+
+    /// Flags the package as an intended downgrade, so it is accepted
+    /// over newer firmware versions.
+    pub fn downgrade(mut self) -> Self {
+        self.downgrade = true;
+        self
+    }
+
+    /// Refuses versions older than `version` once the package is
+    /// installed.
+    pub fn minimum_security_version(mut self, version: impl Into<String>) -> Self {
+        self.minimum_security_version = Some(version.into());
+        self
+    }
+
+    /// Validates the package and returns it.
+    pub fn build(self) -> Result<UpdatePackage, Diagnostics> {
+        let package = UpdatePackage {
+            schema_version: Some(SCHEMA_VERSION),
+            product_uid: self.product_uid,
+            version: self.version,
+            supported_hardware: self.supported_hardware,
+            objects: self.objects,
+            objects_template: Vec::default(),
+            common: self.common,
+            ubi_volumes: self.ubi_volumes,
+            downgrade: self.downgrade,
+            minimum_security_version: self.minimum_security_version,
+        };
+        package.validate(&ValidationContext::default())?;
+
+        Ok(package)
+    }
+}
+
+/// Hardware the package can be installed on. Entries of the list are globs
+/// matched against the hardware name and may be prefixed by `dt:` to match
+/// the device-tree `compatible` strings, by `re:` to use a regular
+/// expression and by `!` to exclude the matching hardware.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum SupportedHardware {
-    #[serde(deserialize_with = "any")]
+    #[serde(deserialize_with = "any", serialize_with = "serialize_any")]
     Any,
     HardwareList(Vec<String>),
 }
@@ -36,6 +210,10 @@ fn any<'de, D: serde::de::Deserializer<'de>>(deserializer: D) -> Result<(), D::E
     }
 }
 
+fn serialize_any<S: serde::Serializer>(serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("any")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -71,4 +249,151 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn round_trip() {
+        let value = json!({
+            "product": "0123456789",
+            "version": "1.0",
+            "supported-hardware": "any",
+            "objects": [
+                [{
+                    "mode": "copy",
+                    "filename": "etc/passwd",
+                    "filesystem": "ext4",
+                    "size": 1024,
+                    "sha256sum": "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722",
+                    "target-type": "device",
+                    "target": "/dev/sda1",
+                    "target-path": "/etc/passwd",
+                    "install-if-different": "sha256sum",
+                    "target-mode": "0640",
+                    "target-uid": 0,
+                    "compressed": false,
+                    "required-uncompressed-size": 0,
+                    "format?": true,
+                    "format-options": "-F",
+                    "mount-options": "",
+                    "install-if": "attributes.display == \"lcd7\""
+                }],
+                [{
+                    "mode": "raw",
+                    "filename": "rootfs.img",
+                    "size": 2048,
+                    "sha256sum": "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722",
+                    "target-type": "mtdname",
+                    "target": "system1",
+                    "compressed": false,
+                    "required-uncompressed-size": 0,
+                    "chunk-size": 131072,
+                    "skip": 0,
+                    "seek": 0,
+                    "count": -1,
+                    "truncate": true
+                }]
+            ]
+        });
+
+        let package = serde_json::from_value::<UpdatePackage>(value.clone()).unwrap();
+        assert_eq!(value, serde_json::to_value(&package).unwrap());
+    }
+
+    #[test]
+    fn builder() {
+        let test_object = |target: &str| crate::objects::Test {
+            filename: "testfile".to_string(),
+            sha256sum: "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722"
+                .to_string(),
+            target: target.to_string(),
+            size: 10,
+            install_if: None,
+        };
+
+        let package = UpdatePackage::builder("0123456789", "1.0")
+            .supported_hardware(vec!["board"])
+            .object(test_object("set0"), test_object("set1"))
+            .minimum_security_version("0.9")
+            .build()
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&package).unwrap(),
+            json!({
+                "schema-version": SCHEMA_VERSION,
+                "product": "0123456789",
+                "version": "1.0",
+                "supported-hardware": ["board"],
+                "objects": [
+                    [{
+                        "mode": "test",
+                        "filename": "testfile",
+                        "sha256sum": "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722",
+                        "target": "set0",
+                        "size": 10
+                    }],
+                    [{
+                        "mode": "test",
+                        "filename": "testfile",
+                        "sha256sum": "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722",
+                        "target": "set1",
+                        "size": 10
+                    }]
+                ],
+                "minimum-security-version": "0.9"
+            })
+        );
+
+        let package = UpdatePackage::builder("0123456789", "1.0")
+            .single_bank_object(test_object("rootfs"))
+            .common_object(test_object("bootloader"))
+            .build()
+            .unwrap();
+        assert!(package.is_single_bank());
+        let value = serde_json::to_value(&package).unwrap();
+        assert_eq!(value["objects"][0][0]["target"], "rootfs");
+        assert_eq!(value["common"][0]["target"], "bootloader");
+
+        let package = UpdatePackage::builder("0123456789", "1.0")
+            .multi_set_object(vec![test_object("a"), test_object("b"), test_object("c")])
+            .build()
+            .unwrap();
+        let value = serde_json::to_value(&package).unwrap();
+        assert_eq!(value["objects"][2][0]["target"], "c");
+
+        let package = UpdatePackage::builder("0123456789", "1.0")
+            .multi_set_object(vec![test_object("a"), test_object("b")])
+            .object(test_object("set0"), test_object("set1"))
+            .build()
+            .unwrap();
+        let value = serde_json::to_value(&package).unwrap();
+        assert_eq!(value["objects"][1][0]["target"], "b");
+        assert_eq!(value["objects"][1][1]["target"], "set1");
+
+        // Sets beyond the second one are kept, failing the build as these
+        // miss the object
+        let err = UpdatePackage::builder("0123456789", "1.0")
+            .multi_set_object(vec![test_object("a"), test_object("b"), test_object("c")])
+            .object(test_object("set0"), test_object("set1"))
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.0.iter().map(|d| d.message.as_str()).collect::<Vec<_>>(),
+            vec!["set A has 2 objects while set C has 1"]
+        );
+
+        let err = UpdatePackage::builder("0123456789", "1.0")
+            .single_bank_object(test_object("rootfs"))
+            .object(test_object("set0"), test_object("set1"))
+            .build()
+            .unwrap_err();
+        assert_eq!(err.0.iter().map(|d| d.path.as_str()).collect::<Vec<_>>(), vec!["$.objects"]);
+
+        let err = UpdatePackage::builder("", "1.0")
+            .supported_hardware(Vec::<String>::new())
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.0.iter().map(|d| d.path.as_str()).collect::<Vec<_>>(),
+            vec!["$.product", "$.supported-hardware", "$.objects"]
+        );
+    }
 }
@@ -0,0 +1,1246 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    definitions::{Bmap, Count, TargetAttributes, TargetType, UbiVolume},
+    template::{self, TEMPLATE_INSTALLATION_SETS},
+    Object, SupportedHardware, UpdatePackage,
+};
+use derive_more::Display;
+use serde_json::Value;
+use std::{
+    fmt,
+    path::{Component, Path},
+};
+
+/// Latest `schema-version` understood by this crate.
+///
+/// Packages declaring this version (or an older one) must not carry
+/// unknown fields, while packages generated for a newer schema are
+/// expected to have them and those are tolerated. Packages without a
+/// `schema-version` predate the field and are also tolerated.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A package has either a single installation set, for single-bank
+/// devices, or one for each set of the device, which are named by letters.
+const MAX_INSTALLATION_SETS: usize = 26;
+
+/// Longest UBI volume name accepted by the kernel, in bytes.
+const MAX_UBI_VOLUME_NAME: usize = 127;
+
+/// Fields a value of the metadata document may have, as declared by the
+/// type it is parsed into. Nested values are only checked when these are
+/// objects, as values of other types have no fields.
+#[derive(Clone, Copy)]
+enum Fields {
+    /// Fields of a type, in groups as these are flattened into it, along
+    /// with the fields of their values.
+    Map(&'static [&'static [(&'static str, Fields)]]),
+    /// Items of an array.
+    Array(&'static Fields),
+    /// Objects, whose fields depend on their install mode.
+    Object,
+    /// Values whose fields are not declared, as extended attributes, or
+    /// which have none.
+    Any,
+}
+
+const PACKAGE_FIELDS: Fields = Fields::Map(&[&[
+    ("schema-version", Fields::Any),
+    ("product", Fields::Any),
+    ("version", Fields::Any),
+    ("supported-hardware", Fields::Any),
+    ("objects", Fields::Array(&Fields::Array(&Fields::Object))),
+    // Templates have the fields of the objects these are expanded to
+    ("objects-template", Fields::Array(&Fields::Object)),
+    ("common", Fields::Array(&Fields::Object)),
+    ("ubi-volumes", Fields::Array(&UBI_VOLUME_FIELDS)),
+    ("downgrade", Fields::Any),
+    ("minimum-security-version", Fields::Any),
+]]);
+
+const UBI_VOLUME_FIELDS: Fields = Fields::Map(&[&[
+    ("name", Fields::Any),
+    ("device", Fields::Any),
+    ("size", Fields::Any),
+    ("volume-type", Fields::Any),
+    ("rename-from", Fields::Any),
+    ("remove", Fields::Any),
+]]);
+
+const OBJECT_FIELDS: &[(&str, Fields)] = &[
+    ("mode", Fields::Any),
+    ("filename", Fields::Any),
+    ("size", Fields::Any),
+    ("sha256sum", Fields::Any),
+    ("install-if", Fields::Any),
+];
+
+const TARGET_TYPE_FIELDS: &[(&str, Fields)] =
+    &[("target-type", Fields::Any), ("target", Fields::Any)];
+
+const TARGET_PERMISSIONS_FIELDS: &[(&str, Fields)] =
+    &[("target-mode", Fields::Any), ("target-gid", Fields::Any), ("target-uid", Fields::Any)];
+
+const TARGET_ATTRIBUTES_FIELDS: &[(&str, Fields)] = &[
+    ("target-xattrs", Fields::Any),
+    ("target-selinux-context", Fields::Any),
+    ("target-acl", Fields::Any),
+];
+
+const TARGET_FORMAT_FIELDS: &[(&str, Fields)] =
+    &[("format?", Fields::Any), ("format-options", Fields::Any)];
+
+const INSTALL_IF_DIFFERENT_FIELDS: Fields = Fields::Map(&[&[
+    ("version", Fields::Any),
+    (
+        "pattern",
+        Fields::Map(&[&[
+            ("regexp", Fields::Any),
+            ("seek", Fields::Any),
+            ("buffer-size", Fields::Any),
+        ]]),
+    ),
+]]);
+
+const BMAP_FIELDS: Fields = Fields::Map(&[&[
+    ("image-size", Fields::Any),
+    ("block-size", Fields::Any),
+    ("mapped-blocks", Fields::Any),
+]]);
+
+/// Fields of the objects of the install mode, `None` for unsupported ones.
+fn mode_fields(mode: &str) -> Option<Fields> {
+    Some(Fields::Map(match mode {
+        "chunked" => &[
+            OBJECT_FIELDS,
+            TARGET_TYPE_FIELDS,
+            &[("image-size", Fields::Any), ("seed", Fields::Any), ("chunk-size", Fields::Any)],
+        ],
+        "copy" => &[
+            OBJECT_FIELDS,
+            TARGET_TYPE_FIELDS,
+            TARGET_PERMISSIONS_FIELDS,
+            TARGET_ATTRIBUTES_FIELDS,
+            TARGET_FORMAT_FIELDS,
+            &[
+                ("filesystem", Fields::Any),
+                ("target-path", Fields::Any),
+                ("install-if-different", INSTALL_IF_DIFFERENT_FIELDS),
+                ("compressed", Fields::Any),
+                ("required-uncompressed-size", Fields::Any),
+                ("mount-options", Fields::Any),
+            ],
+        ],
+        "emmc-boot" => &[
+            OBJECT_FIELDS,
+            &[
+                ("target", Fields::Any),
+                ("compressed", Fields::Any),
+                ("required-uncompressed-size", Fields::Any),
+                ("switch-boot-partition", Fields::Any),
+            ],
+        ],
+        "file-delta" => &[
+            OBJECT_FIELDS,
+            TARGET_TYPE_FIELDS,
+            &[
+                ("filesystem", Fields::Any),
+                ("target-path", Fields::Any),
+                ("source", Fields::Any),
+                ("mount-options", Fields::Any),
+            ],
+        ],
+        "flash" => &[
+            OBJECT_FIELDS,
+            TARGET_TYPE_FIELDS,
+            &[("install-if-different", INSTALL_IF_DIFFERENT_FIELDS)],
+        ],
+        // The fields of imxkobs objects predate the kebab-case naming
+        "imxkobs" => &[
+            OBJECT_FIELDS,
+            &[
+                ("install-if-different", INSTALL_IF_DIFFERENT_FIELDS),
+                ("1k_padding", Fields::Any),
+                ("search_exponent", Fields::Any),
+                ("chip_0_device_path", Fields::Any),
+                ("chip_1_device_path", Fields::Any),
+            ],
+        ],
+        "mender" => &[
+            OBJECT_FIELDS,
+            TARGET_TYPE_FIELDS,
+            &[("required-uncompressed-size", Fields::Any), ("chunk-size", Fields::Any)],
+        ],
+        "raw" => &[
+            OBJECT_FIELDS,
+            TARGET_TYPE_FIELDS,
+            &[
+                ("install-if-different", INSTALL_IF_DIFFERENT_FIELDS),
+                ("compressed", Fields::Any),
+                ("required-uncompressed-size", Fields::Any),
+                ("chunk-size", Fields::Any),
+                ("skip", Fields::Any),
+                ("seek", Fields::Any),
+                ("count", Fields::Any),
+                ("truncate", Fields::Any),
+                ("bmap", BMAP_FIELDS),
+                ("discard-holes", Fields::Any),
+                ("skip-unchanged", Fields::Any),
+            ],
+        ],
+        "raw-delta" => &[
+            OBJECT_FIELDS,
+            TARGET_TYPE_FIELDS,
+            &[
+                ("delta-format", Fields::Any),
+                ("source", Fields::Any),
+                ("source-size", Fields::Any),
+                ("source-sha256sum", Fields::Any),
+                ("result-size", Fields::Any),
+                ("result-sha256sum", Fields::Any),
+                ("chunk-size", Fields::Any),
+                ("seek", Fields::Any),
+            ],
+        ],
+        "tarball" => &[
+            OBJECT_FIELDS,
+            TARGET_TYPE_FIELDS,
+            TARGET_PERMISSIONS_FIELDS,
+            TARGET_FORMAT_FIELDS,
+            &[
+                ("filesystem", Fields::Any),
+                ("target-path", Fields::Any),
+                ("install-if-different", INSTALL_IF_DIFFERENT_FIELDS),
+                ("install-if-different-file", Fields::Any),
+                ("wipe-target", Fields::Any),
+                ("compressed", Fields::Any),
+                ("required-uncompressed-size", Fields::Any),
+                ("mount-options", Fields::Any),
+            ],
+        ],
+        "test" => &[OBJECT_FIELDS, &[("target", Fields::Any)]],
+        "ubifs" => &[
+            OBJECT_FIELDS,
+            TARGET_TYPE_FIELDS,
+            &[("compressed", Fields::Any), ("required-uncompressed-size", Fields::Any)],
+        ],
+        "zephyr" => &[OBJECT_FIELDS, TARGET_TYPE_FIELDS],
+        _ => return None,
+    }))
+}
+
+/// A semantic problem found in the package, `path` points to the offending
+/// value in the metadata document (e.g. `$.objects[0][1].target-path`).
+#[derive(Debug, PartialEq, Display)]
+#[display(fmt = "{}: {}", path, message)]
+pub struct Diagnostic {
+    pub path: String,
+    pub message: String,
+}
+
+/// All problems found while validating a package.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str("; ")?;
+            }
+            fmt::Display::fmt(diagnostic, f)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+type TargetSizeFn = dyn Fn(&TargetType) -> Option<u64>;
+
+/// Information about the device the package is validated against.
+#[derive(Default)]
+pub struct ValidationContext<'a> {
+    /// Install modes the device accepts, any mode is accepted when `None`.
+    pub supported_install_modes: Option<&'a [String]>,
+    /// Size of a target, when known, so objects written at a fixed offset
+    /// can be checked to fit on it.
+    pub target_size: Option<&'a TargetSizeFn>,
+}
+
+impl UpdatePackage {
+    /// Checks the package for semantic problems, reporting all of them.
+    pub fn validate(&self, ctx: &ValidationContext) -> Result<(), Diagnostics> {
+        let mut diagnostics = Diagnostics(Vec::default());
+        diagnostics.check_package(self, ctx);
+        diagnostics.into_result()
+    }
+
+    /// Same as [`UpdatePackage::validate`], also checking the metadata
+    /// document the package was parsed from for unknown fields according
+    /// to its `schema-version`.
+    pub fn validate_document(
+        &self,
+        document: &[u8],
+        ctx: &ValidationContext,
+    ) -> Result<(), Diagnostics> {
+        let mut diagnostics = Diagnostics(Vec::default());
+
+        match self.schema_version {
+            Some(0) => diagnostics.push("$.schema-version", "must be greater than zero"),
+            Some(v) if v <= SCHEMA_VERSION => match serde_json::from_slice(document) {
+                Ok(document) => diagnostics.check_unknown_fields("$", &document, PACKAGE_FIELDS),
+                Err(e) => diagnostics.push("$", e),
+            },
+            _ => {}
+        }
+
+        diagnostics.check_package(self, ctx);
+        diagnostics.into_result()
+    }
+}
+
+impl Diagnostics {
+    fn push(&mut self, path: impl Into<String>, message: impl fmt::Display) {
+        self.0.push(Diagnostic { path: path.into(), message: message.to_string() });
+    }
+
+    fn into_result(self) -> Result<(), Diagnostics> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    fn check_unknown_fields(&mut self, path: &str, document: &Value, fields: Fields) {
+        match (document, fields) {
+            (Value::Object(document), Fields::Map(groups)) => {
+                for (key, value) in document {
+                    let path = field_path(path, key);
+                    match groups.iter().flat_map(|g| g.iter()).find(|(name, _)| name == key) {
+                        Some((_, fields)) => self.check_unknown_fields(&path, value, *fields),
+                        None if value.is_null() => {}
+                        None => self.push(path, "unknown field"),
+                    }
+                }
+            }
+            (Value::Object(object), Fields::Object) => {
+                // Unsupported modes fail to be parsed, so are not found here
+                if let Some(fields) =
+                    object.get("mode").and_then(Value::as_str).and_then(mode_fields)
+                {
+                    self.check_unknown_fields(path, document, fields);
+                }
+            }
+            (Value::Array(document), Fields::Array(fields)) => {
+                for (i, value) in document.iter().enumerate() {
+                    self.check_unknown_fields(&format!("{}[{}]", path, i), value, *fields);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn check_package(&mut self, package: &UpdatePackage, ctx: &ValidationContext) {
+        if package.product_uid.is_empty() {
+            self.push("$.product", "must not be empty");
+        }
+        if package.version.is_empty() {
+            self.push("$.version", "must not be empty");
+        }
+        if let SupportedHardware::HardwareList(ref list) = package.supported_hardware {
+            if list.is_empty() {
+                self.push("$.supported-hardware", "must not be an empty list");
+            }
+        }
+
+        let expanded;
+        let sets = if package.objects_template.is_empty() {
+            &package.objects
+        } else {
+            if !package.objects.is_empty() {
+                self.push("$.objects-template", "must not be used along with objects");
+            }
+            match package.expand_objects_template(TEMPLATE_INSTALLATION_SETS) {
+                Ok(sets) => expanded = sets,
+                Err(diagnostics) => {
+                    self.0.extend(diagnostics.0);
+                    return;
+                }
+            }
+            &expanded
+        };
+        if sets.iter().all(Vec::is_empty) && package.common.is_empty() {
+            self.push("$.objects", "package has no objects");
+        } else if sets.is_empty() {
+            // Packages only updating common objects have a single empty set
+            self.push("$.objects", "must have at least one installation set");
+        }
+        if sets.len() > MAX_INSTALLATION_SETS {
+            self.push(
+                "$.objects",
+                format!("at most {} installation sets are supported", MAX_INSTALLATION_SETS),
+            );
+        }
+        for (set, objects) in sets.iter().enumerate().skip(1) {
+            if objects.len() != sets[0].len() {
+                self.push(
+                    "$.objects",
+                    format!(
+                        "set A has {} objects while set {} has {}",
+                        sets[0].len(),
+                        set_name(set),
+                        objects.len()
+                    ),
+                );
+            }
+        }
+
+        if package.objects_template.is_empty() {
+            for (set, objects) in sets.iter().enumerate() {
+                for (i, object) in objects.iter().enumerate() {
+                    self.check_object(&format!("$.objects[{}][{}]", set, i), object, ctx);
+                }
+            }
+        } else {
+            // Problems of the expanded objects are reported on the template,
+            // once for all sets
+            let mut diagnostics = Diagnostics(Vec::default());
+            for objects in sets.iter() {
+                for (i, object) in objects.iter().enumerate() {
+                    diagnostics.check_object(&format!("$.objects-template[{}]", i), object, ctx);
+                }
+            }
+            let mut reported = Vec::default();
+            for diagnostic in diagnostics.0 {
+                if !reported.contains(&diagnostic.path) {
+                    reported.push(diagnostic.path.clone());
+                    self.0.push(diagnostic);
+                }
+            }
+        }
+        for (i, object) in package.common.iter().enumerate() {
+            self.check_object(&format!("$.common[{}]", i), object, ctx);
+        }
+
+        // Volumes are checked for every set their names are expanded for
+        let mut diagnostics = Diagnostics(Vec::default());
+        for set in 0..sets.len().max(1) {
+            match package.expand_ubi_volumes(set) {
+                Ok(volumes) => diagnostics.check_ubi_volumes(&volumes),
+                Err(errors) => diagnostics.0.extend(errors.0),
+            }
+        }
+        for diagnostic in diagnostics.0 {
+            if !self.0.contains(&diagnostic) {
+                self.0.push(diagnostic);
+            }
+        }
+    }
+
+    fn check_ubi_volumes(&mut self, volumes: &[UbiVolume]) {
+        for (i, volume) in volumes.iter().enumerate() {
+            let path = format!("$.ubi-volumes[{}]", i);
+            for (key, name) in
+                &[("name", Some(&volume.name)), ("rename-from", volume.rename_from.as_ref())]
+            {
+                match name {
+                    Some(name) if name.is_empty() => {
+                        self.push(field_path(&path, key), "must not be empty")
+                    }
+                    Some(name) if name.len() > MAX_UBI_VOLUME_NAME => self.push(
+                        field_path(&path, key),
+                        format!("'{}' is longer than {} bytes", name, MAX_UBI_VOLUME_NAME),
+                    ),
+                    _ => {}
+                }
+            }
+            if let Some(ref device) = volume.device {
+                self.check_absolute(&field_path(&path, "device"), device);
+            }
+
+            if volume.remove {
+                if volume.rename_from.is_some() {
+                    self.push(field_path(&path, "rename-from"), "conflicts with 'remove'");
+                }
+            } else if volume.size == 0 {
+                self.push(field_path(&path, "size"), "must not be zero");
+            }
+
+            if volumes[..i].iter().any(|v| v.name == volume.name) {
+                self.push(
+                    field_path(&path, "name"),
+                    format!("volume '{}' is declared more than once", volume.name),
+                );
+            }
+        }
+    }
+
+    fn check_object(&mut self, path: &str, object: &Object, ctx: &ValidationContext) {
+        if let Some(modes) = ctx.supported_install_modes {
+            if !modes.iter().any(|m| m == object.mode()) {
+                self.push(
+                    field_path(path, "mode"),
+                    format!("'{}' is not a supported install mode", object.mode()),
+                );
+            }
+        }
+
+        match object {
+            Object::Chunked(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                self.check_target_type(path, &o.target_type);
+                if let Some(ref seed) = o.seed {
+                    self.check_absolute(&field_path(path, "seed"), seed);
+                }
+                self.check_fits(path, "image-size", &o.target_type, 0, o.image_size, ctx);
+            }
+            Object::Copy(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                self.check_target_type(path, &o.target_type);
+                self.check_absolute(&field_path(path, "target-path"), &o.target_path);
+                self.check_attributes(path, &o.target_attributes);
+            }
+            Object::EmmcBoot(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                if !is_emmc(&o.target) {
+                    self.push(
+                        field_path(path, "target"),
+                        format!("{:?} is neither an eMMC nor one of its boot partitions", o.target),
+                    );
+                }
+            }
+            Object::FileDelta(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                self.check_target_type(path, &o.target_type);
+                self.check_absolute(&field_path(path, "target-path"), &o.target_path);
+                if let Some(ref source) = o.source {
+                    self.check_source(path, source);
+                }
+            }
+            Object::Flash(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                self.check_target_type(path, &o.target);
+            }
+            Object::Imxkobs(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                for (key, device) in &[
+                    ("chip_0_device_path", &o.chip_0_device_path),
+                    ("chip_1_device_path", &o.chip_1_device_path),
+                ] {
+                    if let Some(device) = device {
+                        self.check_absolute(&field_path(path, key), device);
+                    }
+                }
+            }
+            Object::Mender(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                self.check_target_type(path, &o.target_type);
+            }
+            Object::Raw(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                self.check_target_type(path, &o.target_type);
+
+                let chunk_size = o.chunk_size.0 as u64;
+                match o.skip.0.checked_mul(chunk_size) {
+                    Some(skip) if skip <= o.size => {}
+                    _ => self.push(field_path(path, "skip"), "goes beyond the end of the object"),
+                }
+                let seek = match o.seek.checked_mul(chunk_size) {
+                    Some(seek) => seek,
+                    None => return self.push(field_path(path, "seek"), "offset overflows"),
+                };
+
+                let available = o.size.saturating_sub(o.skip.0.saturating_mul(chunk_size));
+                if let Some(bmap) = &o.bmap {
+                    self.check_bmap(&field_path(path, "bmap"), bmap);
+                }
+                let (key, len) = match (&o.count, o.compressed, &o.bmap) {
+                    (_, _, Some(bmap)) => ("seek", bmap.image_size),
+                    (_, true, None) => ("seek", o.required_uncompressed_size),
+                    (Count::All, false, None) => ("seek", available),
+                    (Count::Limited(n), false, None) => match (*n as u64).checked_mul(chunk_size) {
+                        Some(len) => ("count", len.min(available)),
+                        None => return self.push(field_path(path, "count"), "length overflows"),
+                    },
+                };
+                self.check_fits(path, key, &o.target_type, seek, len, ctx);
+            }
+            Object::RawDelta(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                self.check_target_type(path, &o.target_type);
+                self.check_source(path, &o.source);
+                match o.seek.checked_mul(o.chunk_size.0 as u64) {
+                    Some(seek) => {
+                        self.check_fits(path, "seek", &o.target_type, seek, o.result_size, ctx)
+                    }
+                    None => self.push(field_path(path, "seek"), "offset overflows"),
+                }
+            }
+            Object::Tarball(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                self.check_target_type(path, &o.target);
+                self.check_absolute(&field_path(path, "target-path"), &o.target_path);
+                match (&o.install_if_different, &o.install_if_different_file) {
+                    (Some(_), None) => self.push(
+                        field_path(path, "install-if-different"),
+                        "requires 'install-if-different-file'",
+                    ),
+                    (_, Some(file)) if !is_contained(file) => self.push(
+                        field_path(path, "install-if-different-file"),
+                        format!("{:?} must be relative to 'target-path'", file),
+                    ),
+                    _ => {}
+                }
+                if o.target_permissions.target_mode.is_some() {
+                    self.push(
+                        field_path(path, "target-mode"),
+                        "is not supported, the modes from the archive are used",
+                    );
+                }
+            }
+            Object::Test(o) => self.check_sha256sum(path, &o.sha256sum),
+            Object::Ubifs(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                self.check_target_type(path, &o.target);
+            }
+            Object::Zephyr(o) => {
+                self.check_sha256sum(path, &o.sha256sum);
+                self.check_target_type(path, &o.target);
+                self.check_fits(path, "size", &o.target, 0, o.size, ctx);
+            }
+        }
+    }
+
+    fn check_sha256sum(&mut self, path: &str, sha256sum: &str) {
+        if sha256sum.len() != 64 || !sha256sum.bytes().all(|b| b.is_ascii_hexdigit()) {
+            self.push(field_path(path, "sha256sum"), "must be 64 hexadecimal digits");
+        }
+    }
+
+    fn check_target_type(&mut self, path: &str, target: &TargetType) {
+        match target {
+            TargetType::Device(p) | TargetType::ImageFile(p) => {
+                self.check_absolute(&field_path(path, "target"), p)
+            }
+            TargetType::UBIVolume(s)
+            | TargetType::MTDName(s)
+            | TargetType::PartLabel(s)
+            | TargetType::PartUUID(s)
+            | TargetType::FsLabel(s)
+            | TargetType::GptPartitionName(s)
+                if s.is_empty() =>
+            {
+                self.push(field_path(path, "target"), "must not be empty")
+            }
+            TargetType::PartUUID(s) if !is_partuuid(s) => self.push(
+                field_path(path, "target"),
+                format!("'{}' is neither a GUID nor an MBR partition UUID", s),
+            ),
+            _ => {}
+        }
+    }
+
+    fn check_bmap(&mut self, path: &str, bmap: &Bmap) {
+        if bmap.block_size == 0 {
+            return self.push(field_path(path, "block-size"), "must not be zero");
+        }
+
+        let mut next = 0;
+        for (i, range) in bmap.mapped_blocks.iter().enumerate() {
+            let path = format!("{}[{}]", field_path(path, "mapped-blocks"), i);
+            if range.first < next {
+                self.push(path, "overlaps or precedes the previous range");
+            } else if range.last.saturating_mul(bmap.block_size) >= bmap.image_size {
+                self.push(path, "goes beyond the end of the image");
+            }
+            next = range.last.saturating_add(1);
+        }
+    }
+
+    fn check_attributes(&mut self, path: &str, attributes: &TargetAttributes) {
+        let path = field_path(path, "target-xattrs");
+        for name in attributes.target_xattrs.keys() {
+            let namespace = name.split('.').next().unwrap_or_default();
+            if !["user", "trusted", "security"].contains(&namespace) || !name.contains('.') {
+                self.push(
+                    field_path(&path, name),
+                    "must be in the 'user', 'trusted' or 'security' namespace",
+                );
+            } else if name == "security.selinux" && attributes.target_selinux_context.is_some() {
+                self.push(field_path(&path, name), "conflicts with 'target-selinux-context'");
+            }
+        }
+    }
+
+    /// Checks the source of a delta, whose active set placeholders are only
+    /// expanded at install time.
+    fn check_source(&mut self, path: &str, source: &Path) {
+        let path = field_path(path, "source");
+        match template::expand_active_set(source, 0) {
+            Ok(source) => self.check_absolute(&path, &source),
+            Err(message) => self.push(path, message),
+        }
+    }
+
+    fn check_absolute(&mut self, path: &str, value: &Path) {
+        if !value.is_absolute() {
+            self.push(path, format!("{:?} must be an absolute path", value));
+        }
+    }
+
+    /// Checks `len` bytes written at `offset` fit on the target, reporting
+    /// the problem on `key` when they don't.
+    fn check_fits(
+        &mut self,
+        path: &str,
+        key: &str,
+        target: &TargetType,
+        offset: u64,
+        len: u64,
+        ctx: &ValidationContext,
+    ) {
+        let target_size = match ctx.target_size.and_then(|f| f(target)) {
+            Some(size) => size,
+            None => return,
+        };
+
+        match offset.checked_add(len) {
+            Some(end) if end <= target_size => {}
+            end => self.push(
+                field_path(path, key),
+                format!(
+                    "object ends at byte {}, beyond the target size of {} bytes",
+                    end.map(|e| e.to_string()).unwrap_or_else(|| "overflow".to_string()),
+                    target_size
+                ),
+            ),
+        }
+    }
+}
+
+/// Whether the value is a GUID, as found on GPT, or the MBR disk signature
+/// and partition number as in `1234abcd-02`.
+fn is_partuuid(value: &str) -> bool {
+    let groups = value.split('-').map(str::len).collect::<Vec<_>>();
+    value.bytes().all(|b| b == b'-' || b.is_ascii_hexdigit())
+        && (groups == [8, 4, 4, 4, 12] || groups == [8, 2])
+}
+
+/// Whether the path is an eMMC, as `/dev/mmcblk0`, or one of its hardware
+/// boot partitions, as `/dev/mmcblk0boot1`.
+fn is_emmc(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) if path.is_absolute() => name,
+        _ => return false,
+    };
+    let index = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+
+    match name.strip_prefix("mmcblk") {
+        Some(name) => match name.find("boot") {
+            Some(i) => index(&name[..i]) && ["0", "1"].contains(&&name[i + 4..]),
+            None => index(name),
+        },
+        None => false,
+    }
+}
+
+/// Whether the path is relative and doesn't go up the directory it is
+/// relative to.
+fn is_contained(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+fn set_name(set: usize) -> char {
+    std::char::from_u32('A' as u32 + set as u32).unwrap_or('?')
+}
+
+fn field_path(path: &str, key: &str) -> String {
+    if key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+        format!("{}.{}", path, key)
+    } else {
+        format!("{}['{}']", path, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    const SHA256SUM: &str = "cfe2be1c64b0387500853de0f48303e3de7b1c6f1508dc719eeafa0d41c36722";
+
+    fn raw_object(target: &str, seek: u64, count: i64) -> Value {
+        json!({
+            "mode": "raw",
+            "filename": "rootfs.img",
+            "size": 4096,
+            "sha256sum": SHA256SUM,
+            "target-type": "device",
+            "target": target,
+            "chunk-size": 1024,
+            "seek": seek,
+            "count": count
+        })
+    }
+
+    fn copy_object(target_path: &str) -> Value {
+        json!({
+            "mode": "copy",
+            "filename": "passwd",
+            "filesystem": "ext4",
+            "size": 1024,
+            "sha256sum": SHA256SUM,
+            "target-type": "device",
+            "target": "/dev/sda1",
+            "target-path": target_path
+        })
+    }
+
+    fn package(set_a: Vec<Value>, set_b: Vec<Value>) -> Value {
+        json!({
+            "product": "0123456789",
+            "version": "1.0",
+            "supported-hardware": ["board"],
+            "objects": [set_a, set_b]
+        })
+    }
+
+    fn diagnostics(document: &Value, ctx: &ValidationContext) -> Vec<Diagnostic> {
+        let document = document.to_string().into_bytes();
+        let package = serde_json::from_slice::<UpdatePackage>(&document).unwrap();
+        package.validate_document(&document, ctx).err().map(|d| d.0).unwrap_or_default()
+    }
+
+    fn paths(diagnostics: Vec<Diagnostic>) -> Vec<String> {
+        diagnostics.into_iter().map(|d| d.path).collect()
+    }
+
+    #[test]
+    fn valid_package() {
+        let document = package(vec![copy_object("/etc/passwd")], vec![copy_object("/etc/passwd")]);
+        assert_eq!(diagnostics(&document, &ValidationContext::default()), vec![]);
+    }
+
+    #[test]
+    fn installation_sets() {
+        let mut document = package(vec![copy_object("/etc/passwd")], vec![]);
+        document["objects"] = json!([[copy_object("/etc/passwd")]]);
+        assert_eq!(diagnostics(&document, &ValidationContext::default()), vec![]);
+
+        document["objects"] =
+            json!([[copy_object("/a")], [copy_object("/a")], [copy_object("/a")]]);
+        assert_eq!(diagnostics(&document, &ValidationContext::default()), vec![]);
+
+        document["objects"] = json!([[copy_object("/a")], [copy_object("/a")], []]);
+        assert_eq!(
+            diagnostics(&document, &ValidationContext::default()),
+            vec![Diagnostic {
+                path: "$.objects".to_string(),
+                message: "set A has 1 objects while set C has 0".to_string(),
+            }]
+        );
+
+        document["objects"] = json!(vec![vec![copy_object("/a")]; 27]);
+        assert_eq!(
+            diagnostics(&document, &ValidationContext::default()),
+            vec![Diagnostic {
+                path: "$.objects".to_string(),
+                message: "at most 26 installation sets are supported".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn common_objects() {
+        let mut document = package(vec![], vec![]);
+        document["common"] = json!([raw_object("/dev/mmcblk0boot0", 0, -1), copy_object("a")]);
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec!["$.common[1].target-path"]
+        );
+
+        document["objects"] = json!([]);
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec!["$.objects", "$.common[1].target-path"]
+        );
+    }
+
+    #[test]
+    fn objects_template() {
+        let mut document = package(vec![], vec![]);
+        document["objects"] = json!([]);
+        document["objects-template"] =
+            json!([copy_object("/etc/passwd"), raw_object("/dev/mmcblk0p${set_index+2}", 0, -1)]);
+        document["schema-version"] = json!(SCHEMA_VERSION);
+        assert_eq!(diagnostics(&document, &ValidationContext::default()), vec![]);
+
+        document["objects-template"][0]["target-path"] = json!("etc/${set}/passwd");
+        document["objects-template"][1]["format?"] = json!(true);
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec!["$.objects-template[1]['format?']", "$.objects-template[0].target-path"]
+        );
+
+        document["objects"] = json!([[copy_object("/a")], [copy_object("/a")]]);
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec![
+                "$.objects-template[1]['format?']",
+                "$.objects-template",
+                "$.objects-template[0].target-path"
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_objects() {
+        let mut relative = copy_object("etc/passwd");
+        relative["sha256sum"] = json!("not-a-sha256sum");
+
+        let document = package(
+            vec![relative, raw_object("/dev/sda2", 0, -1)],
+            vec![copy_object("/etc/passwd")],
+        );
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec!["$.objects", "$.objects[0][0].sha256sum", "$.objects[0][0].target-path",]
+        );
+    }
+
+    #[test]
+    fn partition_targets() {
+        let target = |target_type: &str, target: &str| {
+            let mut object = raw_object("", 0, -1);
+            object["target-type"] = json!(target_type);
+            object["target"] = json!(target);
+            object
+        };
+
+        let document = package(
+            vec![
+                target("partuuid", "0fc63daf-8483-4772-8e79-3d69d8477de4"),
+                target("partuuid", "1234ABCD-02"),
+                target("fslabel", "data"),
+                target("imagefile", "/data/app.img"),
+            ],
+            vec![
+                target("partuuid", "0fc63daf-8483"),
+                target("partlabel", ""),
+                target("gpt-partition-name", "b"),
+                target("imagefile", "app.img"),
+            ],
+        );
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec!["$.objects[1][0].target", "$.objects[1][1].target", "$.objects[1][3].target"]
+        );
+    }
+
+    #[test]
+    fn unsupported_mode() {
+        let modes = vec!["copy".to_string()];
+        let document = package(vec![copy_object("/a")], vec![raw_object("/dev/sda2", 0, -1)]);
+        let ctx = ValidationContext { supported_install_modes: Some(&modes), ..Default::default() };
+
+        assert_eq!(
+            diagnostics(&document, &ctx),
+            vec![Diagnostic {
+                path: "$.objects[1][0].mode".to_string(),
+                message: "'raw' is not a supported install mode".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn object_beyond_target() {
+        let target_size = |t: &TargetType| match t {
+            TargetType::Device(p) if p == &PathBuf::from("/dev/sda2") => Some(8192),
+            _ => None,
+        };
+        let ctx = ValidationContext { target_size: Some(&target_size), ..Default::default() };
+
+        let document = package(
+            vec![raw_object("/dev/sda2", 4, -1), raw_object("/dev/sda2", 6, 3)],
+            vec![raw_object("/dev/sda2", 5, -1), raw_object("/dev/sda3", 100, -1)],
+        );
+        assert_eq!(
+            paths(diagnostics(&document, &ctx)),
+            vec!["$.objects[0][1].count", "$.objects[1][0].seek"]
+        );
+    }
+
+    #[test]
+    fn invalid_bmap() {
+        let with_bmap = |block_size: u64, mapped_blocks: &[&str]| {
+            let mut object = raw_object("/dev/sda2", 0, -1);
+            object["bmap"] = json!({
+                "image-size": 8192,
+                "block-size": block_size,
+                "mapped-blocks": mapped_blocks,
+            });
+            object
+        };
+
+        let document = package(
+            vec![with_bmap(1024, &["0-1", "4", "6-7"]), with_bmap(0, &[])],
+            vec![with_bmap(1024, &["2-3", "3-4"]), with_bmap(4096, &["1-2"])],
+        );
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec![
+                "$.objects[0][1].bmap.block-size",
+                "$.objects[1][0].bmap.mapped-blocks[1]",
+                "$.objects[1][1].bmap.mapped-blocks[0]",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_xattrs() {
+        let with_xattrs = |xattrs: Value| {
+            let mut object = copy_object("/etc/passwd");
+            object["target-xattrs"] = xattrs;
+            object["target-selinux-context"] = json!("system_u:object_r:etc_t:s0");
+            object
+        };
+
+        let document = package(
+            vec![with_xattrs(json!({ "user.a": "1", "security.ima": "2" }))],
+            vec![with_xattrs(
+                json!({ "system.posix_acl_access": "", "noprefix": "", "security.selinux": "" }),
+            )],
+        );
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec![
+                "$.objects[1][0].target-xattrs.noprefix",
+                "$.objects[1][0].target-xattrs['security.selinux']",
+                "$.objects[1][0].target-xattrs['system.posix_acl_access']",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_tarball_options() {
+        let tarball = |options: Value| {
+            let mut object = json!({
+                "mode": "tarball",
+                "filename": "rootfs.tar",
+                "filesystem": "ext4",
+                "size": 1024,
+                "sha256sum": SHA256SUM,
+                "target-type": "device",
+                "target": "/dev/sda1",
+                "target-path": "/"
+            });
+            object.as_object_mut().unwrap().extend(options.as_object().unwrap().clone());
+            object
+        };
+
+        let document = package(
+            vec![
+                tarball(json!({ "install-if-different": "sha256sum" })),
+                tarball(json!({
+                    "install-if-different": "sha256sum",
+                    "install-if-different-file": "./etc/version"
+                })),
+            ],
+            vec![
+                tarball(json!({
+                    "install-if-different": "sha256sum",
+                    "install-if-different-file": "../version"
+                })),
+                tarball(json!({ "target-mode": "0644", "target-uid": 0 })),
+            ],
+        );
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec![
+                "$.objects[0][0].install-if-different",
+                "$.objects[1][0].install-if-different-file",
+                "$.objects[1][1].target-mode",
+            ]
+        );
+    }
+
+    #[test]
+    fn emmc_boot_targets() {
+        let emmc_boot = |target: &str| {
+            json!({
+                "mode": "emmc-boot",
+                "filename": "u-boot.imx",
+                "size": 1024,
+                "sha256sum": SHA256SUM,
+                "target": target
+            })
+        };
+
+        let mut document = package(vec![], vec![]);
+        document["common"] = json!([emmc_boot("/dev/mmcblk0"), emmc_boot("/dev/mmcblk1boot1")]);
+        assert_eq!(diagnostics(&document, &ValidationContext::default()), vec![]);
+
+        document["common"] = json!([
+            emmc_boot("/dev/mmcblk0p1"),
+            emmc_boot("/dev/mmcblk0boot2"),
+            emmc_boot("mmcblk0boot0"),
+            emmc_boot("/dev/sda")
+        ]);
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec![
+                "$.common[0].target",
+                "$.common[1].target",
+                "$.common[2].target",
+                "$.common[3].target"
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_ubi_volumes() {
+        let mut document = package(vec![copy_object("/a")], vec![copy_object("/a")]);
+        document["ubi-volumes"] = json!([
+            { "name": "rootfs_${set}", "size": 1024, "rename-from": "system_${set}" },
+            { "name": "data", "remove": true }
+        ]);
+        assert_eq!(diagnostics(&document, &ValidationContext::default()), vec![]);
+
+        document["ubi-volumes"] = json!([
+            { "name": "rootfs_${set}" },
+            { "name": "data", "remove": true, "rename-from": "old-data" },
+            { "name": "rootfs_${set_index}", "size": 1024, "device": "ubi0" },
+            { "name": "a".repeat(128), "size": 1024 }
+        ]);
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec![
+                "$.ubi-volumes[0].size",
+                "$.ubi-volumes[1].rename-from",
+                "$.ubi-volumes[2].device",
+                "$.ubi-volumes[3].name",
+            ]
+        );
+
+        document["ubi-volumes"] = json!([{ "name": "${unknown}", "size": 1024 }]);
+        assert_eq!(
+            diagnostics(&document, &ValidationContext::default()),
+            vec![Diagnostic {
+                path: "$.ubi-volumes[0].name".to_string(),
+                message: "invalid placeholder '${unknown}'".to_string(),
+            }]
+        );
+
+        document["ubi-volumes"] = json!([
+            { "name": "rootfs", "size": 1024 },
+            { "name": "rootfs", "remove": true }
+        ]);
+        assert_eq!(
+            diagnostics(&document, &ValidationContext::default()),
+            vec![Diagnostic {
+                path: "$.ubi-volumes[1].name".to_string(),
+                message: "volume 'rootfs' is declared more than once".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let mut document = package(vec![copy_object("/a")], vec![copy_object("/a")]);
+        document["objects"][0][0]["target_path"] = json!("/b");
+        document["objects"][1][0]["format-options?"] = json!("-F");
+        let ctx = ValidationContext::default();
+
+        // Legacy packages have no schema-version and are not checked
+        assert_eq!(diagnostics(&document, &ctx), vec![]);
+
+        document["schema-version"] = json!(SCHEMA_VERSION);
+        assert_eq!(
+            paths(diagnostics(&document, &ctx)),
+            vec!["$.objects[0][0].target_path", "$.objects[1][0]['format-options?']"]
+        );
+
+        document["schema-version"] = json!(SCHEMA_VERSION + 1);
+        assert_eq!(diagnostics(&document, &ctx), vec![]);
+    }
+
+    #[test]
+    fn delta_sources() {
+        let raw_delta = |source: &str| {
+            json!({
+                "mode": "raw-delta",
+                "filename": "rootfs.bsdiff",
+                "size": 1024,
+                "sha256sum": SHA256SUM,
+                "target-type": "device",
+                "target": "/dev/mmcblk0p3",
+                "delta-format": "bsdiff",
+                "source": source,
+                "source-size": 4096,
+                "source-sha256sum": SHA256SUM,
+                "result-size": 4096,
+                "result-sha256sum": SHA256SUM
+            })
+        };
+
+        let document = package(
+            vec![raw_delta("/dev/mmcblk0p${active_set_index+2}")],
+            vec![raw_delta("/dev/mmcblk0p${active_set_index+2}")],
+        );
+        assert_eq!(diagnostics(&document, &ValidationContext::default()), vec![]);
+
+        let document = package(
+            vec![raw_delta("/dev/mmcblk0p${set_index+2}")],
+            vec![raw_delta("mmcblk0p${active_set_index}")],
+        );
+        assert_eq!(
+            paths(diagnostics(&document, &ValidationContext::default())),
+            vec!["$.objects[0][0].source", "$.objects[1][0].source"]
+        );
+    }
+
+    #[test]
+    fn defaulted_fields() {
+        let mut raw = raw_object("/dev/sda1", 0, -1);
+        raw["discard-holes"] = json!(false);
+        raw["skip-unchanged"] = json!(false);
+        let mut copy = copy_object("/etc/passwd");
+        copy["target-xattrs"] = json!({});
+        let tarball = json!({
+            "mode": "tarball",
+            "filename": "rootfs.tar",
+            "filesystem": "ext4",
+            "size": 1024,
+            "sha256sum": SHA256SUM,
+            "target-type": "device",
+            "target": "/dev/sda2",
+            "target-path": "/",
+            "wipe-target": false
+        });
+        let emmc_boot = json!({
+            "mode": "emmc-boot",
+            "filename": "u-boot.imx",
+            "size": 1024,
+            "sha256sum": SHA256SUM,
+            "target": "/dev/mmcblk0",
+            "switch-boot-partition": false
+        });
+
+        let mut document = package(
+            vec![raw.clone(), copy.clone(), tarball.clone(), emmc_boot.clone()],
+            vec![raw, copy, tarball, emmc_boot],
+        );
+        document["schema-version"] = json!(SCHEMA_VERSION);
+        document["objects-template"] = json!([]);
+        document["common"] = json!([]);
+        document["ubi-volumes"] = json!([{ "name": "rootfs", "size": 1024, "remove": false }]);
+        document["downgrade"] = json!(false);
+        assert_eq!(diagnostics(&document, &ValidationContext::default()), vec![]);
+    }
+}
@@ -2,13 +2,19 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use serde::Deserialize;
+use crate::definitions::TargetType;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub struct Zephyr {
     pub filename: String,
     pub size: u64,
     pub sha256sum: String,
+    #[serde(flatten)]
+    pub target: TargetType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_if: Option<String>,
 }
 
 #[test]
@@ -22,11 +28,15 @@ fn deserialize() {
             size: 1024,
             sha256sum: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
                 .to_string(),
+            target: TargetType::MTDName("image-1".to_string()),
+            install_if: None,
         },
         serde_json::from_value::<Zephyr>(json!({
             "filename": "artifact.zephyr",
             "size": 1024,
             "sha256sum": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "target-type": "mtdname",
+            "target": "image-1",
         }))
         .unwrap()
     );
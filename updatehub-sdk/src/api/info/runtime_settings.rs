@@ -3,8 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::BTreeMap, fmt, path::PathBuf};
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
@@ -36,13 +36,68 @@ pub enum ServerAddress {
 pub struct RuntimeUpdate {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub upgrade_to_installation: Option<InstallationSet>,
+    /// Installation set which was active when the update was installed,
+    /// booted back on rollback.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrade_from_installation: Option<InstallationSet>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub applied_package_uid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum_security_version: Option<String>,
+    /// Checksum of the common objects last installed, by their filename
+    /// and target.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub common_objects: BTreeMap<String, String>,
+    /// Status of the installation sets updates were installed on.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub installation_sets: BTreeMap<InstallationSet, InstallationSetStatus>,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
-#[serde(rename_all = "lowercase")]
-pub enum InstallationSet {
-    A,
-    B,
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstallationSetStatus {
+    pub installed_at: DateTime<Utc>,
+    /// Whether the set has booted and been validated since it was
+    /// installed.
+    pub known_good: bool,
+    /// Whether everything installed on the set has been flushed to the
+    /// storage, which is recorded before the set is made active.
+    #[serde(default)]
+    pub synced: bool,
+}
+
+/// Installation set by its index, named by a lowercase letter when
+/// serialized, so `a` is the first set.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct InstallationSet(pub u8);
+
+impl InstallationSet {
+    pub const A: InstallationSet = InstallationSet(0);
+    pub const B: InstallationSet = InstallationSet(1);
+
+    /// Installation sets are named by letters, so no more than this can be
+    /// used.
+    pub const MAX: u8 = 26;
+}
+
+impl fmt::Display for InstallationSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", (b'a' + self.0) as char)
+    }
+}
+
+impl Serialize for InstallationSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for InstallationSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        match s.as_bytes() {
+            [c @ b'a'..=b'z'] => Ok(InstallationSet(c - b'a')),
+            _ => Err(serde::de::Error::custom(format!("invalid installation set '{}'", s))),
+        }
+    }
 }
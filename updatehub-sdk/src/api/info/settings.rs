@@ -2,7 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::serde_helpers;
+use crate::{api::info::runtime_settings::InstallationSet, serde_helpers};
 use chrono::Duration;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
@@ -55,4 +55,97 @@ pub struct Storage {
 pub struct Update {
     pub download_dir: PathBuf,
     pub supported_install_modes: Vec<String>,
+    /// Define how the package version is compared to the firmware
+    /// version to refuse downgrades. By default, versions are not
+    /// compared.
+    #[serde(default)]
+    pub version_policy: VersionPolicy,
+    /// Define if the device has a single copy of the system, updated in
+    /// place, instead of A/B installation sets. By default, devices have
+    /// two installation sets.
+    #[serde(default)]
+    pub single_bank: bool,
+    /// Command used to reboot into the recovery updater once a
+    /// single-bank package is installed. By default, the device is
+    /// rebooted normally.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovery_reboot_command: Option<String>,
+    /// Define how many installation sets the device has. By default,
+    /// devices have two installation sets.
+    #[serde(default = "default_installation_sets")]
+    pub installation_sets: u8,
+    /// Define how the installation set an update is installed on is
+    /// chosen among the inactive ones. By default, the oldest one is
+    /// chosen.
+    #[serde(default)]
+    pub installation_set_policy: InstallationSetPolicy,
+    /// Define an installation set, such as a factory one, which is never
+    /// installed on. By default, any set can be installed on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub golden_installation_set: Option<InstallationSet>,
+    /// Define how the active installation set is read and swapped. By
+    /// default, the `updatehub-active-get` and `updatehub-active-set`
+    /// scripts are used.
+    #[serde(default)]
+    pub installation_set_backend: InstallationSetBackend,
+    /// Define the UBI volumes swapped by the `ubi-rename` backend. The
+    /// volumes of the active set have these names and the ones of the other
+    /// sets have the set name appended, as in `rootfs_b`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ubi_set_volumes: Vec<String>,
+    /// Define if installed objects are read back and checked against their
+    /// expected content before the installation set is swapped. By
+    /// default, they aren't.
+    #[serde(default)]
+    pub verify_installed_objects: bool,
+}
+
+fn default_installation_sets() -> u8 {
+    2
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionPolicy {
+    Disabled,
+    Semver,
+    Dpkg,
+}
+
+impl Default for VersionPolicy {
+    fn default() -> Self {
+        VersionPolicy::Disabled
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InstallationSetPolicy {
+    /// The set installed the longest time ago.
+    Oldest,
+    /// The oldest set which has not been validated since it was installed,
+    /// or the oldest one when all of them have been.
+    NotKnownGood,
+}
+
+impl Default for InstallationSetPolicy {
+    fn default() -> Self {
+        InstallationSetPolicy::Oldest
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InstallationSetBackend {
+    /// The `updatehub-active-get` and `updatehub-active-set` scripts.
+    Scripts,
+    /// Atomic rename of the `ubi_set_volumes`, so the bootloader always
+    /// boots the volumes with the same names.
+    UbiRename,
+}
+
+impl Default for InstallationSetBackend {
+    fn default() -> Self {
+        InstallationSetBackend::Scripts
+    }
 }
@@ -2,9 +2,15 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::firmware::hook::run_script;
-use sdk::api::info::runtime_settings::InstallationSet;
-use std::{fmt, str::FromStr};
+use crate::{
+    firmware::hook::run_script,
+    utils::ubi::{self, Volume},
+};
+use sdk::api::info::{
+    runtime_settings::{InstallationSet, InstallationSetStatus},
+    settings::{InstallationSetBackend, InstallationSetPolicy, Update},
+};
+use std::{collections::BTreeMap, fmt, str::FromStr};
 
 const GET_SCRIPT: &str = "updatehub-active-get";
 const SET_SCRIPT: &str = "updatehub-active-set";
@@ -13,58 +19,164 @@ const VALIDATE_SCRIPT: &str = "updatehub-active-validated";
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub struct Set(pub InstallationSet);
 
+/// Single-bank packages have only one installation set, which is installed
+/// in place and never swapped.
+pub const SINGLE_BANK: Set = Set(InstallationSet::A);
+
 impl FromStr for Set {
     type Err = super::Error;
 
     fn from_str(s: &str) -> super::Result<Self> {
-        match s.parse::<u8>() {
-            Ok(0) => Ok(Set(InstallationSet::A)),
-            Ok(1) => Ok(Set(InstallationSet::B)),
-            Ok(v) => Err(super::Error::InvalidInstallSet(v)),
-            Err(e) => Err(e.into()),
+        match s.parse::<u8>()? {
+            v if v < InstallationSet::MAX => Ok(Set(InstallationSet(v))),
+            v => Err(super::Error::InvalidInstallSet(v)),
         }
     }
 }
 
 impl fmt::Display for Set {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Set(InstallationSet::A) => "0",
-                Set(InstallationSet::B) => "1",
-            }
-        )
+        write!(f, "{}", (self.0).0)
+    }
+}
+
+pub fn active(settings: &Update) -> super::Result<Set> {
+    match settings.installation_set_backend {
+        InstallationSetBackend::Scripts => Ok(run_script(GET_SCRIPT)?.parse()?),
+        InstallationSetBackend::UbiRename => ubi_active(settings, &ubi::volumes()?),
     }
 }
 
-pub fn active() -> super::Result<Set> {
-    Ok(run_script(GET_SCRIPT)?.parse()?)
+/// Chooses the installation set to install an update on according to the
+/// settings, among the inactive ones but the golden one.
+pub fn inactive(
+    settings: &Update,
+    status: &BTreeMap<InstallationSet, InstallationSetStatus>,
+) -> super::Result<Set> {
+    select(active(settings)?, settings, status)
 }
 
-pub fn inactive() -> super::Result<Set> {
-    match active()? {
-        Set(InstallationSet::A) => Ok(Set(InstallationSet::B)),
-        Set(InstallationSet::B) => Ok(Set(InstallationSet::A)),
+fn select(
+    active: Set,
+    settings: &Update,
+    status: &BTreeMap<InstallationSet, InstallationSetStatus>,
+) -> super::Result<Set> {
+    let candidates = (0..settings.installation_sets)
+        .map(InstallationSet)
+        .filter(|set| *set != active.0 && Some(*set) != settings.golden_installation_set);
+    // Sets never installed on have no status, so they are taken as the
+    // oldest ones and as not known to be good
+    let installed_at = |set: &InstallationSet| status.get(set).map(|s| s.installed_at);
+    let known_good = |set: &InstallationSet| matches!(status.get(set), Some(s) if s.known_good);
+
+    match settings.installation_set_policy {
+        InstallationSetPolicy::Oldest => candidates.min_by_key(installed_at),
+        InstallationSetPolicy::NotKnownGood => {
+            candidates.min_by_key(|set| (known_good(set), installed_at(set)))
+        }
     }
+    .map(Set)
+    .ok_or(super::Error::NoInstallationSetAvailable)
 }
 
-pub fn swap_active() -> super::Result<()> {
-    let _ = run_script(&format!("{} {}", SET_SCRIPT, inactive()?))?;
+/// Makes the installation set the one used on next device boot.
+pub fn swap_active(settings: &Update, set: Set) -> super::Result<()> {
+    match settings.installation_set_backend {
+        InstallationSetBackend::Scripts => {
+            let _ = run_script(&format!("{} {}", SET_SCRIPT, set))?;
+        }
+        InstallationSetBackend::UbiRename => {
+            let volumes = ubi::volumes()?;
+            let active = ubi_active(settings, &volumes)?;
+            if active != set {
+                ubi::rename(ubi_swap(settings, &volumes, active, set)?)?;
+            }
+        }
+    }
     Ok(())
 }
 
-pub fn validate() -> super::Result<()> {
-    let _ = run_script(VALIDATE_SCRIPT)?;
+pub fn validate(settings: &Update) -> super::Result<()> {
+    // Renamed volumes are booted as they are, there is nothing to validate
+    if settings.installation_set_backend == InstallationSetBackend::Scripts {
+        let _ = run_script(VALIDATE_SCRIPT)?;
+    }
     Ok(())
 }
 
+/// With the `ubi-rename` backend, the volumes of the active set have the
+/// names the bootloader boots from, while the ones of every other set have
+/// the set name appended. So the active set is the one without volumes
+/// under its suffixed names.
+fn ubi_active(settings: &Update, volumes: &[Volume]) -> super::Result<Set> {
+    let inconsistent = |msg: String| super::Error::InconsistentUbiSetVolumes(msg);
+    let has = |name: &str| volumes.iter().any(|v| v.name == name);
+
+    let mut active = None;
+    for name in &settings.ubi_set_volumes {
+        if !has(name) {
+            return Err(inconsistent(format!("volume '{}' is missing", name)));
+        }
+        let missing = (0..settings.installation_sets)
+            .map(InstallationSet)
+            .filter(|set| !has(&ubi_set_volume(name, *set)))
+            .collect::<Vec<_>>();
+        let set = match missing.as_slice() {
+            [set] => *set,
+            _ => {
+                return Err(inconsistent(format!(
+                    "volume '{}' must have a copy for every set but the active one",
+                    name
+                )))
+            }
+        };
+        if *active.get_or_insert(set) != set {
+            return Err(inconsistent("volumes disagree on the active set".to_string()));
+        }
+    }
+
+    active.map(Set).ok_or_else(|| inconsistent("no volumes are swapped".to_string()))
+}
+
+/// Renames swapping the volumes of the active set with the ones of `set`.
+fn ubi_swap(
+    settings: &Update,
+    volumes: &[Volume],
+    active: Set,
+    set: Set,
+) -> super::Result<Vec<(Volume, String)>> {
+    let find = |name: &str| {
+        volumes.iter().find(|v| v.name == name).cloned().ok_or_else(|| {
+            super::Error::InconsistentUbiSetVolumes(format!("volume '{}' is missing", name))
+        })
+    };
+
+    let mut renames = Vec::default();
+    for name in &settings.ubi_set_volumes {
+        renames.push((find(name)?, ubi_set_volume(name, active.0)));
+        renames.push((find(&ubi_set_volume(name, set.0))?, name.clone()));
+    }
+    if renames.iter().any(|(v, _)| v.device != renames[0].0.device) {
+        return Err(super::Error::InconsistentUbiSetVolumes(
+            "volumes must be on the same device to be swapped at once".to_string(),
+        ));
+    }
+
+    Ok(renames)
+}
+
+fn ubi_set_volume(name: &str, set: InstallationSet) -> String {
+    format!("{}_{}", name, set)
+}
+
 #[test]
 fn as_str() {
     use pretty_assertions::assert_eq;
     assert_eq!("0", format!("{}", Set(InstallationSet::A)));
     assert_eq!("1", format!("{}", Set(InstallationSet::B)));
+    assert_eq!("2", format!("{}", Set(InstallationSet(2))));
+    assert_eq!(Set(InstallationSet(2)), "2".parse().unwrap());
+    assert!("26".parse::<Set>().is_err());
 }
 
 #[test]
@@ -78,6 +190,8 @@ fn works() {
     let tmpdir = tempdir().unwrap();
     let tmpdir = tmpdir.path();
     env::set_var("PATH", format!("{}", &tmpdir.to_string_lossy()));
+    let settings = crate::settings::Settings::default();
+    let status = BTreeMap::default();
 
     // Create a fake backend using 0 as active. It must test the
     // following:
@@ -85,9 +199,9 @@ fn works() {
     // - inactive is B
     // - swap works
     create_fake_installation_set(&tmpdir, 0);
-    assert_eq!(active().unwrap(), Set(InstallationSet::A));
-    assert_eq!(inactive().unwrap(), Set(InstallationSet::B));
-    assert!(swap_active().is_ok());
+    assert_eq!(active(&settings.update).unwrap(), Set(InstallationSet::A));
+    assert_eq!(inactive(&settings.update, &status).unwrap(), Set(InstallationSet::B));
+    assert!(swap_active(&settings.update, Set(InstallationSet::B)).is_ok());
 
     // Create a fake backend using 1 as active. It must test the
     // following:
@@ -95,7 +209,84 @@ fn works() {
     // - inactive is A
     // - swap works
     create_fake_installation_set(&tmpdir, 1);
-    assert_eq!(active().unwrap(), Set(InstallationSet::B));
-    assert_eq!(inactive().unwrap(), Set(InstallationSet::A));
-    assert!(swap_active().is_ok());
+    assert_eq!(active(&settings.update).unwrap(), Set(InstallationSet::B));
+    assert_eq!(inactive(&settings.update, &status).unwrap(), Set(InstallationSet::A));
+    assert!(swap_active(&settings.update, Set(InstallationSet::A)).is_ok());
+}
+
+#[test]
+fn policy() {
+    use chrono::{TimeZone, Utc};
+    use pretty_assertions::assert_eq;
+
+    let mut settings = crate::settings::Settings::default().update.clone();
+    settings.installation_sets = 4;
+    let status = |installed_at, known_good| InstallationSetStatus {
+        installed_at: Utc.timestamp(installed_at, 0),
+        known_good,
+        synced: true,
+    };
+    let mut sets = BTreeMap::default();
+    sets.insert(InstallationSet(0), status(30, true));
+    sets.insert(InstallationSet(1), status(10, true));
+    sets.insert(InstallationSet(2), status(20, false));
+    let target = |active, settings: &Update, sets: &BTreeMap<_, _>| {
+        select(Set(InstallationSet(active)), settings, sets)
+    };
+
+    // Sets never installed on are the oldest ones
+    assert_eq!(target(0, &settings, &sets).unwrap(), Set(InstallationSet(3)));
+    sets.insert(InstallationSet(3), status(40, true));
+    assert_eq!(target(0, &settings, &sets).unwrap(), Set(InstallationSet(1)));
+    assert_eq!(target(1, &settings, &sets).unwrap(), Set(InstallationSet(2)));
+
+    settings.installation_set_policy = InstallationSetPolicy::NotKnownGood;
+    assert_eq!(target(0, &settings, &sets).unwrap(), Set(InstallationSet(2)));
+    sets.insert(InstallationSet(2), status(20, true));
+    assert_eq!(target(0, &settings, &sets).unwrap(), Set(InstallationSet(1)));
+
+    // The golden set is never installed on
+    settings.golden_installation_set = Some(InstallationSet(1));
+    assert_eq!(target(0, &settings, &sets).unwrap(), Set(InstallationSet(2)));
+    settings.installation_sets = 2;
+    match target(0, &settings, &sets) {
+        Err(super::Error::NoInstallationSetAvailable) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn ubi_rename() {
+    use crate::utils::ubi::tests::volume;
+    use pretty_assertions::assert_eq;
+
+    let mut settings = crate::settings::Settings::default().update.clone();
+    settings.installation_set_backend = InstallationSetBackend::UbiRename;
+    settings.installation_sets = 3;
+    settings.ubi_set_volumes = vec!["kernel".to_string(), "rootfs".to_string()];
+    let mut volumes = vec![
+        volume(0, "kernel_a"),
+        volume(1, "kernel"),
+        volume(2, "kernel_c"),
+        volume(3, "rootfs_a"),
+        volume(4, "rootfs"),
+        volume(5, "rootfs_c"),
+    ];
+
+    let active = ubi_active(&settings, &volumes).unwrap();
+    assert_eq!(active, Set(InstallationSet::B));
+    assert_eq!(
+        ubi_swap(&settings, &volumes, active, Set(InstallationSet(2))).unwrap(),
+        vec![
+            (volume(1, "kernel"), "kernel_b".to_string()),
+            (volume(2, "kernel_c"), "kernel".to_string()),
+            (volume(4, "rootfs"), "rootfs_b".to_string()),
+            (volume(5, "rootfs_c"), "rootfs".to_string()),
+        ]
+    );
+
+    volumes[5].name = "rootfs_b".to_string();
+    assert!(ubi_active(&settings, &volumes).is_err());
+    volumes.truncate(3);
+    assert!(ubi_active(&settings, &volumes).is_err());
 }
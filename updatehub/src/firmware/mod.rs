@@ -39,9 +39,15 @@ pub enum Error {
     #[error("device identity is missing")]
     MissingDeviceIdentity,
 
-    #[error("{0} is a invalid value. The only know ones are 0 or 1")]
+    #[error("{0} is a invalid value. The only know ones are 0 to 25")]
     InvalidInstallSet(u8),
 
+    #[error("no installation set is available to install on")]
+    NoInstallationSetAvailable,
+
+    #[error("UBI volumes don't match the installation sets: {0}")]
+    InconsistentUbiSetVolumes(String),
+
     #[error(transparent)]
     ParseInt(#[from] std::num::ParseIntError),
 
@@ -53,6 +59,9 @@ pub enum Error {
 
     #[error(transparent)]
     Process(#[from] easy_process::Error),
+
+    #[error(transparent)]
+    Utils(#[from] crate::utils::Error),
 }
 
 #[derive(Debug, PartialEq)]
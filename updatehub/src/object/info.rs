@@ -21,14 +21,57 @@ pub(crate) enum Status {
 }
 
 impl_compressed_object_info!(objects::Copy);
+impl_compressed_object_info!(objects::EmmcBoot);
+impl_compressed_object_info!(objects::Mender);
 impl_compressed_object_info!(objects::Raw);
 impl_compressed_object_info!(objects::Ubifs);
+impl_object_info!(objects::FileDelta);
 impl_object_info!(objects::Flash);
 impl_object_info!(objects::Imxkobs);
 impl_object_info!(objects::Tarball);
 impl_object_info!(objects::Test);
+impl_object_info!(objects::Zephyr);
 
-impl_object_for_object_types!(Copy, Flash, Imxkobs, Tarball, Ubifs, Raw, Test);
+impl Info for objects::Chunked {
+    fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    fn len(&self) -> u64 {
+        self.size
+    }
+
+    fn sha256sum(&self) -> &str {
+        &self.sha256sum
+    }
+
+    fn required_install_size(&self) -> u64 {
+        self.image_size
+    }
+}
+
+impl Info for objects::RawDelta {
+    fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    fn len(&self) -> u64 {
+        self.size
+    }
+
+    fn sha256sum(&self) -> &str {
+        &self.sha256sum
+    }
+
+    fn required_install_size(&self) -> u64 {
+        self.result_size
+    }
+}
+
+impl_object_for_object_types!(
+    Chunked, Copy, EmmcBoot, FileDelta, Flash, Imxkobs, Mender, Tarball, Ubifs, Raw, RawDelta,
+    Test, Zephyr
+);
 
 pub(crate) trait Info {
     fn status(&self, download_dir: &Path) -> Result<Status> {
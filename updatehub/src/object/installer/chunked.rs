@@ -0,0 +1,355 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use crate::{
+    object::{Info, Installer},
+    utils::{
+        self,
+        chunker::{Chunker, ChunkerParams},
+        definitions::TargetTypeExt,
+        io::Sha256Writer,
+    },
+};
+use pkg_schema::objects;
+use serde::Deserialize;
+use slog_scope::{debug, info};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const CHUNK_STORE_DIR: &str = "chunks";
+
+/// Index describing the image as a sequence of chunks.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Index {
+    chunker: ChunkerParams,
+    size: u64,
+    sha256sum: String,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Chunk {
+    sha256sum: String,
+    size: u64,
+}
+
+impl Index {
+    fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_reader(io::BufReader::new(fs::File::open(path)?))?)
+    }
+
+    fn wanted(&self) -> HashSet<&str> {
+        self.chunks.iter().map(|c| c.sha256sum.as_str()).collect()
+    }
+}
+
+/// Offset and length of the chunks found in the seed.
+type SeedMap = HashMap<String, (u64, usize)>;
+
+/// Directory used to cache the downloaded chunks.
+pub(crate) fn chunk_store(download_dir: &Path) -> PathBuf {
+    download_dir.join(CHUNK_STORE_DIR)
+}
+
+/// Lists the chunks of the index that are neither in the chunk store nor
+/// in the seed, so they need to be downloaded. Chunks in the store that do
+/// not match their checksum are removed.
+pub(crate) fn missing_chunks(
+    download_dir: &Path,
+    index: &str,
+    seed: Option<&Path>,
+) -> Result<Vec<String>> {
+    let index = Index::load(&download_dir.join(index))?;
+    let store = chunk_store(download_dir);
+
+    let mut wanted = HashSet::new();
+    for chunk in &index.chunks {
+        if wanted.contains(chunk.sha256sum.as_str()) {
+            continue;
+        }
+        if read_stored_chunk(&store, &chunk.sha256sum)?.is_none() {
+            wanted.insert(chunk.sha256sum.as_str());
+        }
+    }
+
+    if let Some(seed) = seed {
+        let seeded = scan_seed(seed, index.chunker, &wanted)?;
+        wanted.retain(|c| !seeded.contains_key(*c));
+    }
+
+    debug!("{} chunks need to be downloaded", wanted.len());
+    Ok(index
+        .chunks
+        .iter()
+        .map(|c| c.sha256sum.as_str())
+        .filter(|c| wanted.remove(c))
+        .map(str::to_owned)
+        .collect())
+}
+
+impl Installer for objects::Chunked {
+    fn check_requirements(&self) -> Result<()> {
+        info!("'chunked' handle checking requirements");
+
+        if self.target_type.valid()?.is_device() {
+            let dev = self.target_type.get_target()?;
+            utils::fs::ensure_disk_space(&dev, self.required_install_size())?;
+            return Ok(());
+        }
+
+        Err(Error::InvalidTargetType(self.target_type.clone()))
+    }
+
+    fn install(&self, download_dir: &Path) -> Result<()> {
+        info!("'chunked' handler Install {} ({})", self.filename, self.sha256sum);
+
+        let device = &self.target_type.get_target()?;
+        let chunk_size = self.chunk_size.0;
+        let index = Index::load(&download_dir.join(self.sha256sum()))?;
+        let store = chunk_store(download_dir);
+
+        let mut seed = match self.seed {
+            Some(ref seed) => {
+                let seeded = scan_seed(seed, index.chunker, &index.wanted())?;
+                Some((utils::io::timed_buf_reader(chunk_size, fs::File::open(seed)?), seeded))
+            }
+            None => None,
+        };
+
+        let mut output = Sha256Writer::new(utils::io::timed_buf_writer(
+            chunk_size,
+            fs::OpenOptions::new().read(true).write(true).open(device)?,
+        ));
+
+        for chunk in &index.chunks {
+            let data = match read_stored_chunk(&store, &chunk.sha256sum)? {
+                Some(data) => data,
+                None => seed
+                    .as_mut()
+                    .map(|(reader, seeded)| read_seed_chunk(reader, seeded, &chunk.sha256sum))
+                    .transpose()?
+                    .flatten()
+                    .ok_or_else(|| Error::MissingChunk(chunk.sha256sum.clone()))?,
+            };
+            if data.len() as u64 != chunk.size {
+                return Err(Error::MissingChunk(chunk.sha256sum.clone()));
+            }
+            output.write_all(&data)?;
+        }
+
+        let (sha256sum, len, mut output) = output.finish();
+        output.flush()?;
+        utils::fs::sync(output.get_ref())?;
+        if len != index.size || sha256sum != index.sha256sum {
+            return Err(Error::ChecksumMismatch(device.clone()));
+        }
+
+        // Once installed the image becomes the seed for the next update, so
+        // only the chunks this index refers to are worth keeping.
+        prune_store(&store, &index.wanted())?;
+
+        Ok(())
+    }
+}
+
+fn read_stored_chunk(store: &Path, sha256sum: &str) -> Result<Option<Vec<u8>>> {
+    let path = store.join(sha256sum);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let data = fs::read(&path)?;
+    if utils::sha256sum(&data) != sha256sum {
+        debug!("removing corrupted or incomplete chunk: {}", sha256sum);
+        fs::remove_file(path)?;
+        return Ok(None);
+    }
+
+    Ok(Some(data))
+}
+
+fn read_seed_chunk<R: Read + Seek>(
+    seed: &mut R,
+    seeded: &SeedMap,
+    sha256sum: &str,
+) -> Result<Option<Vec<u8>>> {
+    let (offset, len) = match seeded.get(sha256sum) {
+        Some(location) => *location,
+        None => return Ok(None),
+    };
+
+    let mut data = vec![0; len];
+    seed.seek(SeekFrom::Start(offset))?;
+    seed.read_exact(&mut data)?;
+
+    Ok(if utils::sha256sum(&data) == sha256sum { Some(data) } else { None })
+}
+
+fn scan_seed(seed: &Path, params: ChunkerParams, wanted: &HashSet<&str>) -> Result<SeedMap> {
+    let mut seeded = SeedMap::new();
+    let mut offset = 0;
+
+    for chunk in Chunker::new(fs::File::open(seed)?, params) {
+        let chunk = chunk?;
+        let sha256sum = utils::sha256sum(&chunk);
+        if wanted.contains(sha256sum.as_str()) {
+            seeded.entry(sha256sum).or_insert((offset, chunk.len()));
+        }
+        offset += chunk.len() as u64;
+    }
+
+    debug!("{} chunks found on seed {:?}", seeded.len(), seed);
+    Ok(seeded)
+}
+
+fn prune_store(store: &Path, wanted: &HashSet<&str>) -> Result<()> {
+    for entry in fs::read_dir(store).into_iter().flatten() {
+        let entry = entry?;
+        if !wanted.contains(entry.file_name().to_string_lossy().as_ref()) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::chunker::tests::{pseudo_random_data, PARAMS};
+    use pkg_schema::definitions;
+    use pretty_assertions::assert_eq;
+    use tempfile::{tempdir, NamedTempFile, TempDir};
+
+    const IMAGE_SIZE: usize = 64 * 1024;
+
+    struct Setup {
+        obj: objects::Chunked,
+        download_dir: TempDir,
+        image: Vec<u8>,
+        chunks: Vec<Vec<u8>>,
+        seed: NamedTempFile,
+        target: NamedTempFile,
+    }
+
+    fn fake_chunked_object() -> Setup {
+        let download_dir = tempdir().unwrap();
+        let image = pseudo_random_data(1, IMAGE_SIZE);
+        let chunks =
+            Chunker::new(image.as_slice(), PARAMS).collect::<io::Result<Vec<_>>>().unwrap();
+
+        let index = serde_json::json!({
+            "chunker": {
+                "min-size": PARAMS.min_size,
+                "avg-size": PARAMS.avg_size,
+                "max-size": PARAMS.max_size,
+            },
+            "size": image.len(),
+            "sha256sum": utils::sha256sum(&image),
+            "chunks": chunks
+                .iter()
+                .map(|c| serde_json::json!({ "sha256sum": utils::sha256sum(c), "size": c.len() }))
+                .collect::<Vec<_>>(),
+        })
+        .to_string();
+        let sha256sum = utils::sha256sum(index.as_bytes());
+        fs::write(download_dir.path().join(&sha256sum), &index).unwrap();
+
+        // The seed holds the previous release, which only differs on the
+        // first bytes of the image.
+        let seed = NamedTempFile::new().unwrap();
+        let mut previous = image.clone();
+        previous[..16].copy_from_slice(b"previous release");
+        fs::write(seed.path(), &previous).unwrap();
+
+        let target = NamedTempFile::new().unwrap();
+        let obj = objects::Chunked {
+            filename: "rootfs.index".to_string(),
+            size: index.len() as u64,
+            sha256sum,
+            target_type: definitions::TargetType::Device(target.path().to_path_buf()),
+
+            image_size: image.len() as u64,
+            seed: Some(seed.path().to_path_buf()),
+            chunk_size: definitions::ChunkSize::default(),
+            install_if: None,
+        };
+
+        Setup { obj, download_dir, image, chunks, seed, target }
+    }
+
+    fn store_chunk(download_dir: &Path, chunk: &[u8]) {
+        let store = chunk_store(download_dir);
+        fs::create_dir_all(&store).unwrap();
+        fs::write(store.join(utils::sha256sum(chunk)), chunk).unwrap();
+    }
+
+    #[test]
+    fn missing_chunks_uses_seed() {
+        let setup = fake_chunked_object();
+        let dir = setup.download_dir.path();
+
+        assert_eq!(
+            missing_chunks(dir, &setup.obj.sha256sum, setup.obj.seed.as_deref()).unwrap(),
+            vec![utils::sha256sum(&setup.chunks[0])]
+        );
+        assert_eq!(
+            missing_chunks(dir, &setup.obj.sha256sum, None).unwrap().len(),
+            setup.chunks.iter().map(|c| utils::sha256sum(c)).collect::<HashSet<_>>().len()
+        );
+
+        store_chunk(dir, &setup.chunks[0]);
+        assert!(missing_chunks(dir, &setup.obj.sha256sum, setup.obj.seed.as_deref())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn missing_chunks_removes_corrupted_chunk() {
+        let setup = fake_chunked_object();
+        let dir = setup.download_dir.path();
+        let chunk = utils::sha256sum(&setup.chunks[0]);
+
+        store_chunk(dir, &setup.chunks[0]);
+        fs::write(chunk_store(dir).join(&chunk), b"corrupted").unwrap();
+
+        assert_eq!(
+            missing_chunks(dir, &setup.obj.sha256sum, setup.obj.seed.as_deref()).unwrap(),
+            vec![chunk.clone()]
+        );
+        assert!(!chunk_store(dir).join(&chunk).exists());
+    }
+
+    #[test]
+    fn install_from_store_and_seed() {
+        let setup = fake_chunked_object();
+        let dir = setup.download_dir.path();
+        store_chunk(dir, &setup.chunks[0]);
+        store_chunk(dir, b"unrelated chunk");
+
+        setup.obj.check_requirements().unwrap();
+        setup.obj.install(dir).unwrap();
+
+        assert_eq!(fs::read(setup.target.path()).unwrap(), setup.image);
+        assert_eq!(fs::read_dir(chunk_store(dir)).unwrap().count(), 1);
+        assert!(setup.seed.path().exists());
+    }
+
+    #[test]
+    fn install_with_missing_chunk() {
+        let setup = fake_chunked_object();
+
+        match setup.obj.install(setup.download_dir.path()) {
+            Err(Error::MissingChunk(c)) => assert_eq!(c, utils::sha256sum(&setup.chunks[0])),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
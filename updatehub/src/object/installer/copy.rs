@@ -12,7 +12,6 @@ use slog_scope::info;
 use std::{
     fs,
     io::{self, Write},
-    os::unix::fs::PermissionsExt,
     path::Path,
 };
 
@@ -20,18 +19,23 @@ impl Installer for objects::Copy {
     fn check_requirements(&self) -> Result<()> {
         info!("'copy' handle checking requirements");
 
-        if let definitions::TargetType::Device(dev) = self.target_type.valid()? {
-            utils::fs::ensure_disk_space(&dev, self.required_install_size())?;
-            return Ok(());
+        match self.target_type.valid()? {
+            // The space available inside the image is only known once it is mounted
+            definitions::TargetType::ImageFile(_) => Ok(()),
+            target if target.is_device() => {
+                let dev = target.get_target()?;
+                utils::fs::ensure_disk_space(&dev, self.required_install_size())?;
+                Ok(())
+            }
+            _ => Err(Error::InvalidTargetType(self.target_type.clone())),
         }
-
-        Err(Error::InvalidTargetType(self.target_type.clone()))
     }
 
     fn install(&self, download_dir: &Path) -> Result<()> {
         info!("'copy' handler Install {} ({})", self.filename, self.sha256sum);
 
-        let device = self.target_type.get_target()?;
+        let block_device = self.target_type.get_block_device()?;
+        let device = block_device.path();
         let filesystem = self.filesystem;
         let mount_options = &self.mount_options;
         let format_options = &self.target_format.format_options;
@@ -41,7 +45,7 @@ impl Installer for objects::Copy {
         let source = download_dir.join(sha256sum);
 
         handle_install_if_different!(self.install_if_different, sha256sum, {
-            utils::fs::mount_map(&device, filesystem, mount_options, |path| {
+            utils::fs::mount_map(device, filesystem, mount_options, |path| {
                 fs::File::open(&path.join(&target_path)).map_err(Error::from)
             })
             .map_err(Error::from)
@@ -49,51 +53,90 @@ impl Installer for objects::Copy {
         });
 
         if self.target_format.should_format {
-            utils::fs::format(&device, filesystem, &format_options)?;
+            utils::fs::format(device, filesystem, &format_options)?;
         }
 
-        utils::fs::mount_map(&device, filesystem, mount_options, |path| {
+        utils::fs::mount_map(device, filesystem, mount_options, |path| {
             let dest = path.join(&target_path);
-            let mut input = utils::io::timed_buf_reader(chunk_size, fs::File::open(source)?);
-            let mut output = utils::io::timed_buf_writer(
-                chunk_size,
-                fs::OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&dest)?,
-            );
-
-            // File's access mode is changed here as we might not have write permission over
-            // it. It will be restored or overwritten later on by the target_mode parameter
-            let metadata = dest.metadata()?;
-            let orig_mode = metadata.permissions().mode();
-            metadata.permissions().set_mode(0o100_666);
-
-            if self.compressed {
-                compress_tools::uncompress_data(&mut input, &mut output)?;
-            } else {
-                io::copy(&mut input, &mut output)?;
+            let file_name = dest.file_name().ok_or(Error::InvalidPath)?;
+            let temp = dest.with_file_name(format!(".{}.updatehub", file_name.to_string_lossy()));
+
+            // The content is written to a temporary file beside the destination,
+            // which then replaces it at once so it is never seen partially written
+            let res = write_temp_file(self, &source, &temp, &dest, chunk_size);
+            if res.is_err() {
+                let _ = fs::remove_file(&temp);
             }
-            output.flush()?;
-            metadata.permissions().set_mode(orig_mode);
+            res?;
 
-            if let Some(mode) = self.target_permissions.target_mode {
-                utils::fs::chmod(&dest, mode)?;
-            }
-
-            utils::fs::chown(
-                &dest,
-                &self.target_permissions.target_uid,
-                &self.target_permissions.target_gid,
-            )?;
+            fs::rename(&temp, &dest)?;
+            utils::fs::sync(&fs::File::open(dest.parent().unwrap_or(path))?)?;
 
             Ok(())
         })
         .map_err(Error::from)
         .and_then(|r| r)
     }
+
+    fn verify(&self, download_dir: &Path) -> Result<()> {
+        info!("'copy' handler Verify {} ({})", self.filename, self.sha256sum);
+
+        if !super::is_verifiable(&self.install_if_different) {
+            info!("skipping verification as the object is installed only if its version differs");
+            return Ok(());
+        }
+
+        let source = download_dir.join(self.sha256sum());
+        let (sha256sum, len) = super::content_sha256sum(&source, self.compressed)?;
+        let block_device = self.target_type.get_block_device()?;
+        let target_path = self.target_path.strip_prefix("/").unwrap_or(&self.target_path);
+
+        utils::fs::mount_map(block_device.path(), self.filesystem, &self.mount_options, |path| {
+            let dest = path.join(target_path);
+            if dest.metadata()?.len() != len {
+                return Err(Error::VerificationFailed(dest));
+            }
+            super::verify_read_back(&dest, &sha256sum, len)
+        })?
+    }
+}
+
+/// Writes the object content into `temp`, taking the metadata of `dest` when
+/// it exists before applying the requested one.
+fn write_temp_file(
+    obj: &objects::Copy,
+    source: &Path,
+    temp: &Path,
+    dest: &Path,
+    chunk_size: usize,
+) -> Result<()> {
+    let mut input = utils::io::timed_buf_reader(chunk_size, fs::File::open(source)?);
+    let mut output = utils::io::timed_buf_writer(
+        chunk_size,
+        fs::OpenOptions::new().write(true).create(true).truncate(true).open(temp)?,
+    );
+
+    if obj.compressed {
+        compress_tools::uncompress_data(&mut input, &mut output)?;
+    } else {
+        io::copy(&mut input, &mut output)?;
+    }
+    output.flush()?;
+
+    if dest.exists() {
+        utils::fs::copy_metadata(dest, temp)?;
+    }
+
+    utils::fs::chown(temp, &obj.target_permissions.target_uid, &obj.target_permissions.target_gid)?;
+
+    if let Some(mode) = obj.target_permissions.target_mode {
+        utils::fs::chmod(temp, mode)?;
+    }
+
+    utils::fs::set_attributes(temp, &obj.target_attributes)?;
+    utils::fs::sync(output.get_ref())?;
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -154,11 +197,12 @@ mod tests {
         source.write_all(&data)?;
 
         // When needed, create a file inside the mounted device
-        if let Some(perm) = original_permissions {
+        if let Some(perm) = &original_permissions {
             utils::fs::mount_map(&device, definitions::Filesystem::Ext4, &"", |path| {
                 let file = path.join(&"original_file");
                 fs::File::create(&file)?
                     .write_all(&iter::repeat(ORIGINAL_BYTE).take(FILE_SIZE).collect::<Vec<_>>())?;
+                xattr::set(&file, "user.origin", b"original")?;
 
                 if let Some(mode) = perm.target_mode {
                     utils::fs::chmod(&file, mode)?;
@@ -180,10 +224,12 @@ mod tests {
             target_path: PathBuf::from("original_file"),
             install_if_different: None,
             target_permissions: definitions::TargetPermissions::default(),
+            target_attributes: definitions::TargetAttributes::default(),
             compressed: false,
             required_uncompressed_size: 0,
             target_format: definitions::TargetFormat::default(),
             mount_options: String::default(),
+            install_if: None,
         };
 
         // Change copy object to be used on current test
@@ -192,7 +238,22 @@ mod tests {
         // Peform Install
         obj.check_requirements()?;
         obj.setup()?;
-        obj.install(&download_dir.path())?;
+        if let Err(e) = obj.install(&download_dir.path()) {
+            // A failed install must leave the original file untouched
+            utils::fs::mount_map(&device, obj.filesystem, &"", |path| {
+                assert!(!path.join(".original_file.updatehub").exists());
+                if original_permissions.is_some() {
+                    assert_eq!(
+                        fs::read(path.join("original_file"))?,
+                        iter::repeat(ORIGINAL_BYTE).take(FILE_SIZE).collect::<Vec<_>>()
+                    );
+                }
+                std::io::Result::Ok(())
+            })??;
+            loopdev.detach()?;
+            return Err(e);
+        }
+        obj.verify(&download_dir.path())?;
 
         // Validade File
         #[allow(clippy::redundant_clone)]
@@ -216,21 +277,40 @@ mod tests {
                 rd2.consume(len2);
             }
 
+            assert!(!dest.with_file_name(".original_file.updatehub").exists());
+
+            // Unless overwritten, the metadata of the replaced file is kept
+            let original =
+                original_permissions.as_ref().filter(|_| !obj.target_format.should_format);
+            let expected = |f: fn(&definitions::TargetPermissions) -> Option<u32>| {
+                f(&obj.target_permissions).or_else(|| original.and_then(f))
+            };
+
             let metadata = dest.metadata()?;
-            if let Some(mode) = obj.target_permissions.target_mode {
+            if let Some(mode) = expected(|p| p.target_mode) {
                 assert_eq!(mode, metadata.mode() % 0o1000);
             };
 
-            if let Some(uid) = obj.target_permissions.target_uid {
-                let uid = uid.as_u32();
+            if let Some(uid) = expected(|p| p.target_uid.as_ref().map(IdExt::as_u32)) {
                 assert_eq!(uid, metadata.uid());
             };
 
-            if let Some(gid) = obj.target_permissions.target_gid {
-                let gid = gid.as_u32();
+            if let Some(gid) = expected(|p| p.target_gid.as_ref().map(IdExt::as_u32)) {
                 assert_eq!(gid, metadata.gid());
             };
 
+            if original.is_some() {
+                assert_eq!(Some(b"original".to_vec()), xattr::get(&dest, "user.origin")?);
+            }
+
+            for (name, value) in &obj.target_attributes.target_xattrs {
+                assert_eq!(Some(value.as_bytes().to_vec()), xattr::get(&dest, name)?);
+            }
+
+            if obj.target_attributes.target_acl.is_some() {
+                assert!(xattr::get(&dest, "system.posix_acl_access")?.is_some());
+            }
+
             std::io::Result::Ok(())
         })??;
 
@@ -312,4 +392,51 @@ mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    #[ignore]
+    fn copy_with_attributes() {
+        exec_test_with_copy(
+            |obj| {
+                obj.target_permissions.target_mode = Some(0o640);
+                obj.target_attributes.target_xattrs =
+                    iter::once(("user.checksum".to_string(), "abc".to_string())).collect();
+                obj.target_attributes.target_acl = Some(
+                    std::convert::TryFrom::try_from(
+                        "user::rw-,user:1000:r--,group::r--,mask::r--,other::---".to_string(),
+                    )
+                    .unwrap(),
+                );
+            },
+            Some(definitions::TargetPermissions {
+                target_mode: Some(0o666),
+                target_gid: Some(definitions::target_permissions::Gid::Number(1000)),
+                target_uid: Some(definitions::target_permissions::Uid::Number(1000)),
+            }),
+            false,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn copy_failure_keeps_original() {
+        // The kernel refuses an ACL without the group and other entries
+        let res = exec_test_with_copy(
+            |obj| {
+                obj.target_attributes.target_acl =
+                    Some(definitions::Acl(vec![definitions::AclEntry {
+                        tag: definitions::AclTag::UserObj,
+                        perm: 6,
+                    }]))
+            },
+            Some(definitions::TargetPermissions {
+                target_mode: Some(0o666),
+                target_gid: Some(definitions::target_permissions::Gid::Number(1000)),
+                target_uid: Some(definitions::target_permissions::Uid::Number(1000)),
+            }),
+            false,
+        );
+        assert!(res.is_err());
+    }
 }
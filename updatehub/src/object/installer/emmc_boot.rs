@@ -0,0 +1,129 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::Result;
+use crate::{
+    object::{Info, Installer},
+    utils::{
+        self,
+        emmc::{BootPartition, WriteGuard},
+    },
+};
+use pkg_schema::objects;
+use slog_scope::info;
+use std::{fs, io, path::Path};
+
+impl Installer for objects::EmmcBoot {
+    fn check_requirements(&self) -> Result<()> {
+        info!("'emmc-boot' handle checking requirements");
+
+        let partition = BootPartition::from_target(&self.target)?;
+        utils::fs::ensure_disk_space(&partition.path(), self.required_install_size())?;
+
+        Ok(())
+    }
+
+    fn setup(&mut self) -> Result<()> {
+        // The partition the eMMC doesn't boot from is resolved once, so it
+        // is kept after the boot partition is switched
+        self.target = BootPartition::from_target(&self.target)?.path();
+        info!("'emmc-boot' handler writing to {}", self.target.display());
+
+        Ok(())
+    }
+
+    // The boot partition is only switched, if asked so, once all the
+    // objects are installed, along with the installation set
+    fn install(&self, download_dir: &Path) -> Result<()> {
+        info!("'emmc-boot' handler Install {} ({})", self.filename, self.sha256sum);
+
+        write(self, download_dir, WriteGuard::new)
+    }
+
+    fn verify(&self, download_dir: &Path) -> Result<()> {
+        info!("'emmc-boot' handler Verify {} ({})", self.filename, self.sha256sum);
+
+        let source = download_dir.join(self.sha256sum());
+        let (sha256sum, len) = super::content_sha256sum(&source, self.compressed)?;
+        super::verify_read_back(&self.target, &sha256sum, len)
+    }
+}
+
+fn write<F>(obj: &objects::EmmcBoot, download_dir: &Path, write_enable: F) -> Result<()>
+where
+    F: FnOnce(&BootPartition) -> utils::Result<WriteGuard>,
+{
+    let partition = BootPartition::from_target(&obj.target)?;
+    let _write_enabled = write_enable(&partition)?;
+    let mut input = fs::File::open(download_dir.join(obj.sha256sum()))?;
+    let mut target = fs::OpenOptions::new().write(true).open(&obj.target)?;
+    if obj.compressed {
+        compress_tools::uncompress_data(&mut input, &mut target)?;
+    } else {
+        io::copy(&mut input, &mut target)?;
+    }
+
+    Ok(utils::fs::sync(&target)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    const CONTENT: &[u8] = b"bootloader";
+    const SHA256SUM: &str = "3b4a12881d11f33cff968a24d7c53723a8232cde9a8d91e29fdbd6a95ae6adf0";
+
+    fn fake_emmc_boot_obj(target: &Path) -> objects::EmmcBoot {
+        objects::EmmcBoot {
+            filename: "u-boot.imx".to_string(),
+            size: CONTENT.len() as u64,
+            sha256sum: SHA256SUM.to_string(),
+            target: target.to_path_buf(),
+
+            compressed: false,
+            required_uncompressed_size: 0,
+            switch_boot_partition: true,
+            install_if: None,
+        }
+    }
+
+    #[test]
+    fn check_requirements_with_invalid_target() {
+        for target in &["/dev/sda", "/dev/mmcblk0p1", "/dev/mmcblk0boot2"] {
+            let obj = fake_emmc_boot_obj(Path::new(target));
+            assert!(obj.check_requirements().is_err(), "{}", target);
+        }
+    }
+
+    #[test]
+    fn setup_keeps_boot_partition_target() {
+        let mut obj = fake_emmc_boot_obj(Path::new("/dev/mmcblk1boot0"));
+        obj.setup().unwrap();
+        assert_eq!(obj.target, PathBuf::from("/dev/mmcblk1boot0"));
+    }
+
+    #[test]
+    fn install_while_writable() {
+        let download_dir = tempfile::tempdir().unwrap();
+        fs::write(download_dir.path().join(SHA256SUM), CONTENT).unwrap();
+
+        let dev = tempfile::tempdir().unwrap();
+        let target = dev.path().join("mmcblk0boot1");
+        fs::write(&target, vec![0; 64]).unwrap();
+
+        let sysfs = tempfile::tempdir().unwrap();
+        let force_ro = sysfs.path().join("mmcblk0boot1").join("force_ro");
+        fs::create_dir(force_ro.parent().unwrap()).unwrap();
+        fs::write(&force_ro, "1\n").unwrap();
+
+        let obj = fake_emmc_boot_obj(&target);
+        write(&obj, download_dir.path(), |p| WriteGuard::with_sysfs(sysfs.path(), p)).unwrap();
+
+        assert_eq!(&fs::read(&target).unwrap()[..CONTENT.len()], CONTENT);
+        assert_eq!(fs::read_to_string(&force_ro).unwrap(), "1");
+        obj.verify(download_dir.path()).unwrap();
+    }
+}
@@ -0,0 +1,430 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use crate::{
+    object::{Info, Installer},
+    utils::{self, definitions::TargetTypeExt, io::Sha256Writer},
+};
+use pkg_schema::{
+    definitions,
+    objects::{self, FileChange, FileDeltaManifest},
+};
+use slog_scope::info;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const MANIFEST: &str = "manifest.json";
+
+impl Installer for objects::FileDelta {
+    fn check_requirements(&self) -> Result<()> {
+        info!("'file-delta' handle checking requirements");
+
+        if let Some(ref source) = self.source {
+            if !source.exists() {
+                return Err(utils::Error::DeviceDoesNotExist.into());
+            }
+        }
+
+        match self.target_type.valid()? {
+            definitions::TargetType::ImageFile(_) => Ok(()),
+            target if target.is_device() => Ok(()),
+            _ => Err(Error::InvalidTargetType(self.target_type.clone())),
+        }
+    }
+
+    fn install(&self, download_dir: &Path) -> Result<()> {
+        info!("'file-delta' handler Install {} ({})", self.filename, self.sha256sum);
+
+        let chunk_size = definitions::ChunkSize::default().0;
+        let target_path = self.target_path.strip_prefix("/").unwrap_or(&self.target_path);
+        let delta = download_dir.join(self.sha256sum());
+
+        // Image files are written directly, so they grow to fit the clone
+        if let Some(ref source) = self.source {
+            let target = self.target_type.get_target()?;
+            info!("'file-delta' handler cloning {:?} into {:?}", source, target);
+            let mut input = utils::io::timed_buf_reader(chunk_size, fs::File::open(source)?);
+            let mut output = utils::io::timed_buf_writer(
+                chunk_size,
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create(matches!(self.target_type, definitions::TargetType::ImageFile(_)))
+                    .open(&target)?,
+            );
+            io::copy(&mut input, &mut output)?;
+            output.flush()?;
+            utils::fs::sync(output.get_ref())?;
+        }
+
+        let block_device = self.target_type.get_block_device()?;
+        let device = block_device.path();
+        utils::fs::mount_map(device, self.filesystem, &self.mount_options, |path| {
+            let root = path.join(target_path);
+            utils::archive::decompressed(&delta, |reader| apply(&root, reader, chunk_size))
+        })?
+    }
+}
+
+/// Applies the changes of the delta archive read from `reader` to the
+/// directory `root`.
+fn apply(root: &Path, reader: &mut dyn Read, chunk_size: usize) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = archive.entries()?;
+    let manifest: FileDeltaManifest = match entries.next().transpose()? {
+        Some(entry) if entry.path()?.as_ref() == Path::new(MANIFEST) => {
+            serde_json::from_reader(entry)?
+        }
+        _ => return Err(Error::InvalidFileDelta(format!("'{}' must come first", MANIFEST))),
+    };
+    let path_of = |change: &FileChange| -> Result<PathBuf> {
+        Ok(utils::archive::contained_path(root, change.path())?)
+    };
+
+    for change in &manifest.changes {
+        if let FileChange::Delete { .. } = change {
+            let path = path_of(change)?;
+            match path.symlink_metadata() {
+                Ok(m) if m.is_dir() => fs::remove_dir_all(&path)?,
+                Ok(_) => fs::remove_file(&path)?,
+                Err(_) => {}
+            }
+        }
+    }
+
+    for change in &manifest.changes {
+        if let FileChange::Directory { permissions, .. } = change {
+            let path = path_of(change)?;
+            fs::create_dir_all(&path)?;
+            set_permissions(&path, permissions)?;
+        }
+    }
+
+    // The content of files and patches is applied as the archive is read
+    let mut pending = HashMap::new();
+    for change in &manifest.changes {
+        if let FileChange::File { .. } | FileChange::Patch { .. } = change {
+            pending.insert(path_of(change)?, change);
+        }
+    }
+    for entry in entries {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = utils::archive::contained_path(root, &entry.path()?)?;
+        let change = pending
+            .remove(&path)
+            .ok_or_else(|| Error::InvalidFileDelta(format!("{:?} is not in the manifest", path)))?;
+        let len = entry.size();
+        replace_file(&path, change, entry, len, chunk_size)?;
+    }
+    if let Some(path) = pending.keys().next() {
+        return Err(Error::InvalidFileDelta(format!("missing content for {:?}", path)));
+    }
+
+    for change in &manifest.changes {
+        if let FileChange::Symlink { target, .. } = change {
+            let path = path_of(change)?;
+            if path.symlink_metadata().is_ok() {
+                fs::remove_file(&path)?;
+            }
+            std::os::unix::fs::symlink(target, &path)?;
+        }
+    }
+
+    for change in &manifest.changes {
+        if let FileChange::Permissions { permissions, .. } = change {
+            set_permissions(&path_of(change)?, permissions)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the file at `path` with the one built from `content` according
+/// to the change, through a temporary file so it is never seen partially
+/// written.
+fn replace_file(
+    path: &Path,
+    change: &FileChange,
+    content: impl Read,
+    content_len: u64,
+    chunk_size: usize,
+) -> Result<()> {
+    let file_name = path.file_name().ok_or(Error::InvalidPath)?;
+    let temp = path.with_file_name(format!(".{}.updatehub", file_name.to_string_lossy()));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let res = write_file(path, &temp, change, content, content_len, chunk_size);
+    if res.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    res?;
+
+    Ok(fs::rename(&temp, path)?)
+}
+
+fn write_file(
+    path: &Path,
+    temp: &Path,
+    change: &FileChange,
+    mut content: impl Read,
+    content_len: u64,
+    chunk_size: usize,
+) -> Result<()> {
+    let mut output =
+        Sha256Writer::new(utils::io::timed_buf_writer(chunk_size, fs::File::create(temp)?));
+
+    let (sha256sum, permissions) = match change {
+        FileChange::File { sha256sum, permissions, .. } => {
+            io::copy(&mut content, &mut output)?;
+            (sha256sum, permissions)
+        }
+        FileChange::Patch { delta_format, source_sha256sum, sha256sum, permissions, .. } => {
+            let mut source = utils::io::timed_buf_reader(chunk_size, fs::File::open(path)?);
+            let mut hasher = Sha256Writer::new(io::sink());
+            io::copy(&mut source, &mut hasher)?;
+            let (source_sum, source_size, _) = hasher.finish();
+            if source_sum != *source_sha256sum {
+                return Err(Error::ChecksumMismatch(path.to_path_buf()));
+            }
+            source.seek(SeekFrom::Start(0))?;
+
+            match delta_format {
+                definitions::DeltaFormat::Bsdiff => {
+                    let mut patch = Vec::default();
+                    content.read_to_end(&mut patch)?;
+                    utils::delta::bspatch(&mut source, source_size, &patch, &mut output)
+                }
+                // Patched files can't take more than the space left
+                definitions::DeltaFormat::Vcdiff => utils::delta::vcdiff_patch(
+                    &mut source,
+                    source_size,
+                    content,
+                    content_len,
+                    utils::fs::available_space(temp)?,
+                    &mut output,
+                ),
+            }?;
+            (sha256sum, permissions)
+        }
+        _ => unreachable!("only files and patches have content"),
+    };
+
+    let (result_sum, _, mut output) = output.finish();
+    output.flush()?;
+    if result_sum != *sha256sum {
+        return Err(Error::ChecksumMismatch(path.to_path_buf()));
+    }
+
+    if path.exists() {
+        utils::fs::copy_metadata(path, temp)?;
+    }
+    set_permissions(temp, permissions)?;
+    utils::fs::sync(output.get_ref())?;
+
+    Ok(())
+}
+
+fn set_permissions(path: &Path, permissions: &definitions::TargetPermissions) -> Result<()> {
+    utils::fs::chown(path, &permissions.target_uid, &permissions.target_gid)?;
+    if let Some(mode) = permissions.target_mode {
+        utils::fs::chmod(path, mode)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{object::installer::tests::SERIALIZE, utils::delta::tests::fake_bsdiff};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::os::unix::fs::MetadataExt;
+
+    fn fake_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::default());
+        for (path, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            // Unsafe names can't be set through the header methods, which check them
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn fake_delta(manifest: serde_json::Value, contents: &[(&str, &[u8])]) -> Vec<u8> {
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        fake_archive(&[&[(MANIFEST, manifest.as_slice())], contents].concat())
+    }
+
+    fn fake_root() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("lib/old")).unwrap();
+        fs::write(root.path().join("lib/old/plugin.so"), b"old plugin").unwrap();
+        fs::write(root.path().join("lib/app.so"), b"library v1").unwrap();
+        fs::write(root.path().join("etc.conf"), b"conf").unwrap();
+        root
+    }
+
+    #[test]
+    fn apply_changes() {
+        let root = fake_root();
+        let patch = fake_bsdiff(b"library v1", b"library v2", &[(10, 0, 0)]);
+        let delta = fake_delta(
+            json!({
+                "changes": [
+                    { "type": "delete", "path": "lib/old" },
+                    { "type": "directory", "path": "share/app", "target-mode": "0750" },
+                    {
+                        "type": "file",
+                        "path": "bin/app",
+                        "sha256sum": utils::sha256sum(b"binary"),
+                        "target-mode": "0755"
+                    },
+                    {
+                        "type": "patch",
+                        "path": "lib/app.so",
+                        "delta-format": "bsdiff",
+                        "source-sha256sum": utils::sha256sum(b"library v1"),
+                        "sha256sum": utils::sha256sum(b"library v2")
+                    },
+                    { "type": "symlink", "path": "lib/app.so.2", "target": "app.so" },
+                    { "type": "permissions", "path": "etc.conf", "target-mode": "0600" }
+                ]
+            }),
+            &[("bin/app", b"binary"), ("./lib/app.so", &patch)],
+        );
+
+        apply(root.path(), &mut delta.as_slice(), 1024).unwrap();
+
+        let path = |p: &str| root.path().join(p);
+        let mode = |p: &str| fs::metadata(path(p)).unwrap().mode() % 0o1000;
+        assert!(!path("lib/old").exists());
+        assert_eq!(mode("share/app"), 0o750);
+        assert_eq!(fs::read(path("bin/app")).unwrap(), b"binary");
+        assert_eq!(mode("bin/app"), 0o755);
+        assert_eq!(fs::read(path("lib/app.so")).unwrap(), b"library v2");
+        assert_eq!(fs::read_link(path("lib/app.so.2")).unwrap(), PathBuf::from("app.so"));
+        assert_eq!(fs::read(path("lib/app.so.2")).unwrap(), b"library v2");
+        assert_eq!(mode("etc.conf"), 0o600);
+        assert!(!path("lib/.app.so.updatehub").exists());
+    }
+
+    #[test]
+    fn invalid_delta() {
+        let file = |path: &str| json!({ "type": "file", "path": path, "sha256sum": "" });
+        let cases = vec![
+            // The manifest is not the first entry
+            fake_archive(&[("bin/app", b"binary")]),
+            fake_delta(json!({ "changes": [file("bin/app")] }), &[]),
+            fake_delta(json!({ "changes": [] }), &[("bin/app", b"binary")]),
+            fake_delta(json!({ "changes": [file("../escape")] }), &[("../escape", b"")]),
+            fake_delta(json!({ "changes": [{ "type": "delete", "path": "/etc" }] }), &[]),
+        ];
+
+        for delta in cases {
+            let root = fake_root();
+            assert!(apply(root.path(), &mut delta.as_slice(), 1024).is_err());
+        }
+    }
+
+    #[test]
+    fn patch_source_mismatch() {
+        let root = fake_root();
+        let patch = fake_bsdiff(b"library v0", b"library v2", &[(10, 0, 0)]);
+        let delta = fake_delta(
+            json!({
+                "changes": [{
+                    "type": "patch",
+                    "path": "lib/app.so",
+                    "delta-format": "bsdiff",
+                    "source-sha256sum": utils::sha256sum(b"library v0"),
+                    "sha256sum": utils::sha256sum(b"library v2")
+                }]
+            }),
+            &[("lib/app.so", &patch)],
+        );
+
+        match apply(root.path(), &mut delta.as_slice(), 1024) {
+            Err(Error::ChecksumMismatch(p)) => assert_eq!(p, root.path().join("lib/app.so")),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(fs::read(root.path().join("lib/app.so")).unwrap(), b"library v1");
+        assert!(!root.path().join("lib/.app.so.updatehub").exists());
+    }
+
+    #[test]
+    #[ignore]
+    fn install_cloning_source() {
+        use std::io::Write;
+
+        // The active set's filesystem, with a file to be replaced
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        source.seek(SeekFrom::Start(2 * 1024 * 1024)).unwrap();
+        source.write_all(&[0]).unwrap();
+        utils::fs::format(source.path(), definitions::Filesystem::Ext4, &None).unwrap();
+        let target = tempfile::NamedTempFile::new().unwrap();
+
+        let delta = fake_delta(
+            json!({
+                "changes": [
+                    { "type": "file", "path": "app/data", "sha256sum": utils::sha256sum(b"v2") }
+                ]
+            }),
+            &[("app/data", b"v2")],
+        );
+        let download_dir = tempfile::tempdir().unwrap();
+        let sha256sum = utils::sha256sum(&delta);
+        fs::write(download_dir.path().join(&sha256sum), &delta).unwrap();
+
+        let obj = objects::FileDelta {
+            filename: "app.delta.tar".to_string(),
+            filesystem: definitions::Filesystem::Ext4,
+            size: delta.len() as u64,
+            sha256sum,
+            target_type: definitions::TargetType::ImageFile(target.path().to_path_buf()),
+            target_path: PathBuf::from("/"),
+
+            source: Some(source.path().to_path_buf()),
+            mount_options: String::default(),
+            install_if: None,
+        };
+
+        // Loop device next_free is not thread safe
+        let mutex = SERIALIZE.clone();
+        let _mutex = mutex.lock().unwrap();
+
+        let device = utils::fs::BlockDevice::from_image_file(source.path()).unwrap();
+        utils::fs::mount_map(device.path(), obj.filesystem, "", |path| {
+            fs::create_dir(path.join("app")).unwrap();
+            fs::write(path.join("app/data"), b"v1").unwrap();
+            fs::write(path.join("app/kept"), b"kept").unwrap();
+        })
+        .unwrap();
+        drop(device);
+
+        obj.check_requirements().unwrap();
+        obj.install(download_dir.path()).unwrap();
+
+        let device = utils::fs::BlockDevice::from_image_file(target.path()).unwrap();
+        utils::fs::mount_map(device.path(), obj.filesystem, "", |path| {
+            assert_eq!(fs::read(path.join("app/data")).unwrap(), b"v2");
+            assert_eq!(fs::read(path.join("app/kept")).unwrap(), b"kept");
+        })
+        .unwrap();
+    }
+}
@@ -5,18 +5,16 @@
 use super::{Error, Result};
 use crate::{
     object::{Info, Installer},
-    utils::{self, definitions::TargetTypeExt},
+    utils::{self, definitions::TargetTypeExt, mtd::MtdDevice},
 };
 
 use pkg_schema::{definitions, objects};
 use slog_scope::info;
+use std::fs::File;
 
 impl Installer for objects::Flash {
     fn check_requirements(&self) -> Result<()> {
         info!("'flash' handle checking requirements");
-        utils::fs::is_executable_in_path("nandwrite")?;
-        utils::fs::is_executable_in_path("flashcp")?;
-        utils::fs::is_executable_in_path("flash_erase")?;
 
         match self.target {
             definitions::TargetType::Device(_) | definitions::TargetType::MTDName(_) => {
@@ -41,14 +39,33 @@ impl Installer for objects::Flash {
             std::fs::File::open(&target).map_err(Error::from)
         });
 
-        let is_nand = utils::mtd::is_nand(&target)?;
+        let mut device = MtdDevice::open(&target)?;
+        let mut reported = 0;
+        utils::mtd::write_image(&mut device, File::open(source)?, |written| {
+            let percent = written * 100 / self.size.max(1);
+            if percent >= reported + 10 {
+                reported = percent - percent % 10;
+                info!("'flash' handler wrote {}% of {}", reported, self.filename);
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn verify(&self, download_dir: &std::path::Path) -> Result<()> {
+        info!("'flash' handler Verify {} ({})", self.filename, self.sha256sum);
 
-        easy_process::run(&format!("flash_erase {:?} 0 0", target))?;
+        if !super::is_verifiable(&self.install_if_different) {
+            info!("skipping verification as the object is installed only if its version differs");
+            return Ok(());
+        }
 
-        if is_nand {
-            easy_process::run(&format!("nandwrite -p {:?} {:?}", target, source))?;
-        } else {
-            easy_process::run(&format!("flashcp {:?} {:?}", source, target))?;
+        // Compared against the source as bad blocks skipped when writing
+        // shift the data in the device
+        let target = self.target.get_target()?;
+        let source = download_dir.join(self.sha256sum());
+        if !utils::mtd::verify_image(&mut MtdDevice::open(&target)?, File::open(source)?)? {
+            return Err(Error::VerificationFailed(target));
         }
 
         Ok(())
@@ -58,12 +75,8 @@ impl Installer for objects::Flash {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{
-        object::installer::tests::create_echo_bins,
-        utils::mtd::tests::{FakeMtd, MtdKind, SERIALIZE},
-    };
+    use crate::utils::mtd::tests::{FakeMtd, MtdKind, SERIALIZE};
     use pretty_assertions::assert_eq;
-    use std::env;
 
     fn fake_flash_obj(target: &str) -> objects::Flash {
         objects::Flash {
@@ -73,38 +86,10 @@ mod tests {
             target: definitions::TargetType::MTDName(target.to_string()),
 
             install_if_different: None,
+            install_if: None,
         }
     }
 
-    #[test]
-    fn check_requirements_with_missing_binaries() {
-        let flash_obj = fake_flash_obj("system0");
-
-        env::set_var("PATH", "");
-        let (_handle, _) = create_echo_bins(&["flash_erase"]).unwrap();
-        assert!(flash_obj.check_requirements().is_err());
-
-        env::set_var("PATH", "");
-        let (_handle, _) = create_echo_bins(&["flashcp"]).unwrap();
-        assert!(flash_obj.check_requirements().is_err());
-
-        env::set_var("PATH", "");
-        let (_handle, _) = create_echo_bins(&["nandwrite"]).unwrap();
-        assert!(flash_obj.check_requirements().is_err());
-
-        env::set_var("PATH", "");
-        let (_handle, _) = create_echo_bins(&["flash_erase", "nandwrite"]).unwrap();
-        assert!(flash_obj.check_requirements().is_err());
-
-        env::set_var("PATH", "");
-        let (_handle, _) = create_echo_bins(&["flash_erase", "flashcp"]).unwrap();
-        assert!(flash_obj.check_requirements().is_err());
-
-        env::set_var("PATH", "");
-        let (_handle, _) = create_echo_bins(&["nandwrite", "nandwrite"]).unwrap();
-        assert!(flash_obj.check_requirements().is_err());
-    }
-
     #[test]
     #[ignore]
     fn install_nor() {
@@ -114,19 +99,15 @@ mod tests {
         let flash_obj = fake_flash_obj("system0");
         let download_dir = tempfile::tempdir().unwrap();
         let source = download_dir.path().join(&flash_obj.sha256sum);
-
-        let (_handle, calls) = create_echo_bins(&["flash_erase", "flashcp", "nandwrite"]).unwrap();
+        let data = (0..flash_obj.size).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&source, &data).unwrap();
 
         flash_obj.check_requirements().unwrap();
         flash_obj.install(download_dir.path()).unwrap();
+        flash_obj.verify(download_dir.path()).unwrap();
 
-        let expected = format!(
-            "flash_erase {} 0 0\nflashcp {} {}\n",
-            target.to_str().unwrap(),
-            source.to_str().unwrap(),
-            target.to_str().unwrap()
-        );
-
-        assert_eq!(std::fs::read_to_string(calls).unwrap(), expected);
+        let content = std::fs::read(target).unwrap();
+        assert_eq!(&content[..data.len()], data.as_slice());
+        assert!(content[data.len()..].iter().all(|b| *b == 0xFF));
     }
 }
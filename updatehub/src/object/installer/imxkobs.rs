@@ -80,6 +80,7 @@ mod tests {
             search_exponent: 2,
             chip_0_device_path: Some(PathBuf::from("/dev/sda1")),
             chip_1_device_path: Some(PathBuf::from("/dev/sda2")),
+            install_if: None,
         }
     }
 
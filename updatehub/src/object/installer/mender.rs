@@ -0,0 +1,378 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use crate::{
+    firmware::Metadata,
+    object::{Info, Installer},
+    utils::{self, definitions::TargetTypeExt, io::Sha256Writer},
+};
+use pkg_schema::objects;
+use serde::Deserialize;
+use slog_scope::{debug, info};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+// Compression suffixes, in order of preference, used by the
+// `mender-artifact` tool for the header and data tarballs.
+const COMPRESSION_SUFFIXES: &[&str] = &[".gz", ".xz", ".zst", ""];
+const ROOTFS_IMAGE: &str = "rootfs-image";
+
+#[derive(Deserialize)]
+struct Version {
+    format: String,
+    version: u8,
+}
+
+#[derive(Deserialize)]
+struct HeaderInfo {
+    // Version 2 calls the payloads as 'updates'
+    #[serde(alias = "updates")]
+    payloads: Vec<Payload>,
+    // Used by version 2 of the format
+    #[serde(default)]
+    device_types_compatible: Vec<String>,
+    // Used by version 3 of the format
+    #[serde(default)]
+    artifact_depends: ArtifactDepends,
+}
+
+#[derive(Default, Deserialize)]
+struct ArtifactDepends {
+    #[serde(default)]
+    device_type: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+impl HeaderInfo {
+    fn device_types(&self) -> &[String] {
+        if self.device_types_compatible.is_empty() {
+            &self.artifact_depends.device_type
+        } else {
+            &self.device_types_compatible
+        }
+    }
+}
+
+impl Installer for objects::Mender {
+    fn check_requirements(&self) -> Result<()> {
+        info!("'mender' handle checking requirements");
+
+        if self.target_type.valid()?.is_device() {
+            let dev = self.target_type.get_target()?;
+            utils::fs::ensure_disk_space(&dev, self.required_install_size())?;
+            return Ok(());
+        }
+
+        Err(Error::InvalidTargetType(self.target_type.clone()))
+    }
+
+    fn check_compatibility(&self, download_dir: &Path, firmware: &Metadata) -> Result<()> {
+        info!("'mender' handle checking compatibility");
+
+        let header_info = read_header_info(&download_dir.join(self.sha256sum()))?;
+        if !header_info.device_types().iter().any(|t| t == &firmware.hardware) {
+            return Err(Error::IncompatibleHardware(firmware.hardware.clone()));
+        }
+
+        Ok(())
+    }
+
+    fn install(&self, download_dir: &Path) -> Result<()> {
+        info!("'mender' handler Install {} ({})", self.filename, self.sha256sum);
+
+        let device = &self.target_type.get_target()?;
+        let artifact = download_dir.join(self.sha256sum());
+        let chunk_size = self.chunk_size.0;
+
+        let header_info = read_header_info(&artifact)?;
+        match header_info.payloads.as_slice() {
+            [Payload { kind: Some(kind) }] if kind == ROOTFS_IMAGE => {}
+            _ => {
+                return Err(Error::InvalidMenderArtifact(format!(
+                    "only a single '{}' payload is supported",
+                    ROOTFS_IMAGE
+                )));
+            }
+        }
+
+        // The manifest lists the checksum of each file inside the payload
+        let (name, sha256sum) = read_payload_entry(&read_manifest(&artifact)?)?;
+
+        // The payload is kept compressed in a temporary file, so it can be
+        // checked against the manifest before anything is written to the
+        // target device and then uncompressed directly into it.
+        let mut payload = tempfile::NamedTempFile::new_in(download_dir)?;
+        extract_compressed_member(&artifact, "data/0000.tar", payload.as_file_mut())?;
+
+        let mut hasher = Sha256Writer::new(io::sink());
+        compress_tools::uncompress_archive_file(
+            fs::File::open(payload.path())?,
+            &mut hasher,
+            &name,
+        )?;
+        if hasher.finish().0 != sha256sum {
+            return Err(Error::InvalidMenderArtifact(format!("checksum mismatch for '{}'", name)));
+        }
+        debug!("checksum of '{}' matches the artifact's manifest", name);
+
+        let target = fs::OpenOptions::new().write(true).open(device)?;
+        let mut output =
+            utils::sparse::Writer::new(utils::io::timed_buf_writer(chunk_size, target), 0)?;
+        compress_tools::uncompress_archive_file(
+            fs::File::open(payload.path())?,
+            &mut output,
+            &name,
+        )?;
+        let (image, mut output) = output.finish()?;
+        output.flush()?;
+        utils::fs::sync(output.get_ref())?;
+        info!("'mender' handler wrote {} bytes to the target", image.written);
+
+        Ok(())
+    }
+}
+
+fn read_header_info(artifact: &Path) -> Result<HeaderInfo> {
+    let mut version = Vec::default();
+    compress_tools::uncompress_archive_file(fs::File::open(artifact)?, &mut version, "version")?;
+    let version = serde_json::from_slice::<Version>(&version)?;
+    if version.format != "mender" || !(2..=3).contains(&version.version) {
+        return Err(Error::InvalidMenderArtifact(format!(
+            "unsupported format: {} (version {})",
+            version.format, version.version
+        )));
+    }
+
+    let mut header = Vec::default();
+    extract_compressed_member(artifact, "header.tar", &mut header)?;
+
+    let mut header_info = Vec::default();
+    compress_tools::uncompress_archive_file(header.as_slice(), &mut header_info, "header-info")?;
+
+    Ok(serde_json::from_slice(&header_info)?)
+}
+
+fn read_manifest(artifact: &Path) -> Result<Vec<(String, String)>> {
+    let mut manifest = Vec::default();
+    compress_tools::uncompress_archive_file(fs::File::open(artifact)?, &mut manifest, "manifest")?;
+
+    String::from_utf8_lossy(&manifest)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(checksum), Some(file)) => Ok((checksum.to_owned(), file.to_owned())),
+                _ => Err(Error::InvalidMenderArtifact(format!("invalid manifest entry: {}", line))),
+            }
+        })
+        .collect()
+}
+
+/// Returns the name and checksum of the single file in the payload.
+fn read_payload_entry(manifest: &[(String, String)]) -> Result<(String, String)> {
+    let mut entries = manifest
+        .iter()
+        .filter_map(|(c, f)| f.strip_prefix("data/0000/").map(|f| (f.to_owned(), c.to_owned())));
+
+    match (entries.next(), entries.next()) {
+        (Some(entry), None) => Ok(entry),
+        _ => Err(Error::InvalidMenderArtifact("payload must have a single file".to_string())),
+    }
+}
+
+fn extract_compressed_member<W: Write>(
+    artifact: &Path,
+    base: &str,
+    mut target: W,
+) -> Result<String> {
+    for suffix in COMPRESSION_SUFFIXES {
+        let name = format!("{}{}", base, suffix);
+        match compress_tools::uncompress_archive_file(fs::File::open(artifact)?, &mut target, &name)
+        {
+            Ok(_) => return Ok(name),
+            Err(compress_tools::Error::FileNotFound) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(Error::InvalidMenderArtifact(format!("missing '{}' in artifact", base)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use pkg_schema::definitions;
+    use pretty_assertions::assert_eq;
+    use std::{io::Read, iter, path::PathBuf};
+    use tempfile::{tempdir, NamedTempFile, TempDir};
+
+    const PAYLOAD_BYTE: u8 = 0xA;
+    const PAYLOAD_SIZE: usize = 4096;
+
+    fn tar_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::default());
+        for (name, data) in entries {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut e = GzEncoder::new(Vec::default(), Compression::default());
+        e.write_all(data).unwrap();
+        e.finish().unwrap()
+    }
+
+    fn fake_mender_artifact(
+        header_info: serde_json::Value,
+        files: &[&str],
+    ) -> (TempDir, String, NamedTempFile) {
+        let payload = iter::repeat(PAYLOAD_BYTE).take(PAYLOAD_SIZE).collect::<Vec<_>>();
+        fake_mender_artifact_with_manifest(header_info, files, &payload)
+    }
+
+    // The manifest lists the checksum of `manifest_payload`, which differs
+    // from the one in the artifact for tampered artifacts.
+    fn fake_mender_artifact_with_manifest(
+        header_info: serde_json::Value,
+        files: &[&str],
+        manifest_payload: &[u8],
+    ) -> (TempDir, String, NamedTempFile) {
+        let download_dir = tempdir().unwrap();
+        let payload = iter::repeat(PAYLOAD_BYTE).take(PAYLOAD_SIZE).collect::<Vec<_>>();
+
+        let version = br#"{"format": "mender", "version": 3}"#;
+        let header = gzip(&tar_archive(&[("header-info", header_info.to_string().as_bytes())]));
+        let data =
+            gzip(&tar_archive(&files.iter().map(|f| (*f, payload.as_slice())).collect::<Vec<_>>()));
+
+        let mut manifest = format!(
+            "{}  version\n{}  header.tar.gz\n",
+            utils::sha256sum(version),
+            utils::sha256sum(&header)
+        );
+        for file in files {
+            manifest += &format!("{}  data/0000/{}\n", utils::sha256sum(manifest_payload), file);
+        }
+
+        let artifact = tar_archive(&[
+            ("version", version),
+            ("manifest", manifest.as_bytes()),
+            ("header.tar.gz", &header),
+            ("data/0000.tar.gz", &data),
+        ]);
+        let sha256sum = utils::sha256sum(&artifact);
+        fs::write(download_dir.path().join(&sha256sum), artifact).unwrap();
+
+        let target = NamedTempFile::new().unwrap();
+        (download_dir, sha256sum, target)
+    }
+
+    fn fake_mender_obj(sha256sum: &str, target: &Path) -> objects::Mender {
+        objects::Mender {
+            filename: "artifact.mender".to_string(),
+            size: 1024,
+            sha256sum: sha256sum.to_string(),
+            target_type: definitions::TargetType::Device(PathBuf::from(target)),
+
+            required_uncompressed_size: 0,
+            chunk_size: definitions::ChunkSize::default(),
+            install_if: None,
+        }
+    }
+
+    fn rootfs_header_info(device_type: &str) -> serde_json::Value {
+        serde_json::json!({
+            "payloads": [{ "type": "rootfs-image" }],
+            "artifact_provides": { "artifact_name": "release-1" },
+            "artifact_depends": { "device_type": [device_type] }
+        })
+    }
+
+    #[test]
+    fn install_rootfs_image() {
+        let (download_dir, sha256sum, mut target) =
+            fake_mender_artifact(rootfs_header_info("board"), &["rootfs.ext4"]);
+        let obj = fake_mender_obj(&sha256sum, target.path());
+        let firmware = crate::tests::TestEnvironment::build().finish().firmware.data;
+
+        obj.check_requirements().unwrap();
+        obj.check_compatibility(download_dir.path(), &firmware).unwrap();
+        obj.install(download_dir.path()).unwrap();
+
+        let mut content = Vec::default();
+        target.read_to_end(&mut content).unwrap();
+        assert_eq!(content, iter::repeat(PAYLOAD_BYTE).take(PAYLOAD_SIZE).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn incompatible_hardware() {
+        let (download_dir, sha256sum, target) =
+            fake_mender_artifact(rootfs_header_info("other-board"), &["rootfs.ext4"]);
+        let obj = fake_mender_obj(&sha256sum, target.path());
+        let firmware = crate::tests::TestEnvironment::build().finish().firmware.data;
+
+        match obj.check_compatibility(download_dir.path(), &firmware) {
+            Err(Error::IncompatibleHardware(hw)) => assert_eq!(hw, "board"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn unsupported_payload_type() {
+        let (download_dir, sha256sum, target) = fake_mender_artifact(
+            serde_json::json!({
+                "payloads": [{ "type": "single-file" }],
+                "artifact_depends": { "device_type": ["board"] }
+            }),
+            &["rootfs.ext4"],
+        );
+        let obj = fake_mender_obj(&sha256sum, target.path());
+
+        assert!(obj.install(download_dir.path()).is_err());
+    }
+
+    #[test]
+    fn payload_with_multiple_files() {
+        let (download_dir, sha256sum, target) =
+            fake_mender_artifact(rootfs_header_info("board"), &["rootfs.ext4", "extra"]);
+        let obj = fake_mender_obj(&sha256sum, target.path());
+
+        assert!(obj.install(download_dir.path()).is_err());
+    }
+
+    #[test]
+    fn tampered_payload_is_not_written() {
+        let (download_dir, sha256sum, mut target) = fake_mender_artifact_with_manifest(
+            rootfs_header_info("board"),
+            &["rootfs.ext4"],
+            b"original payload",
+        );
+        let obj = fake_mender_obj(&sha256sum, target.path());
+
+        match obj.install(download_dir.path()) {
+            Err(Error::InvalidMenderArtifact(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let mut content = Vec::default();
+        target.read_to_end(&mut content).unwrap();
+        assert!(content.is_empty(), "target was written before the checksum was checked");
+    }
+}
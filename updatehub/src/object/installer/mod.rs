@@ -2,20 +2,29 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+pub(crate) mod chunked;
 mod copy;
+mod emmc_boot;
+mod file_delta;
 mod flash;
 mod imxkobs;
+mod mender;
 mod raw;
+mod raw_delta;
 mod tarball;
 mod test;
 mod ubifs;
+mod zephyr;
 
 use super::{Error, Result};
-use crate::utils;
+use crate::{
+    firmware::Metadata,
+    utils::{self, io::Sha256Writer},
+};
 use find_binary_version::{self as fbv, BinaryKind};
 use pkg_schema::{definitions, Object};
 use slog_scope::debug;
-use std::io;
+use std::{fs, io, path::Path};
 
 pub(crate) trait Installer {
     fn check_requirements(&self) -> Result<()> {
@@ -23,6 +32,11 @@ pub(crate) trait Installer {
         Ok(())
     }
 
+    fn check_compatibility(&self, _: &std::path::Path, _: &Metadata) -> Result<()> {
+        debug!("running default check_compatibility");
+        Ok(())
+    }
+
     fn setup(&mut self) -> Result<()> {
         debug!("running default setup");
         Ok(())
@@ -34,6 +48,13 @@ pub(crate) trait Installer {
     }
 
     fn install(&self, download_dir: &std::path::Path) -> Result<()>;
+
+    /// Reads back what has been installed, checking it against the
+    /// object's expected content.
+    fn verify(&self, _: &std::path::Path) -> Result<()> {
+        debug!("running default verify");
+        Ok(())
+    }
 }
 
 impl Installer for Object {
@@ -41,6 +62,14 @@ impl Installer for Object {
         for_any_object!(self, o, { o.check_requirements() })
     }
 
+    fn check_compatibility(
+        &self,
+        download_dir: &std::path::Path,
+        firmware: &Metadata,
+    ) -> Result<()> {
+        for_any_object!(self, o, { o.check_compatibility(download_dir, firmware) })
+    }
+
     fn setup(&mut self) -> Result<()> {
         for_any_object!(self, o, { o.setup() })
     }
@@ -52,6 +81,48 @@ impl Installer for Object {
     fn cleanup(&mut self) -> Result<()> {
         for_any_object!(self, o, { o.cleanup() })
     }
+
+    fn verify(&self, download_dir: &std::path::Path) -> Result<()> {
+        for_any_object!(self, o, { o.verify(download_dir) })
+    }
+}
+
+/// Checks whether the object can be verified, objects which are installed
+/// only when their version differs might have been kept with a different
+/// content.
+fn is_verifiable(rule: &Option<definitions::InstallIfDifferent>) -> bool {
+    !matches!(
+        rule,
+        Some(definitions::InstallIfDifferent::KnownPattern { .. })
+            | Some(definitions::InstallIfDifferent::CustomPattern { .. })
+    )
+}
+
+/// Computes the SHA-256 and length of the object's content, decompressing
+/// it when needed.
+fn content_sha256sum(source: &Path, compressed: bool) -> Result<(String, u64)> {
+    let mut input = fs::File::open(source)?;
+    let mut output = Sha256Writer::new(io::sink());
+    if compressed {
+        compress_tools::uncompress_data(&mut input, &mut output)?;
+    } else {
+        io::copy(&mut input, &mut output)?;
+    }
+    let (sha256sum, len, _) = output.finish();
+
+    Ok((sha256sum, len))
+}
+
+/// Checks the first `len` bytes read back from `target` have the expected
+/// SHA-256.
+fn verify_read_back(target: &Path, sha256sum: &str, len: u64) -> Result<()> {
+    let mut output = Sha256Writer::new(io::sink());
+    io::copy(&mut io::Read::take(fs::File::open(target)?, len), &mut output)?;
+
+    match output.finish() {
+        (read_sha256sum, read_len, _) if read_sha256sum == sha256sum && read_len == len => Ok(()),
+        _ => Err(Error::VerificationFailed(target.to_path_buf())),
+    }
 }
 
 fn check_if_different<R: io::Read + io::Seek>(
@@ -14,7 +14,7 @@ use pkg_schema::{definitions, objects};
 use slog_scope::info;
 use std::{
     fs,
-    io::{BufRead, Read, Seek, SeekFrom, Write},
+    io::{BufRead, Seek, SeekFrom, Write},
     path::Path,
 };
 
@@ -22,27 +22,31 @@ impl Installer for objects::Raw {
     fn check_requirements(&self) -> Result<()> {
         info!("'raw' handle checking requirements");
 
-        if let definitions::TargetType::Device(dev) = self.target_type.valid()? {
-            utils::fs::ensure_disk_space(&dev, self.required_install_size())?;
-            return Ok(());
+        match self.target_type.valid()? {
+            // Image files grow within the filesystem holding them
+            definitions::TargetType::ImageFile(image) => {
+                let dir = image.parent().unwrap_or(image);
+                utils::fs::ensure_disk_space(dir, self.required_install_size())?;
+                Ok(())
+            }
+            target if target.is_device() => {
+                let dev = target.get_target()?;
+                utils::fs::ensure_disk_space(&dev, self.required_install_size())?;
+                Ok(())
+            }
+            _ => Err(Error::InvalidTargetType(self.target_type.clone())),
         }
-
-        Err(Error::InvalidTargetType(self.target_type.clone()))
     }
 
     fn install(&self, download_dir: &Path) -> Result<()> {
         info!("'raw' handler Install {} ({})", self.filename, self.sha256sum);
 
-        let device = match self.target_type {
-            definitions::TargetType::Device(ref p) => p,
-            _ => unreachable!("device should be secured by check_requirements"),
-        };
+        let device = &self.target_type.get_target()?;
         let source = download_dir.join(self.sha256sum());
         let chunk_size = self.chunk_size.0;
         let seek = self.seek * chunk_size as u64;
         let skip = self.skip.0 * chunk_size as u64;
         let truncate = self.truncate.0;
-        let count = self.count.clone();
 
         handle_install_if_different!(self.install_if_different, &self.sha256sum, {
             fs::OpenOptions::new()
@@ -58,45 +62,115 @@ impl Installer for objects::Raw {
 
         let mut input = utils::io::timed_buf_reader(chunk_size, fs::File::open(source)?);
         input.seek(SeekFrom::Start(skip))?;
-        let mut output = utils::io::timed_buf_writer(
-            chunk_size,
-            fs::OpenOptions::new().read(true).write(true).truncate(truncate).open(device)?,
-        );
-        output.seek(SeekFrom::Start(seek))?;
-
-        if self.compressed {
-            match count {
-                definitions::Count::All => compress_tools::uncompress_data(&mut input, &mut output),
-                definitions::Count::Limited(n) => {
-                    compress_tools::uncompress_data(&mut input.take(n as u64), &mut output)
-                }
-            }?;
+        let target = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(matches!(self.target_type, definitions::TargetType::ImageFile(_)))
+            .truncate(truncate)
+            .open(device)?;
+        let (image, written) = if self.skip_unchanged {
+            let output = utils::io::CompareWriter::new(target, chunk_size)?;
+            let (image, output) = write_image(self, input, output, seek)?;
+            (image, output.written())
         } else {
-            for _ in count {
-                let buf = input.fill_buf()?;
-                let len = buf.len();
-
-                // We break the loop in case we have no bytes left for
-                // read (EOF is reached).
-                if len == 0 {
-                    break;
-                }
-
-                output.write_all(&buf)?;
-                input.consume(len);
-            }
+            let output = utils::io::timed_buf_writer(chunk_size, target);
+            let (image, _) = write_image(self, input, output, seek)?;
+            let written = image.written;
+            (image, written)
+        };
+        info!("'raw' handler wrote {} bytes to the target", written);
+
+        // Holes at the end of the image don't grow regular files
+        let target = fs::OpenOptions::new().write(true).open(device)?;
+        let metadata = target.metadata()?;
+        if metadata.is_file() && metadata.len() < image.end {
+            target.set_len(image.end)?;
+        }
+
+        if self.discard_holes {
+            utils::fs::discard(device, &image.holes)?;
+        }
+        utils::fs::sync(&target)?;
+
+        Ok(())
+    }
+
+    fn verify(&self, download_dir: &Path) -> Result<()> {
+        info!("'raw' handler Verify {} ({})", self.filename, self.sha256sum);
+
+        if !super::is_verifiable(&self.install_if_different) {
+            info!("skipping verification as the object is installed only if its version differs");
+            return Ok(());
+        }
+
+        let device = self.target_type.get_target()?;
+        let chunk_size = self.chunk_size.0;
+        let source = download_dir.join(self.sha256sum());
+        let mut input = utils::io::timed_buf_reader(chunk_size, fs::File::open(source)?);
+        input.seek(SeekFrom::Start(self.skip.0 * chunk_size as u64))?;
+
+        let output = utils::io::ReadBackWriter::new(fs::File::open(&device)?);
+        let (_, output) = write_image(self, input, output, self.seek * chunk_size as u64)?;
+        if !output.matches() {
+            return Err(Error::VerificationFailed(device));
         }
 
         Ok(())
     }
 }
 
+/// Writes the source image into `output` at `seek`, returning the extent
+/// of the image written and the output itself.
+fn write_image<R, W>(
+    obj: &objects::Raw,
+    mut input: R,
+    output: W,
+    seek: u64,
+) -> Result<(utils::sparse::Image, W)>
+where
+    R: BufRead,
+    W: Write + Seek,
+{
+    let mut output = match &obj.bmap {
+        Some(bmap) => utils::sparse::Writer::with_bmap(output, seek, bmap)?,
+        None => utils::sparse::Writer::new(output, seek)?,
+    };
+
+    if obj.compressed {
+        match obj.count {
+            definitions::Count::All => compress_tools::uncompress_data(&mut input, &mut output),
+            definitions::Count::Limited(n) => {
+                compress_tools::uncompress_data(&mut input.take(n as u64), &mut output)
+            }
+        }?;
+    } else {
+        for _ in obj.count.clone() {
+            let buf = input.fill_buf()?;
+            let len = buf.len();
+
+            // We break the loop in case we have no bytes left for
+            // read (EOF is reached).
+            if len == 0 {
+                break;
+            }
+
+            output.write_all(&buf)?;
+            input.consume(len);
+        }
+    }
+
+    Ok(output.finish()?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use flate2::{write::GzEncoder, Compression};
     use pretty_assertions::assert_eq;
-    use std::{io, iter};
+    use std::{
+        io::{self, Read},
+        iter,
+    };
     use tempfile::{tempdir, NamedTempFile, TempDir};
 
     const DEFAULT_BYTE: u8 = 0xF;
@@ -144,6 +218,10 @@ mod tests {
                 seek,
                 count,
                 truncate: definitions::Truncate(truncate),
+                bmap: None,
+                discard_holes: false,
+                skip_unchanged: false,
+                install_if: None,
             },
             download_dir,
             source,
@@ -304,4 +382,91 @@ mod tests {
             .unwrap();
         check_unwritten_blocks(target_guard.as_file_mut(), 1024, 1024).unwrap();
     }
+
+    #[test]
+    fn raw_into_new_image_file() {
+        let size = 2048;
+        let chunk_size = 8;
+        let count = definitions::Count::All;
+
+        let (mut obj, download_dir, _source_guard, _target_guard, original_data) =
+            fake_raw_object(size, chunk_size, 0, 0, count.clone(), false, false).unwrap();
+        let image = download_dir.path().join("app.img");
+        obj.target_type = definitions::TargetType::ImageFile(image.clone());
+        obj.check_requirements().unwrap();
+        obj.setup().unwrap();
+        obj.install(download_dir.path()).unwrap();
+
+        validate_file(original_data, &mut fs::File::open(image).unwrap(), chunk_size, 0, 0, count)
+            .unwrap();
+    }
+
+    #[test]
+    fn raw_sparse_image_with_discarded_holes() {
+        use crate::utils::sparse::tests::{fake_sparse_image, Chunk};
+
+        let image = fake_sparse_image(&[
+            Chunk::Raw(&[ORIGINAL_BYTE; 1024]),
+            Chunk::DontCare(512),
+            Chunk::Fill(256, [ORIGINAL_BYTE; 4]),
+            Chunk::DontCare(1024),
+        ]);
+        let (mut obj, download_dir, mut source_guard, mut target_guard, _) =
+            fake_raw_object(0, 8, 0, 0, definitions::Count::All, false, false).unwrap();
+        source_guard.write_all(&image).unwrap();
+        target_guard.write_all(&[DEFAULT_BYTE; 12288]).unwrap();
+        obj.size = image.len() as u64;
+        obj.discard_holes = true;
+        obj.check_requirements().unwrap();
+        obj.setup().unwrap();
+        obj.install(download_dir.path()).unwrap();
+
+        let mut written = Vec::default();
+        target_guard.as_file_mut().seek(SeekFrom::Start(0)).unwrap();
+        target_guard.as_file_mut().read_to_end(&mut written).unwrap();
+        assert_eq!(written.len(), 12288);
+        assert!(written[..1024].iter().all(|b| *b == ORIGINAL_BYTE));
+        // Holes are only discarded in whole 4 KiB blocks
+        assert!(written[1024..3072].iter().all(|b| *b == DEFAULT_BYTE));
+        assert!(written[3072..4096].iter().all(|b| *b == ORIGINAL_BYTE));
+        assert!(written[4096..8192].iter().all(|b| *b == 0));
+        assert!(written[8192..].iter().all(|b| *b == DEFAULT_BYTE));
+    }
+
+    #[test]
+    fn raw_skip_unchanged_chunks() {
+        let size = 2048;
+        let chunk_size = 128;
+        let count = definitions::Count::All;
+
+        let (mut obj, download_dir, _source_guard, mut target_guard, original_data) =
+            fake_raw_object(size, chunk_size, 0, 0, count.clone(), false, false).unwrap();
+        obj.skip_unchanged = true;
+
+        // Only the chunks already holding the image are left untouched
+        target_guard.write_all(&original_data[..1000]).unwrap();
+        let input = io::BufReader::new(original_data.as_slice());
+        let output = utils::io::CompareWriter::new(target_guard.reopen().unwrap(), chunk_size);
+        let (image, output) = write_image(&obj, input, output.unwrap(), 0).unwrap();
+        assert_eq!(image.written, size);
+        assert_eq!(output.written(), size - 7 * chunk_size as u64);
+
+        obj.install(download_dir.path()).unwrap();
+        validate_file(original_data, target_guard.as_file_mut(), chunk_size, 0, 0, count).unwrap();
+    }
+
+    #[test]
+    fn raw_verify_read_back() {
+        let (obj, download_dir, _source_guard, mut target_guard, _) =
+            fake_raw_object(2048, 8, 0, 4, definitions::Count::All, false, true).unwrap();
+        obj.install(download_dir.path()).unwrap();
+        obj.verify(download_dir.path()).unwrap();
+
+        target_guard.as_file_mut().seek(SeekFrom::Start(100)).unwrap();
+        target_guard.as_file_mut().write_all(&[DEFAULT_BYTE]).unwrap();
+        match obj.verify(download_dir.path()) {
+            Err(Error::VerificationFailed(_)) => {}
+            r => panic!("Unexpected verification result: {:?}", r),
+        }
+    }
 }
@@ -0,0 +1,193 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use crate::{
+    object::{Info, Installer},
+    utils::{self, definitions::TargetTypeExt, io::Sha256Writer},
+};
+use pkg_schema::{definitions, objects};
+use slog_scope::info;
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+impl Installer for objects::RawDelta {
+    fn check_requirements(&self) -> Result<()> {
+        info!("'raw-delta' handle checking requirements");
+
+        if !self.source.exists() {
+            return Err(utils::Error::DeviceDoesNotExist.into());
+        }
+
+        if self.target_type.valid()?.is_device() {
+            let dev = self.target_type.get_target()?;
+            utils::fs::ensure_disk_space(&dev, self.required_install_size())?;
+            return Ok(());
+        }
+
+        Err(Error::InvalidTargetType(self.target_type.clone()))
+    }
+
+    fn install(&self, download_dir: &Path) -> Result<()> {
+        info!("'raw-delta' handler Install {} ({})", self.filename, self.sha256sum);
+
+        let device = &self.target_type.get_target()?;
+        let patch = download_dir.join(self.sha256sum());
+        let chunk_size = self.chunk_size.0;
+        let seek = self.seek * chunk_size as u64;
+
+        let mut source = utils::io::timed_buf_reader(chunk_size, fs::File::open(&self.source)?);
+        let mut hasher = Sha256Writer::new(io::sink());
+        io::copy(&mut (&mut source).take(self.source_size), &mut hasher)?;
+        let (sha256sum, len, _) = hasher.finish();
+        if len != self.source_size || sha256sum != self.source_sha256sum {
+            return Err(Error::ChecksumMismatch(self.source.clone()));
+        }
+
+        let mut output = utils::io::timed_buf_writer(
+            chunk_size,
+            fs::OpenOptions::new().read(true).write(true).open(device)?,
+        );
+        output.seek(SeekFrom::Start(seek))?;
+        let mut output = Sha256Writer::new(output);
+
+        match self.delta_format {
+            definitions::DeltaFormat::Bsdiff => {
+                utils::delta::bspatch(&mut source, self.source_size, &fs::read(patch)?, &mut output)
+            }
+            definitions::DeltaFormat::Vcdiff => utils::delta::vcdiff_patch(
+                &mut source,
+                self.source_size,
+                utils::io::timed_buf_reader(chunk_size, fs::File::open(patch)?),
+                self.size,
+                self.result_size,
+                &mut output,
+            ),
+        }?;
+
+        let (sha256sum, len, mut output) = output.finish();
+        output.flush()?;
+        utils::fs::sync(output.get_ref())?;
+        if len != self.result_size || sha256sum != self.result_sha256sum {
+            return Err(Error::ChecksumMismatch(device.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::delta::tests::{fake_bsdiff, fake_vcdiff};
+    use pretty_assertions::assert_eq;
+    use std::iter;
+    use tempfile::{tempdir, NamedTempFile, TempDir};
+
+    const SOURCE_BYTE: u8 = 0xA;
+    const SOURCE_SIZE: usize = 4096;
+
+    fn fake_raw_delta_object(
+        delta_format: definitions::DeltaFormat,
+        patch: &[u8],
+        source: &[u8],
+        result: &[u8],
+    ) -> (objects::RawDelta, TempDir, NamedTempFile, NamedTempFile) {
+        let download_dir = tempdir().unwrap();
+        let sha256sum = utils::sha256sum(patch);
+        fs::write(download_dir.path().join(&sha256sum), patch).unwrap();
+
+        // The source partition is usually bigger than the image it holds
+        let source_dev = NamedTempFile::new().unwrap();
+        fs::write(source_dev.path(), [source, &[0xF; 512][..]].concat()).unwrap();
+        let target_dev = NamedTempFile::new().unwrap();
+
+        let obj = objects::RawDelta {
+            filename: "rootfs.delta".to_string(),
+            size: patch.len() as u64,
+            sha256sum,
+            target_type: definitions::TargetType::Device(target_dev.path().to_path_buf()),
+
+            delta_format,
+            source: source_dev.path().to_path_buf(),
+            source_size: source.len() as u64,
+            source_sha256sum: utils::sha256sum(source),
+            result_size: result.len() as u64,
+            result_sha256sum: utils::sha256sum(result),
+
+            chunk_size: definitions::ChunkSize::default(),
+            seek: 0,
+            install_if: None,
+        };
+
+        (obj, download_dir, source_dev, target_dev)
+    }
+
+    fn source_and_result() -> (Vec<u8>, Vec<u8>) {
+        let source = iter::repeat(SOURCE_BYTE).take(SOURCE_SIZE).collect::<Vec<_>>();
+        let mut result = source.clone();
+        result[100] = 0xFF;
+        result.extend_from_slice(b"new content");
+        (source, result)
+    }
+
+    #[test]
+    fn install_bsdiff() {
+        let (source, result) = source_and_result();
+        let patch = fake_bsdiff(&source, &result, &[(SOURCE_SIZE, 11, 0)]);
+        let (obj, download_dir, _source, target) =
+            fake_raw_delta_object(definitions::DeltaFormat::Bsdiff, &patch, &source, &result);
+
+        obj.check_requirements().unwrap();
+        obj.install(download_dir.path()).unwrap();
+
+        assert_eq!(fs::read(target.path()).unwrap(), result);
+    }
+
+    #[test]
+    fn install_vcdiff() {
+        let (source, _) = source_and_result();
+        let patch = fake_vcdiff(&source, 10, 0xFF, b"new content");
+        let result = [&source[..], &[0xFF; 10], b"new content"].concat();
+        let (obj, download_dir, _source, target) =
+            fake_raw_delta_object(definitions::DeltaFormat::Vcdiff, &patch, &source, &result);
+
+        obj.check_requirements().unwrap();
+        obj.install(download_dir.path()).unwrap();
+
+        assert_eq!(fs::read(target.path()).unwrap(), result);
+    }
+
+    #[test]
+    fn source_mismatch() {
+        let (source, result) = source_and_result();
+        let patch = fake_bsdiff(&source, &result, &[(SOURCE_SIZE, 11, 0)]);
+        let (obj, download_dir, source_dev, target) =
+            fake_raw_delta_object(definitions::DeltaFormat::Bsdiff, &patch, &source, &result);
+        fs::write(source_dev.path(), &result).unwrap();
+
+        match obj.install(download_dir.path()) {
+            Err(Error::ChecksumMismatch(p)) => assert_eq!(p, source_dev.path()),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert!(fs::read(target.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn result_mismatch() {
+        let (source, result) = source_and_result();
+        let patch = fake_bsdiff(&source, &result, &[(SOURCE_SIZE, 11, 0)]);
+        let (mut obj, download_dir, _source, target) =
+            fake_raw_delta_object(definitions::DeltaFormat::Bsdiff, &patch, &source, &result);
+        obj.result_sha256sum = utils::sha256sum(&source);
+
+        match obj.install(download_dir.path()) {
+            Err(Error::ChecksumMismatch(p)) => assert_eq!(p, target.path()),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
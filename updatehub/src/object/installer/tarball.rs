@@ -2,13 +2,13 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use super::Result;
+use super::{Error, Result};
 use crate::{
     object::{Info, Installer},
     utils::{self, definitions::TargetTypeExt},
 };
 use pkg_schema::{definitions, objects};
-use slog_scope::info;
+use slog_scope::{debug, error, info};
 use std::path::Path;
 
 impl Installer for objects::Tarball {
@@ -18,7 +18,11 @@ impl Installer for objects::Tarball {
         match self.target {
             definitions::TargetType::Device(_)
             | definitions::TargetType::UBIVolume(_)
-            | definitions::TargetType::MTDName(_) => {
+            | definitions::TargetType::MTDName(_)
+            | definitions::TargetType::PartLabel(_)
+            | definitions::TargetType::PartUUID(_)
+            | definitions::TargetType::FsLabel(_)
+            | definitions::TargetType::GptPartitionName(_) => {
                 utils::fs::ensure_disk_space(
                     &self.target.get_target()?,
                     self.required_install_size(),
@@ -26,41 +30,120 @@ impl Installer for objects::Tarball {
                 self.target.valid()?;
                 Ok(())
             }
+            // The space available inside the image is only known once it is mounted
+            definitions::TargetType::ImageFile(_) => {
+                self.target.valid()?;
+                Ok(())
+            }
         }
     }
 
     fn install(&self, download_dir: &Path) -> Result<()> {
         info!("'tarball' handler Install {} ({})", self.filename, self.sha256sum);
 
-        let device = self.target.get_target()?;
+        let block_device = self.target.get_block_device()?;
+        let device = block_device.path();
         let filesystem = self.filesystem;
         let mount_options = &self.mount_options;
         let format_options = &self.target_format.format_options;
         let sha256sum = self.sha256sum();
         let target_path = self.target_path.strip_prefix("/").unwrap_or(&self.target_path);
         let source = download_dir.join(sha256sum);
+        let check_file = match (&self.install_if_different, &self.install_if_different_file) {
+            (Some(rule), Some(file)) => Some((rule, target_path.join(file))),
+            _ => None,
+        };
+
+        if let Some((rule, file)) = &check_file {
+            match utils::fs::mount_map(device, filesystem, mount_options, |path| {
+                is_installed(&path.join(file), rule, sha256sum)
+            })
+            .map_err(Error::from)
+            .and_then(|r| r)
+            {
+                Ok(true) => {
+                    info!("installation has been skipped (install if different): {}", rule);
+                    return Ok(());
+                }
+                Ok(false) => {
+                    debug!("installation will proceed (installation if different): {}", rule)
+                }
+                Err(e) => {
+                    error!("install if different check ({}) check failed, error: {}", rule, e)
+                }
+            }
+        }
 
         if self.target_format.should_format {
-            utils::fs::format(&device, filesystem, format_options)?;
+            utils::fs::format(device, filesystem, format_options)?;
         }
 
-        Ok(utils::fs::mount_map(&device, filesystem, mount_options, |path| {
+        Ok(utils::fs::mount_map(device, filesystem, mount_options, |path| {
             let dest = path.join(target_path);
-            let mut source = std::fs::File::open(source)?;
+            if dest.exists() && !dest.canonicalize()?.starts_with(path.canonicalize()?) {
+                return Err(utils::Error::UnsafeArchiveEntry(self.target_path.clone()));
+            }
+
+            // Entries are checked before anything is changed on the target,
+            // existing content is not taken into account when it is going away
+            let existing = if self.wipe_target { None } else { Some(dest.as_path()) };
+            let entries = utils::archive::safe_entries(&source, existing)?;
+            if self.wipe_target {
+                utils::fs::remove_dir_content(&dest)?;
+            }
+
+            let mut source = std::fs::File::open(&source)?;
             compress_tools::uncompress_archive(
                 &mut source,
                 &dest,
                 compress_tools::Ownership::Preserve,
             )?;
+
+            let (uid, gid) =
+                (&self.target_permissions.target_uid, &self.target_permissions.target_gid);
+            if uid.is_some() || gid.is_some() {
+                for entry in entries {
+                    utils::fs::lchown(&dest.join(entry), uid, gid)?;
+                }
+            }
+
+            if let Some((definitions::InstallIfDifferent::CheckSum, file)) = &check_file {
+                let file = path.join(file);
+                if let Some(parent) = file.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(file, sha256sum)?;
+            }
+
             utils::Result::Ok(())
         })??)
     }
 }
 
+/// Whether the object is already installed according to `file`, which
+/// holds the checksum of the object installed last or a version matched by
+/// the rule's pattern.
+fn is_installed(
+    file: &Path,
+    rule: &definitions::InstallIfDifferent,
+    sha256sum: &str,
+) -> Result<bool> {
+    if !file.exists() {
+        return Ok(false);
+    }
+
+    match rule {
+        definitions::InstallIfDifferent::CheckSum => {
+            Ok(std::fs::read_to_string(file)?.trim() == sha256sum)
+        }
+        rule => super::check_if_different(&mut std::fs::File::open(file)?, rule, sha256sum),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::object::installer::tests::SERIALIZE;
+    use crate::{object::installer::tests::SERIALIZE, utils::definitions::IdExt};
     use pretty_assertions::assert_eq;
     use std::{
         fs,
@@ -103,16 +186,22 @@ mod tests {
             target: definitions::TargetType::Device(device.clone()),
             target_path: PathBuf::from("/"),
 
+            install_if_different: None,
+            install_if_different_file: None,
+            target_permissions: definitions::TargetPermissions::default(),
+            wipe_target: false,
             compressed: false,
             required_uncompressed_size: CONTENT_SIZE as u64,
             target_format: definitions::TargetFormat::default(),
             mount_options: String::default(),
+            install_if: None,
         };
         f(&mut obj);
 
         // Setup preinstall structure
         utils::fs::mount_map(&device, definitions::Filesystem::Ext4, &"", |path| {
             fs::create_dir(path.join("existing_dir"))?;
+            fs::write(path.join("existing_dir/stale"), b"stale")?;
             utils::Result::Ok(())
         })??;
 
@@ -126,15 +215,25 @@ mod tests {
         utils::fs::mount_map(&device, obj.filesystem, &obj.mount_options.clone(), |path| {
             let assert_metadata = |p: &Path| -> crate::utils::Result<()> {
                 let metadata = p.metadata()?;
+                let permissions = &obj.target_permissions;
                 assert_eq!(metadata.mode() % 0o1000, 0o664);
-                assert_eq!(metadata.uid(), 1000);
-                assert_eq!(metadata.gid(), 1000);
+                assert_eq!(
+                    metadata.uid(),
+                    permissions.target_uid.as_ref().map_or(1000, IdExt::as_u32)
+                );
+                assert_eq!(
+                    metadata.gid(),
+                    permissions.target_gid.as_ref().map_or(1000, IdExt::as_u32)
+                );
 
                 Ok(())
             };
             let dest = path.join(&obj.target_path.strip_prefix("/")?);
             assert_metadata(&dest.join("tree/branch1/leaf"))?;
             assert_metadata(&dest.join("tree/branch2/leaf"))?;
+            if obj.target_path == Path::new("/existing_dir") {
+                assert_eq!(dest.join("stale").exists(), !obj.wipe_target);
+            }
 
             utils::Result::Ok(())
         })??;
@@ -155,4 +254,114 @@ mod tests {
     fn install_over_unformated_partion() {
         exec_test_with_tarball(|obj| obj.target_path = PathBuf::from("/existing_dir")).unwrap();
     }
+
+    #[test]
+    #[ignore]
+    fn install_into_image_file() {
+        let mut image = tempfile::NamedTempFile::new().unwrap();
+        image.seek(SeekFrom::Start(1024 * 1024 + CONTENT_SIZE as u64)).unwrap();
+        image.write_all(&[0]).unwrap();
+        utils::fs::format(image.path(), definitions::Filesystem::Ext4, &None).unwrap();
+
+        let obj = objects::Tarball {
+            filename: "".to_string(),
+            filesystem: definitions::Filesystem::Ext4,
+            size: CONTENT_SIZE as u64,
+            sha256sum: "tree.tar".to_string(),
+            target: definitions::TargetType::ImageFile(image.path().to_path_buf()),
+            target_path: PathBuf::from("/"),
+
+            install_if_different: None,
+            install_if_different_file: None,
+            target_permissions: definitions::TargetPermissions::default(),
+            wipe_target: false,
+            compressed: false,
+            required_uncompressed_size: CONTENT_SIZE as u64,
+            target_format: definitions::TargetFormat::default(),
+            mount_options: String::default(),
+            install_if: None,
+        };
+
+        // Loop device next_free is not thread safe
+        let mutex = SERIALIZE.clone();
+        let _mutex = mutex.lock().unwrap();
+        obj.check_requirements().unwrap();
+        obj.install(&PathBuf::from("fixtures")).unwrap();
+
+        let device = utils::fs::BlockDevice::from_image_file(image.path()).unwrap();
+        utils::fs::mount_map(device.path(), obj.filesystem, "", |path| {
+            assert!(path.join("tree/branch1/leaf").exists());
+            assert!(path.join("tree/branch2/leaf").exists());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn install_wiping_target() {
+        exec_test_with_tarball(|obj| {
+            obj.target_path = PathBuf::from("/existing_dir");
+            obj.wipe_target = true;
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn install_with_target_owner() {
+        exec_test_with_tarball(|obj| {
+            obj.target_permissions.target_uid =
+                Some(definitions::target_permissions::Uid::Number(0));
+            obj.target_permissions.target_gid =
+                Some(definitions::target_permissions::Gid::Number(0));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn skip_install_if_checksum_matches() {
+        let mut image = tempfile::NamedTempFile::new().unwrap();
+        image.seek(SeekFrom::Start(1024 * 1024 + CONTENT_SIZE as u64)).unwrap();
+        image.write_all(&[0]).unwrap();
+        utils::fs::format(image.path(), definitions::Filesystem::Ext4, &None).unwrap();
+
+        let obj = objects::Tarball {
+            filename: "".to_string(),
+            filesystem: definitions::Filesystem::Ext4,
+            size: CONTENT_SIZE as u64,
+            sha256sum: "tree.tar".to_string(),
+            target: definitions::TargetType::ImageFile(image.path().to_path_buf()),
+            target_path: PathBuf::from("/"),
+
+            install_if_different: Some(definitions::InstallIfDifferent::CheckSum),
+            install_if_different_file: Some(PathBuf::from("etc/tree.sha256sum")),
+            target_permissions: definitions::TargetPermissions::default(),
+            wipe_target: false,
+            compressed: false,
+            required_uncompressed_size: CONTENT_SIZE as u64,
+            target_format: definitions::TargetFormat::default(),
+            mount_options: String::default(),
+            install_if: None,
+        };
+
+        // Loop device next_free is not thread safe
+        let mutex = SERIALIZE.clone();
+        let _mutex = mutex.lock().unwrap();
+        let device = || utils::fs::BlockDevice::from_image_file(image.path()).unwrap();
+
+        obj.install(&PathBuf::from("fixtures")).unwrap();
+        utils::fs::mount_map(device().path(), obj.filesystem, "", |path| {
+            assert_eq!(fs::read_to_string(path.join("etc/tree.sha256sum")).unwrap(), "tree.tar");
+            fs::remove_file(path.join("tree/branch1/leaf")).unwrap();
+        })
+        .unwrap();
+
+        // The manifest matches so nothing is extracted again
+        obj.install(&PathBuf::from("fixtures")).unwrap();
+        utils::fs::mount_map(device().path(), obj.filesystem, "", |path| {
+            assert!(!path.join("tree/branch1/leaf").exists());
+        })
+        .unwrap();
+    }
 }
@@ -46,6 +46,14 @@ impl Installer for objects::Ubifs {
 
         Ok(())
     }
+
+    fn verify(&self, download_dir: &std::path::Path) -> Result<()> {
+        info!("'ubifs' handler Verify {} ({})", self.filename, self.sha256sum);
+
+        let source = download_dir.join(self.sha256sum());
+        let (sha256sum, len) = super::content_sha256sum(&source, self.compressed)?;
+        super::verify_read_back(&self.target.get_target()?, &sha256sum, len)
+    }
 }
 
 #[cfg(test)]
@@ -67,6 +75,7 @@ mod tests {
 
             compressed: false,
             required_uncompressed_size: 2048,
+            install_if: None,
         }
     }
 
@@ -0,0 +1,333 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use crate::{
+    firmware::Metadata,
+    object::{Info, Installer},
+    utils::{self, definitions::TargetTypeExt, mtd::MtdDevice},
+};
+use pkg_schema::{definitions, objects};
+use slog_scope::info;
+use std::{
+    convert::TryInto,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+};
+
+// From https://github.com/mcu-tools/mcuboot/blob/master/boot/bootutil/include/bootutil/image.h
+const IMAGE_MAGIC: u32 = 0x96f3_b83d;
+const IMAGE_HEADER_SIZE: usize = 32;
+const IMAGE_TLV_INFO_MAGIC: u16 = 0x6907;
+const IMAGE_TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+const IMAGE_TLV_INFO_SIZE: usize = 4;
+const IMAGE_TLV_SHA256: u16 = 0x10;
+const SHA256_SIZE: usize = 32;
+
+// From https://github.com/mcu-tools/mcuboot/blob/master/boot/bootutil/src/bootutil_misc.c
+const BOOT_MAGIC: [u8; 16] = [
+    0x77, 0xc2, 0x95, 0xf3, 0x60, 0xd2, 0xef, 0x7f, 0x35, 0x52, 0x50, 0x0f, 0x2c, 0xb6, 0x79, 0x80,
+];
+const ERASED_BYTE: u8 = 0xff;
+
+#[derive(Debug)]
+struct ImageHeader {
+    header_size: usize,
+    protected_tlv_size: usize,
+    image_size: usize,
+}
+
+impl ImageHeader {
+    fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < IMAGE_HEADER_SIZE {
+            return Err(invalid_image("image is smaller than its header"));
+        }
+
+        if read_u32(data, 0) != IMAGE_MAGIC {
+            return Err(invalid_image("bad header magic"));
+        }
+
+        let header = ImageHeader {
+            header_size: read_u16(data, 8) as usize,
+            protected_tlv_size: read_u16(data, 10) as usize,
+            image_size: read_u32(data, 12) as usize,
+        };
+        if header.header_size < IMAGE_HEADER_SIZE {
+            return Err(invalid_image("header size is smaller than the header itself"));
+        }
+
+        Ok(header)
+    }
+
+    /// Range of the image covered by the SHA-256 TLV, which includes the
+    /// header, the image body and the protected TLV area.
+    fn hashed_range(&self) -> Result<Range<usize>> {
+        let end = self
+            .header_size
+            .checked_add(self.image_size)
+            .and_then(|end| end.checked_add(self.protected_tlv_size))
+            .ok_or_else(|| invalid_image("image size overflows"))?;
+        Ok(0..end)
+    }
+}
+
+impl Installer for objects::Zephyr {
+    fn check_requirements(&self) -> Result<()> {
+        info!("'zephyr' handle checking requirements");
+
+        match self.target {
+            definitions::TargetType::MTDName(_) | definitions::TargetType::Device(_) => {}
+            _ => return Err(Error::InvalidTargetType(self.target.clone())),
+        }
+
+        self.target.valid()?;
+        Ok(())
+    }
+
+    fn check_compatibility(&self, download_dir: &Path, _: &Metadata) -> Result<()> {
+        info!("'zephyr' handle checking image {}", self.filename);
+        validate_image(&fs::read(download_dir.join(self.sha256sum()))?)?;
+        Ok(())
+    }
+
+    fn install(&self, download_dir: &Path) -> Result<()> {
+        info!("'zephyr' handler Install {} ({})", self.filename, self.sha256sum);
+
+        let target = self.target.get_target()?;
+        let image = fs::read(download_dir.join(self.sha256sum()))?;
+        let image_len = validate_image(&image)?;
+
+        if let definitions::TargetType::MTDName(_) = self.target {
+            utils::mtd::erase(&mut MtdDevice::open(&target)?)?;
+        }
+
+        let mut output = fs::OpenOptions::new().read(true).write(true).open(&target)?;
+        let slot_size = output.seek(SeekFrom::End(0))?;
+        if (image_len + BOOT_MAGIC.len()) as u64 > slot_size {
+            return Err(invalid_image("image does not fit in the secondary slot"));
+        }
+
+        output.seek(SeekFrom::Start(0))?;
+        output.write_all(&image[..image_len])?;
+
+        // Block devices do not get erased, so we clear anything left between the
+        // image and the trailer, as a stale trailer would confuse the bootloader.
+        if let definitions::TargetType::Device(_) = self.target {
+            let padding = slot_size - (image_len + BOOT_MAGIC.len()) as u64;
+            io::copy(&mut io::repeat(ERASED_BYTE).take(padding), &mut output)?;
+        }
+
+        // Writing only the magic to the secondary slot trailer marks the image
+        // as pending in test mode, so MCUboot swaps it on next boot and reverts
+        // unless the new image confirms itself.
+        output.seek(SeekFrom::Start(slot_size - BOOT_MAGIC.len() as u64))?;
+        output.write_all(&BOOT_MAGIC)?;
+        utils::fs::sync(&output)?;
+
+        Ok(())
+    }
+}
+
+/// Validates the MCUboot header and TLVs, returning the length of the image
+/// including its trailing TLV area.
+fn validate_image(data: &[u8]) -> Result<usize> {
+    let header = ImageHeader::parse(data)?;
+    let hashed = header.hashed_range()?;
+
+    if header.protected_tlv_size != 0 {
+        let offset = hashed.end - header.protected_tlv_size;
+        if bounded(offset, IMAGE_TLV_INFO_SIZE, data.len()).is_none() {
+            return Err(invalid_image("image is smaller than its declared size"));
+        }
+        if read_u16(data, offset) != IMAGE_TLV_PROT_INFO_MAGIC
+            || read_u16(data, offset + 2) as usize != header.protected_tlv_size
+        {
+            return Err(invalid_image("bad protected TLV area"));
+        }
+    }
+
+    let info = hashed.end;
+    let mut offset = bounded(info, IMAGE_TLV_INFO_SIZE, data.len())
+        .ok_or_else(|| invalid_image("image is smaller than its declared size"))?;
+    if read_u16(data, info) != IMAGE_TLV_INFO_MAGIC {
+        return Err(invalid_image("bad TLV info magic"));
+    }
+
+    let end = bounded(info, read_u16(data, info + 2) as usize, data.len())
+        .ok_or_else(|| invalid_image("TLV area goes beyond the end of the image"))?;
+
+    let mut digest = None;
+    while offset < end {
+        let start = bounded(offset, 4, end).ok_or_else(|| invalid_image("truncated TLV entry"))?;
+        let kind = read_u16(data, offset);
+        let len = read_u16(data, offset + 2) as usize;
+        let next = bounded(start, len, end).ok_or_else(|| invalid_image("truncated TLV entry"))?;
+        let value = &data[start..next];
+
+        if kind == IMAGE_TLV_SHA256 {
+            if len != SHA256_SIZE {
+                return Err(invalid_image("bad SHA-256 TLV length"));
+            }
+            digest = Some(value);
+        }
+
+        offset = next;
+    }
+
+    match digest {
+        Some(digest) if utils::hex_encode(digest) == utils::sha256sum(&data[hashed]) => Ok(end),
+        Some(_) => Err(invalid_image("SHA-256 TLV does not match the image")),
+        None => Err(invalid_image("missing SHA-256 TLV")),
+    }
+}
+
+fn invalid_image(reason: &str) -> Error {
+    Error::InvalidMcubootImage(reason.to_owned())
+}
+
+/// End of the `len` bytes starting at `offset`, as long as it doesn't go
+/// beyond `limit`.
+fn bounded(offset: usize, len: usize, limit: usize) -> Option<usize> {
+    offset.checked_add(len).filter(|end| *end <= limit)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::iter;
+    use tempfile::{tempdir, NamedTempFile, TempDir};
+
+    const SLOT_SIZE: usize = 4096;
+    const IMAGE_BYTE: u8 = 0xA;
+    const IMAGE_SIZE: usize = 1000;
+
+    fn fake_mcuboot_image(protected_tlvs: bool) -> Vec<u8> {
+        let mut image = Vec::new();
+        let protected_tlv_size = if protected_tlvs { 12 } else { 0 };
+
+        image.extend_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        image.extend_from_slice(&0u32.to_le_bytes());
+        image.extend_from_slice(&(IMAGE_HEADER_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&(protected_tlv_size as u16).to_le_bytes());
+        image.extend_from_slice(&(IMAGE_SIZE as u32).to_le_bytes());
+        image.resize(IMAGE_HEADER_SIZE, 0);
+        image.extend(iter::repeat(IMAGE_BYTE).take(IMAGE_SIZE));
+
+        if protected_tlvs {
+            image.extend_from_slice(&IMAGE_TLV_PROT_INFO_MAGIC.to_le_bytes());
+            image.extend_from_slice(&(protected_tlv_size as u16).to_le_bytes());
+            image.extend_from_slice(&0x50u16.to_le_bytes());
+            image.extend_from_slice(&4u16.to_le_bytes());
+            image.extend_from_slice(&1u32.to_le_bytes());
+        }
+
+        let digest = openssl::sha::sha256(&image);
+        image.extend_from_slice(&IMAGE_TLV_INFO_MAGIC.to_le_bytes());
+        image.extend_from_slice(&((IMAGE_TLV_INFO_SIZE + 4 + SHA256_SIZE) as u16).to_le_bytes());
+        image.extend_from_slice(&IMAGE_TLV_SHA256.to_le_bytes());
+        image.extend_from_slice(&(SHA256_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&digest);
+
+        image
+    }
+
+    fn fake_zephyr_object(image: &[u8]) -> (objects::Zephyr, TempDir, NamedTempFile) {
+        let download_dir = tempdir().unwrap();
+        let sha256sum = utils::sha256sum(image);
+        fs::write(download_dir.path().join(&sha256sum), image).unwrap();
+
+        let target = NamedTempFile::new().unwrap();
+        fs::write(target.path(), vec![0; SLOT_SIZE]).unwrap();
+
+        let obj = objects::Zephyr {
+            filename: "zephyr.signed.bin".to_string(),
+            size: image.len() as u64,
+            sha256sum,
+            target: definitions::TargetType::Device(target.path().to_path_buf()),
+            install_if: None,
+        };
+
+        (obj, download_dir, target)
+    }
+
+    #[test]
+    fn validate_valid_image() {
+        let image = fake_mcuboot_image(false);
+        assert_eq!(validate_image(&image).unwrap(), image.len());
+
+        let image = fake_mcuboot_image(true);
+        assert_eq!(validate_image(&image).unwrap(), image.len());
+    }
+
+    #[test]
+    fn validate_invalid_image() {
+        let mut image = fake_mcuboot_image(false);
+        image[0] = 0;
+        assert!(validate_image(&image).is_err());
+
+        let mut image = fake_mcuboot_image(false);
+        image[IMAGE_HEADER_SIZE] = 0;
+        assert!(validate_image(&image).is_err());
+
+        let image = fake_mcuboot_image(true);
+        assert!(validate_image(&image[..image.len() - 1]).is_err());
+
+        let image = fake_mcuboot_image(false);
+        assert!(validate_image(&image[..IMAGE_HEADER_SIZE + IMAGE_SIZE]).is_err());
+
+        let mut image = fake_mcuboot_image(false);
+        image[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(validate_image(&image).is_err());
+    }
+
+    #[test]
+    fn overflowing_sizes() {
+        let header = ImageHeader {
+            header_size: IMAGE_HEADER_SIZE,
+            protected_tlv_size: 0,
+            image_size: usize::MAX,
+        };
+        match header.hashed_range() {
+            Err(Error::InvalidMcubootImage(reason)) => assert_eq!(reason, "image size overflows"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(bounded(usize::MAX, 4, usize::MAX), None);
+        assert_eq!(bounded(4, 4, 8), Some(8));
+    }
+
+    #[test]
+    fn install_marks_image_as_pending() {
+        let image = fake_mcuboot_image(false);
+        let (obj, download_dir, target) = fake_zephyr_object(&image);
+
+        obj.check_requirements().unwrap();
+        obj.install(download_dir.path()).unwrap();
+
+        let slot = fs::read(target.path()).unwrap();
+        assert_eq!(slot.len(), SLOT_SIZE);
+        assert_eq!(&slot[..image.len()], &image[..]);
+        assert!(slot[image.len()..SLOT_SIZE - BOOT_MAGIC.len()].iter().all(|b| *b == ERASED_BYTE));
+        assert_eq!(&slot[SLOT_SIZE - BOOT_MAGIC.len()..], &BOOT_MAGIC);
+    }
+
+    #[test]
+    fn install_image_bigger_than_slot() {
+        let image = fake_mcuboot_image(false);
+        let (obj, download_dir, target) = fake_zephyr_object(&image);
+        fs::write(target.path(), vec![0; image.len()]).unwrap();
+
+        assert!(obj.install(download_dir.path()).is_err());
+    }
+}
@@ -85,13 +85,19 @@ macro_rules! impl_compressed_object_info {
 macro_rules! for_any_object {
     ($mode:ident, $alias:ident, $code:block) => {
         match $mode {
+            Object::Chunked($alias) => $code,
             Object::Copy($alias) => $code,
+            Object::EmmcBoot($alias) => $code,
+            Object::FileDelta($alias) => $code,
             Object::Flash($alias) => $code,
             Object::Imxkobs($alias) => $code,
+            Object::Mender($alias) => $code,
             Object::Raw($alias) => $code,
+            Object::RawDelta($alias) => $code,
             Object::Tarball($alias) => $code,
             Object::Test($alias) => $code,
             Object::Ubifs($alias) => $code,
+            Object::Zephyr($alias) => $code,
         }
     };
 }
@@ -24,12 +24,36 @@ pub enum Error {
     #[error("Unsupported target type: {0:?}")]
     InvalidTargetType(pkg_schema::definitions::TargetType),
 
+    #[error("Incompatible with hardware: {0}")]
+    IncompatibleHardware(String),
+
+    #[error("Invalid Mender artifact: {0}")]
+    InvalidMenderArtifact(String),
+
+    #[error("Invalid MCUboot image: {0}")]
+    InvalidMcubootImage(String),
+
+    #[error("Checksum mismatch on: {0:?}")]
+    ChecksumMismatch(std::path::PathBuf),
+
+    #[error("Read-back verification failed on: {0:?}")]
+    VerificationFailed(std::path::PathBuf),
+
+    #[error("Invalid file delta: {0}")]
+    InvalidFileDelta(String),
+
+    #[error("Chunk not available on store nor seed: {0}")]
+    MissingChunk(String),
+
     #[error("Utils error: {0}")]
     Utils(#[from] crate::utils::Error),
 
     #[error("Io error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("Json error: {0}")]
+    SerdeJson(#[from] serde_json::Error),
+
     #[error("Process error: {0}")]
     Process(#[from] easy_process::Error),
 }
@@ -2,15 +2,25 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::firmware::{
-    self,
-    installation_set::{self, Set},
+use crate::{
+    firmware::{
+        self,
+        installation_set::{self, Set},
+    },
+    object::Info,
+    settings::Settings,
 };
 use chrono::{DateTime, NaiveDateTime, Utc};
 use derive_more::{Deref, DerefMut};
+use pkg_schema::Object;
 use sdk::api::info::runtime_settings as api;
 use slog_scope::{debug, warn};
-use std::{fs, io, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -26,6 +36,12 @@ pub enum Error {
 
     #[error("invalid runtime settings destination")]
     InvalidDestination,
+
+    #[error("installation set {0} has no update installed on it")]
+    UnknownInstallationSet(Set),
+
+    #[error("installation set {0} was not synced to the storage")]
+    InstallationSetNotSynced(Set),
 }
 
 #[derive(Clone, Debug, Deref, DerefMut, PartialEq)]
@@ -40,7 +56,14 @@ impl Default for RuntimeSettings {
                 now: false,
                 server_address: api::ServerAddress::Default,
             },
-            update: api::RuntimeUpdate { upgrade_to_installation: None, applied_package_uid: None },
+            update: api::RuntimeUpdate {
+                upgrade_to_installation: None,
+                upgrade_from_installation: None,
+                applied_package_uid: None,
+                minimum_security_version: None,
+                common_objects: BTreeMap::default(),
+                installation_sets: BTreeMap::default(),
+            },
             path: std::path::PathBuf::new(),
             persistent: false,
         })
@@ -92,7 +115,17 @@ impl RuntimeSettings {
         }
 
         debug!("saving runtime settings from {:?}...", &self.path);
-        fs::write(&self.path, self.serialize()?)?;
+        // The settings are replaced at once and synced, as they track the
+        // installation progress across power cuts
+        let name = self.path.file_name().ok_or_else(|| Error::InvalidDestination)?;
+        let tmp = self.path.with_file_name(format!("{}.tmp", name.to_string_lossy()));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(self.serialize()?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        if !parent.as_os_str().is_empty() {
+            fs::File::open(parent)?.sync_all()?;
+        }
 
         Ok(())
     }
@@ -101,7 +134,7 @@ impl RuntimeSettings {
         Ok(serde_json::to_string(&self.0)?)
     }
 
-    pub(crate) fn get_inactive_installation_set(&self) -> Result<Set> {
+    pub(crate) fn get_inactive_installation_set(&self, settings: &Settings) -> Result<Set> {
         Ok(match self.update.upgrade_to_installation {
             // If upgrade_to_installation has already been set
             // the current inactive installation_set will already be swapped
@@ -109,7 +142,7 @@ impl RuntimeSettings {
             Some(s) => Set(s),
             // If no installation has been made so far we can check
             // the system for the current inactive installation set
-            None => installation_set::inactive()?,
+            None => installation_set::inactive(&settings.update, &self.update.installation_sets)?,
         })
     }
 
@@ -156,8 +189,79 @@ impl RuntimeSettings {
         self.save()
     }
 
-    pub(crate) fn set_upgrading_to(&mut self, new_install_set: Set) -> Result<()> {
+    pub(crate) fn minimum_security_version(&self) -> Option<&str> {
+        self.update.minimum_security_version.as_deref()
+    }
+
+    pub(crate) fn set_minimum_security_version(&mut self, version: &str) -> Result<()> {
+        self.update.minimum_security_version = Some(version.to_string());
+        self.save()
+    }
+
+    /// Whether the common object was already installed with the same
+    /// content by a previous update.
+    pub(crate) fn is_common_object_installed(&self, object: &Object) -> bool {
+        self.update.common_objects.get(&common_object_key(object)).map(String::as_str)
+            == Some(object.sha256sum())
+    }
+
+    pub(crate) fn set_common_object_installed(&mut self, object: &Object) -> Result<()> {
+        self.update
+            .common_objects
+            .insert(common_object_key(object), object.sha256sum().to_string());
+        self.save()
+    }
+
+    pub(crate) fn set_upgrading_to(&mut self, active: Set, new_install_set: Set) -> Result<()> {
         self.update.upgrade_to_installation = Some(new_install_set.0);
+        self.update.upgrade_from_installation = Some(active.0);
+        self.save()
+    }
+
+    /// Installation set which was active when the update was installed.
+    pub(crate) fn upgrading_from(&self) -> Option<Set> {
+        self.update.upgrade_from_installation.map(Set)
+    }
+
+    /// Records the installation set is being installed on, so it is no
+    /// longer known to be good until validated.
+    pub(crate) fn set_installing(&mut self, set: Set) -> Result<()> {
+        self.update.installation_sets.insert(
+            set.0,
+            api::InstallationSetStatus {
+                installed_at: Utc::now(),
+                known_good: false,
+                synced: false,
+            },
+        );
+        self.save()
+    }
+
+    /// Records everything installed on the set has been flushed to the
+    /// storage, so it can be made active.
+    pub(crate) fn set_installation_set_synced(&mut self, set: Set) -> Result<()> {
+        self.update
+            .installation_sets
+            .get_mut(&set.0)
+            .ok_or(Error::UnknownInstallationSet(set))?
+            .synced = true;
+        self.save()
+    }
+
+    /// Fails if the set was installed on but not synced afterwards, so it
+    /// must be neither made active nor validated. Sets installed by versions
+    /// which didn't record it are taken as synced.
+    pub(crate) fn ensure_installation_set_synced(&self, set: Set) -> Result<()> {
+        match self.update.installation_sets.get(&set.0) {
+            Some(status) if !status.synced => Err(Error::InstallationSetNotSynced(set)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn set_installation_set_validated(&mut self, set: Set) -> Result<()> {
+        if let Some(status) = self.update.installation_sets.get_mut(&set.0) {
+            status.known_good = true;
+        }
         self.save()
     }
 
@@ -181,6 +285,7 @@ impl RuntimeSettings {
 
     pub(crate) fn reset_installation_settings(&mut self) -> Result<()> {
         self.update.upgrade_to_installation = None;
+        self.update.upgrade_from_installation = None;
         self.update.applied_package_uid = None;
 
         // Ensure we do a probe as soon as possible so full update
@@ -191,6 +296,27 @@ impl RuntimeSettings {
     }
 }
 
+/// Common objects are tracked by their filename along with where they are
+/// installed, as objects with the same filename may go to different targets.
+fn common_object_key(object: &Object) -> String {
+    let target = match object {
+        Object::Copy(o) => format!("{} {}", o.target_type, o.target_path.display()),
+        Object::FileDelta(o) => format!("{} {}", o.target_type, o.target_path.display()),
+        Object::Tarball(o) => format!("{} {}", o.target, o.target_path.display()),
+        Object::EmmcBoot(o) => o.target.display().to_string(),
+        Object::Imxkobs(o) => o
+            .chip_0_device_path
+            .iter()
+            .chain(&o.chip_1_device_path)
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+        Object::Test(o) => o.target.clone(),
+        o => o.target_type().map(ToString::to_string).unwrap_or_default(),
+    };
+    format!("{}: {}", object.filename(), target)
+}
+
 #[test]
 fn default() {
     use pretty_assertions::assert_eq;
@@ -202,7 +328,14 @@ fn default() {
             now: false,
             server_address: api::ServerAddress::Default,
         },
-        update: api::RuntimeUpdate { upgrade_to_installation: None, applied_package_uid: None },
+        update: api::RuntimeUpdate {
+            upgrade_to_installation: None,
+            upgrade_from_installation: None,
+            applied_package_uid: None,
+            minimum_security_version: None,
+            common_objects: BTreeMap::default(),
+            installation_sets: BTreeMap::default(),
+        },
         path: std::path::PathBuf::new(),
         persistent: false,
     });
@@ -250,3 +383,43 @@ fn load_bad_formated_file() {
     );
     fs::remove_file(old_file).unwrap();
 }
+
+#[test]
+fn installation_set_synced() {
+    use sdk::api::info::runtime_settings::InstallationSet;
+
+    let mut settings = RuntimeSettings::default();
+    let set = Set(InstallationSet::B);
+    match settings.set_installation_set_synced(set) {
+        Err(Error::UnknownInstallationSet(s)) => assert_eq!(s, set),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    settings.ensure_installation_set_synced(set).unwrap();
+
+    settings.set_installing(set).unwrap();
+    match settings.ensure_installation_set_synced(set) {
+        Err(Error::InstallationSetNotSynced(s)) => assert_eq!(s, set),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    settings.set_installation_set_synced(set).unwrap();
+    settings.ensure_installation_set_synced(set).unwrap();
+}
+
+#[test]
+fn common_objects_by_target() {
+    let object = |target: &str| {
+        Object::from(pkg_schema::objects::Test {
+            filename: "bootloader".to_string(),
+            sha256sum: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                .to_string(),
+            target: target.to_string(),
+            size: 0,
+            install_if: None,
+        })
+    };
+
+    let mut settings = RuntimeSettings::default();
+    settings.set_common_object_installed(&object("/dev/mmcblk0boot0")).unwrap();
+    assert!(settings.is_common_object_installed(&object("/dev/mmcblk0boot0")));
+    assert!(!settings.is_common_object_installed(&object("/dev/mmcblk0boot1")));
+}
@@ -4,7 +4,7 @@
 
 use chrono::Duration;
 use derive_more::{Deref, DerefMut};
-use sdk::api::info::settings as api;
+use sdk::api::info::{runtime_settings::InstallationSet, settings as api};
 use slog_scope::{debug, error};
 use std::{fs, io, path::Path};
 use thiserror::Error;
@@ -23,6 +23,12 @@ pub enum Error {
     InvalidInterval,
     #[error("invalid server address")]
     InvalidServerAddress,
+    #[error("invalid number of installation sets")]
+    InvalidInstallationSets,
+    #[error("invalid golden installation set")]
+    InvalidGoldenInstallationSet,
+    #[error("missing UBI volumes for the ubi-rename installation set backend")]
+    MissingUbiSetVolumes,
 
     #[cfg(feature = "v1-parsing")]
     #[error("fail reading ini the file: {0}")]
@@ -43,11 +49,32 @@ impl Default for Settings {
             update: api::Update {
                 download_dir: "/tmp/updatehub".into(),
                 supported_install_modes: [
-                    "dry-run", "copy", "flash", "imxkobs", "raw", "tarball", "ubifs",
+                    "dry-run",
+                    "chunked",
+                    "copy",
+                    "emmc-boot",
+                    "file-delta",
+                    "flash",
+                    "imxkobs",
+                    "mender",
+                    "raw",
+                    "raw-delta",
+                    "tarball",
+                    "ubifs",
+                    "zephyr",
                 ]
                 .iter()
                 .map(|i| (*i).to_string())
                 .collect(),
+                version_policy: api::VersionPolicy::default(),
+                single_bank: false,
+                recovery_reboot_command: None,
+                installation_sets: 2,
+                installation_set_policy: api::InstallationSetPolicy::default(),
+                golden_installation_set: None,
+                installation_set_backend: api::InstallationSetBackend::default(),
+                ubi_set_volumes: Vec::default(),
+                verify_installed_objects: false,
             },
             network: api::Network {
                 server_address: "https://api.updatehub.io".to_string(),
@@ -92,6 +119,31 @@ impl Settings {
             return Err(Error::InvalidServerAddress);
         }
 
+        if !(2..=InstallationSet::MAX).contains(&settings.update.installation_sets) {
+            error!(
+                "invalid setting for installation sets, it must be between 2 and {}",
+                InstallationSet::MAX
+            );
+            return Err(Error::InvalidInstallationSets);
+        }
+
+        if let Some(golden) = settings.update.golden_installation_set {
+            if golden.0 >= settings.update.installation_sets {
+                error!(
+                    "invalid setting for golden installation set, the device has no set {}",
+                    golden
+                );
+                return Err(Error::InvalidGoldenInstallationSet);
+            }
+        }
+
+        if settings.update.installation_set_backend == api::InstallationSetBackend::UbiRename
+            && settings.update.ubi_set_volumes.is_empty()
+        {
+            error!("invalid setting for installation set backend, ubi-rename needs the volumes");
+            return Err(Error::MissingUbiSetVolumes);
+        }
+
         Ok(settings)
     }
 }
@@ -208,7 +260,19 @@ fn v1_parse(content: &str, _: Error) -> Result<api::Settings> {
             Self {
                 download_dir: "/tmp/updatehub".into(),
                 supported_install_modes: [
-                    "dry-run", "copy", "flash", "imxkobs", "raw", "tarball", "ubifs",
+                    "dry-run",
+                    "chunked",
+                    "copy",
+                    "emmc-boot",
+                    "file-delta",
+                    "flash",
+                    "imxkobs",
+                    "mender",
+                    "raw",
+                    "raw-delta",
+                    "tarball",
+                    "ubifs",
+                    "zephyr",
                 ]
                 .iter()
                 .map(|i| i.to_string())
@@ -251,6 +315,15 @@ fn v1_parse(content: &str, _: Error) -> Result<api::Settings> {
         update: api::Update {
             download_dir: old_settings.update.download_dir,
             supported_install_modes: old_settings.update.supported_install_modes,
+            version_policy: api::VersionPolicy::default(),
+            single_bank: false,
+            recovery_reboot_command: None,
+            installation_sets: 2,
+            installation_set_policy: api::InstallationSetPolicy::default(),
+            golden_installation_set: None,
+            installation_set_backend: api::InstallationSetBackend::default(),
+            ubi_set_volumes: Vec::default(),
+            verify_installed_objects: false,
         },
     })
 }
@@ -300,6 +373,15 @@ metadata="/usr/share/updatehub"
                     .iter()
                     .map(|i| (*i).to_string())
                     .collect(),
+                version_policy: api::VersionPolicy::default(),
+                single_bank: false,
+                recovery_reboot_command: None,
+                installation_sets: 2,
+                installation_set_policy: api::InstallationSetPolicy::default(),
+                golden_installation_set: None,
+                installation_set_backend: api::InstallationSetBackend::default(),
+                ubi_set_volumes: Vec::default(),
+                verify_installed_objects: false,
             },
             network: api::Network {
                 server_address: "https://api.updatehub.io".to_string(),
@@ -310,6 +392,102 @@ metadata="/usr/share/updatehub"
         assert_eq!(Settings::parse(sample).unwrap(), expected);
     }
 
+    #[test]
+    fn version_policy() {
+        let sample = r#"
+[network]
+server_address="https://api.updatehub.io"
+listen_socket="localhost:8080"
+
+[storage]
+read_only = false
+runtime_settings="/data/updatehub/state.data"
+
+[polling]
+enabled=true
+interval="60s"
+
+[update]
+download_dir="/tmp/updatehub"
+supported_install_modes=["copy", "tarball"]
+version_policy="dpkg"
+
+[firmware]
+metadata="/usr/share/updatehub"
+"#;
+        assert_eq!(
+            Settings::parse(sample).unwrap().update.version_policy,
+            api::VersionPolicy::Dpkg
+        );
+    }
+
+    #[test]
+    fn installation_sets() {
+        let sample = |update: &str| {
+            format!(
+                r#"
+[network]
+server_address="https://api.updatehub.io"
+listen_socket="localhost:8080"
+
+[storage]
+read_only = false
+runtime_settings="/data/updatehub/state.data"
+
+[polling]
+enabled=true
+interval="60s"
+
+[update]
+download_dir="/tmp/updatehub"
+supported_install_modes=["copy", "tarball"]
+{}
+
+[firmware]
+metadata="/usr/share/updatehub"
+"#,
+                update
+            )
+        };
+
+        let settings = Settings::parse(&sample(
+            r#"installation_sets=3
+installation_set_policy="not-known-good"
+golden_installation_set="c""#,
+        ))
+        .unwrap();
+        assert_eq!(settings.update.installation_sets, 3);
+        assert_eq!(
+            settings.update.installation_set_policy,
+            api::InstallationSetPolicy::NotKnownGood
+        );
+        assert_eq!(settings.update.golden_installation_set, Some(InstallationSet(2)));
+
+        match Settings::parse(&sample("installation_sets=1")) {
+            Err(Error::InvalidInstallationSets) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match Settings::parse(&sample("golden_installation_set=\"c\"")) {
+            Err(Error::InvalidGoldenInstallationSet) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let settings = Settings::parse(&sample(
+            r#"installation_set_backend="ubi-rename"
+ubi_set_volumes=["kernel", "rootfs"]"#,
+        ))
+        .unwrap();
+        assert_eq!(
+            settings.update.installation_set_backend,
+            api::InstallationSetBackend::UbiRename
+        );
+        assert_eq!(settings.update.ubi_set_volumes, vec!["kernel", "rootfs"]);
+        match Settings::parse(&sample("installation_set_backend=\"ubi-rename\"")) {
+            Err(Error::MissingUbiSetVolumes) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
     #[test]
     fn invalid_polling_interval() {
         let sample = r#"
@@ -375,11 +553,32 @@ metadata="/usr/share/updatehub"
             update: api::Update {
                 download_dir: "/tmp/updatehub".into(),
                 supported_install_modes: [
-                    "dry-run", "copy", "flash", "imxkobs", "raw", "tarball", "ubifs",
+                    "dry-run",
+                    "chunked",
+                    "copy",
+                    "emmc-boot",
+                    "file-delta",
+                    "flash",
+                    "imxkobs",
+                    "mender",
+                    "raw",
+                    "raw-delta",
+                    "tarball",
+                    "ubifs",
+                    "zephyr",
                 ]
                 .iter()
                 .map(|i| i.to_string())
                 .collect(),
+                version_policy: api::VersionPolicy::default(),
+                single_bank: false,
+                recovery_reboot_command: None,
+                installation_sets: 2,
+                installation_set_policy: api::InstallationSetPolicy::default(),
+                golden_installation_set: None,
+                installation_set_backend: api::InstallationSetBackend::default(),
+                ubi_set_volumes: Vec::default(),
+                verify_installed_objects: false,
             },
             network: api::Network {
                 server_address: "https://api.updatehub.io".to_string(),
@@ -420,6 +619,15 @@ ListenSocket=localhost:8313
             update: api::Update {
                 download_dir: "/tmp/download".into(),
                 supported_install_modes: ["mode1", "mode2"].iter().map(|i| i.to_string()).collect(),
+                version_policy: api::VersionPolicy::default(),
+                single_bank: false,
+                recovery_reboot_command: None,
+                installation_sets: 2,
+                installation_set_policy: api::InstallationSetPolicy::default(),
+                golden_installation_set: None,
+                installation_set_backend: api::InstallationSetBackend::default(),
+                ubi_set_volumes: Vec::default(),
+                verify_installed_objects: false,
             },
             network: api::Network {
                 server_address: "http://localhost".to_string(),
@@ -16,7 +16,7 @@ use std::fmt;
 pub(super) struct Download {
     pub(super) update_package: UpdatePackage,
     pub(super) installation_set: installation_set::Set,
-    pub(super) download_chan: tokio::sync::mpsc::Receiver<Vec<cloud::Result<()>>>,
+    pub(super) download_chan: tokio::sync::mpsc::Receiver<Vec<Result<()>>>,
 }
 
 impl PartialEq for Download {
@@ -76,6 +76,7 @@ impl StateChangeImpl for Download {
             .update_package
             .objects(self.installation_set)
             .iter()
+            .chain(self.update_package.inner.common.iter())
             .all(|o| o.status(download_dir).ok() == Some(object::info::Status::Ready))
         {
             Ok((
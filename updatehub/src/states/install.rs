@@ -10,8 +10,14 @@ use crate::{
     firmware::installation_set,
     object::{self, Installer},
     update_package::{UpdatePackage, UpdatePackageExt},
+    utils::{emmc::BootPartition, ubi},
+};
+use pkg_schema::{
+    definitions::{TargetType, UbiVolume},
+    Object,
 };
 use slog_scope::{debug, info};
+use std::path::Path;
 
 #[derive(Debug, PartialEq)]
 pub(super) struct Install {
@@ -64,31 +70,94 @@ impl StateChangeImpl for Install {
         let package_uid = self.update_package.package_uid();
         info!("installing update: {}", &package_uid);
 
-        let installation_set = shared_state.runtime_settings.get_inactive_installation_set()?;
-        info!("using installation set as target {}", installation_set);
+        self.update_package.expand_objects_template(&shared_state.settings)?;
+        // Packages only updating common objects leave the installation
+        // sets untouched, so there is nothing to swap
+        let swap_sets = !shared_state.settings.update.single_bank
+            && !self.update_package.inner.is_common_only();
+        let (installation_set, active) = if shared_state.settings.update.single_bank {
+            info!("installing single-bank package in place");
+            (installation_set::SINGLE_BANK, installation_set::SINGLE_BANK)
+        } else {
+            let installation_set = shared_state
+                .runtime_settings
+                .get_inactive_installation_set(&shared_state.settings)?;
+            if swap_sets {
+                info!("using installation set as target {}", installation_set);
+                shared_state.runtime_settings.set_installing(installation_set)?;
+            }
+            (installation_set, installation_set::active(&shared_state.settings.update)?)
+        };
+
+        self.update_package.retain_applicable_objects(installation_set, &shared_state.firmware)?;
+        self.update_package.retain_pending_common_objects(&shared_state.runtime_settings);
+        // Deltas are applied on top of the set the device runs from
+        self.update_package.expand_active_set(installation_set, active)?;
+
+        let download_dir = &shared_state.settings.update.download_dir;
+        let firmware = &shared_state.firmware;
+        let objs = self
+            .update_package
+            .objects(installation_set)
+            .iter()
+            .chain(self.update_package.inner.common.iter());
+
+        // The device is left untouched unless every object is compatible
+        objs.clone().try_for_each(|obj| obj.check_compatibility(download_dir, firmware))?;
+
+        // Volumes are set up before the objects are installed, so these find
+        // their targets. Objects targeting a volume the changes bring up are
+        // only checked once it exists.
+        let changes = ubi_changes(&self.update_package.ubi_volumes(installation_set)?)?;
+        let (pending, ready): (Vec<_>, Vec<_>) =
+            objs.partition(|obj| targets_new_volume(obj, &changes));
+        ready.into_iter().try_for_each(object::Installer::check_requirements)?;
+        apply_ubi_changes(changes)?;
+        pending.into_iter().try_for_each(object::Installer::check_requirements)?;
 
-        // FIXME: What is missing:
-        //
-        // - verify if the object needs to be installed, accordingly to the install if
-        //   different rule.
+        // Common objects are written last as, unlike the installation set,
+        // they are in use by the running system
+        let verify = shared_state.settings.update.verify_installed_objects;
+        install_objects(self.update_package.objects_mut(installation_set), download_dir, verify)?;
+        install_objects(&mut self.update_package.inner.common, download_dir, verify)?;
 
-        let objs = self.update_package.objects_mut(installation_set);
-        objs.iter().try_for_each(object::Installer::check_requirements)?;
-        objs.iter_mut().try_for_each(object::Installer::setup)?;
-        objs.iter_mut().try_for_each(|obj| {
-            obj.install(&shared_state.settings.update.download_dir)?;
-            obj.cleanup()
-        })?;
+        // Installers sync their own targets, this also covers the ones
+        // written by external tools before the device might be rebooted
+        nix::unistd::sync();
+
+        // Boot partitions are switched along with the installation set,
+        // once everything else is written
+        switch_boot_partitions(self.update_package.objects(installation_set), download_dir)?;
+        switch_boot_partitions(&self.update_package.inner.common, download_dir)?;
+
+        for obj in self.update_package.inner.common.iter() {
+            shared_state.runtime_settings.set_common_object_installed(obj)?;
+        }
 
         // Avoid installing same package twice.
         shared_state.runtime_settings.set_applied_package_uid(&package_uid)?;
 
-        // Set upgrading to the new installation set
-        shared_state.runtime_settings.set_upgrading_to(installation_set)?;
+        // Older versions are not accepted anymore once the package is installed
+        if let Some(minimum) = self.update_package.raised_minimum_security_version(
+            shared_state.settings.update.version_policy,
+            shared_state.runtime_settings.minimum_security_version(),
+        )? {
+            info!("raising minimum security version to {}", minimum);
+            shared_state.runtime_settings.set_minimum_security_version(minimum)?;
+        }
+
+        // Single-bank devices have no other installation set to boot from
+        if swap_sets {
+            // Set upgrading to the new installation set, so the sync and
+            // swap are done again on startup if they are interrupted
+            shared_state.runtime_settings.set_upgrading_to(active, installation_set)?;
+            shared_state.runtime_settings.set_installation_set_synced(installation_set)?;
 
-        // Swap installation set so it is used next device boot.
-        installation_set::swap_active()?;
-        info!("swapping active installation set");
+            // Swap installation set so it is used next device boot.
+            shared_state.runtime_settings.ensure_installation_set_synced(installation_set)?;
+            installation_set::swap_active(&shared_state.settings.update, installation_set)?;
+            info!("swapping active installation set");
+        }
 
         info!("update installed successfully");
         Ok((
@@ -98,11 +167,71 @@ impl StateChangeImpl for Install {
     }
 }
 
+/// Makes the eMMCs boot from the partitions written by the objects asking
+/// so, which are read back first so the device only boots from a partition
+/// known to be good.
+fn switch_boot_partitions(objs: &[Object], download_dir: &Path) -> object::Result<()> {
+    objs.iter().try_for_each(|obj| match obj {
+        Object::EmmcBoot(o) if o.switch_boot_partition => {
+            obj.verify(download_dir)?;
+            Ok(BootPartition::from_target(&o.target)?.enable()?)
+        }
+        _ => Ok(()),
+    })
+}
+
+/// Changes for the UBI volumes to match the declared ones.
+fn ubi_changes(volumes: &[UbiVolume]) -> object::Result<Vec<ubi::Change>> {
+    if volumes.is_empty() {
+        return Ok(Vec::default());
+    }
+
+    Ok(ubi::plan(volumes, &ubi::volumes()?))
+}
+
+fn apply_ubi_changes(changes: Vec<ubi::Change>) -> object::Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    info!("setting up UBI volumes");
+    Ok(ubi::apply(changes)?)
+}
+
+/// Whether the object targets a volume which is created, or renamed to, by
+/// the changes, so it doesn't exist before these are made.
+fn targets_new_volume(obj: &Object, changes: &[ubi::Change]) -> bool {
+    let name = match obj.target_type() {
+        Some(TargetType::UBIVolume(name)) => name,
+        _ => return false,
+    };
+
+    changes.iter().any(|change| match change {
+        ubi::Change::Create { name: new, .. } | ubi::Change::Rename(_, new) => new == name,
+        _ => false,
+    })
+}
+
+fn install_objects(objs: &mut [Object], download_dir: &Path, verify: bool) -> object::Result<()> {
+    objs.iter_mut().try_for_each(object::Installer::setup)?;
+    objs.iter_mut().try_for_each(|obj| {
+        obj.install(download_dir)?;
+        if verify {
+            obj.verify(download_dir)?;
+        }
+        obj.cleanup()
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::update_package::tests::get_update_package;
+    use crate::update_package::tests::{
+        get_common_only_update_package, get_single_bank_update_package, get_update_json,
+        get_update_package, SHA256SUM,
+    };
     use pretty_assertions::assert_eq;
+    use sdk::api::info::settings::VersionPolicy;
 
     #[actix_rt::test]
     async fn has_package_uid_if_succeed() {
@@ -120,4 +249,130 @@ mod test {
             s => panic!("Invalid success: {:?}", s),
         }
     }
+
+    #[actix_rt::test]
+    async fn installation_set_synced_before_swap() {
+        let setup = crate::tests::TestEnvironment::build().finish();
+        let mut shared_state = setup.gen_shared_state();
+        let state = Install { update_package: get_update_package() };
+
+        let machine = State::Install(state).move_to_next_state(&mut shared_state).await.unwrap().0;
+
+        assert_state!(machine, Reboot);
+        let set = shared_state.runtime_settings.update.upgrade_to_installation.unwrap();
+        assert!(shared_state.runtime_settings.update.installation_sets[&set].synced);
+    }
+
+    #[actix_rt::test]
+    async fn single_bank_package() {
+        let setup = crate::tests::TestEnvironment::build().finish();
+        let mut shared_state = setup.gen_shared_state();
+        shared_state.settings.update.single_bank = true;
+        let state = Install { update_package: get_single_bank_update_package() };
+
+        let machine = State::Install(state).move_to_next_state(&mut shared_state).await.unwrap().0;
+
+        assert_state!(machine, Reboot);
+        assert_eq!(shared_state.runtime_settings.update.upgrade_to_installation, None);
+    }
+
+    #[actix_rt::test]
+    async fn common_only_package() {
+        let setup = crate::tests::TestEnvironment::build().finish();
+        let mut shared_state = setup.gen_shared_state();
+        let state = Install { update_package: get_common_only_update_package() };
+
+        let machine = State::Install(state).move_to_next_state(&mut shared_state).await.unwrap().0;
+
+        // The installation sets are neither written nor swapped
+        assert_state!(machine, Reboot);
+        assert_eq!(shared_state.runtime_settings.update.upgrade_to_installation, None);
+        assert!(shared_state
+            .runtime_settings
+            .is_common_object_installed(&get_common_only_update_package().inner.common[0]));
+    }
+
+    #[actix_rt::test]
+    async fn common_objects_installed_once() {
+        let setup = crate::tests::TestEnvironment::build().finish();
+        let mut shared_state = setup.gen_shared_state();
+
+        let mut json = get_update_json(SHA256SUM);
+        json["common"] = serde_json::json!([{
+            "mode": "test",
+            "filename": "bootloader",
+            "target": "/dev/mmcblk0boot0",
+            "sha256sum": SHA256SUM,
+            "size": 10
+        }]);
+
+        // The same object is installed again when going to another target
+        for (target, expected) in
+            &[("/dev/mmcblk0boot0", 1), ("/dev/mmcblk0boot0", 0), ("/dev/mmcblk0boot1", 1)]
+        {
+            json["common"][0]["target"] = serde_json::json!(target);
+            let update_package = UpdatePackage::parse(json.to_string().as_bytes()).unwrap();
+            let common = UpdatePackage::parse(json.to_string().as_bytes()).unwrap().inner.common;
+            let machine = State::Install(Install { update_package })
+                .move_to_next_state(&mut shared_state)
+                .await
+                .unwrap()
+                .0;
+            match machine {
+                State::Reboot(s) => assert_eq!(s.update_package.inner.common.len(), *expected),
+                s => panic!("Invalid success: {:?}", s),
+            }
+            assert!(shared_state.runtime_settings.is_common_object_installed(&common[0]));
+        }
+    }
+
+    #[test]
+    fn objects_targeting_new_volumes() {
+        let ubifs = |name: &str| {
+            Object::from(pkg_schema::objects::Ubifs {
+                filename: "ubifs".to_string(),
+                size: 1024,
+                sha256sum: SHA256SUM.to_string(),
+                target: TargetType::UBIVolume(name.to_string()),
+                compressed: false,
+                required_uncompressed_size: 0,
+                install_if: None,
+            })
+        };
+        let changes = vec![
+            ubi::Change::Resize(ubi::tests::volume(0, "rootfs"), 8 * 1024),
+            ubi::Change::Rename(ubi::tests::volume(1, "data_old"), "data".to_string()),
+            ubi::Change::Create {
+                device: "/dev/ubi0".into(),
+                name: "rootfs_b".to_string(),
+                size: 4 * 1024,
+                volume_type: pkg_schema::definitions::UbiVolumeType::Dynamic,
+            },
+        ];
+
+        assert!(!targets_new_volume(&ubifs("rootfs"), &changes));
+        assert!(targets_new_volume(&ubifs("data"), &changes));
+        assert!(targets_new_volume(&ubifs("rootfs_b"), &changes));
+        assert!(!targets_new_volume(&ubifs("rootfs_b"), &[]));
+    }
+
+    #[actix_rt::test]
+    async fn raises_minimum_security_version() {
+        let setup = crate::tests::TestEnvironment::build().finish();
+        let mut shared_state = setup.gen_shared_state();
+        shared_state.settings.update.version_policy = VersionPolicy::Semver;
+        shared_state.runtime_settings.set_minimum_security_version("1.0").unwrap();
+
+        let mut json = get_update_json(SHA256SUM);
+        json["minimum-security-version"] = serde_json::json!("1.1");
+        let update_package = UpdatePackage::parse(json.to_string().as_bytes()).unwrap();
+
+        let machine = State::Install(Install { update_package })
+            .move_to_next_state(&mut shared_state)
+            .await
+            .unwrap()
+            .0;
+        assert_state!(machine, Reboot);
+        assert_eq!(shared_state.runtime_settings.minimum_security_version(), Some("1.1"));
+    }
 }
@@ -193,18 +193,52 @@ fn handle_startup_callbacks(
     runtime_settings: &mut RuntimeSettings,
 ) -> crate::Result<()> {
     if let Some(expected_set) = runtime_settings.update.upgrade_to_installation {
+        let active = firmware::installation_set::active(&settings.update)?;
+        let expected = firmware::installation_set::Set(expected_set);
+        if expected != active && runtime_settings.ensure_installation_set_synced(expected).is_err()
+        {
+            // The update was installed but the agent stopped before the
+            // installation set was made active
+            warn!("installation set {} was not synced, syncing and swapping to it", expected);
+            nix::unistd::sync();
+            runtime_settings.set_installation_set_synced(expected)?;
+            firmware::installation_set::swap_active(&settings.update, expected)?;
+            easy_process::run("reboot")?;
+            return Ok(());
+        }
+
         info!("booting from a recent installation");
-        if expected_set == firmware::installation_set::active()?.0 {
-            match firmware::validate_callback(&settings.firmware.metadata)? {
+        if expected == active {
+            // A set which was not synced before being booted is not trusted
+            let transition = match runtime_settings.ensure_installation_set_synced(active) {
+                Ok(()) => firmware::validate_callback(&settings.firmware.metadata)?,
+                Err(e) => {
+                    warn!("refusing to validate: {}", e);
+                    Transition::Cancel
+                }
+            };
+            match transition {
                 Transition::Cancel => {
-                    warn!("validate callback has failed");
-                    firmware::installation_set::swap_active()?;
+                    warn!("installation set has not been validated");
+                    let previous = match runtime_settings.upgrading_from() {
+                        Some(set) => set,
+                        // Installations made before the previous set was
+                        // recorded had only the other set to swap to
+                        None => firmware::installation_set::inactive(
+                            &settings.update,
+                            &runtime_settings.update.installation_sets,
+                        )?,
+                    };
+                    firmware::installation_set::swap_active(&settings.update, previous)?;
                     warn!("swapped active installation set and running rollback");
                     firmware::rollback_callback(&settings.firmware.metadata)?;
                     runtime_settings.reset_installation_settings()?;
                     easy_process::run("reboot")?;
                 }
-                Transition::Continue => firmware::installation_set::validate()?,
+                Transition::Continue => {
+                    firmware::installation_set::validate(&settings.update)?;
+                    runtime_settings.set_installation_set_validated(active)?;
+                }
             }
         }
         runtime_settings.reset_installation_settings()?;
@@ -8,9 +8,10 @@ use super::{
 };
 use crate::{
     firmware::installation_set,
-    object::{self, Info},
+    object::{self, installer::chunked, Info},
     update_package::{UpdatePackage, UpdatePackageExt},
 };
+use pkg_schema::Object;
 use slog_scope::error;
 
 #[derive(Debug, PartialEq)]
@@ -29,12 +30,20 @@ impl StateChangeImpl for PrepareDownload {
     }
 
     async fn handle(
-        self,
+        mut self,
         shared_state: &mut SharedState,
     ) -> Result<(State, machine::StepTransition)> {
-        let installation_set = installation_set::inactive()?;
+        self.update_package.expand_objects_template(&shared_state.settings)?;
+        let installation_set = if shared_state.settings.update.single_bank {
+            installation_set::SINGLE_BANK
+        } else {
+            shared_state.runtime_settings.get_inactive_installation_set(&shared_state.settings)?
+        };
         let download_dir = shared_state.settings.update.download_dir.to_owned();
 
+        self.update_package.retain_applicable_objects(installation_set, &shared_state.firmware)?;
+        self.update_package.retain_pending_common_objects(&shared_state.runtime_settings);
+
         self.update_package.clear_unrelated_files(
             &download_dir,
             installation_set,
@@ -46,6 +55,7 @@ impl StateChangeImpl for PrepareDownload {
             .update_package
             .objects(installation_set)
             .iter()
+            .chain(self.update_package.inner.common.iter())
             .filter(|o| {
                 let obj_status = o
                     .status(&download_dir)
@@ -59,6 +69,19 @@ impl StateChangeImpl for PrepareDownload {
             .map(|obj| obj.sha256sum().to_owned())
             .collect();
 
+        // Chunked objects have their chunk index downloaded as the object
+        // itself, the chunks are only known once the index is available.
+        let chunked_list: Vec<_> = self
+            .update_package
+            .objects(installation_set)
+            .iter()
+            .chain(self.update_package.inner.common.iter())
+            .filter_map(|o| match o {
+                Object::Chunked(o) => Some((o.sha256sum.clone(), o.seed.clone())),
+                _ => None,
+            })
+            .collect();
+
         // Get ownership of remaining data that will be sent to new thread
         let server = shared_state.server_address().to_owned();
         let product_uid = shared_state.firmware.product_uid.to_owned();
@@ -71,9 +94,33 @@ impl StateChangeImpl for PrepareDownload {
             let mut results = Vec::default();
             for shasum in shasum_list.iter() {
                 results.push(
-                    api.download_object(&product_uid, &package_uid, &download_dir, &shasum).await,
+                    api.download_object(&product_uid, &package_uid, &download_dir, &shasum)
+                        .await
+                        .map_err(Into::into),
                 );
             }
+
+            // Download the chunks missing from both the chunk store and the seed
+            let chunk_store = chunked::chunk_store(&download_dir);
+            for (index, seed) in chunked_list.iter() {
+                match chunked::missing_chunks(&download_dir, index, seed.as_deref()) {
+                    Ok(chunks) => {
+                        for chunk in chunks.iter() {
+                            results.push(
+                                api.download_object(
+                                    &product_uid,
+                                    &package_uid,
+                                    &chunk_store,
+                                    chunk,
+                                )
+                                .await
+                                .map_err(Into::into),
+                            );
+                        }
+                    }
+                    Err(e) => results.push(Err(e.into())),
+                }
+            }
             sndr.send(results).await.expect("unable to send response about object downlod");
         });
 
@@ -39,7 +39,7 @@ impl StateChangeImpl for PrepareLocalInstall {
         let mut metadata = Vec::with_capacity(1024);
         let mut source = fs::File::open(self.update_file)?;
         compress_tools::uncompress_archive_file(&mut source, &mut metadata, "metadata")?;
-        let update_package = UpdatePackage::parse(&metadata)?;
+        let mut update_package = UpdatePackage::parse(&metadata)?;
         trace!("successfuly uncompressed metadata file");
 
         if let Some(key) = shared_state.firmware.pub_key.as_ref() {
@@ -59,9 +59,24 @@ impl StateChangeImpl for PrepareLocalInstall {
             }
         }
 
+        update_package.validate(&shared_state.settings)?;
+        update_package.check_version(
+            shared_state.settings.update.version_policy,
+            &shared_state.firmware,
+            shared_state.runtime_settings.minimum_security_version(),
+        )?;
+
+        update_package.expand_objects_template(&shared_state.settings)?;
+        let installation_set = if shared_state.settings.update.single_bank {
+            installation_set::SINGLE_BANK
+        } else {
+            shared_state.runtime_settings.get_inactive_installation_set(&shared_state.settings)?
+        };
+
         for object in update_package
-            .objects(installation_set::active()?)
+            .objects(installation_set)
             .iter()
+            .chain(update_package.inner.common.iter())
             .map(crate::object::Info::sha256sum)
         {
             source.seek(SeekFrom::Start(0))?;
@@ -74,10 +89,56 @@ impl StateChangeImpl for PrepareLocalInstall {
 
         update_package.clear_unrelated_files(
             &dest_path,
-            installation_set::inactive()?,
+            installation_set,
             &shared_state.settings,
         )?;
 
         Ok((State::Install(Install { update_package }), machine::StepTransition::Immediate))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        states::TransitionError,
+        update_package::tests::{get_update_json, SHA256SUM},
+    };
+    use sdk::api::info::settings::VersionPolicy;
+
+    fn update_file(version: &str) -> tempfile::NamedTempFile {
+        let mut json = get_update_json(SHA256SUM);
+        json["version"] = serde_json::json!(version);
+        let metadata = json.to_string();
+
+        let mut header = tar::Header::new_ustar();
+        header.set_size(metadata.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(tempfile::NamedTempFile::new().unwrap());
+        builder.append_data(&mut header, "metadata", metadata.as_bytes()).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn downgrade() {
+        let setup = crate::tests::TestEnvironment::build().finish();
+        let mut shared_state = setup.gen_shared_state();
+        shared_state.settings.update.version_policy = VersionPolicy::Semver;
+
+        // The firmware version is 1.1
+        let update_file = update_file("1.0");
+        let machine = State::PrepareLocalInstall(PrepareLocalInstall {
+            update_file: update_file.path().to_path_buf(),
+        })
+        .move_to_next_state(&mut shared_state)
+        .await;
+
+        match machine {
+            Err(TransitionError::UpdatePackage(crate::update_package::Error::Downgrade {
+                ..
+            })) => {}
+            res => panic!("Unexpected result from transition: {:?}", res),
+        }
+    }
+}
@@ -34,9 +34,21 @@ impl StateChangeImpl for Reboot {
         "reboot"
     }
 
-    async fn handle(self, _: &mut SharedState) -> Result<(State, machine::StepTransition)> {
-        info!("triggering reboot");
-        let output = easy_process::run("reboot")?;
+    async fn handle(
+        self,
+        shared_state: &mut SharedState,
+    ) -> Result<(State, machine::StepTransition)> {
+        let command = match shared_state.settings.update.recovery_reboot_command {
+            Some(ref command) if shared_state.settings.update.single_bank => {
+                info!("triggering reboot into recovery");
+                command.as_str()
+            }
+            _ => {
+                info!("triggering reboot");
+                "reboot"
+            }
+        };
+        let output = easy_process::run(command)?;
         if !output.stdout.is_empty() || !output.stderr.is_empty() {
             warn!("  reboot output: stdout: {}, stderr: {}", output.stdout, output.stderr);
         }
@@ -47,7 +59,9 @@ impl StateChangeImpl for Reboot {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::update_package::tests::get_update_package;
+    use crate::update_package::tests::{
+        get_common_only_update_package, get_single_bank_update_package, get_update_package,
+    };
     use pretty_assertions::assert_eq;
 
     #[actix_rt::test]
@@ -61,6 +75,31 @@ mod test {
         assert_state!(machine, EntryPoint);
     }
 
+    #[actix_rt::test]
+    async fn single_bank_reboots_into_recovery() {
+        let setup = crate::tests::TestEnvironment::build()
+            .add_echo_binary("reboot")
+            .add_echo_binary("reboot-recovery")
+            .finish();
+        let mut shared_state = setup.gen_shared_state();
+        shared_state.settings.update.recovery_reboot_command = Some("reboot-recovery".to_owned());
+
+        let state = Reboot { update_package: get_update_package() };
+        State::Reboot(state).move_to_next_state(&mut shared_state).await.unwrap();
+        let state = Reboot { update_package: get_common_only_update_package() };
+        State::Reboot(state).move_to_next_state(&mut shared_state).await.unwrap();
+
+        shared_state.settings.update.single_bank = true;
+        for update_package in [get_single_bank_update_package(), get_common_only_update_package()] {
+            let state = Reboot { update_package };
+            State::Reboot(state).move_to_next_state(&mut shared_state).await.unwrap();
+        }
+
+        let output = std::fs::read_to_string(&setup.binaries.data).unwrap();
+        let commands = output.lines().map(|l| l.rsplit('/').next().unwrap()).collect::<Vec<_>>();
+        assert_eq!(commands, vec!["reboot", "reboot", "reboot-recovery", "reboot-recovery"]);
+    }
+
     #[test]
     fn reboot_has_transition_callback_trait() {
         let state = Reboot { update_package: get_update_package() };
@@ -24,7 +24,13 @@ fn startup_without_upgrade() {
 fn startup_with_normal_upgrade() {
     let mut setup = crate::tests::TestEnvironment::build().finish();
     let output_file_path = &setup.binaries.data;
-    setup.runtime_settings.data.set_upgrading_to(Set(InstallationSet::A)).unwrap();
+    setup.runtime_settings.data.set_installing(Set(InstallationSet::A)).unwrap();
+    setup.runtime_settings.data.set_installation_set_synced(Set(InstallationSet::A)).unwrap();
+    setup
+        .runtime_settings
+        .data
+        .set_upgrading_to(Set(InstallationSet::B), Set(InstallationSet::A))
+        .unwrap();
 
     handle_startup_callbacks(&setup.settings.data, &mut setup.runtime_settings.data).unwrap();
 
@@ -36,6 +42,10 @@ fn startup_with_normal_upgrade() {
         !fs::read_to_string(output_file_path).unwrap().contains("rollback-callback"),
         "Rollback callback should not be called",
     );
+    assert!(
+        setup.runtime_settings.data.update.installation_sets[&InstallationSet::A].known_good,
+        "Installation set was not marked as known to be good",
+    );
 }
 
 #[test]
@@ -48,7 +58,11 @@ fn startup_on_faulty_upgrade() {
         format!("#!/bin/sh\necho $0 >> {}\nexit 1", output_file_path.to_string_lossy()),
     )
     .unwrap();
-    setup.runtime_settings.data.set_upgrading_to(Set(InstallationSet::A)).unwrap();
+    setup
+        .runtime_settings
+        .data
+        .set_upgrading_to(Set(InstallationSet::B), Set(InstallationSet::A))
+        .unwrap();
 
     handle_startup_callbacks(&setup.settings.data, &mut setup.runtime_settings.data).unwrap();
 
@@ -69,7 +83,11 @@ fn startup_on_faulty_upgrade() {
 #[test]
 fn startup_on_wrong_install_set() {
     let mut setup = crate::tests::TestEnvironment::build().finish();
-    setup.runtime_settings.data.set_upgrading_to(Set(InstallationSet::B)).unwrap();
+    setup
+        .runtime_settings
+        .data
+        .set_upgrading_to(Set(InstallationSet::A), Set(InstallationSet::B))
+        .unwrap();
 
     handle_startup_callbacks(&setup.settings.data, &mut setup.runtime_settings.data).unwrap();
 
@@ -79,3 +97,52 @@ fn startup_on_wrong_install_set() {
         Ok(content) => panic!("Output file should be empty, instead we have: {}", content),
     }
 }
+
+#[test]
+fn startup_on_unsynced_upgrade() {
+    let mut setup = crate::tests::TestEnvironment::build().add_echo_binary("reboot").finish();
+    let output_file_path = &setup.binaries.data;
+    fs::write(
+        setup.binaries.stored_path.join("updatehub-active-set"),
+        format!("#!/bin/sh\necho $0 $1 >> {}", output_file_path.to_string_lossy()),
+    )
+    .unwrap();
+    setup.runtime_settings.data.set_installing(Set(InstallationSet::B)).unwrap();
+    setup
+        .runtime_settings
+        .data
+        .set_upgrading_to(Set(InstallationSet::A), Set(InstallationSet::B))
+        .unwrap();
+
+    handle_startup_callbacks(&setup.settings.data, &mut setup.runtime_settings.data).unwrap();
+
+    // The swap is done again, and the update is validated on next boot
+    let output = fs::read_to_string(output_file_path).unwrap();
+    assert!(output.contains("updatehub-active-set 1"), "Installation set was not swapped");
+    assert!(output.contains("reboot"), "Reboot was not called");
+    assert!(setup.runtime_settings.data.update.installation_sets[&InstallationSet::B].synced);
+    assert_eq!(
+        setup.runtime_settings.data.update.upgrade_to_installation,
+        Some(InstallationSet::B)
+    );
+}
+
+#[test]
+fn startup_on_unsynced_active_set() {
+    let mut setup = crate::tests::TestEnvironment::build().add_echo_binary("reboot").finish();
+    let output_file_path = &setup.binaries.data;
+    setup.runtime_settings.data.set_installing(Set(InstallationSet::A)).unwrap();
+    setup
+        .runtime_settings
+        .data
+        .set_upgrading_to(Set(InstallationSet::B), Set(InstallationSet::A))
+        .unwrap();
+
+    handle_startup_callbacks(&setup.settings.data, &mut setup.runtime_settings.data).unwrap();
+
+    // The set is rolled back without being validated
+    let output = fs::read_to_string(output_file_path).unwrap();
+    assert!(!output.contains("validate-callback"), "Validate callback should not be called");
+    assert!(output.contains("rollback-callback"), "Rollback callback was not called");
+    assert!(!setup.runtime_settings.data.update.installation_sets[&InstallationSet::A].known_good);
+}
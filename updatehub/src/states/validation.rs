@@ -27,7 +27,7 @@ impl StateChangeImpl for Validation {
     }
 
     async fn handle(
-        self,
+        mut self,
         shared_state: &mut SharedState,
     ) -> Result<(State, machine::StepTransition)> {
         if let Some(key) = shared_state.firmware.pub_key.as_ref() {
@@ -45,6 +45,12 @@ impl StateChangeImpl for Validation {
 
         // Ensure the package is compatible
         self.package.compatible_with(&shared_state.firmware)?;
+        self.package.validate(&shared_state.settings)?;
+        self.package.check_version(
+            shared_state.settings.update.version_policy,
+            &shared_state.firmware,
+            shared_state.runtime_settings.minimum_security_version(),
+        )?;
 
         if shared_state
             .runtime_settings
@@ -67,7 +73,14 @@ impl StateChangeImpl for Validation {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{states::TransitionError, update_package::tests::get_update_package};
+    use crate::{
+        states::TransitionError,
+        update_package::{
+            tests::{get_update_json, get_update_package, SHA256SUM},
+            UpdatePackage,
+        },
+    };
+    use sdk::api::info::settings::VersionPolicy;
 
     #[actix_rt::test]
     async fn normal_transition() {
@@ -101,6 +114,82 @@ mod tests {
         }
     }
 
+    #[actix_rt::test]
+    async fn unsupported_install_mode() {
+        let setup = crate::tests::TestEnvironment::build().finish();
+        let mut shared_state = setup.gen_shared_state();
+        shared_state.settings.update.supported_install_modes = vec!["copy".to_string()];
+        let package = get_update_package();
+        let sign = None;
+
+        let machine = State::Validation(Validation { package, sign })
+            .move_to_next_state(&mut shared_state)
+            .await;
+
+        match machine {
+            Err(TransitionError::UpdatePackage(crate::update_package::Error::InvalidPackage(
+                d,
+            ))) => assert_eq!(d.0.len(), 2),
+            res => panic!("Unexpected result from transition: {:?}", res),
+        }
+    }
+
+    fn versioned_package(version: &str, extra: serde_json::Value) -> UpdatePackage {
+        let mut json = get_update_json(SHA256SUM);
+        json["version"] = serde_json::json!(version);
+        for (key, value) in extra.as_object().unwrap() {
+            json[key] = value.clone();
+        }
+        UpdatePackage::parse(json.to_string().as_bytes()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn version_policy() {
+        let setup = crate::tests::TestEnvironment::build().finish();
+        let mut shared_state = setup.gen_shared_state();
+        shared_state.settings.update.version_policy = VersionPolicy::Semver;
+
+        let validate = |package| State::Validation(Validation { package, sign: None });
+
+        // The firmware version is 1.1
+        let machine = validate(versioned_package("1.0", serde_json::json!({})))
+            .move_to_next_state(&mut shared_state)
+            .await;
+        match machine {
+            Err(TransitionError::UpdatePackage(crate::update_package::Error::Downgrade {
+                ..
+            })) => {}
+            res => panic!("Unexpected result from transition: {:?}", res),
+        }
+
+        let machine = validate(versioned_package("1.0", serde_json::json!({ "downgrade": true })))
+            .move_to_next_state(&mut shared_state)
+            .await
+            .unwrap()
+            .0;
+        assert_state!(machine, PrepareDownload);
+
+        shared_state.runtime_settings.set_minimum_security_version("1.2").unwrap();
+        for package in [
+            versioned_package("1.0", serde_json::json!({ "downgrade": true })),
+            versioned_package("1.1.1", serde_json::json!({})),
+        ] {
+            match validate(package).move_to_next_state(&mut shared_state).await {
+                Err(TransitionError::UpdatePackage(
+                    crate::update_package::Error::BelowMinimumSecurityVersion { .. },
+                )) => {}
+                res => panic!("Unexpected result from transition: {:?}", res),
+            }
+        }
+
+        let machine = validate(versioned_package("1.2", serde_json::json!({})))
+            .move_to_next_state(&mut shared_state)
+            .await
+            .unwrap()
+            .0;
+        assert_state!(machine, PrepareDownload);
+    }
+
     #[actix_rt::test]
     async fn skip_same_package_uid() {
         let setup = crate::tests::TestEnvironment::build().finish();
@@ -0,0 +1,375 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use crate::firmware::{api::MetadataValue, Metadata};
+use std::{cmp::Ordering, iter::Peekable, str::Chars};
+
+const ATTRIBUTES_SCOPE: &str = "attributes.";
+const IDENTITY_SCOPE: &str = "identity.";
+
+/// Evaluates an object's `install-if` expression against the device
+/// attributes and identity of the firmware.
+///
+/// Expressions compare `attributes.<key>` or `identity.<key>` to literals
+/// using `==`, `!=`, `<`, `<=`, `>` and `>=`, and can be combined by `&&`,
+/// `||`, `!` and parenthesis, e.g. `attributes.emmc_size >= 16G &&
+/// attributes.display == "lcd7"`. A variable alone is true when the key is
+/// present.
+///
+/// Numbers may have a `K`, `M`, `G` or `T` suffix, as powers of 1024, and
+/// are compared numerically, other values are only compared for equality.
+/// Keys with several values satisfy a comparison when any of them does and
+/// missing keys never do.
+pub(crate) fn evaluate(expression: &str, firmware: &Metadata) -> Result<bool> {
+    Ok(parse(expression)?.evaluate(firmware))
+}
+
+/// Checks the expression syntax, without evaluating it.
+pub(crate) fn check(expression: &str) -> Result<()> {
+    parse(expression).map(|_| ())
+}
+
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Operator, Operand),
+    Defined(Variable),
+}
+
+#[derive(Debug, PartialEq)]
+enum Operand {
+    Variable(Variable),
+    Literal(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum Variable {
+    Attribute(String),
+    Identity(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Op(Operator),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+fn parse(expression: &str) -> Result<Expr> {
+    let invalid = |reason: &str| Error::InvalidCondition(format!("{}: {}", expression, reason));
+
+    let mut parser =
+        Parser { tokens: tokenize(expression).map_err(invalid)?.into_iter().peekable() };
+    let expr = parser.or().map_err(invalid)?;
+    if parser.tokens.next().is_some() {
+        return Err(invalid("unexpected trailing input"));
+    }
+
+    Ok(expr)
+}
+
+fn tokenize(expression: &str) -> std::result::Result<Vec<Token>, &'static str> {
+    let mut tokens = Vec::default();
+    let mut chars = expression.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '&' if next_is(&mut chars, '&') => Token::And,
+            '|' if next_is(&mut chars, '|') => Token::Or,
+            '=' if next_is(&mut chars, '=') => Token::Op(Operator::Eq),
+            '!' if next_is(&mut chars, '=') => Token::Op(Operator::Ne),
+            '!' => Token::Not,
+            '<' if next_is(&mut chars, '=') => Token::Op(Operator::Le),
+            '<' => Token::Op(Operator::Lt),
+            '>' if next_is(&mut chars, '=') => Token::Op(Operator::Ge),
+            '>' => Token::Op(Operator::Gt),
+            '"' => {
+                let mut s = String::default();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => s.push(chars.next().ok_or("unterminated string")?),
+                        Some(c) => s.push(c),
+                        None => return Err("unterminated string"),
+                    }
+                }
+                Token::Str(s)
+            }
+            c if is_word_char(c) => {
+                let mut s = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !is_word_char(c) {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                Token::Word(s)
+            }
+            _ => return Err("unexpected character"),
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn next_is(chars: &mut Peekable<Chars>, c: char) -> bool {
+    if chars.peek() == Some(&c) {
+        chars.next();
+        return true;
+    }
+    false
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    fn or(&mut self) -> std::result::Result<Expr, &'static str> {
+        let mut expr = self.and()?;
+        while self.tokens.peek() == Some(&Token::Or) {
+            self.tokens.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> std::result::Result<Expr, &'static str> {
+        let mut expr = self.unary()?;
+        while self.tokens.peek() == Some(&Token::And) {
+            self.tokens.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> std::result::Result<Expr, &'static str> {
+        match self.tokens.peek() {
+            Some(Token::Not) => {
+                self.tokens.next();
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some(Token::LParen) => {
+                self.tokens.next();
+                let expr = self.or()?;
+                match self.tokens.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err("missing closing parenthesis"),
+                }
+            }
+            _ => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> std::result::Result<Expr, &'static str> {
+        let lhs = self.operand()?;
+        let op = match self.tokens.peek() {
+            Some(Token::Op(op)) => *op,
+            _ => {
+                return match lhs {
+                    Operand::Variable(var) => Ok(Expr::Defined(var)),
+                    Operand::Literal(_) => Err("expected a comparison"),
+                };
+            }
+        };
+        self.tokens.next();
+
+        Ok(Expr::Compare(lhs, op, self.operand()?))
+    }
+
+    fn operand(&mut self) -> std::result::Result<Operand, &'static str> {
+        match self.tokens.next() {
+            Some(Token::Str(s)) => Ok(Operand::Literal(s)),
+            Some(Token::Word(w)) => {
+                if let Some(key) = w.strip_prefix(ATTRIBUTES_SCOPE).filter(|k| !k.is_empty()) {
+                    Ok(Operand::Variable(Variable::Attribute(key.to_string())))
+                } else if let Some(key) = w.strip_prefix(IDENTITY_SCOPE).filter(|k| !k.is_empty()) {
+                    Ok(Operand::Variable(Variable::Identity(key.to_string())))
+                } else if w.starts_with(|c: char| c.is_ascii_digit()) {
+                    Ok(Operand::Literal(w))
+                } else {
+                    Err("unknown variable")
+                }
+            }
+            _ => Err("expected a value"),
+        }
+    }
+}
+
+impl Expr {
+    fn evaluate(&self, firmware: &Metadata) -> bool {
+        match self {
+            Expr::Or(lhs, rhs) => lhs.evaluate(firmware) || rhs.evaluate(firmware),
+            Expr::And(lhs, rhs) => lhs.evaluate(firmware) && rhs.evaluate(firmware),
+            Expr::Not(expr) => !expr.evaluate(firmware),
+            Expr::Defined(var) => !var.values(firmware).is_empty(),
+            Expr::Compare(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.values(firmware), rhs.values(firmware));
+                lhs.iter().any(|l| rhs.iter().any(|r| op.holds(l, r)))
+            }
+        }
+    }
+}
+
+impl Operand {
+    fn values<'a>(&'a self, firmware: &'a Metadata) -> Vec<&'a str> {
+        match self {
+            Operand::Variable(var) => var.values(firmware),
+            Operand::Literal(s) => vec![s.as_str()],
+        }
+    }
+}
+
+impl Variable {
+    fn values<'a>(&self, firmware: &'a Metadata) -> Vec<&'a str> {
+        let (map, key): (&MetadataValue, _) = match self {
+            Variable::Attribute(key) => (&firmware.device_attributes, key),
+            Variable::Identity(key) => (&firmware.device_identity, key),
+        };
+        map.0.get(key).map(|v| v.iter().map(String::as_str).collect()).unwrap_or_default()
+    }
+}
+
+impl Operator {
+    fn holds(self, lhs: &str, rhs: &str) -> bool {
+        let ordering = match (parse_number(lhs), parse_number(rhs)) {
+            (Some(lhs), Some(rhs)) => lhs.cmp(&rhs),
+            // Non numeric values have no order, only equality
+            _ => match self {
+                Operator::Eq => return lhs == rhs,
+                Operator::Ne => return lhs != rhs,
+                _ => return false,
+            },
+        };
+
+        match self {
+            Operator::Eq => ordering == Ordering::Equal,
+            Operator::Ne => ordering != Ordering::Equal,
+            Operator::Lt => ordering == Ordering::Less,
+            Operator::Le => ordering != Ordering::Greater,
+            Operator::Gt => ordering == Ordering::Greater,
+            Operator::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last()?.to_ascii_uppercase() {
+        'K' => (&s[..s.len() - 1], 10),
+        'M' => (&s[..s.len() - 1], 20),
+        'G' => (&s[..s.len() - 1], 30),
+        'T' => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firmware::api;
+
+    fn firmware() -> Metadata {
+        let value = |entries: &[(&str, &[&str])]| {
+            MetadataValue(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.iter().map(|s| s.to_string()).collect()))
+                    .collect(),
+            )
+        };
+
+        Metadata(api::Metadata {
+            product_uid: "229ffd7e08721d716163fc81a2dbaf6c90d449f0a3b009b6a2defe8a0b0d7381"
+                .to_string(),
+            version: "1.1".to_string(),
+            hardware: "board".to_string(),
+            pub_key: None,
+            device_identity: value(&[("id1", &["value1"])]),
+            device_attributes: value(&[
+                ("emmc_size", &["15634268160"]),
+                ("display", &["lcd7"]),
+                ("bus", &["i2c", "spi"]),
+            ]),
+        })
+    }
+
+    #[test]
+    fn comparisons() {
+        let firmware = firmware();
+        let eval = |expr: &str| evaluate(expr, &firmware).unwrap();
+
+        assert!(eval(r#"attributes.display == "lcd7""#));
+        assert!(!eval(r#"attributes.display != "lcd7""#));
+        assert!(eval("attributes.emmc_size >= 8G"));
+        assert!(!eval("attributes.emmc_size >= 16G"));
+        assert!(eval("attributes.emmc_size < 16g"));
+        assert!(eval("14910M <= attributes.emmc_size"));
+        assert!(eval(r#"attributes.bus == "spi""#));
+        assert!(eval(r#"identity.id1 == "value1""#));
+        assert!(!eval(r#"attributes.display > "lcd5""#));
+        assert!(!eval(r#"attributes.missing == "lcd7""#));
+    }
+
+    #[test]
+    fn logical_operators() {
+        let firmware = firmware();
+        let eval = |expr: &str| evaluate(expr, &firmware).unwrap();
+
+        assert!(eval(r#"attributes.emmc_size >= 8G && attributes.display == "lcd7""#));
+        assert!(eval(r#"attributes.emmc_size >= 16G || attributes.display == "lcd7""#));
+        assert!(!eval(r#"!(attributes.emmc_size >= 8G && attributes.display == "lcd7")"#));
+        assert!(eval("attributes.display && !attributes.missing"));
+        assert!(eval(r#"!attributes.missing || attributes.display == "lcd5" && attributes.bus"#));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expr in &[
+            "",
+            "attributes.",
+            "emmc_size >= 8G",
+            r#"attributes.display == "lcd7"#,
+            "attributes.emmc_size >=",
+            "(attributes.display",
+            "attributes.display attributes.bus",
+            "attributes.display & attributes.bus",
+            "8G",
+        ] {
+            match check(expr) {
+                Err(Error::InvalidCondition(_)) => {}
+                res => panic!("Unexpected result for {:?}: {:?}", expr, res),
+            }
+        }
+    }
+}
@@ -2,18 +2,31 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+mod condition;
 mod supported_hardware;
+mod version;
 
 use self::supported_hardware::SupportedHardwareExt;
 use crate::{
     firmware::{installation_set::Set, Metadata},
     object::{self, Info},
+    runtime_settings::RuntimeSettings,
     settings::Settings,
+    utils::definitions::TargetTypeExt,
+};
+use pkg_schema::{
+    definitions::{TargetType, UbiVolume},
+    Object,
+};
+use sdk::api::info::settings::VersionPolicy;
+use slog_scope::{error, info};
+use std::{
+    cmp::Ordering,
+    fs,
+    io::{self, Seek, SeekFrom},
+    os::unix::fs::FileTypeExt,
+    path::Path,
 };
-use pkg_schema::Object;
-use sdk::api::info::runtime_settings::InstallationSet;
-use slog_scope::error;
-use std::{fs, io, path::Path};
 use thiserror::Error;
 use walkdir::WalkDir;
 
@@ -34,15 +47,88 @@ pub enum Error {
 
     #[error("Incompatible with hardware: {0}")]
     IncompatibleHardware(String),
+
+    #[error("Invalid supported hardware pattern: {0}")]
+    InvalidHardwarePattern(String),
+
+    #[error("Invalid update package: {0}")]
+    InvalidPackage(#[from] pkg_schema::Diagnostics),
+
+    #[error("Package has {0} installation sets while the device is single-bank")]
+    NotSingleBank(usize),
+
+    #[error("Package has {found} installation sets while the device has {expected}")]
+    InstallationSetsMismatch { found: usize, expected: usize },
+
+    #[error("Invalid install-if condition: {0}")]
+    InvalidCondition(String),
+
+    #[error("Invalid version: {0}")]
+    InvalidVersion(String),
+
+    #[error("Package version {version} is older than the firmware version {current}")]
+    Downgrade { version: String, current: String },
+
+    #[error("Package version {version} is older than the minimum security version {minimum}")]
+    BelowMinimumSecurityVersion { version: String, minimum: String },
 }
 
 pub(crate) trait UpdatePackageExt {
     fn compatible_with(&self, firmware: &Metadata) -> Result<()>;
 
+    /// Checks the package for semantic problems before anything is
+    /// downloaded or installed, expanding its objects template.
+    fn validate(&mut self, settings: &Settings) -> Result<()>;
+
+    /// Refuses packages older than the firmware, unless flagged as a
+    /// downgrade, and older than the minimum security version.
+    fn check_version(
+        &self,
+        policy: VersionPolicy,
+        firmware: &Metadata,
+        minimum_security_version: Option<&str>,
+    ) -> Result<()>;
+
+    /// Minimum security version to be stored once the package is
+    /// installed, if it raises the current one.
+    fn raised_minimum_security_version(
+        &self,
+        policy: VersionPolicy,
+        minimum_security_version: Option<&str>,
+    ) -> Result<Option<&str>>;
+
+    /// Expands the objects template, if any, into the objects of each
+    /// installation set of the device, so these are returned by `objects`.
+    /// The single empty set of packages only updating common objects is
+    /// expanded likewise. Nothing is done once the package is expanded.
+    fn expand_objects_template(&mut self, settings: &Settings) -> Result<()>;
+
     fn objects(&self, installation_set: Set) -> &Vec<Object>;
 
+    /// Expands the placeholders of the delta sources, of the objects of the
+    /// installation set and common ones, for the set the device runs from.
+    fn expand_active_set(&mut self, installation_set: Set, active: Set) -> Result<()>;
+
+    /// UBI volumes to set up before installing on the installation set.
+    fn ubi_volumes(&self, installation_set: Set) -> Result<Vec<UbiVolume>>;
+
+    /// Drops the objects, of the installation set and common ones, whose
+    /// `install-if` condition does not hold for the firmware, so they are
+    /// neither downloaded nor installed.
+    fn retain_applicable_objects(
+        &mut self,
+        installation_set: Set,
+        firmware: &Metadata,
+    ) -> Result<()>;
+
+    /// Drops the common objects already installed with the same content
+    /// by a previous update, so they are neither downloaded nor installed.
+    fn retain_pending_common_objects(&mut self, runtime_settings: &RuntimeSettings);
+
     fn objects_mut(&mut self, installation_set: Set) -> &mut Vec<Object>;
 
+    /// Objects of the installation set, followed by the common objects,
+    /// which match the status.
     fn filter_objects(
         &self,
         settings: &Settings,
@@ -63,18 +149,153 @@ impl UpdatePackageExt for UpdatePackage {
         self.inner.supported_hardware.compatible_with(&firmware.hardware)
     }
 
-    fn objects(&self, installation_set: Set) -> &Vec<Object> {
-        match installation_set.0 {
-            InstallationSet::A => &self.inner.objects.0,
-            InstallationSet::B => &self.inner.objects.1,
+    fn validate(&mut self, settings: &Settings) -> Result<()> {
+        let target_size = |target: &TargetType| match target {
+            TargetType::UBIVolume(_) | TargetType::MTDName(_) => None,
+            // Only block devices have a fixed size, regular files grow as needed
+            _ => target.get_target().ok().and_then(|dev| {
+                fs::metadata(&dev).ok().filter(|m| m.file_type().is_block_device()).and_then(|_| {
+                    fs::File::open(&dev).and_then(|mut f| f.seek(SeekFrom::End(0))).ok()
+                })
+            }),
+        };
+        let ctx = pkg_schema::ValidationContext {
+            supported_install_modes: Some(&settings.update.supported_install_modes),
+            target_size: Some(&target_size),
+        };
+
+        self.inner.validate_document(&self.raw, &ctx)?;
+
+        // Templates, and the single empty set of packages only updating
+        // common objects, are expanded for as many sets as the device has
+        self.expand_objects_template(settings)?;
+        let sets = installation_sets(settings);
+        let found = self.inner.objects.len();
+        if found != sets {
+            if settings.update.single_bank {
+                return Err(Error::NotSingleBank(found));
+            }
+            return Err(Error::InstallationSetsMismatch { found, expected: sets });
         }
+
+        self.inner
+            .objects
+            .iter()
+            .flatten()
+            .chain(self.inner.common.iter())
+            .filter_map(Object::install_if)
+            .try_for_each(condition::check)
     }
 
-    fn objects_mut(&mut self, installation_set: Set) -> &mut Vec<Object> {
-        match installation_set.0 {
-            InstallationSet::A => &mut self.inner.objects.0,
-            InstallationSet::B => &mut self.inner.objects.1,
+    fn check_version(
+        &self,
+        policy: VersionPolicy,
+        firmware: &Metadata,
+        minimum_security_version: Option<&str>,
+    ) -> Result<()> {
+        let version = &self.inner.version;
+
+        // An invalid minimum would make every following package to be
+        // refused once stored, so it is refused upfront
+        if let Some(ref new) = self.inner.minimum_security_version {
+            version::compare(policy, new, new)?;
+        }
+
+        if let Some(minimum) = minimum_security_version {
+            if version::compare(policy, version, minimum)? == Some(Ordering::Less) {
+                return Err(Error::BelowMinimumSecurityVersion {
+                    version: version.clone(),
+                    minimum: minimum.to_string(),
+                });
+            }
+        }
+
+        if version::compare(policy, version, &firmware.version)? == Some(Ordering::Less) {
+            if !self.inner.downgrade {
+                return Err(Error::Downgrade {
+                    version: version.clone(),
+                    current: firmware.version.clone(),
+                });
+            }
+            info!(
+                "downgrading from {} to {} as requested by the package",
+                firmware.version, version
+            );
+        }
+
+        Ok(())
+    }
+
+    fn raised_minimum_security_version(
+        &self,
+        policy: VersionPolicy,
+        minimum_security_version: Option<&str>,
+    ) -> Result<Option<&str>> {
+        let new = match self.inner.minimum_security_version.as_deref() {
+            Some(new) => new,
+            None => return Ok(None),
+        };
+
+        // Nothing is stored when versions are not compared
+        if version::compare(policy, new, new)?.is_none() {
+            return Ok(None);
+        }
+
+        Ok(match minimum_security_version {
+            Some(current) if version::compare(policy, new, current)? != Some(Ordering::Greater) => {
+                None
+            }
+            _ => Some(new),
+        })
+    }
+
+    fn expand_objects_template(&mut self, settings: &Settings) -> Result<()> {
+        let sets = installation_sets(settings);
+        if !self.inner.objects_template.is_empty() {
+            self.inner.objects = self.inner.expand_objects_template(sets)?;
+            self.inner.objects_template.clear();
+        } else if self.inner.is_common_only() {
+            // Packages only updating common objects have a single empty set
+            self.inner.objects.resize_with(sets, Vec::default);
         }
+
+        Ok(())
+    }
+
+    fn objects(&self, installation_set: Set) -> &Vec<Object> {
+        &self.inner.objects[set_index(installation_set)]
+    }
+
+    fn expand_active_set(&mut self, installation_set: Set, active: Set) -> Result<()> {
+        Ok(self.inner.expand_active_set(set_index(installation_set), set_index(active))?)
+    }
+
+    fn ubi_volumes(&self, installation_set: Set) -> Result<Vec<UbiVolume>> {
+        Ok(self.inner.expand_ubi_volumes(usize::from((installation_set.0).0))?)
+    }
+
+    fn retain_applicable_objects(
+        &mut self,
+        installation_set: Set,
+        firmware: &Metadata,
+    ) -> Result<()> {
+        retain_applicable(self.objects_mut(installation_set), firmware)?;
+        retain_applicable(&mut self.inner.common, firmware)
+    }
+
+    fn retain_pending_common_objects(&mut self, runtime_settings: &RuntimeSettings) {
+        self.inner.common.retain(|o| {
+            let installed = runtime_settings.is_common_object_installed(o);
+            if installed {
+                info!("skipping common object {} as it is already installed", o.filename());
+            }
+            !installed
+        });
+    }
+
+    fn objects_mut(&mut self, installation_set: Set) -> &mut Vec<Object> {
+        let index = set_index(installation_set);
+        &mut self.inner.objects[index]
     }
 
     fn filter_objects(
@@ -85,6 +306,7 @@ impl UpdatePackageExt for UpdatePackage {
     ) -> Vec<&Object> {
         self.objects(installation_set)
             .iter()
+            .chain(self.inner.common.iter())
             .filter(|o| {
                 o.status(&settings.update.download_dir)
                     .map_err(|e| {
@@ -113,6 +335,7 @@ impl UpdatePackageExt for UpdatePackage {
                 !self
                     .objects(installation_set)
                     .iter()
+                    .chain(self.inner.common.iter())
                     .map(object::Info::sha256sum)
                     .any(|x| x == e.file_name())
             })
@@ -137,3 +360,38 @@ impl UpdatePackageExt for UpdatePackage {
         Ok(())
     }
 }
+
+fn retain_applicable(objects: &mut Vec<Object>, firmware: &Metadata) -> Result<()> {
+    let mut result = Ok(());
+    objects.retain(|o| match o.install_if() {
+        None => true,
+        Some(expression) => match condition::evaluate(expression, firmware) {
+            Ok(true) => true,
+            Ok(false) => {
+                info!("skipping object {} as '{}' does not hold", o.filename(), expression);
+                false
+            }
+            Err(e) => {
+                result = Err(e);
+                true
+            }
+        },
+    });
+
+    result
+}
+
+/// Installation sets of the device, single-bank devices have only one.
+fn installation_sets(settings: &Settings) -> usize {
+    if settings.update.single_bank {
+        1
+    } else {
+        usize::from(settings.update.installation_sets)
+    }
+}
+
+// Single-bank devices install on `installation_set::SINGLE_BANK`, which is
+// the first and only set of their packages
+fn set_index(installation_set: Set) -> usize {
+    usize::from((installation_set.0).0)
+}
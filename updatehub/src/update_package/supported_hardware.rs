@@ -3,25 +3,194 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::Error;
+use regex::Regex;
+use std::fs;
 
 pub(crate) use pkg_schema::SupportedHardware;
 
+const DEVICE_TREE_COMPATIBLE: &str = "/proc/device-tree/compatible";
+const EXCLUSION_PREFIX: char = '!';
+const DEVICE_TREE_PREFIX: &str = "dt:";
+const REGEX_PREFIX: &str = "re:";
+
 pub(crate) trait SupportedHardwareExt {
     fn compatible_with(&self, hardware: &str) -> Result<(), Error>;
 }
 
 impl SupportedHardwareExt for SupportedHardware {
     fn compatible_with(&self, hardware: &str) -> Result<(), Error> {
-        let hardware = hardware.to_string();
         let compatible = match self {
             SupportedHardware::Any => true,
-            SupportedHardware::HardwareList(l) => l.contains(&hardware),
+            SupportedHardware::HardwareList(l) => {
+                let patterns =
+                    l.iter().map(|e| Pattern::parse(e)).collect::<Result<Vec<_>, _>>()?;
+                let device_tree = if patterns.iter().any(|p| p.device_tree) {
+                    device_tree_compatible()
+                } else {
+                    Vec::default()
+                };
+                matches(&patterns, hardware, &device_tree)
+            }
         };
 
         if !compatible {
-            return Err(Error::IncompatibleHardware(hardware));
+            return Err(Error::IncompatibleHardware(hardware.to_string()));
         }
 
         Ok(())
     }
 }
+
+/// An entry of the supported hardware list, which is a glob matched
+/// against the hardware name. Entries can be prefixed by `dt:` to be
+/// matched against the device-tree `compatible` strings instead, by
+/// `re:` to use a regular expression and by `!` to exclude the hardware
+/// they match, e.g. `!dt:re:^acme,board-rev-[ab]$`.
+struct Pattern {
+    exclusion: bool,
+    device_tree: bool,
+    regex: Regex,
+}
+
+impl Pattern {
+    fn parse(entry: &str) -> Result<Self, Error> {
+        let (exclusion, entry) = match entry.strip_prefix(EXCLUSION_PREFIX) {
+            Some(entry) => (true, entry),
+            None => (false, entry),
+        };
+        let (device_tree, entry) = match entry.strip_prefix(DEVICE_TREE_PREFIX) {
+            Some(entry) => (true, entry),
+            None => (false, entry),
+        };
+        let regex = match entry.strip_prefix(REGEX_PREFIX) {
+            Some(re) => Regex::new(re),
+            None => Regex::new(&glob_to_regex(entry)),
+        }
+        .map_err(|e| Error::InvalidHardwarePattern(format!("{}: {}", entry, e)))?;
+
+        Ok(Pattern { exclusion, device_tree, regex })
+    }
+
+    fn is_match(&self, hardware: &str, device_tree: &[String]) -> bool {
+        if self.device_tree {
+            device_tree.iter().any(|c| self.regex.is_match(c))
+        } else {
+            self.regex.is_match(hardware)
+        }
+    }
+}
+
+/// The hardware is compatible when no exclusion matches it and any of the
+/// other entries does, a list holding only exclusions accepts everything
+/// else.
+fn matches(patterns: &[Pattern], hardware: &str, device_tree: &[String]) -> bool {
+    let (exclusions, inclusions): (Vec<_>, Vec<_>) = patterns.iter().partition(|p| p.exclusion);
+
+    !exclusions.iter().any(|p| p.is_match(hardware, device_tree))
+        && (inclusions.is_empty() || inclusions.iter().any(|p| p.is_match(hardware, device_tree)))
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut chars = glob.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                if chars.clone().next() == Some('!') {
+                    chars.next();
+                    re.push('^');
+                }
+                for c in &mut chars {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' || c == '^' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    re.push('$');
+    re
+}
+
+fn device_tree_compatible() -> Vec<String> {
+    // The property is a list of NUL terminated strings, from the most to the
+    // least specific one.
+    fs::read(DEVICE_TREE_COMPATIBLE)
+        .map(|c| {
+            c.split(|b| *b == 0)
+                .filter(|s| !s.is_empty())
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_compatible(list: &[&str], hardware: &str, device_tree: &[&str]) -> bool {
+        let patterns = list.iter().map(|e| Pattern::parse(e).unwrap()).collect::<Vec<_>>();
+        let device_tree = device_tree.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        matches(&patterns, hardware, &device_tree)
+    }
+
+    #[test]
+    fn exact_and_glob() {
+        assert!(is_compatible(&["board"], "board", &[]));
+        assert!(!is_compatible(&["board"], "board-rev-a", &[]));
+        assert!(is_compatible(&["other", "board-rev-*"], "board-rev-a", &[]));
+        assert!(is_compatible(&["board-rev-?"], "board-rev-b", &[]));
+        assert!(is_compatible(&["board-rev-[a-c]"], "board-rev-c", &[]));
+        assert!(!is_compatible(&["board-rev-[!a-c]"], "board-rev-c", &[]));
+        assert!(!is_compatible(&["board.rev"], "boardxrev", &[]));
+    }
+
+    #[test]
+    fn regex() {
+        assert!(is_compatible(&["re:^board-rev-[0-9]+$"], "board-rev-12", &[]));
+        assert!(!is_compatible(&["re:^board-rev-[0-9]+$"], "board-rev-a", &[]));
+        assert!(Pattern::parse("re:board-(").is_err());
+    }
+
+    #[test]
+    fn exclusion() {
+        assert!(!is_compatible(&["board-*", "!board-rev-a*"], "board-rev-a1", &[]));
+        assert!(is_compatible(&["board-*", "!board-rev-a*"], "board-rev-b1", &[]));
+        assert!(is_compatible(&["!board-rev-a*"], "other", &[]));
+        assert!(!is_compatible(&["!board-rev-a*"], "board-rev-a", &[]));
+    }
+
+    #[test]
+    fn device_tree() {
+        let device_tree = ["acme,board-rev-b", "acme,board"];
+
+        assert!(is_compatible(&["dt:acme,board"], "unknown", &device_tree));
+        assert!(!is_compatible(&["dt:acme,other"], "unknown", &device_tree));
+        assert!(!is_compatible(&["dt:*", "!dt:re:rev-[ab]$"], "unknown", &device_tree));
+        assert!(!is_compatible(&["dt:acme,board"], "unknown", &[]));
+    }
+
+    #[test]
+    fn any_hardware() {
+        assert!(SupportedHardware::Any.compatible_with("board").is_ok());
+        assert!(SupportedHardware::HardwareList(vec!["board-*".to_string()])
+            .compatible_with("board-rev-a")
+            .is_ok());
+        match SupportedHardware::HardwareList(vec!["other".to_string()]).compatible_with("board") {
+            Err(Error::IncompatibleHardware(hw)) => assert_eq!(hw, "board"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
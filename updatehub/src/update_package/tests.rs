@@ -4,6 +4,7 @@
 
 use super::*;
 use pretty_assertions::assert_eq;
+use sdk::api::info::runtime_settings::InstallationSet;
 use serde_json::json;
 
 pub(crate) const SHA256SUM: &str =
@@ -47,6 +48,19 @@ pub(crate) fn get_update_package() -> UpdatePackage {
         .unwrap()
 }
 
+pub(crate) fn get_single_bank_update_package() -> UpdatePackage {
+    let mut json = get_update_json(SHA256SUM);
+    json["objects"] = json!([json["objects"][0]]);
+    UpdatePackage::parse(&json.to_string().into_bytes()).unwrap()
+}
+
+pub(crate) fn get_common_only_update_package() -> UpdatePackage {
+    let mut json = get_update_json(SHA256SUM);
+    json["common"] = json!([json["objects"][0][0]]);
+    json["objects"] = json!([[]]);
+    UpdatePackage::parse(&json.to_string().into_bytes()).unwrap()
+}
+
 pub(crate) fn get_update_package_with_shasum(shasum: &str) -> UpdatePackage {
     UpdatePackage::parse(&get_update_json(shasum).to_string().into_bytes()).unwrap()
 }
@@ -90,23 +104,17 @@ fn complete_object_file() {
 
     create_fake_object(OBJECT, SHA256SUM, settings);
 
-    assert!(
-        update_package
-            .filter_objects(settings, Set(InstallationSet::A), object::info::Status::Missing)
-            .is_empty()
-    );
+    assert!(update_package
+        .filter_objects(settings, Set(InstallationSet::A), object::info::Status::Missing)
+        .is_empty());
 
-    assert!(
-        update_package
-            .filter_objects(settings, Set(InstallationSet::A), object::info::Status::Incomplete)
-            .is_empty()
-    );
+    assert!(update_package
+        .filter_objects(settings, Set(InstallationSet::A), object::info::Status::Incomplete)
+        .is_empty());
 
-    assert!(
-        update_package
-            .filter_objects(settings, Set(InstallationSet::A), object::info::Status::Corrupted)
-            .is_empty()
-    );
+    assert!(update_package
+        .filter_objects(settings, Set(InstallationSet::A), object::info::Status::Corrupted)
+        .is_empty());
 
     assert_eq!(
         update_package
@@ -116,3 +124,169 @@ fn complete_object_file() {
         1
     );
 }
+
+#[test]
+fn conditional_objects() {
+    let setup = crate::tests::TestEnvironment::build().finish();
+    let object = |filename: &str, install_if: &str| {
+        json!({
+            "mode": "test",
+            "filename": filename,
+            "target": "/dev/device1",
+            "sha256sum": SHA256SUM,
+            "size": 10,
+            "install-if": install_if
+        })
+    };
+    let package_with = |objects: Vec<serde_json::Value>| {
+        let mut json = get_update_json(SHA256SUM);
+        json["objects"][0] = json!(objects);
+        UpdatePackage::parse(&json.to_string().into_bytes()).unwrap()
+    };
+
+    let mut update_package = package_with(vec![
+        object("matching", r#"attributes.attr1 == "attrvalue1" && identity.id2"#),
+        object("not-matching", r#"attributes.attr1 == "attrvalue2""#),
+    ]);
+    update_package
+        .retain_applicable_objects(Set(InstallationSet::A), &setup.firmware.data)
+        .unwrap();
+    assert_eq!(
+        update_package
+            .objects(Set(InstallationSet::A))
+            .iter()
+            .map(object::Info::filename)
+            .collect::<Vec<_>>(),
+        vec!["matching"]
+    );
+    assert_eq!(update_package.objects(Set(InstallationSet::B)).len(), 1);
+
+    let mut update_package = package_with(vec![object("invalid", "attributes.attr1 ==")]);
+    match update_package.retain_applicable_objects(Set(InstallationSet::A), &setup.firmware.data) {
+        Err(Error::InvalidCondition(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match update_package.validate(&setup.settings.data) {
+        Err(Error::InvalidCondition(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn single_bank() {
+    let setup = crate::tests::TestEnvironment::build().finish();
+    let mut settings = setup.settings.data.clone();
+    let mut update_package = get_single_bank_update_package();
+
+    // The device, not the package, tells whether it is installed in place
+    match update_package.validate(&settings) {
+        Err(Error::InstallationSetsMismatch { found: 1, expected: 2 }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    settings.update.single_bank = true;
+    update_package.validate(&settings).unwrap();
+    match get_update_package().validate(&settings) {
+        Err(Error::NotSingleBank(2)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn common_only() {
+    let setup = crate::tests::TestEnvironment::build().finish();
+    let mut settings = setup.settings.data.clone();
+
+    for (single_bank, sets) in &[(false, 2), (true, 1)] {
+        settings.update.single_bank = *single_bank;
+        let mut update_package = get_common_only_update_package();
+        update_package.validate(&settings).unwrap();
+        assert_eq!(update_package.inner.objects.len(), *sets);
+        assert!(update_package.objects(Set(InstallationSet::A)).is_empty());
+        assert_eq!(update_package.inner.common.len(), 1);
+    }
+}
+
+#[test]
+fn installation_sets() {
+    let setup = crate::tests::TestEnvironment::build().finish();
+    let mut settings = setup.settings.data.clone();
+    settings.update.installation_sets = 3;
+
+    match get_update_package().validate(&settings) {
+        Err(Error::InstallationSetsMismatch { found: 2, expected: 3 }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match get_single_bank_update_package().validate(&settings) {
+        Err(Error::InstallationSetsMismatch { found: 1, expected: 3 }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    let mut json = get_update_json(SHA256SUM);
+    let set = json["objects"][1].clone();
+    json["objects"].as_array_mut().unwrap().push(set);
+    let mut update_package = UpdatePackage::parse(&json.to_string().into_bytes()).unwrap();
+    update_package.validate(&settings).unwrap();
+    assert_eq!(update_package.objects(Set(InstallationSet(2))).len(), 1);
+}
+
+#[test]
+fn objects_template() {
+    let mut json = get_update_json(SHA256SUM);
+    json["objects-template"] = json!([json["objects"][0][0]]);
+    json["objects-template"][0]["target"] = json!("/dev/device${set_index+1}");
+    json.as_object_mut().unwrap().remove("objects");
+    let mut update_package = UpdatePackage::parse(&json.to_string().into_bytes()).unwrap();
+    let setup = crate::tests::TestEnvironment::build().finish();
+    let mut settings = setup.settings.data.clone();
+    settings.update.installation_sets = 3;
+
+    // The template is expanded once, by the validation, and kept expanded
+    update_package.validate(&settings).unwrap();
+    assert!(update_package.inner.objects_template.is_empty());
+    update_package.expand_objects_template(&settings).unwrap();
+    for (set, target) in &[
+        (InstallationSet::A, "/dev/device1"),
+        (InstallationSet::B, "/dev/device2"),
+        (InstallationSet(2), "/dev/device3"),
+    ] {
+        match update_package.objects(Set(*set)).as_slice() {
+            [Object::Test(o)] => assert_eq!(o.target, *target),
+            objects => panic!("Unexpected objects: {:?}", objects),
+        }
+    }
+}
+
+#[test]
+fn delta_sources() {
+    let mut json = get_update_json(SHA256SUM);
+    json["objects-template"] = json!([{
+        "mode": "raw-delta",
+        "filename": "rootfs.bsdiff",
+        "size": 10,
+        "sha256sum": SHA256SUM,
+        "target-type": "device",
+        "target": "/dev/device${set_index+1}",
+        "delta-format": "bsdiff",
+        "source": "/dev/device${active_set_index+1}",
+        "source-size": 10,
+        "source-sha256sum": SHA256SUM,
+        "result-size": 10,
+        "result-sha256sum": SHA256SUM
+    }]);
+    json.as_object_mut().unwrap().remove("objects");
+    let mut update_package = UpdatePackage::parse(&json.to_string().into_bytes()).unwrap();
+    let setup = crate::tests::TestEnvironment::build().finish();
+    let mut settings = setup.settings.data.clone();
+    settings.update.installation_sets = 3;
+    settings.update.supported_install_modes.push("raw-delta".to_string());
+    update_package.validate(&settings).unwrap();
+
+    // The source comes from the set the device runs from, not the target one
+    let (target, active) = (Set(InstallationSet(2)), Set(InstallationSet::A));
+    update_package.expand_active_set(target, active).unwrap();
+    match update_package.objects(target).as_slice() {
+        [Object::RawDelta(o)] => assert_eq!(o.source, Path::new("/dev/device1")),
+        objects => panic!("Unexpected objects: {:?}", objects),
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use sdk::api::info::settings::VersionPolicy;
+use std::cmp::Ordering;
+
+/// Compares two versions according to the policy, `None` is returned when
+/// the policy does not compare versions.
+pub(crate) fn compare(policy: VersionPolicy, a: &str, b: &str) -> Result<Option<Ordering>> {
+    Ok(match policy {
+        VersionPolicy::Disabled => None,
+        VersionPolicy::Semver => Some(Semver::parse(a)?.cmp(&Semver::parse(b)?)),
+        VersionPolicy::Dpkg => Some(dpkg_compare(a, b)?),
+    })
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Identifier {
+    // Numeric identifiers always have lower precedence than alphanumeric ones
+    Numeric(u64),
+    AlphaNumeric(String),
+}
+
+/// Semantic version, missing minor and patch numbers are taken as zero and a
+/// leading `v` is accepted, as both are common on firmware versions.
+#[derive(Debug, PartialEq, Eq)]
+struct Semver {
+    core: [u64; 3],
+    pre_release: Vec<Identifier>,
+}
+
+impl Semver {
+    fn parse(version: &str) -> Result<Self> {
+        let invalid = || Error::InvalidVersion(version.to_string());
+        let s = version.trim();
+        let s = s.strip_prefix('v').unwrap_or(s);
+        // Build metadata does not take part on precedence
+        let s = s.split('+').next().unwrap_or_default();
+        let (core, pre_release) = match s.find('-') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+
+        let mut numbers = [0; 3];
+        let mut parts = core.split('.');
+        for (i, part) in parts.by_ref().take(3).enumerate() {
+            numbers[i] = part.parse().map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        let pre_release = pre_release
+            .map(|p| {
+                p.split('.')
+                    .map(|id| {
+                        if id.is_empty() {
+                            return Err(invalid());
+                        }
+                        Ok(id
+                            .parse()
+                            .map(Identifier::Numeric)
+                            .unwrap_or_else(|_| Identifier::AlphaNumeric(id.to_string())))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Semver { core: numbers, pre_release })
+    }
+}
+
+impl PartialOrd for Semver {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Semver {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.core.cmp(&other.core).then_with(|| {
+            // A pre-release has lower precedence than the release itself
+            match (self.pre_release.is_empty(), other.pre_release.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self.pre_release.cmp(&other.pre_release),
+            }
+        })
+    }
+}
+
+/// Compares versions as `dpkg --compare-versions` does, in the
+/// `[epoch:]upstream[-revision]` format.
+fn dpkg_compare(a: &str, b: &str) -> Result<Ordering> {
+    fn split(version: &str) -> Result<(u64, &str, &str)> {
+        let (epoch, rest) = match version.find(':') {
+            Some(i) => (
+                version[..i].parse().map_err(|_| Error::InvalidVersion(version.to_string()))?,
+                &version[i + 1..],
+            ),
+            None => (0, version),
+        };
+        let (upstream, revision) = match rest.rfind('-') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+        if upstream.is_empty() || !upstream.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(Error::InvalidVersion(version.to_string()));
+        }
+
+        Ok((epoch, upstream, revision))
+    }
+
+    let (a_epoch, a_upstream, a_revision) = split(a.trim())?;
+    let (b_epoch, b_upstream, b_revision) = split(b.trim())?;
+
+    Ok(a_epoch
+        .cmp(&b_epoch)
+        .then_with(|| verrevcmp(a_upstream, b_upstream))
+        .then_with(|| verrevcmp(a_revision, b_revision)))
+}
+
+// Port of dpkg's lib/dpkg/version.c, where '~' sorts before anything, even
+// the end of the string, and letters sort before other characters.
+fn verrevcmp(a: &str, b: &str) -> Ordering {
+    fn order(c: Option<&u8>) -> i32 {
+        match c {
+            None => 0,
+            Some(c) if c.is_ascii_digit() => 0,
+            Some(c) if c.is_ascii_alphabetic() => i32::from(*c),
+            Some(b'~') => -1,
+            Some(c) => i32::from(*c) + 256,
+        }
+    }
+    let is_digit = |c: Option<&u8>| matches!(c, Some(c) if c.is_ascii_digit());
+
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !is_digit(a.get(i))) || (j < b.len() && !is_digit(b.get(j))) {
+            let (ac, bc) = (order(a.get(i)), order(b.get(j)));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+
+        let mut first_diff = Ordering::Equal;
+        while is_digit(a.get(i)) && is_digit(b.get(j)) {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        if is_digit(a.get(i)) {
+            return Ordering::Greater;
+        }
+        if is_digit(b.get(j)) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn semver(a: &str, b: &str) -> Ordering {
+        compare(VersionPolicy::Semver, a, b).unwrap().unwrap()
+    }
+
+    fn dpkg(a: &str, b: &str) -> Ordering {
+        compare(VersionPolicy::Dpkg, a, b).unwrap().unwrap()
+    }
+
+    #[test]
+    fn semver_precedence() {
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.0.1",
+            "1.2",
+            "v2",
+        ];
+        for pair in ordered.windows(2) {
+            assert_eq!(semver(pair[0], pair[1]), Ordering::Less, "{} < {}", pair[0], pair[1]);
+        }
+
+        assert_eq!(semver("1.0", "1.0.0+build.5"), Ordering::Equal);
+        assert!(compare(VersionPolicy::Semver, "1.0.0.0", "1.0").is_err());
+        assert!(compare(VersionPolicy::Semver, "release", "1.0").is_err());
+        assert!(compare(VersionPolicy::Semver, "1.0.0-", "1.0").is_err());
+    }
+
+    #[test]
+    fn dpkg_precedence() {
+        let ordered =
+            ["1.0~rc1", "1.0", "1.0-1", "1.0-2", "1.0a", "1.0+git1", "1.0.1", "1.10", "2:0.1"];
+        for pair in ordered.windows(2) {
+            assert_eq!(dpkg(pair[0], pair[1]), Ordering::Less, "{} < {}", pair[0], pair[1]);
+        }
+
+        assert_eq!(dpkg("1.01", "1.1"), Ordering::Equal);
+        assert_eq!(dpkg("0:1.0", "1.0"), Ordering::Equal);
+        assert!(compare(VersionPolicy::Dpkg, "x:1.0", "1.0").is_err());
+    }
+
+    #[test]
+    fn disabled() {
+        assert_eq!(compare(VersionPolicy::Disabled, "2.0", "1.0").unwrap(), None);
+    }
+}
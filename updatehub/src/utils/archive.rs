@@ -0,0 +1,358 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use std::{
+    collections::HashSet,
+    ffi::{CStr, CString, OsStr},
+    fs, io,
+    os::{
+        raw::{c_char, c_int},
+        unix::{ffi::OsStrExt, net::UnixStream},
+    },
+    path::{Component, Path, PathBuf},
+    ptr, thread,
+};
+
+/// Lists the entries of the archive in `source`, in any of the formats and
+/// compressions `compress_tools::uncompress_archive` extracts, failing if
+/// any of them would be written outside the directory it is extracted into.
+/// That is the case for absolute paths, paths with `..` components and
+/// paths which go through a symbolic link, either from the archive or, when
+/// `existing` is given, already present in that directory. The archive is
+/// read by libarchive, as when it is extracted, so both see the same
+/// entries.
+pub(crate) fn safe_entries(source: &Path, existing: Option<&Path>) -> Result<Vec<PathBuf>> {
+    check_entries(Entries::open(source)?, existing)
+}
+
+/// Runs `f` over the decompressed content of `source`. The data is
+/// decompressed on a separate thread and streamed over a socket, so it
+/// doesn't need to be stored uncompressed.
+pub(crate) fn decompressed<T, E, F>(source: &Path, f: F) -> std::result::Result<T, E>
+where
+    F: FnOnce(&mut dyn io::Read) -> std::result::Result<T, E>,
+    E: From<io::Error> + From<compress_tools::Error>,
+{
+    let (mut reader, writer) = UnixStream::pair()?;
+    let source = fs::File::open(source)?;
+    let feeder = thread::spawn(move || compress_tools::uncompress_data(source, writer));
+
+    let res = f(&mut reader);
+    // Consume what is left, like the padding after the end of an archive, so
+    // the feeder can finish
+    io::copy(&mut reader, &mut io::sink())?;
+    feeder.join().unwrap_or_else(|e| std::panic::resume_unwind(e))?;
+
+    res
+}
+
+/// Joins the relative `path` to `root`, failing if the result would be
+/// outside of it because of `..` components or symbolic links.
+pub(crate) fn contained_path(root: &Path, path: &Path) -> Result<PathBuf> {
+    let unsafe_path = || Error::UnsafeArchiveEntry(path.to_path_buf());
+    let relative = normalize(path).filter(|p| !p.as_os_str().is_empty()).ok_or_else(unsafe_path)?;
+    if relative.ancestors().skip(1).filter(|a| !a.as_os_str().is_empty()).any(|a| {
+        root.join(a).symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or_default()
+    }) {
+        return Err(unsafe_path());
+    }
+
+    Ok(root.join(relative))
+}
+
+fn check_entries(
+    entries: impl Iterator<Item = Result<Entry>>,
+    existing: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::default();
+    let mut symlinks = HashSet::new();
+
+    let through_symlink = |path: &Path, symlinks: &HashSet<PathBuf>| {
+        path.ancestors().skip(1).filter(|a| !a.as_os_str().is_empty()).any(|a| {
+            symlinks.contains(a)
+                || existing
+                    .and_then(|root| root.join(a).symlink_metadata().ok())
+                    .map(|m| m.file_type().is_symlink())
+                    .unwrap_or_default()
+        })
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let path = normalize(&entry.path).ok_or(Error::UnsafeArchiveEntry(entry.path))?;
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        if through_symlink(&path, &symlinks) {
+            return Err(Error::UnsafeArchiveEntry(path));
+        }
+
+        if let Some(link) = &entry.hard_link {
+            match normalize(link) {
+                Some(link) if !through_symlink(&link, &symlinks) => {}
+                _ => return Err(Error::UnsafeArchiveEntry(path)),
+            }
+        }
+
+        if entry.is_symlink {
+            symlinks.insert(path.clone());
+        } else {
+            symlinks.remove(&path);
+        }
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+struct Entry {
+    path: PathBuf,
+    hard_link: Option<PathBuf>,
+    is_symlink: bool,
+}
+
+/// Headers of the entries of an archive, as read by libarchive.
+struct Entries(*mut ffi::archive);
+
+impl Entries {
+    fn open(source: &Path) -> Result<Self> {
+        let filename = CString::new(source.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let archive = unsafe { ffi::archive_read_new() };
+        if archive.is_null() {
+            return Err(compress_tools::Error::ArchiveNull.into());
+        }
+
+        // Same formats and filters the archives are extracted with
+        let entries = Entries(archive);
+        unsafe {
+            entries.check(ffi::archive_read_support_filter_all(archive))?;
+            entries.check(ffi::archive_read_support_format_all(archive))?;
+            entries.check(ffi::archive_read_support_format_raw(archive))?;
+            entries.check(ffi::archive_read_open_filename(
+                archive,
+                filename.as_ptr(),
+                ffi::BLOCK_SIZE,
+            ))?;
+        }
+
+        Ok(entries)
+    }
+
+    fn check(&self, res: c_int) -> Result<()> {
+        if res == ffi::ARCHIVE_OK {
+            return Ok(());
+        }
+
+        let message = unsafe { ffi::archive_error_string(self.0) };
+        let message = if message.is_null() {
+            "unknown error".to_string()
+        } else {
+            unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned()
+        };
+        Err(compress_tools::Error::ExtractionError(message).into())
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut entry = ptr::null_mut();
+        match unsafe { ffi::archive_read_next_header(self.0, &mut entry) } {
+            ffi::ARCHIVE_EOF => None,
+            res => Some(self.check(res).and_then(|_| unsafe {
+                let path = |name: *const c_char| {
+                    Some(name)
+                        .filter(|n| !n.is_null())
+                        .map(|n| PathBuf::from(OsStr::from_bytes(CStr::from_ptr(n).to_bytes())))
+                };
+                Ok(Entry {
+                    path: path(ffi::archive_entry_pathname(entry))
+                        .ok_or_else(|| Error::UnsafeArchiveEntry(PathBuf::default()))?,
+                    hard_link: path(ffi::archive_entry_hardlink(entry)),
+                    is_symlink: ffi::archive_entry_filetype(entry) & ffi::AE_IFMT == ffi::AE_IFLNK,
+                })
+            })),
+        }
+    }
+}
+
+impl Drop for Entries {
+    fn drop(&mut self) {
+        unsafe { ffi::archive_read_free(self.0) };
+    }
+}
+
+/// Removes the `.` components of a relative path, returning `None` if it is
+/// absolute or has `..` components.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .map(|c| match c {
+            Component::Normal(c) => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_uint};
+
+    // From https://github.com/libarchive/libarchive/blob/master/libarchive/archive.h
+    // and https://github.com/libarchive/libarchive/blob/master/libarchive/archive_entry.h
+    pub const ARCHIVE_OK: c_int = 0;
+    pub const ARCHIVE_EOF: c_int = 1;
+    pub const AE_IFMT: c_uint = 0o170000;
+    pub const AE_IFLNK: c_uint = 0o120000;
+    pub const BLOCK_SIZE: usize = 10240;
+
+    pub enum archive {}
+    pub enum archive_entry {}
+
+    #[link(name = "archive")]
+    extern "C" {
+        pub fn archive_read_new() -> *mut archive;
+        pub fn archive_read_support_filter_all(archive: *mut archive) -> c_int;
+        pub fn archive_read_support_format_all(archive: *mut archive) -> c_int;
+        pub fn archive_read_support_format_raw(archive: *mut archive) -> c_int;
+        pub fn archive_read_open_filename(
+            archive: *mut archive,
+            filename: *const c_char,
+            block_size: usize,
+        ) -> c_int;
+        pub fn archive_read_next_header(
+            archive: *mut archive,
+            entry: *mut *mut archive_entry,
+        ) -> c_int;
+        pub fn archive_read_free(archive: *mut archive) -> c_int;
+        pub fn archive_error_string(archive: *mut archive) -> *const c_char;
+        pub fn archive_entry_pathname(entry: *mut archive_entry) -> *const c_char;
+        pub fn archive_entry_hardlink(entry: *mut archive_entry) -> *const c_char;
+        pub fn archive_entry_filetype(entry: *mut archive_entry) -> c_uint;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn archive(f: impl FnOnce(&mut tar::Builder<Vec<u8>>)) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::default());
+        f(&mut builder);
+        builder.into_inner().unwrap()
+    }
+
+    fn append(builder: &mut tar::Builder<Vec<u8>>, kind: tar::EntryType, path: &str, link: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(0);
+        // Unsafe names can't be set through the header methods, which check them
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_cksum();
+        builder.append(&header, io::empty()).unwrap();
+    }
+
+    fn cpio(entries: &[(u32, &str, &str)]) -> Vec<u8> {
+        let mut data = Vec::default();
+        let trailer = (0, "TRAILER!!!", "");
+        for (ino, (mode, path, content)) in entries.iter().chain(Some(&trailer)).enumerate() {
+            let fields = [ino as u32, *mode, 0, 0, 1, 0, content.len() as u32, 0, 0, 0, 0];
+            data.extend_from_slice(b"070701");
+            for field in fields.iter().chain(&[path.len() as u32 + 1, 0]) {
+                data.extend_from_slice(format!("{:08x}", field).as_bytes());
+            }
+            data.extend_from_slice(path.as_bytes());
+            data.push(0);
+            data.resize(data.len() + (4 - data.len() % 4) % 4, 0);
+            data.extend_from_slice(content.as_bytes());
+            data.resize(data.len() + (4 - data.len() % 4) % 4, 0);
+        }
+        data
+    }
+
+    fn entries(data: &[u8], existing: Option<&Path>) -> Result<Vec<PathBuf>> {
+        let source = tempfile::NamedTempFile::new()?;
+        fs::write(source.path(), data)?;
+        safe_entries(source.path(), existing)
+    }
+
+    #[test]
+    fn valid_entries() {
+        let data = archive(|b| {
+            append(b, tar::EntryType::Directory, "./tree/", "");
+            append(b, tar::EntryType::Regular, "./tree/leaf", "");
+            append(b, tar::EntryType::Symlink, "tree/abs", "/etc");
+            append(b, tar::EntryType::Symlink, "tree/rel", "../tree");
+            append(b, tar::EntryType::Link, "tree/hard", "tree/leaf");
+        });
+
+        assert_eq!(
+            entries(&data, None).unwrap(),
+            ["tree", "tree/leaf", "tree/abs", "tree/rel", "tree/hard"]
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn unsafe_entries() {
+        let cases: Vec<Vec<u8>> = vec![
+            archive(|b| append(b, tar::EntryType::Regular, "/etc/passwd", "")),
+            archive(|b| append(b, tar::EntryType::Regular, "tree/../../passwd", "")),
+            archive(|b| append(b, tar::EntryType::Link, "tree/hard", "../passwd")),
+            archive(|b| append(b, tar::EntryType::Link, "tree/hard", "/etc/passwd")),
+            archive(|b| {
+                append(b, tar::EntryType::Symlink, "etc", "/etc");
+                append(b, tar::EntryType::Regular, "etc/passwd", "");
+            }),
+        ];
+        for data in cases {
+            assert!(entries(&data, None).is_err());
+        }
+
+        let root = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink("/etc", root.path().join("etc")).unwrap();
+        let data = archive(|b| append(b, tar::EntryType::Regular, "etc/passwd", ""));
+        assert!(entries(&data, None).is_ok());
+        assert!(entries(&data, Some(root.path())).is_err());
+    }
+
+    #[test]
+    fn compressed_archive() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let data = archive(|b| append(b, tar::EntryType::Regular, "leaf", ""));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        source.write_all(&encoder.finish().unwrap()).unwrap();
+
+        assert_eq!(safe_entries(source.path(), None).unwrap(), vec![PathBuf::from("leaf")]);
+        assert_eq!(
+            safe_entries(Path::new("fixtures/tree.tar"), None).unwrap().len(),
+            tar::Archive::new(fs::File::open("fixtures/tree.tar").unwrap())
+                .entries()
+                .unwrap()
+                .count()
+        );
+    }
+
+    #[test]
+    fn other_formats() {
+        let data = cpio(&[(0o100644, "leaf", "leaf")]);
+        assert_eq!(entries(&data, None).unwrap(), vec![PathBuf::from("leaf")]);
+
+        let data = cpio(&[(0o120777, "etc", "/etc"), (0o100644, "etc/passwd", "")]);
+        assert!(entries(&data, None).is_err());
+        let data = cpio(&[(0o100644, "../passwd", "")]);
+        assert!(entries(&data, None).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::io::{self, BufRead, BufReader, Read};
+
+lazy_static! {
+    // Gear table generated with SplitMix64 seeded with zero, index
+    // generators must use the same table to produce matching chunks.
+    static ref GEAR: [u64; 256] = {
+        let mut table = [0; 256];
+        let mut state = 0u64;
+        for entry in table.iter_mut() {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *entry = z ^ (z >> 31);
+        }
+        table
+    };
+}
+
+/// Parameters for the content-defined chunking.
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ChunkerParams {
+    pub(crate) min_size: usize,
+    pub(crate) avg_size: usize,
+    pub(crate) max_size: usize,
+}
+
+/// Splits a stream in content-defined chunks using a gear rolling hash, so
+/// unchanged regions of an image produce the same chunks even when data is
+/// inserted or removed before them.
+pub(crate) struct Chunker<R> {
+    reader: BufReader<R>,
+    params: ChunkerParams,
+    mask: u64,
+}
+
+impl<R: Read> Chunker<R> {
+    pub(crate) fn new(reader: R, params: ChunkerParams) -> Self {
+        let bits = params.avg_size.next_power_of_two().trailing_zeros();
+        let mask = if bits == 0 { 0 } else { !0u64 << (64 - bits) };
+        Chunker { reader: BufReader::new(reader), params, mask }
+    }
+
+    /// Returns the next chunk or `None` when the input is exhausted.
+    pub(crate) fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk = Vec::with_capacity(self.params.avg_size);
+        let mut hash = 0u64;
+
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(if chunk.is_empty() { None } else { Some(chunk) });
+            }
+
+            let mut cut = None;
+            for (i, b) in buf.iter().enumerate() {
+                hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+                let len = chunk.len() + i + 1;
+                if len >= self.params.max_size
+                    || (len >= self.params.min_size && hash & self.mask == 0)
+                {
+                    cut = Some(i + 1);
+                    break;
+                }
+            }
+
+            let used = cut.unwrap_or(buf.len());
+            chunk.extend_from_slice(&buf[..used]);
+            self.reader.consume(used);
+            if cut.is_some() {
+                return Ok(Some(chunk));
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    pub(crate) const PARAMS: ChunkerParams =
+        ChunkerParams { min_size: 256, avg_size: 1024, max_size: 4096 };
+
+    /// Deterministic pseudo random data, so chunk boundaries are exercised
+    pub(crate) fn pseudo_random_data(seed: u64, size: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunks_respect_bounds() {
+        let data = pseudo_random_data(1, 64 * 1024);
+        let chunks = Chunker::new(data.as_slice(), PARAMS).collect::<io::Result<Vec<_>>>().unwrap();
+
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks.iter().all(|c| c.len() <= PARAMS.max_size));
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.len() >= PARAMS.min_size));
+    }
+
+    #[test]
+    fn chunks_are_content_defined() {
+        let data = pseudo_random_data(1, 64 * 1024);
+        let mut changed = b"inserted at the beginning".to_vec();
+        changed.extend_from_slice(&data);
+
+        let original =
+            Chunker::new(data.as_slice(), PARAMS).collect::<io::Result<Vec<_>>>().unwrap();
+        let changed =
+            Chunker::new(changed.as_slice(), PARAMS).collect::<io::Result<Vec<_>>>().unwrap();
+
+        let shared = changed.iter().filter(|c| original.contains(c)).count();
+        assert!(
+            shared >= original.len() - 2,
+            "only {} of {} chunks shared",
+            shared,
+            original.len()
+        );
+    }
+
+    #[test]
+    fn empty_input() {
+        assert!(Chunker::new(io::empty(), PARAMS).next_chunk().unwrap().is_none());
+    }
+}
@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::{Error, Result};
-use crate::utils::mtd;
+use crate::utils::{fs::BlockDevice, mtd, partition};
 use pkg_schema::definitions::{
     target_permissions::{Gid, Uid},
     TargetType,
@@ -18,6 +18,14 @@ pub(crate) trait TargetTypeExt {
 
     /// Gets device's path for mounting.
     fn get_target(&self) -> Result<PathBuf>;
+
+    /// Gets the device holding the target's filesystem, attaching image
+    /// files to a loop device for as long as it is kept.
+    fn get_block_device(&self) -> Result<BlockDevice>;
+
+    /// Checks whether the target is a device, given either by its path or
+    /// by an identifier of its partition.
+    fn is_device(&self) -> bool;
 }
 
 impl TargetTypeExt for TargetType {
@@ -32,6 +40,17 @@ impl TargetTypeExt for TargetType {
                 }
                 &self
             }
+            TargetType::ImageFile(p) => {
+                // Image files might be created by the installation itself
+                let existing = if p.exists() { p.as_path() } else { p.parent().unwrap_or(p) };
+                if !existing.exists() {
+                    return Err(Error::DeviceDoesNotExist);
+                }
+                if existing.metadata()?.permissions().readonly() {
+                    return Err(Error::MissingWritePermission(existing.to_path_buf()));
+                }
+                &self
+            }
             TargetType::UBIVolume(s) => {
                 let dev = mtd::target_device_from_ubi_volume_name(s)?;
                 if dev.metadata()?.permissions().readonly() {
@@ -46,14 +65,46 @@ impl TargetTypeExt for TargetType {
                 }
                 &self
             }
+            TargetType::PartLabel(_)
+            | TargetType::PartUUID(_)
+            | TargetType::FsLabel(_)
+            | TargetType::GptPartitionName(_) => {
+                let dev = self.get_target()?;
+                if dev.metadata()?.permissions().readonly() {
+                    return Err(Error::MissingWritePermission(dev));
+                }
+                &self
+            }
         })
     }
 
     fn get_target(&self) -> Result<PathBuf> {
         match self {
-            TargetType::Device(p) => Ok(p.clone()),
+            TargetType::Device(p) | TargetType::ImageFile(p) => Ok(p.clone()),
             TargetType::UBIVolume(s) => mtd::target_device_from_ubi_volume_name(s),
             TargetType::MTDName(s) => mtd::target_device_from_mtd_name(s),
+            TargetType::PartLabel(s) => partition::target_device_from_partlabel(s),
+            TargetType::PartUUID(s) => partition::target_device_from_partuuid(s),
+            TargetType::FsLabel(s) => partition::target_device_from_fslabel(s),
+            TargetType::GptPartitionName(s) => partition::target_device_from_gpt_partition_name(s),
+        }
+    }
+
+    fn get_block_device(&self) -> Result<BlockDevice> {
+        match self {
+            TargetType::ImageFile(p) => BlockDevice::from_image_file(p),
+            _ => Ok(BlockDevice::from_device(self.get_target()?)),
+        }
+    }
+
+    fn is_device(&self) -> bool {
+        match self {
+            TargetType::Device(_)
+            | TargetType::PartLabel(_)
+            | TargetType::PartUUID(_)
+            | TargetType::FsLabel(_)
+            | TargetType::GptPartitionName(_) => true,
+            TargetType::UBIVolume(_) | TargetType::MTDName(_) | TargetType::ImageFile(_) => false,
         }
     }
 }
@@ -0,0 +1,688 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use bzip2::read::BzDecoder;
+use std::{
+    cmp,
+    convert::TryInto,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+const BSDIFF_MAGIC: &[u8] = b"BSDIFF40";
+const BSDIFF_HEADER_SIZE: usize = 32;
+
+// From https://tools.ietf.org/html/rfc3284
+const VCDIFF_MAGIC: [u8; 4] = [0xd6, 0xc3, 0xc4, 0x00];
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+const VCD_NEAR_SIZE: usize = 4;
+const VCD_SAME_SIZE: usize = 3;
+
+const NOOP: u8 = 0;
+const ADD: u8 = 1;
+const RUN: u8 = 2;
+const COPY: u8 = 3;
+
+/// Applies a `bsdiff` patch to the first `source_size` bytes of `source`,
+/// streaming the result into `output`.
+pub(crate) fn bspatch<S, W>(
+    source: &mut S,
+    source_size: u64,
+    patch: &[u8],
+    output: &mut W,
+) -> Result<()>
+where
+    S: Read + Seek,
+    W: Write,
+{
+    if patch.len() < BSDIFF_HEADER_SIZE || &patch[..BSDIFF_MAGIC.len()] != BSDIFF_MAGIC {
+        return Err(invalid_delta("bad bsdiff header"));
+    }
+
+    let ctrl_len = offtin(&patch[8..16]);
+    let diff_len = offtin(&patch[16..24]);
+    let new_size = offtin(&patch[24..32]);
+    if ctrl_len < 0
+        || diff_len < 0
+        || new_size < 0
+        || (BSDIFF_HEADER_SIZE as i64)
+            .checked_add(ctrl_len)
+            .and_then(|len| len.checked_add(diff_len))
+            .filter(|len| *len <= patch.len() as i64)
+            .is_none()
+    {
+        return Err(invalid_delta("bad bsdiff header"));
+    }
+
+    let (ctrl, rest) = patch[BSDIFF_HEADER_SIZE..].split_at(ctrl_len as usize);
+    let (diff, extra) = rest.split_at(diff_len as usize);
+    let mut ctrl = BzDecoder::new(ctrl);
+    let mut diff = BzDecoder::new(diff);
+    let mut extra = BzDecoder::new(extra);
+
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut old = vec![0; BUFFER_SIZE];
+    let mut old_pos = 0i64;
+    let mut new_pos = 0i64;
+    while new_pos < new_size {
+        let mut entry = [0; 24];
+        ctrl.read_exact(&mut entry)?;
+        let add_len = offtin(&entry[..8]);
+        let copy_len = offtin(&entry[8..16]);
+        let seek_len = offtin(&entry[16..]);
+        let next_pos = new_pos
+            .checked_add(add_len)
+            .and_then(|pos| pos.checked_add(copy_len))
+            .filter(|pos| add_len >= 0 && copy_len >= 0 && *pos <= new_size)
+            .ok_or_else(|| invalid_delta("corrupted bsdiff control block"))?;
+
+        // The diff block holds the byte-wise difference against the source,
+        // bytes outside of the source range are taken as they are.
+        let mut remaining = add_len;
+        while remaining > 0 {
+            let len = cmp::min(remaining, BUFFER_SIZE as i64);
+            diff.read_exact(&mut buf[..len as usize])?;
+
+            let start = cmp::max(old_pos, 0);
+            let end = cmp::min(old_pos.saturating_add(len), source_size as i64);
+            if start < end {
+                let old = &mut old[..(end - start) as usize];
+                source.seek(SeekFrom::Start(start as u64))?;
+                source.read_exact(old)?;
+
+                let offset = (start - old_pos) as usize;
+                for (b, o) in buf[offset..].iter_mut().zip(old.iter()) {
+                    *b = b.wrapping_add(*o);
+                }
+            }
+
+            output.write_all(&buf[..len as usize])?;
+            old_pos = old_pos.saturating_add(len);
+            remaining -= len;
+        }
+
+        io::copy(&mut (&mut extra).take(copy_len as u64), output).and_then(|n| {
+            if n == copy_len as u64 {
+                Ok(())
+            } else {
+                Err(io::ErrorKind::UnexpectedEof.into())
+            }
+        })?;
+
+        new_pos = next_pos;
+        old_pos = old_pos
+            .checked_add(seek_len)
+            .ok_or_else(|| invalid_delta("corrupted bsdiff control block"))?;
+    }
+
+    Ok(())
+}
+
+/// Applies a VCDIFF (RFC 3284) patch of `patch_len` bytes to the first
+/// `source_size` bytes of `source`, streaming the decoded windows into
+/// `output`. The lengths read from the patch are bounded by what is left of
+/// it and by the `result_size`, so a malformed patch can't exhaust memory.
+///
+/// Secondary compression, custom code tables and windows referencing the
+/// target itself are not supported.
+pub(crate) fn vcdiff_patch<S, P, W>(
+    source: &mut S,
+    source_size: u64,
+    patch: P,
+    patch_len: u64,
+    result_size: u64,
+    output: &mut W,
+) -> Result<()>
+where
+    S: Read + Seek,
+    P: Read,
+    W: Write,
+{
+    let mut patch = patch.take(patch_len);
+    let mut magic = [0; 4];
+    patch.read_exact(&mut magic)?;
+    if magic != VCDIFF_MAGIC {
+        return Err(invalid_delta("bad vcdiff header"));
+    }
+
+    let header_indicator = read_byte(&mut patch)?;
+    if header_indicator & (VCD_DECOMPRESS | VCD_CODETABLE) != 0 {
+        return Err(invalid_delta("unsupported vcdiff secondary compression or code table"));
+    }
+    if header_indicator & VCD_APPHEADER != 0 {
+        let len = read_int(&mut patch)?;
+        io::copy(&mut (&mut patch).take(len), &mut io::sink())?;
+    }
+
+    let code_table = default_code_table();
+    let mut written = 0u64;
+    loop {
+        let mut window_indicator = [0];
+        if patch.read(&mut window_indicator)? == 0 {
+            break;
+        }
+        let window_indicator = window_indicator[0];
+        if window_indicator & VCD_TARGET != 0 {
+            return Err(invalid_delta("unsupported vcdiff target window"));
+        }
+
+        let mut segment = Segment { source: &mut *source, pos: 0, len: 0, position: None };
+        if window_indicator & VCD_SOURCE != 0 {
+            segment.len = read_int(&mut patch)?;
+            segment.pos = read_int(&mut patch)?;
+            if segment.pos.checked_add(segment.len).filter(|end| *end <= source_size).is_none() {
+                return Err(invalid_delta("vcdiff source segment out of source range"));
+            }
+        }
+
+        let _delta_len = read_int(&mut patch)?;
+        let target_len = read_int(&mut patch)?;
+        written = written
+            .checked_add(target_len)
+            .filter(|written| *written <= result_size)
+            .ok_or_else(|| invalid_delta("vcdiff window beyond the result size"))?;
+        let target_len =
+            target_len.try_into().map_err(|_| invalid_delta("vcdiff window is too big"))?;
+        if read_byte(&mut patch)? != 0 {
+            return Err(invalid_delta("unsupported vcdiff secondary compression"));
+        }
+        let mut section = || -> Result<Vec<u8>> {
+            match read_int(&mut patch)? {
+                len if len <= patch.limit() => Ok(vec![0; len as usize]),
+                _ => Err(invalid_delta("vcdiff section beyond the patch")),
+            }
+        };
+        let (mut data, mut inst, mut addr) = (section()?, section()?, section()?);
+        if window_indicator & VCD_ADLER32 != 0 {
+            read_byte(&mut patch)?;
+            read_byte(&mut patch)?;
+            read_byte(&mut patch)?;
+            read_byte(&mut patch)?;
+        }
+        patch.read_exact(&mut data)?;
+        patch.read_exact(&mut inst)?;
+        patch.read_exact(&mut addr)?;
+
+        let target = decode_window(
+            &code_table,
+            &mut segment,
+            target_len,
+            &mut data.as_slice(),
+            &mut inst.as_slice(),
+            &mut addr.as_slice(),
+        )?;
+        output.write_all(&target)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Default)]
+struct Instruction {
+    kind: u8,
+    size: u8,
+    mode: u8,
+}
+
+fn default_code_table() -> Vec<[Instruction; 2]> {
+    let noop = Instruction::default();
+    let inst = |kind, size, mode| Instruction { kind, size, mode };
+    let mut table = Vec::with_capacity(256);
+
+    table.push([inst(RUN, 0, 0), noop]);
+    for size in 0..18 {
+        table.push([inst(ADD, size, 0), noop]);
+    }
+    for mode in 0..9 {
+        table.push([inst(COPY, 0, mode), noop]);
+        for size in 4..19 {
+            table.push([inst(COPY, size, mode), noop]);
+        }
+    }
+    for mode in 0..6 {
+        for add in 1..5 {
+            for copy in 4..7 {
+                table.push([inst(ADD, add, 0), inst(COPY, copy, mode)]);
+            }
+        }
+    }
+    for mode in 6..9 {
+        for add in 1..5 {
+            table.push([inst(ADD, add, 0), inst(COPY, 4, mode)]);
+        }
+    }
+    for mode in 0..9 {
+        table.push([inst(COPY, 4, mode), inst(ADD, 1, 0)]);
+    }
+
+    table
+}
+
+/// Part of the source a window copies from, read as it is copied so the
+/// segment, which may be as big as the source, is not held in memory.
+struct Segment<'a, S> {
+    source: &'a mut S,
+    pos: u64,
+    len: u64,
+    /// Position the source is at, to avoid seeking on sequential copies.
+    position: Option<u64>,
+}
+
+impl<S: Read + Seek> Segment<'_, S> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let pos = self.pos + offset;
+        if self.position != Some(pos) {
+            self.source.seek(SeekFrom::Start(pos))?;
+        }
+        self.position = None;
+        self.source.read_exact(buf)?;
+        self.position = Some(pos + buf.len() as u64);
+        Ok(())
+    }
+}
+
+struct AddressCache {
+    near: [u64; VCD_NEAR_SIZE],
+    next_slot: usize,
+    same: [u64; VCD_SAME_SIZE * 256],
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        AddressCache { near: [0; VCD_NEAR_SIZE], next_slot: 0, same: [0; VCD_SAME_SIZE * 256] }
+    }
+
+    fn decode(&mut self, here: u64, mode: u8, addr: &mut &[u8]) -> Result<u64> {
+        let mode = mode as usize;
+        let address = match mode {
+            0 => read_int(addr)?,
+            1 => here
+                .checked_sub(read_int(addr)?)
+                .ok_or_else(|| invalid_delta("bad vcdiff address"))?,
+            m if m < 2 + VCD_NEAR_SIZE => self.near[m - 2]
+                .checked_add(read_int(addr)?)
+                .ok_or_else(|| invalid_delta("bad vcdiff address"))?,
+            m if m < 2 + VCD_NEAR_SIZE + VCD_SAME_SIZE => {
+                self.same[(m - 2 - VCD_NEAR_SIZE) * 256 + read_byte(addr)? as usize]
+            }
+            _ => return Err(invalid_delta("bad vcdiff address mode")),
+        };
+
+        self.near[self.next_slot] = address;
+        self.next_slot = (self.next_slot + 1) % VCD_NEAR_SIZE;
+        self.same[address as usize % (VCD_SAME_SIZE * 256)] = address;
+
+        Ok(address)
+    }
+}
+
+fn decode_window<S: Read + Seek>(
+    code_table: &[[Instruction; 2]],
+    segment: &mut Segment<S>,
+    target_len: usize,
+    data: &mut &[u8],
+    inst: &mut &[u8],
+    addr: &mut &[u8],
+) -> Result<Vec<u8>> {
+    let mut target = Vec::with_capacity(target_len);
+    let mut cache = AddressCache::new();
+
+    while !inst.is_empty() {
+        for instruction in &code_table[read_byte(inst)? as usize] {
+            if instruction.kind == NOOP {
+                continue;
+            }
+
+            let size = match instruction.size {
+                0 => read_int(inst)?,
+                s => u64::from(s),
+            };
+            if size > (target_len - target.len()) as u64 {
+                return Err(invalid_delta("vcdiff window is bigger than declared"));
+            }
+            let size = size as usize;
+
+            match instruction.kind {
+                ADD => {
+                    let (bytes, rest) = split(data, size)?;
+                    target.extend_from_slice(bytes);
+                    *data = rest;
+                }
+                RUN => {
+                    let byte = read_byte(data)?;
+                    target.resize(target.len() + size, byte);
+                }
+                _ => {
+                    let here = segment.len + target.len() as u64;
+                    let address = cache.decode(here, instruction.mode, addr)?;
+                    if address >= here {
+                        return Err(invalid_delta("vcdiff copy from the future"));
+                    }
+
+                    let from_segment = cmp::min(segment.len.saturating_sub(address), size as u64);
+                    if from_segment > 0 {
+                        let start = target.len();
+                        target.resize(start + from_segment as usize, 0);
+                        segment.read_at(address, &mut target[start..])?;
+                    }
+                    if from_segment < size as u64 {
+                        // Copies may overlap the data being produced, so they
+                        // need to go byte by byte.
+                        let start = (address + from_segment - segment.len) as usize;
+                        for pos in start..start + size - from_segment as usize {
+                            let byte = target[pos];
+                            target.push(byte);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if target.len() != target_len {
+        return Err(invalid_delta("vcdiff window is smaller than declared"));
+    }
+
+    Ok(target)
+}
+
+fn split(data: &[u8], size: usize) -> Result<(&[u8], &[u8])> {
+    if size > data.len() {
+        return Err(invalid_delta("vcdiff data section is too short"));
+    }
+    Ok(data.split_at(size))
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_int<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut value = 0u64;
+    loop {
+        let byte = read_byte(reader)?;
+        value = value
+            .checked_mul(128)
+            .map(|v| v | u64::from(byte & 0x7f))
+            .ok_or_else(|| invalid_delta("vcdiff integer overflow"))?;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn offtin(buf: &[u8]) -> i64 {
+    let value = i64::from_le_bytes(buf.try_into().unwrap()) & i64::MAX;
+    if buf[7] & 0x80 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+fn invalid_delta(reason: &str) -> Error {
+    Error::InvalidDelta(reason.to_owned())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use bzip2::{write::BzEncoder, Compression};
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn bzip2(data: &[u8]) -> Vec<u8> {
+        let mut e = BzEncoder::new(Vec::default(), Compression::Default);
+        e.write_all(data).unwrap();
+        e.finish().unwrap()
+    }
+
+    fn offtout(value: i64) -> [u8; 8] {
+        let mut buf = value.abs().to_le_bytes();
+        if value < 0 {
+            buf[7] |= 0x80;
+        }
+        buf
+    }
+
+    /// Builds a `bsdiff` patch from (add, copy, seek) control entries
+    pub(crate) fn fake_bsdiff(
+        source: &[u8],
+        result: &[u8],
+        controls: &[(usize, usize, i64)],
+    ) -> Vec<u8> {
+        let (mut ctrl, mut diff, mut extra) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut old_pos, mut new_pos) = (0i64, 0);
+        for (add, copy, seek) in controls {
+            ctrl.extend_from_slice(&offtout(*add as i64));
+            ctrl.extend_from_slice(&offtout(*copy as i64));
+            ctrl.extend_from_slice(&offtout(*seek));
+
+            for i in 0..*add {
+                let old = source.get((old_pos + i as i64) as usize).copied().unwrap_or(0);
+                diff.push(result[new_pos + i].wrapping_sub(old));
+            }
+            extra.extend_from_slice(&result[new_pos + add..new_pos + add + copy]);
+
+            new_pos += add + copy;
+            old_pos += *add as i64 + seek;
+        }
+
+        let (ctrl, diff, extra) = (bzip2(&ctrl), bzip2(&diff), bzip2(&extra));
+        let mut patch = BSDIFF_MAGIC.to_vec();
+        patch.extend_from_slice(&offtout(ctrl.len() as i64));
+        patch.extend_from_slice(&offtout(diff.len() as i64));
+        patch.extend_from_slice(&offtout(result.len() as i64));
+        patch.extend(ctrl);
+        patch.extend(diff);
+        patch.extend(extra);
+        patch
+    }
+
+    fn write_int(buf: &mut Vec<u8>, mut value: u64) {
+        let mut bytes = vec![(value & 0x7f) as u8];
+        value >>= 7;
+        while value != 0 {
+            bytes.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        buf.extend(bytes.iter().rev());
+    }
+
+    /// Builds a single window VCDIFF patch, copying the whole `source`
+    /// followed by a run of `run_len` bytes and the bytes in `add`.
+    pub(crate) fn fake_vcdiff(source: &[u8], run_len: usize, run_byte: u8, add: &[u8]) -> Vec<u8> {
+        let (mut data, mut inst, mut addr) = (Vec::new(), Vec::new(), Vec::new());
+
+        // COPY size 0 (explicit size), mode VCD_SELF
+        inst.push(19);
+        write_int(&mut inst, source.len() as u64);
+        write_int(&mut addr, 0);
+        // RUN size 0 (explicit size)
+        inst.push(0);
+        write_int(&mut inst, run_len as u64);
+        data.push(run_byte);
+        // ADD size 0 (explicit size)
+        inst.push(1);
+        write_int(&mut inst, add.len() as u64);
+        data.extend_from_slice(add);
+
+        let mut encoding = Vec::new();
+        write_int(&mut encoding, (source.len() + run_len + add.len()) as u64);
+        encoding.push(0);
+        write_int(&mut encoding, data.len() as u64);
+        write_int(&mut encoding, inst.len() as u64);
+        write_int(&mut encoding, addr.len() as u64);
+        encoding.extend(data);
+        encoding.extend(inst);
+        encoding.extend(addr);
+
+        let mut patch = VCDIFF_MAGIC.to_vec();
+        patch.push(0);
+        patch.push(VCD_SOURCE);
+        write_int(&mut patch, source.len() as u64);
+        write_int(&mut patch, 0);
+        write_int(&mut patch, encoding.len() as u64);
+        patch.extend(encoding);
+        patch
+    }
+
+    #[test]
+    fn default_code_table_layout() {
+        let table = default_code_table();
+        assert_eq!(table.len(), 256);
+        assert_eq!((table[19][0].kind, table[19][0].size, table[19][0].mode), (COPY, 0, 0));
+        assert_eq!((table[162][0].kind, table[162][0].size, table[162][0].mode), (COPY, 18, 8));
+        assert_eq!((table[163][1].kind, table[163][1].size), (COPY, 4));
+        assert_eq!((table[255][0].mode, table[255][1].kind), (8, ADD));
+    }
+
+    #[test]
+    fn bspatch_applies_patch() {
+        let source = (0..200u8).collect::<Vec<_>>();
+        let mut result = source[..100].to_vec();
+        result[10] = 0xff;
+        result.extend_from_slice(b"XY");
+        result.extend_from_slice(&source[..50]);
+
+        let patch = fake_bsdiff(&source, &result, &[(100, 2, -100), (50, 0, 0)]);
+        let mut output = Vec::default();
+        bspatch(&mut Cursor::new(&source), source.len() as u64, &patch, &mut output).unwrap();
+        assert_eq!(output, result);
+    }
+
+    #[test]
+    fn bspatch_reads_beyond_source() {
+        let source = b"0123456789".to_vec();
+        let result = b"0123456789abcdefABCDEF".to_vec();
+
+        let patch = fake_bsdiff(&source, &result, &[(16, 6, 0)]);
+        let mut output = Vec::default();
+        bspatch(&mut Cursor::new(&source), source.len() as u64, &patch, &mut output).unwrap();
+        assert_eq!(output, result);
+    }
+
+    #[test]
+    fn bspatch_invalid_patch() {
+        let source = b"0123456789".to_vec();
+        let mut output = Vec::default();
+
+        let patch = fake_bsdiff(&source, &source, &[(5, 5, 0)]);
+        assert!(bspatch(&mut Cursor::new(&source), 10, &patch[..20], &mut output).is_err());
+
+        // Drop the extra block
+        let len = BSDIFF_HEADER_SIZE as i64 + offtin(&patch[8..16]) + offtin(&patch[16..24]);
+        assert!(
+            bspatch(&mut Cursor::new(&source), 10, &patch[..len as usize], &mut output).is_err()
+        );
+    }
+
+    #[test]
+    fn vcdiff_applies_patch() {
+        let source = (0..200u8).collect::<Vec<_>>();
+        let patch = fake_vcdiff(&source, 50, 0xAA, b"appended");
+
+        let mut expected = source.clone();
+        expected.extend(std::iter::repeat(0xAA).take(50));
+        expected.extend_from_slice(b"appended");
+
+        let mut output = Vec::default();
+        vcdiff_patch(
+            &mut Cursor::new(&source),
+            source.len() as u64,
+            &patch[..],
+            patch.len() as u64,
+            expected.len() as u64,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn vcdiff_overlapping_copy() {
+        // ADD 'ab' followed by a COPY of 6 bytes from target address 0
+        let mut patch = VCDIFF_MAGIC.to_vec();
+        patch.extend_from_slice(&[0, 0, 11, 8, 0, 2, 3, 1, b'a', b'b', 3, 19, 6, 0]);
+
+        let mut output = Vec::default();
+        vcdiff_patch(
+            &mut Cursor::new(Vec::new()),
+            0,
+            &patch[..],
+            patch.len() as u64,
+            8,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(output, b"abababab");
+    }
+
+    #[test]
+    fn vcdiff_invalid_patch() {
+        let source = (0..200u8).collect::<Vec<_>>();
+        let patch = fake_vcdiff(&source, 50, 0xAA, b"appended");
+        let patch_with_len = |patch: &[u8], source_size: u64, result_size: u64| {
+            let mut output = Vec::default();
+            vcdiff_patch(
+                &mut Cursor::new(&source),
+                source_size,
+                patch,
+                patch.len() as u64,
+                result_size,
+                &mut output,
+            )
+        };
+
+        assert!(patch_with_len(&patch, 200, 258).is_ok());
+        assert!(patch_with_len(&patch, 100, 258).is_err());
+        assert!(patch_with_len(&patch[1..], 200, 258).is_err());
+        assert!(patch_with_len(&patch[..patch.len() - 1], 200, 258).is_err());
+        match patch_with_len(&patch, 200, 257) {
+            Err(Error::InvalidDelta(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // A window declaring huge sections is refused before these are read
+        let mut patch = VCDIFF_MAGIC.to_vec();
+        patch.extend_from_slice(&[0, 0, 11, 8, 0, 0xff, 0xff, 0xff, 0xff, 0x7f, 0, 0]);
+        match patch_with_len(&patch, 0, 8) {
+            Err(Error::InvalidDelta(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn bspatch_overflowing_lengths() {
+        let source = b"0123456789".to_vec();
+        let mut output = Vec::default();
+
+        let mut patch = fake_bsdiff(&source, &source, &[(5, 5, 0)]);
+        patch[8..16].copy_from_slice(&offtout(i64::MAX));
+        match bspatch(&mut Cursor::new(&source), 10, &patch, &mut output) {
+            Err(Error::InvalidDelta(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let ctrl = bzip2(&[offtout(i64::MAX), offtout(i64::MAX), offtout(0)].concat());
+        let mut patch = BSDIFF_MAGIC.to_vec();
+        patch.extend_from_slice(&offtout(ctrl.len() as i64));
+        patch.extend_from_slice(&offtout(0));
+        patch.extend_from_slice(&offtout(10));
+        patch.extend(ctrl);
+        match bspatch(&mut Cursor::new(&source), 10, &patch, &mut output) {
+            Err(Error::InvalidDelta(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use slog_scope::{error, info};
+use std::{
+    fs,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+const SYSFS_BLOCK: &str = "/sys/block";
+
+// From https://github.com/torvalds/linux/blob/master/include/linux/mmc/mmc.h
+const EXT_CSD_PART_CONFIG: usize = 179;
+const EXT_CSD_PART_CONFIG_BOOT_MASK: u8 = 0x38;
+const EXT_CSD_PART_CONFIG_BOOT_SHIFT: u8 = 3;
+
+/// Hardware boot partition of an eMMC, as `/dev/mmcblk0boot1`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BootPartition {
+    /// The eMMC itself, as `/dev/mmcblk0`.
+    pub(crate) device: PathBuf,
+    pub(crate) index: u8,
+}
+
+impl BootPartition {
+    /// Parses the target of an object, which is either a boot partition or
+    /// the eMMC, in which case the boot partition the eMMC doesn't boot
+    /// from is returned.
+    pub(crate) fn from_target(target: &Path) -> Result<Self> {
+        let invalid = || Error::InvalidEmmcTarget(target.to_path_buf());
+        let name = target.file_name().and_then(|n| n.to_str()).ok_or_else(invalid)?;
+        let index = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+
+        match name.strip_prefix("mmcblk").and_then(|n| n.find("boot").map(|i| (n, i))) {
+            Some((n, i)) if index(&n[..i]) => {
+                let index = match &n[i + 4..] {
+                    "0" => 0,
+                    "1" => 1,
+                    _ => return Err(invalid()),
+                };
+                let device = target.with_file_name(&name[..name.len() - "boot0".len()]);
+                Ok(BootPartition { device, index })
+            }
+            Some(_) => Err(invalid()),
+            None if name.strip_prefix("mmcblk").map(index).unwrap_or_default() => {
+                let index = match boot_partition_enabled(read_ext_csd(target)?[EXT_CSD_PART_CONFIG])
+                {
+                    Some(0) => 1,
+                    _ => 0,
+                };
+                Ok(BootPartition { device: target.to_path_buf(), index })
+            }
+            None => Err(invalid()),
+        }
+    }
+
+    pub(crate) fn path(&self) -> PathBuf {
+        let mut path = self.device.clone().into_os_string();
+        path.push(format!("boot{}", self.index));
+        PathBuf::from(path)
+    }
+
+    /// Makes the eMMC boot from this partition.
+    pub(crate) fn enable(&self) -> Result<()> {
+        info!("enabling boot from {}", self.path().display());
+        let config = read_ext_csd(&self.device)?[EXT_CSD_PART_CONFIG];
+        write_ext_csd(
+            &self.device,
+            EXT_CSD_PART_CONFIG,
+            enabling_boot_partition(config, self.index),
+        )
+    }
+}
+
+/// Allows the boot partition to be written while it is held. The kernel
+/// sets boot partitions as read-only, through their `force_ro` attribute,
+/// to keep them from being written by accident.
+pub(crate) struct WriteGuard {
+    force_ro: PathBuf,
+    previous: String,
+}
+
+impl WriteGuard {
+    pub(crate) fn new(partition: &BootPartition) -> Result<Self> {
+        Self::with_sysfs(Path::new(SYSFS_BLOCK), partition)
+    }
+
+    pub(crate) fn with_sysfs(sysfs: &Path, partition: &BootPartition) -> Result<Self> {
+        let name = partition.path().file_name().map(ToOwned::to_owned).unwrap_or_default();
+        let force_ro = sysfs.join(name).join("force_ro");
+        let previous = fs::read_to_string(&force_ro)?.trim_end().to_string();
+        fs::write(&force_ro, "0")?;
+
+        Ok(WriteGuard { force_ro, previous })
+    }
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.force_ro, &self.previous) {
+            error!("failed to restore {}: {}", self.force_ro.display(), e);
+        }
+    }
+}
+
+/// Boot partition enabled in the `PARTITION_CONFIG` register, if the eMMC
+/// boots from one of them.
+fn boot_partition_enabled(config: u8) -> Option<u8> {
+    match (config & EXT_CSD_PART_CONFIG_BOOT_MASK) >> EXT_CSD_PART_CONFIG_BOOT_SHIFT {
+        1 => Some(0),
+        2 => Some(1),
+        _ => None,
+    }
+}
+
+/// `PARTITION_CONFIG` register enabling the boot partition, keeping the
+/// boot acknowledge and partition access bits.
+fn enabling_boot_partition(config: u8, index: u8) -> u8 {
+    (config & !EXT_CSD_PART_CONFIG_BOOT_MASK) | ((index + 1) << EXT_CSD_PART_CONFIG_BOOT_SHIFT)
+}
+
+fn read_ext_csd(device: &Path) -> Result<[u8; 512]> {
+    let device = fs::OpenOptions::new().read(true).write(true).open(device)?;
+    let mut ext_csd = [0; 512];
+    let mut cmd = ffi::mmc_ioc_cmd {
+        opcode: ffi::MMC_SEND_EXT_CSD,
+        flags: ffi::MMC_RSP_SPI_R1 | ffi::MMC_RSP_R1 | ffi::MMC_CMD_ADTC,
+        blksz: ext_csd.len() as u32,
+        blocks: 1,
+        data_ptr: ext_csd.as_mut_ptr() as u64,
+        ..ffi::mmc_ioc_cmd::default()
+    };
+    unsafe { ffi::mmc_ioc_cmd(device.as_raw_fd(), &mut cmd)? };
+
+    Ok(ext_csd)
+}
+
+fn write_ext_csd(device: &Path, index: usize, value: u8) -> Result<()> {
+    let device = fs::OpenOptions::new().read(true).write(true).open(device)?;
+    let mut cmd = ffi::mmc_ioc_cmd {
+        write_flag: 1,
+        opcode: ffi::MMC_SWITCH,
+        arg: (ffi::MMC_SWITCH_MODE_WRITE_BYTE << 24)
+            | ((index as u32) << 16)
+            | (u32::from(value) << 8)
+            | ffi::EXT_CSD_CMD_SET_NORMAL,
+        flags: ffi::MMC_RSP_SPI_R1B | ffi::MMC_RSP_R1B | ffi::MMC_CMD_AC,
+        ..ffi::mmc_ioc_cmd::default()
+    };
+    unsafe { ffi::mmc_ioc_cmd(device.as_raw_fd(), &mut cmd)? };
+
+    Ok(())
+}
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use nix::ioctl_readwrite;
+
+    // From https://github.com/torvalds/linux/blob/master/include/uapi/linux/mmc/ioctl.h
+    // and https://github.com/torvalds/linux/blob/master/include/linux/mmc/core.h
+    const MMC_BLOCK_MAJOR: u8 = 179;
+    const MMC_IOC_CMD: u8 = 0;
+
+    pub const MMC_SWITCH: u32 = 6;
+    pub const MMC_SEND_EXT_CSD: u32 = 8;
+    pub const MMC_SWITCH_MODE_WRITE_BYTE: u32 = 0x03;
+    pub const EXT_CSD_CMD_SET_NORMAL: u32 = 1;
+
+    const MMC_RSP_PRESENT: u32 = 1 << 0;
+    const MMC_RSP_CRC: u32 = 1 << 2;
+    const MMC_RSP_BUSY: u32 = 1 << 3;
+    const MMC_RSP_OPCODE: u32 = 1 << 4;
+    const MMC_RSP_SPI_S1: u32 = 1 << 7;
+    const MMC_RSP_SPI_BUSY: u32 = 1 << 10;
+    pub const MMC_CMD_AC: u32 = 0;
+    pub const MMC_CMD_ADTC: u32 = 1 << 5;
+    pub const MMC_RSP_R1: u32 = MMC_RSP_PRESENT | MMC_RSP_CRC | MMC_RSP_OPCODE;
+    pub const MMC_RSP_R1B: u32 = MMC_RSP_R1 | MMC_RSP_BUSY;
+    pub const MMC_RSP_SPI_R1: u32 = MMC_RSP_SPI_S1;
+    pub const MMC_RSP_SPI_R1B: u32 = MMC_RSP_SPI_S1 | MMC_RSP_SPI_BUSY;
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct mmc_ioc_cmd {
+        pub write_flag: i32,
+        pub is_acmd: i32,
+        pub opcode: u32,
+        pub arg: u32,
+        pub response: [u32; 4],
+        pub flags: u32,
+        pub blksz: u32,
+        pub blocks: u32,
+        pub postsleep_min_us: u32,
+        pub postsleep_max_us: u32,
+        pub data_timeout_ns: u32,
+        pub cmd_timeout_ms: u32,
+        pub pad: u32,
+        pub data_ptr: u64,
+    }
+
+    ioctl_readwrite!(mmc_ioc_cmd, MMC_BLOCK_MAJOR, MMC_IOC_CMD, mmc_ioc_cmd);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn boot_partition_targets() {
+        let partition = BootPartition::from_target(Path::new("/dev/mmcblk10boot1")).unwrap();
+        assert_eq!(partition, BootPartition { device: PathBuf::from("/dev/mmcblk10"), index: 1 });
+        assert_eq!(partition.path(), PathBuf::from("/dev/mmcblk10boot1"));
+
+        for invalid in &["/dev/mmcblk0boot2", "/dev/mmcblkboot0", "/dev/mmcblk0p1", "/dev/sda"] {
+            assert!(BootPartition::from_target(Path::new(invalid)).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn partition_config() {
+        assert_eq!(boot_partition_enabled(0b0000_1000), Some(0));
+        assert_eq!(boot_partition_enabled(0b0101_0001), Some(1));
+        assert_eq!(boot_partition_enabled(0b0011_1000), None);
+        assert_eq!(boot_partition_enabled(0), None);
+
+        assert_eq!(enabling_boot_partition(0b0100_1000, 1), 0b0101_0000);
+        assert_eq!(enabling_boot_partition(0b0011_1001, 0), 0b0000_1001);
+    }
+
+    #[test]
+    fn write_guard() {
+        let sysfs = tempfile::tempdir().unwrap();
+        let force_ro = sysfs.path().join("mmcblk0boot1").join("force_ro");
+        fs::create_dir(force_ro.parent().unwrap()).unwrap();
+        fs::write(&force_ro, "1\n").unwrap();
+        let partition = BootPartition { device: PathBuf::from("/dev/mmcblk0"), index: 1 };
+
+        {
+            let _guard = WriteGuard::with_sysfs(sysfs.path(), &partition).unwrap();
+            assert_eq!(fs::read_to_string(&force_ro).unwrap(), "0");
+        }
+        assert_eq!(fs::read_to_string(&force_ro).unwrap(), "1");
+    }
+}
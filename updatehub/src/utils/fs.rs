@@ -6,23 +6,84 @@ use super::{Error, Result};
 use crate::utils::definitions::IdExt;
 use pkg_schema::definitions::{
     target_permissions::{Gid, Uid},
-    Filesystem,
+    Acl, AclTag, Filesystem, TargetAttributes,
+};
+use slog_scope::error;
+use std::{
+    fs, io,
+    ops::Range,
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
 };
-use std::{io, path::Path};
 use sys_mount::{Mount, Unmount, UnmountDrop};
 
-pub(crate) fn ensure_disk_space(target: &Path, required: u64) -> Result<()> {
-    let stat = nix::sys::statvfs::statvfs(target)?;
+/// Device holding a filesystem to be formatted or mounted. Image files are
+/// attached to a loop device, which is detached when this is dropped.
+pub(crate) struct BlockDevice {
+    path: PathBuf,
+    loop_device: Option<loopdev::LoopDevice>,
+}
 
-    // stat fields might be 32 or 64 bytes depending on host arch
-    let available = stat.block_size() as u64 * stat.blocks_free() as u64;
+impl BlockDevice {
+    pub(crate) fn from_device(path: PathBuf) -> Self {
+        BlockDevice { path, loop_device: None }
+    }
 
-    if required > available {
+    pub(crate) fn from_image_file(image: &Path) -> Result<Self> {
+        let control = loopdev::LoopControl::open()?;
+
+        // Another process might take the free device before we attach to it
+        let mut attempts = 3;
+        loop {
+            let loop_device = control.next_free()?;
+            match loop_device.attach_file(image) {
+                Ok(()) => {
+                    let path = loop_device.path().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "loop device has no path")
+                    })?;
+                    return Ok(BlockDevice { path, loop_device: Some(loop_device) });
+                }
+                Err(e) if e.raw_os_error() == Some(nix::libc::EBUSY) && attempts > 1 => {
+                    attempts -= 1
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for BlockDevice {
+    fn drop(&mut self) {
+        if let Some(loop_device) = &self.loop_device {
+            if let Err(e) = loop_device.detach() {
+                error!("failed to detach loop device {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+pub(crate) fn ensure_disk_space(target: &Path, required: u64) -> Result<()> {
+    if required > available_space(target)? {
         return Err(Error::NotEnoughSpace);
     }
     Ok(())
 }
 
+/// Space left on the filesystem holding the target.
+pub(crate) fn available_space(target: &Path) -> Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(target)?;
+
+    // stat fields might be 32 or 64 bytes depending on host arch
+    Ok(stat.block_size() as u64 * stat.blocks_free() as u64)
+}
+
 pub(crate) fn is_executable_in_path(cmd: &str) -> Result<()> {
     match quale::which(cmd) {
         Some(_) => Ok(()),
@@ -61,7 +122,28 @@ where
     // closure is run.
     let _guard = mount(source, &tmpdir, fs, options)?;
 
-    Ok(f(tmpdir))
+    let res = f(tmpdir);
+
+    // Unmounting is lazy, so the filesystem is synced while still mounted
+    let dir = std::fs::File::open(tmpdir)?;
+    nix::errno::Errno::result(unsafe {
+        nix::libc::syscall(nix::libc::SYS_syncfs, dir.as_raw_fd())
+    })?;
+
+    Ok(res)
+}
+
+/// Flushes the data written to the target down to the storage. Block
+/// devices also have their buffers dropped, so the data is read back from
+/// the storage.
+pub(crate) fn sync<F: AsRawFd>(target: &F) -> Result<()> {
+    let fd = target.as_raw_fd();
+    nix::unistd::fsync(fd)?;
+    if nix::sys::stat::fstat(fd)?.st_mode & nix::libc::S_IFMT == nix::libc::S_IFBLK {
+        unsafe { ffi::blk_flsbuf(fd)? };
+    }
+
+    Ok(())
 }
 
 pub(crate) fn mount(
@@ -80,6 +162,38 @@ pub(crate) fn mount(
     .into_unmount_drop(sys_mount::UnmountFlags::DETACH))
 }
 
+/// Discards the byte ranges of the target, letting the storage reclaim
+/// them. Ranges are shrunk to whole 4 KiB blocks so no data around them is
+/// lost, and targets which are neither block devices nor regular files are
+/// left untouched.
+pub(crate) fn discard(target: &Path, ranges: &[Range<u64>]) -> Result<()> {
+    const ALIGNMENT: u64 = 4096;
+
+    let file = fs::OpenOptions::new().write(true).open(target)?;
+    let file_type = file.metadata()?.file_type();
+    for range in ranges {
+        let start = range.start + (ALIGNMENT - range.start % ALIGNMENT) % ALIGNMENT;
+        let end = range.end / ALIGNMENT * ALIGNMENT;
+        if start >= end {
+            continue;
+        }
+
+        if file_type.is_block_device() {
+            unsafe { ffi::blk_discard(file.as_raw_fd(), &[start, end - start])? };
+        } else if file_type.is_file() {
+            nix::fcntl::fallocate(
+                file.as_raw_fd(),
+                nix::fcntl::FallocateFlags::FALLOC_FL_PUNCH_HOLE
+                    | nix::fcntl::FallocateFlags::FALLOC_FL_KEEP_SIZE,
+                start as i64,
+                (end - start) as i64,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
 pub(crate) fn chmod(path: &Path, mode: u32) -> Result<()> {
     nix::sys::stat::fchmodat(
         None,
@@ -98,3 +212,115 @@ pub(crate) fn chown(path: &Path, uid: &Option<Uid>, gid: &Option<Gid>) -> Result
         gid.as_ref().map(|id| nix::unistd::Gid::from_raw(id.as_u32())),
     )?)
 }
+
+/// Changes the ownership of `path`, or of the symbolic link itself when it
+/// is one.
+pub(crate) fn lchown(path: &Path, uid: &Option<Uid>, gid: &Option<Gid>) -> Result<()> {
+    Ok(nix::unistd::fchownat(
+        None,
+        path,
+        uid.as_ref().map(|id| nix::unistd::Uid::from_raw(id.as_u32())),
+        gid.as_ref().map(|id| nix::unistd::Gid::from_raw(id.as_u32())),
+        nix::unistd::FchownatFlags::NoFollowSymlink,
+    )?)
+}
+
+/// Removes everything inside `dir`, keeping the directory itself as it
+/// might be a mount point.
+pub(crate) fn remove_dir_content(dir: &Path) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the permissions, ownership and extended attributes, which
+/// include the SELinux context and ACLs, of `from` over to `to` so it can
+/// take its place.
+pub(crate) fn copy_metadata(from: &Path, to: &Path) -> Result<()> {
+    let metadata = from.metadata()?;
+    // Changing the owner clears the set-user-ID and set-group-ID bits, so the
+    // permissions are copied last
+    nix::unistd::chown(
+        to,
+        Some(nix::unistd::Uid::from_raw(metadata.uid())),
+        Some(nix::unistd::Gid::from_raw(metadata.gid())),
+    )?;
+    fs::set_permissions(to, metadata.permissions())?;
+
+    let names = match xattr::list(from) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(nix::libc::ENOTSUP) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for name in names {
+        if let Some(value) = xattr::get(from, &name)? {
+            xattr::set(to, &name, &value)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the extended attributes, SELinux context and ACL requested for
+/// the installed file.
+pub(crate) fn set_attributes(path: &Path, attributes: &TargetAttributes) -> Result<()> {
+    for (name, value) in &attributes.target_xattrs {
+        xattr::set(path, name, value.as_bytes())?;
+    }
+
+    if let Some(context) = &attributes.target_selinux_context {
+        xattr::set(path, "security.selinux", context.as_bytes())?;
+    }
+
+    if let Some(acl) = &attributes.target_acl {
+        xattr::set(path, "system.posix_acl_access", &acl_xattr(acl))?;
+    }
+
+    Ok(())
+}
+
+/// Encodes the ACL as the value of the `system.posix_acl_access` attribute,
+/// as in `include/uapi/linux/posix_acl_xattr.h`.
+fn acl_xattr(acl: &Acl) -> Vec<u8> {
+    const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+    // The kernel requires the entries to be sorted by tag and id
+    let mut entries = acl.0.clone();
+    entries.sort_by_key(|e| e.tag);
+
+    let mut value = 2_u32.to_le_bytes().to_vec();
+    for entry in entries {
+        let (tag, id): (u16, u32) = match entry.tag {
+            AclTag::UserObj => (0x01, ACL_UNDEFINED_ID),
+            AclTag::User(id) => (0x02, id),
+            AclTag::GroupObj => (0x04, ACL_UNDEFINED_ID),
+            AclTag::Group(id) => (0x08, id),
+            AclTag::Mask => (0x10, ACL_UNDEFINED_ID),
+            AclTag::Other => (0x20, ACL_UNDEFINED_ID),
+        };
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&u16::from(entry.perm).to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+
+    value
+}
+
+mod ffi {
+    use nix::{ioctl_none_bad, ioctl_write_ptr_bad, request_code_none};
+
+    // From https://github.com/torvalds/linux/blob/master/include/uapi/linux/fs.h
+    ioctl_none_bad!(blk_flsbuf, request_code_none!(0x12, 97));
+    ioctl_write_ptr_bad!(blk_discard, request_code_none!(0x12, 119), [u64; 2]);
+}
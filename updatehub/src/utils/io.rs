@@ -2,13 +2,167 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::utils;
+use openssl::sha::Sha256;
 use std::{
-    io::{BufReader, BufWriter, Read, Seek, Write},
+    cmp,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     os::unix::io::AsRawFd,
     time::Duration,
 };
 use timeout_readwrite::{TimeoutReader, TimeoutWriter};
 
+/// Writer which computes the SHA-256 and length of everything written
+/// through it.
+pub(crate) struct Sha256Writer<W> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W: Write> Sha256Writer<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Sha256Writer { inner, hasher: Sha256::new(), len: 0 }
+    }
+
+    /// Consumes the writer returning the hex encoded checksum, the amount
+    /// of bytes written and the inner writer.
+    pub(crate) fn finish(self) -> (String, u64, W) {
+        (utils::hex_encode(&self.hasher.finish()), self.len, self.inner)
+    }
+}
+
+impl<W: Write> Write for Sha256Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer which reads back every chunk from the target before writing it,
+/// leaving untouched the chunks which already hold the same data.
+pub(crate) struct CompareWriter<F> {
+    target: F,
+    chunk: Vec<u8>,
+    current: Vec<u8>,
+    chunk_size: usize,
+    position: u64,
+    written: u64,
+}
+
+impl<F: Read + Write + Seek> CompareWriter<F> {
+    pub(crate) fn new(mut target: F, chunk_size: usize) -> io::Result<Self> {
+        let position = target.stream_position()?;
+        Ok(CompareWriter {
+            target,
+            chunk: Vec::with_capacity(chunk_size),
+            current: Vec::with_capacity(chunk_size),
+            chunk_size,
+            position,
+            written: 0,
+        })
+    }
+
+    /// Amount of bytes which have actually been written to the target.
+    pub(crate) fn written(&self) -> u64 {
+        self.written
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+
+        self.current.clear();
+        (&mut self.target).take(self.chunk.len() as u64).read_to_end(&mut self.current)?;
+        if self.current != self.chunk {
+            self.target.seek(SeekFrom::Start(self.position))?;
+            self.target.write_all(&self.chunk)?;
+            self.written += self.chunk.len() as u64;
+        }
+        self.position += self.chunk.len() as u64;
+        self.chunk.clear();
+
+        Ok(())
+    }
+}
+
+impl<F: Read + Write + Seek> Write for CompareWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = cmp::min(self.chunk_size - self.chunk.len(), buf.len());
+        self.chunk.extend_from_slice(&buf[..n]);
+        if self.chunk.len() == self.chunk_size {
+            self.write_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.target.flush()
+    }
+}
+
+impl<F: Read + Write + Seek> Seek for CompareWriter<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.write_chunk()?;
+        self.position = self.target.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+/// Writer which, instead of writing to the target, reads the same ranges
+/// back from it, computing the SHA-256 of both the data given and the data
+/// read.
+pub(crate) struct ReadBackWriter<R> {
+    target: R,
+    expected: Sha256,
+    actual: Sha256,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read + Seek> ReadBackWriter<R> {
+    pub(crate) fn new(target: R) -> Self {
+        ReadBackWriter {
+            target,
+            expected: Sha256::new(),
+            actual: Sha256::new(),
+            buffer: Vec::default(),
+        }
+    }
+
+    /// Checks whether the data read back matches the data given.
+    pub(crate) fn matches(self) -> bool {
+        self.expected.finish() == self.actual.finish()
+    }
+}
+
+impl<R: Read + Seek> Write for ReadBackWriter<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.clear();
+        (&mut self.target).take(buf.len() as u64).read_to_end(&mut self.buffer)?;
+        self.expected.update(buf);
+        self.actual.update(&self.buffer);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Seek for ReadBackWriter<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.target.seek(pos)
+    }
+}
+
 pub(crate) fn timed_buf_reader<R>(chunk_size: usize, reader: R) -> BufReader<TimeoutReader<R>>
 where
     R: Read + Seek + AsRawFd,
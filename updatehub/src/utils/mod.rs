@@ -2,10 +2,17 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+pub(crate) mod archive;
+pub(crate) mod chunker;
 pub(crate) mod definitions;
+pub(crate) mod delta;
+pub(crate) mod emmc;
 pub(crate) mod fs;
 pub(crate) mod io;
 pub(crate) mod mtd;
+pub(crate) mod partition;
+pub(crate) mod sparse;
+pub(crate) mod ubi;
 
 use thiserror::Error;
 
@@ -43,8 +50,29 @@ pub enum Error {
     #[error("Unable to find match for mtd device: {0}")]
     NoMtdDevice(String),
 
+    #[error("Unable to find partition: {0}")]
+    NoPartition(String),
+
+    #[error("Partition {0} matches more than one device: {1:?}")]
+    AmbiguousPartition(String, Vec<std::path::PathBuf>),
+
     #[error("Not enough storage space for installation")]
     NotEnoughSpace,
+
+    #[error("Invalid delta: {0}")]
+    InvalidDelta(String),
+
+    #[error("Data read back from flash doesn't match what was written at {0:#x}")]
+    FlashMismatch(u64),
+
+    #[error("Invalid UBI volume change: {0}")]
+    InvalidUbiVolume(String),
+
+    #[error("Archive entry would be extracted outside of the target: {0:?}")]
+    UnsafeArchiveEntry(std::path::PathBuf),
+
+    #[error("Not an eMMC or one of its boot partitions: {0:?}")]
+    InvalidEmmcTarget(std::path::PathBuf),
 }
 
 /// Encode a bytes stream in hex
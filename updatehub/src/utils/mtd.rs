@@ -2,14 +2,217 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-pub(crate) use self::ffi::is_nand;
 use super::{Error, Result};
+use slog_scope::warn;
 use std::{
     fs,
-    io::{BufRead, BufReader},
-    path::PathBuf,
+    io::{self, BufRead, BufReader, Read},
+    mem::MaybeUninit,
+    os::unix::{fs::FileExt, io::AsRawFd},
+    path::{Path, PathBuf},
 };
 
+// From https://github.com/torvalds/linux/blob/master/include/uapi/mtd/mtd-abi.h
+const MTD_NANDFLASH: u8 = 4;
+const MTD_MLCNANDFLASH: u8 = 8;
+
+/// Geometry of a MTD device.
+#[derive(Debug, Clone)]
+pub(crate) struct MtdInfo {
+    pub(crate) kind: u8,
+    pub(crate) size: u64,
+    pub(crate) erase_size: u64,
+    pub(crate) write_size: u64,
+}
+
+impl MtdInfo {
+    pub(crate) fn is_nand(&self) -> bool {
+        self.kind == MTD_NANDFLASH || self.kind == MTD_MLCNANDFLASH
+    }
+}
+
+/// Operations used to write an image to a MTD device, offsets are in bytes
+/// from the device start.
+pub(crate) trait Mtd {
+    fn info(&self) -> &MtdInfo;
+    fn is_bad_block(&mut self, offset: u64) -> Result<bool>;
+    fn erase_block(&mut self, offset: u64) -> Result<()>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+}
+
+/// MTD character device, as `/dev/mtd0`.
+pub(crate) struct MtdDevice {
+    file: fs::File,
+    info: MtdInfo,
+}
+
+impl MtdDevice {
+    pub(crate) fn open(device: &Path) -> Result<Self> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(device)?;
+        let info = unsafe {
+            let mut info = MaybeUninit::<ffi::mtd_info_user>::uninit();
+            ffi::mtd_get_info(file.as_raw_fd(), info.as_mut_ptr())?;
+            info.assume_init()
+        };
+        let info = MtdInfo {
+            kind: info.kind,
+            size: u64::from(info.size),
+            erase_size: u64::from(info.erasesize),
+            write_size: u64::from(info.writesize.max(1)),
+        };
+
+        Ok(MtdDevice { file, info })
+    }
+}
+
+impl Mtd for MtdDevice {
+    fn info(&self) -> &MtdInfo {
+        &self.info
+    }
+
+    fn is_bad_block(&mut self, offset: u64) -> Result<bool> {
+        let offset = offset as i64;
+        Ok(unsafe { ffi::mtd_get_bad_block(self.file.as_raw_fd(), &offset)? } > 0)
+    }
+
+    fn erase_block(&mut self, offset: u64) -> Result<()> {
+        let erase = ffi::erase_info_user64 { start: offset, length: self.info.erase_size };
+        unsafe { ffi::mtd_erase64(self.file.as_raw_fd(), &erase)? };
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        Ok(self.file.write_all_at(data, offset)?)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        Ok(self.file.read_exact_at(buf, offset)?)
+    }
+}
+
+/// Writes the image to the device from its start, erasing each block before
+/// writing it and skipping the bad blocks of NAND devices. The last page is
+/// padded with `0xFF`, like erased flash, and each block is read back to be
+/// checked. The blocks after the image are erased too, so nothing is left
+/// from a previous image. `progress` is called with the number of bytes of
+/// the image written so far.
+pub(crate) fn write_image<M, R>(
+    mtd: &mut M,
+    mut image: R,
+    mut progress: impl FnMut(u64),
+) -> Result<()>
+where
+    M: Mtd,
+    R: Read,
+{
+    let info = mtd.info().clone();
+    let mut block = vec![0; info.erase_size as usize];
+    let mut read_back = vec![0; block.len()];
+    let mut pending = read_block(&mut image, &mut block)?;
+    let mut written = 0;
+
+    for offset in good_blocks(mtd)? {
+        mtd.erase_block(offset)?;
+        if pending == 0 {
+            continue;
+        }
+
+        let len = padded_len(pending, &info);
+        for b in &mut block[pending..len] {
+            *b = 0xFF;
+        }
+        mtd.write_at(offset, &block[..len])?;
+        mtd.read_at(offset, &mut read_back[..len])?;
+        if block[..len] != read_back[..len] {
+            return Err(Error::FlashMismatch(offset));
+        }
+
+        written += pending as u64;
+        progress(written);
+        pending = read_block(&mut image, &mut block)?;
+    }
+
+    if pending != 0 {
+        return Err(Error::NotEnoughSpace);
+    }
+
+    Ok(())
+}
+
+/// Checks the device holds the image written by [`write_image`].
+pub(crate) fn verify_image<M, R>(mtd: &mut M, mut image: R) -> Result<bool>
+where
+    M: Mtd,
+    R: Read,
+{
+    let info = mtd.info().clone();
+    let mut block = vec![0; info.erase_size as usize];
+    let mut read_back = vec![0; block.len()];
+
+    for offset in good_blocks(mtd)? {
+        let pending = read_block(&mut image, &mut block)?;
+        if pending == 0 {
+            return Ok(true);
+        }
+
+        let len = padded_len(pending, &info);
+        mtd.read_at(offset, &mut read_back[..len])?;
+        if block[..pending] != read_back[..pending]
+            || read_back[pending..len].iter().any(|b| *b != 0xFF)
+        {
+            return Ok(false);
+        }
+    }
+
+    Ok(read_block(&mut image, &mut block)? == 0)
+}
+
+/// Erases the whole device, leaving its bad blocks untouched.
+pub(crate) fn erase<M: Mtd>(mtd: &mut M) -> Result<()> {
+    good_blocks(mtd)?.into_iter().try_for_each(|offset| mtd.erase_block(offset))
+}
+
+/// Offsets of the blocks which can be written, the bad blocks are only
+/// tracked on NAND devices.
+fn good_blocks<M: Mtd>(mtd: &mut M) -> Result<Vec<u64>> {
+    let info = mtd.info().clone();
+    let mut blocks = Vec::default();
+    let mut offset = 0;
+    while offset < info.size {
+        if info.is_nand() && mtd.is_bad_block(offset)? {
+            warn!("skipping bad block at {:#x}", offset);
+        } else {
+            blocks.push(offset);
+        }
+        offset += info.erase_size;
+    }
+
+    Ok(blocks)
+}
+
+/// Length to write for `len` bytes of data, rounded up to whole pages.
+fn padded_len(len: usize, info: &MtdInfo) -> usize {
+    let page = info.write_size as usize;
+    len + (page - len % page) % page
+}
+
+/// Fills `buf` with the image, returning how much of it was filled, which is
+/// less than its length only at the end of the image.
+fn read_block<R: Read>(image: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match image.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
+
 pub(crate) fn target_device_from_ubi_volume_name(volume: &str) -> Result<PathBuf> {
     let re = regex::Regex::new(r"^Volume ID:   (?P<volume>\d+) \(on ubi(\d+)\)$").unwrap();
     walkdir::WalkDir::new("/dev")
@@ -59,39 +262,35 @@ pub(crate) fn target_device_from_mtd_name(name: &str) -> Result<PathBuf> {
 }
 
 mod ffi {
-    use crate::utils::Result;
-    use nix::ioctl_read;
-    use std::{mem::MaybeUninit, os::unix::io::AsRawFd, path::Path};
+    use nix::{ioctl_read, ioctl_write_ptr};
 
     // From https://github.com/torvalds/linux/blob/master/include/uapi/mtd/mtd-abi.h
-    const MTD_NANDFLASH: u8 = 4;
-    const MTD_MLCNANDFLASH: u8 = 8;
-    const MEMGETINFO: u8 = b'M';
-    const MEMGETINFO_MODE: u8 = 1;
+    const MTD_IOC_MAGIC: u8 = b'M';
+    const MEMGETINFO: u8 = 1;
+    const MEMGETBADBLOCK: u8 = 11;
+    const MEMERASE64: u8 = 20;
 
     #[repr(C)]
     pub struct mtd_info_user {
-        kind: u8,
-        flags: u32,
-        size: u32,
-        erasesize: u32,
-        writesize: u32,
-        oobsize: u32,
-        padding: u64,
+        pub kind: u8,
+        pub flags: u32,
+        pub size: u32,
+        pub erasesize: u32,
+        pub writesize: u32,
+        pub oobsize: u32,
+        pub padding: u64,
     }
 
-    ioctl_read!(mtd_get_info, MEMGETINFO, MEMGETINFO_MODE, mtd_info_user);
-
-    pub fn is_nand(device: &Path) -> Result<bool> {
-        let device = std::fs::File::open(device)?;
-        let info = unsafe {
-            let mut info = MaybeUninit::<mtd_info_user>::uninit();
-            mtd_get_info(device.as_raw_fd(), info.as_mut_ptr())?;
-            info.assume_init()
-        };
-
-        Ok(info.kind == MTD_NANDFLASH || info.kind == MTD_MLCNANDFLASH)
+    #[repr(C)]
+    pub struct erase_info_user64 {
+        pub start: u64,
+        pub length: u64,
     }
+
+    ioctl_read!(mtd_get_info, MTD_IOC_MAGIC, MEMGETINFO, mtd_info_user);
+    ioctl_write_ptr!(mtd_get_bad_block, MTD_IOC_MAGIC, MEMGETBADBLOCK, i64);
+    // Offsets of 4GiB and beyond don't fit the 32 bits of MEMERASE
+    ioctl_write_ptr!(mtd_erase64, MTD_IOC_MAGIC, MEMERASE64, erase_info_user64);
 }
 
 #[cfg(test)]
@@ -180,6 +379,138 @@ pub(crate) mod tests {
         pub static ref SERIALIZE: Arc<Mutex<()>> = Arc::new(Mutex::default());
     }
 
+    /// MTD device kept in memory, which only accepts whole pages written to
+    /// erased good blocks, like flash does.
+    pub(crate) struct MemoryMtd {
+        pub(crate) info: MtdInfo,
+        pub(crate) data: Vec<u8>,
+        pub(crate) bad_blocks: Vec<u64>,
+    }
+
+    impl MemoryMtd {
+        pub(crate) fn new(kind: MtdKind, blocks: u64) -> Self {
+            let (kind, erase_size, write_size) = match kind {
+                MtdKind::Nand => (MTD_NANDFLASH, 16 * 1024, 512),
+                MtdKind::Nor => (3, 4 * 1024, 1),
+            };
+            let size = blocks * erase_size;
+            MemoryMtd {
+                info: MtdInfo { kind, size, erase_size, write_size },
+                data: vec![0; size as usize],
+                bad_blocks: Vec::default(),
+            }
+        }
+
+        fn check_block(&self, offset: u64) -> Result<()> {
+            if self.bad_blocks.contains(&(offset - offset % self.info.erase_size)) {
+                return Err(io::Error::from_raw_os_error(nix::libc::EIO).into());
+            }
+            Ok(())
+        }
+    }
+
+    impl Mtd for MemoryMtd {
+        fn info(&self) -> &MtdInfo {
+            &self.info
+        }
+
+        fn is_bad_block(&mut self, offset: u64) -> Result<bool> {
+            Ok(self.bad_blocks.contains(&offset))
+        }
+
+        fn erase_block(&mut self, offset: u64) -> Result<()> {
+            assert_eq!(offset % self.info.erase_size, 0);
+            self.check_block(offset)?;
+            let block = offset as usize..(offset + self.info.erase_size) as usize;
+            for b in &mut self.data[block] {
+                *b = 0xFF;
+            }
+            Ok(())
+        }
+
+        fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+            assert_eq!(offset % self.info.write_size, 0);
+            assert_eq!(data.len() as u64 % self.info.write_size, 0);
+            self.check_block(offset)?;
+            for (b, new) in self.data[offset as usize..].iter_mut().zip(data) {
+                // Programming can only clear bits of the erased flash
+                assert_eq!(*b & new, *new, "write to non erased flash");
+                *b = *new;
+            }
+            Ok(())
+        }
+
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            Ok(())
+        }
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn write_nor_image() {
+        let mut mtd = MemoryMtd::new(MtdKind::Nor, 4);
+        mtd.data = vec![0; mtd.data.len()];
+        let data = image(5000);
+        let mut reported = Vec::default();
+
+        write_image(&mut mtd, data.as_slice(), |n| reported.push(n)).unwrap();
+        assert_eq!(&mtd.data[..5000], data.as_slice());
+        assert!(mtd.data[5000..].iter().all(|b| *b == 0xFF), "rest of the device must be erased");
+        assert_eq!(reported, vec![4096, 5000]);
+        assert!(verify_image(&mut mtd, data.as_slice()).unwrap());
+
+        mtd.data[100] = 0;
+        assert!(!verify_image(&mut mtd, data.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn write_nand_skipping_bad_blocks() {
+        let mut mtd = MemoryMtd::new(MtdKind::Nand, 4);
+        mtd.bad_blocks = vec![16 * 1024];
+        let data = image(20 * 1024 + 100);
+
+        write_image(&mut mtd, data.as_slice(), |_| ()).unwrap();
+        assert_eq!(&mtd.data[..16 * 1024], &data[..16 * 1024]);
+        assert_eq!(&mtd.data[32 * 1024..36 * 1024 + 100], &data[16 * 1024..]);
+        assert!(
+            mtd.data[36 * 1024 + 100..].iter().all(|b| *b == 0xFF),
+            "last page must be padded and the rest of the device erased"
+        );
+        assert!(verify_image(&mut mtd, data.as_slice()).unwrap());
+
+        mtd.data[32 * 1024] ^= 0xFF;
+        assert!(!verify_image(&mut mtd, data.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn write_without_enough_good_blocks() {
+        let mut mtd = MemoryMtd::new(MtdKind::Nand, 2);
+        mtd.bad_blocks = vec![0];
+        let data = image(20 * 1024);
+
+        assert!(matches!(
+            write_image(&mut mtd, data.as_slice(), |_| ()),
+            Err(Error::NotEnoughSpace)
+        ));
+        assert!(!verify_image(&mut mtd, data.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn erase_skipping_bad_blocks() {
+        let mut mtd = MemoryMtd::new(MtdKind::Nand, 3);
+        mtd.bad_blocks = vec![16 * 1024];
+
+        erase(&mut mtd).unwrap();
+        assert!(mtd.data[..16 * 1024].iter().all(|b| *b == 0xFF));
+        assert!(mtd.data[16 * 1024..32 * 1024].iter().all(|b| *b == 0));
+        assert!(mtd.data[32 * 1024..].iter().all(|b| *b == 0xFF));
+    }
+
     #[test]
     #[ignore]
     fn device_from_mtd_name() {
@@ -206,11 +537,11 @@ pub(crate) mod tests {
 
         {
             let _mtd = FakeMtd::new(&[], MtdKind::Nand).unwrap();
-            assert_eq!(is_nand(&PathBuf::from("/dev/mtd0")).unwrap(), true);
+            assert!(MtdDevice::open(Path::new("/dev/mtd0")).unwrap().info().is_nand());
         }
         {
             let _mtd = FakeMtd::new(&[], MtdKind::Nor).unwrap();
-            assert_eq!(is_nand(&PathBuf::from("/dev/mtd0")).unwrap(), false);
+            assert!(!MtdDevice::open(Path::new("/dev/mtd0")).unwrap().info().is_nand());
         }
     }
 
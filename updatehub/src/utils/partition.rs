@@ -0,0 +1,466 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+const DEV_DIR: &str = "/dev";
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const EXT_MAGIC: [u8; 2] = [0x53, 0xef];
+// Tables are far smaller, larger ones mean a corrupted header
+const MAX_GPT_ENTRIES: u32 = 1024;
+const MAX_GPT_ENTRY_SIZE: usize = 4096;
+
+pub(crate) fn target_device_from_partlabel(label: &str) -> Result<PathBuf> {
+    BlockDevices::system().resolve(&Key::PartLabel(label))
+}
+
+pub(crate) fn target_device_from_partuuid(uuid: &str) -> Result<PathBuf> {
+    BlockDevices::system().resolve(&Key::PartUuid(uuid))
+}
+
+pub(crate) fn target_device_from_fslabel(label: &str) -> Result<PathBuf> {
+    BlockDevices::system().resolve(&Key::FsLabel(label))
+}
+
+pub(crate) fn target_device_from_gpt_partition_name(name: &str) -> Result<PathBuf> {
+    BlockDevices::system().resolve(&Key::GptPartitionName(name))
+}
+
+/// Stable identifier of a partition, which unlike the kernel device name
+/// does not depend on the order the disks are probed.
+#[derive(Debug)]
+enum Key<'a> {
+    PartLabel(&'a str),
+    PartUuid(&'a str),
+    FsLabel(&'a str),
+    GptPartitionName(&'a str),
+}
+
+impl Key<'_> {
+    fn value(&self) -> &str {
+        match self {
+            Key::PartLabel(v) | Key::PartUuid(v) | Key::FsLabel(v) | Key::GptPartitionName(v) => v,
+        }
+    }
+
+    /// Directory of `/dev/disk` where udev links the partitions by the
+    /// identifier, if any.
+    fn udev_dir(&self) -> Option<&'static str> {
+        match self {
+            Key::PartLabel(_) => Some("by-partlabel"),
+            Key::PartUuid(_) => Some("by-partuuid"),
+            Key::FsLabel(_) => Some("by-label"),
+            Key::GptPartitionName(_) => None,
+        }
+    }
+}
+
+enum PartitionTable {
+    Gpt(Vec<GptEntry>),
+    Mbr { signature: u32 },
+    Unknown,
+}
+
+struct GptEntry {
+    name: String,
+    uuid: String,
+}
+
+impl PartitionTable {
+    /// Whether the partition with the number, starting at 1, matches the
+    /// key.
+    fn matches(&self, number: usize, key: &Key) -> bool {
+        match (self, key) {
+            (PartitionTable::Gpt(entries), Key::PartLabel(name))
+            | (PartitionTable::Gpt(entries), Key::GptPartitionName(name)) => {
+                matches!(entries.get(number.wrapping_sub(1)), Some(e) if e.name == *name)
+            }
+            (PartitionTable::Gpt(entries), Key::PartUuid(uuid)) => {
+                let entry = entries.get(number.wrapping_sub(1));
+                matches!(entry, Some(e) if e.uuid.eq_ignore_ascii_case(uuid))
+            }
+            (PartitionTable::Mbr { signature }, Key::PartUuid(uuid)) => {
+                format!("{:08x}-{:02x}", signature, number).eq_ignore_ascii_case(uuid)
+            }
+            _ => false,
+        }
+    }
+}
+
+struct BlockDevices {
+    sys: PathBuf,
+    dev: PathBuf,
+}
+
+impl BlockDevices {
+    fn system() -> Self {
+        BlockDevices { sys: PathBuf::from(SYS_CLASS_BLOCK), dev: PathBuf::from(DEV_DIR) }
+    }
+
+    /// Finds the single device matching the key, a key matching more than
+    /// one device is refused as writing to the wrong one may brick the
+    /// device, as when a card cloned from the internal storage is inserted.
+    /// When nothing is found, udev links are tried as these may know
+    /// partition tables and filesystems which are not read here.
+    fn resolve(&self, key: &Key) -> Result<PathBuf> {
+        let mut found = self.find(key)?;
+        match found.len() {
+            1 => Ok(found.remove(0)),
+            0 => key
+                .udev_dir()
+                .map(|dir| self.dev.join("disk").join(dir).join(udev_escape(key.value())))
+                .filter(|link| link.exists())
+                .map(fs::canonicalize)
+                .transpose()?
+                .ok_or_else(|| Error::NoPartition(key.value().to_owned())),
+            _ => Err(Error::AmbiguousPartition(key.value().to_owned(), found)),
+        }
+    }
+
+    fn find(&self, key: &Key) -> Result<Vec<PathBuf>> {
+        let mut found = Vec::default();
+
+        if let Key::FsLabel(label) = key {
+            for name in self.names()? {
+                let device = self.dev.join(&name);
+                if filesystem_label(&device).ok().flatten().as_deref() == Some(*label) {
+                    found.push(device);
+                }
+            }
+            return Ok(found);
+        }
+
+        let mut tables = BTreeMap::new();
+        for name in self.names()? {
+            let number = match fs::read_to_string(self.sys.join(&name).join("partition")) {
+                Ok(number) => number.trim().parse::<usize>().ok(),
+                // Only partitions have this attribute, whole disks do not
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let disk = match fs::canonicalize(self.sys.join(&name))?
+                .parent()
+                .and_then(Path::file_name)
+                .map(|disk| disk.to_string_lossy().into_owned())
+            {
+                Some(disk) => disk,
+                None => continue,
+            };
+            let table = tables.entry(disk.clone()).or_insert_with(|| self.partition_table(&disk));
+
+            if matches!(number, Some(number) if table.matches(number, key)) {
+                found.push(self.dev.join(&name));
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Names of the block devices, sorted so errors are reproducible.
+    fn names(&self) -> Result<Vec<String>> {
+        let mut names = fs::read_dir(&self.sys)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    }
+
+    /// Disks which cannot be read, such as card readers without a card,
+    /// have no partitions to be matched.
+    fn partition_table(&self, disk: &str) -> PartitionTable {
+        let block_size = fs::read_to_string(self.sys.join(disk).join("queue/logical_block_size"))
+            .ok()
+            .and_then(|size| size.trim().parse().ok())
+            .unwrap_or(512);
+        read_partition_table(&self.dev.join(disk), block_size).unwrap_or(PartitionTable::Unknown)
+    }
+}
+
+fn read_partition_table(disk: &Path, block_size: u64) -> io::Result<PartitionTable> {
+    let mut disk = fs::File::open(disk)?;
+
+    let header = read_at(&mut disk, block_size, 92)?;
+    if header.starts_with(GPT_SIGNATURE) {
+        let entries_lba = read_u64(&header, 72);
+        let count = read_u32(&header, 80).min(MAX_GPT_ENTRIES);
+        let entry_size = read_u32(&header, 84) as usize;
+        if !(128..=MAX_GPT_ENTRY_SIZE).contains(&entry_size) {
+            return Ok(PartitionTable::Unknown);
+        }
+
+        let table = read_at(&mut disk, entries_lba * block_size, count as usize * entry_size)?;
+        return Ok(PartitionTable::Gpt(table.chunks(entry_size).map(gpt_entry).collect()));
+    }
+
+    let mbr = read_at(&mut disk, 0, 512)?;
+    if mbr[510..] == MBR_SIGNATURE {
+        return Ok(PartitionTable::Mbr { signature: read_u32(&mbr, 440) });
+    }
+
+    Ok(PartitionTable::Unknown)
+}
+
+fn gpt_entry(entry: &[u8]) -> GptEntry {
+    let guid = &entry[16..32];
+    let name = entry[56..128]
+        .chunks(2)
+        .map(|c| read_u16(c, 0))
+        .take_while(|c| *c != 0)
+        .collect::<Vec<_>>();
+
+    GptEntry {
+        name: String::from_utf16_lossy(&name),
+        // The first three fields of the GUID are stored little-endian
+        uuid: format!(
+            "{:08x}-{:04x}-{:04x}-{}-{}",
+            read_u32(guid, 0),
+            read_u16(guid, 4),
+            read_u16(guid, 6),
+            super::hex_encode(&guid[8..10]),
+            super::hex_encode(&guid[10..16]),
+        ),
+    }
+}
+
+/// Label of the ext2/3/4 or FAT filesystem on the device, if any.
+fn filesystem_label(device: &Path) -> io::Result<Option<String>> {
+    let mut device = fs::File::open(device)?;
+    let trimmed = |label: &[u8]| {
+        let label = String::from_utf8_lossy(label);
+        let label = label.trim_end_matches(&['\0', ' '][..]);
+        Some(label.to_string()).filter(|l| !l.is_empty() && l != "NO NAME")
+    };
+
+    let superblock = read_at(&mut device, 1024, 136)?;
+    if superblock[56..58] == EXT_MAGIC {
+        return Ok(trimmed(&superblock[120..136]));
+    }
+
+    let boot = read_at(&mut device, 0, 512)?;
+    if boot[510..] == MBR_SIGNATURE {
+        if &boot[82..87] == b"FAT32" {
+            return Ok(trimmed(&boot[71..82]));
+        }
+        if &boot[54..57] == b"FAT" {
+            return Ok(trimmed(&boot[43..54]));
+        }
+    }
+
+    Ok(None)
+}
+
+fn read_at(file: &mut fs::File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Escapes the value as udev does for the names of its links.
+fn udev_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'0'..=b'9'
+            | b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'#'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b':'
+            | b'='
+            | b'@'
+            | b'_' => (b as char).to_string(),
+            b => format!("\\x{:02x}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::os::unix::fs::symlink;
+    use tempfile::{tempdir, TempDir};
+
+    const LINUX_FILESYSTEM: &[u8] = &[
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ];
+
+    /// Fake sysfs and /dev holding the disks as regular files, each with
+    /// its partitions as children as in the sysfs device tree.
+    struct Fake {
+        tmpdir: TempDir,
+        devices: BlockDevices,
+    }
+
+    impl Fake {
+        fn new() -> Self {
+            let tmpdir = tempdir().unwrap();
+            let devices = BlockDevices {
+                sys: tmpdir.path().join("sys/class/block"),
+                dev: tmpdir.path().join("dev"),
+            };
+            fs::create_dir_all(&devices.sys).unwrap();
+            fs::create_dir_all(&devices.dev).unwrap();
+            Fake { tmpdir, devices }
+        }
+
+        fn add_disk(&self, disk: &str, image: &[u8], partitions: &[(usize, &[u8])]) {
+            let tree = self.tmpdir.path().join("sys/devices").join(disk);
+            fs::create_dir_all(&tree).unwrap();
+            symlink(&tree, self.devices.sys.join(disk)).unwrap();
+            fs::write(self.devices.dev.join(disk), image).unwrap();
+
+            for (number, content) in partitions {
+                let name = partition_name(disk, *number);
+                fs::create_dir_all(tree.join(&name)).unwrap();
+                fs::write(tree.join(&name).join("partition"), format!("{}\n", number)).unwrap();
+                symlink(tree.join(&name), self.devices.sys.join(&name)).unwrap();
+                fs::write(self.devices.dev.join(&name), content).unwrap();
+            }
+        }
+
+        fn partition(&self, name: &str) -> PathBuf {
+            self.devices.dev.join(name)
+        }
+    }
+
+    fn partition_name(disk: &str, number: usize) -> String {
+        if disk.ends_with(|c: char| c.is_ascii_digit()) {
+            format!("{}p{}", disk, number)
+        } else {
+            format!("{}{}", disk, number)
+        }
+    }
+
+    fn gpt_disk(names: &[&str]) -> Vec<u8> {
+        let mut disk = vec![0; 512 * 4];
+        disk[512..520].copy_from_slice(GPT_SIGNATURE);
+        disk[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+        disk[512 + 80..512 + 84].copy_from_slice(&(names.len() as u32).to_le_bytes());
+        disk[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+
+        for (i, name) in names.iter().enumerate() {
+            let entry = &mut disk[1024 + i * 128..1024 + (i + 1) * 128];
+            entry[..16].copy_from_slice(LINUX_FILESYSTEM);
+            entry[16..32].copy_from_slice(LINUX_FILESYSTEM);
+            entry[31] = i as u8;
+            for (j, c) in name.encode_utf16().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        disk
+    }
+
+    fn ext4(label: &str) -> Vec<u8> {
+        let mut fs = vec![0; 2048];
+        fs[1024 + 56..1024 + 58].copy_from_slice(&EXT_MAGIC);
+        fs[1024 + 120..1024 + 120 + label.len()].copy_from_slice(label.as_bytes());
+        fs
+    }
+
+    fn vfat(label: &str) -> Vec<u8> {
+        let mut fs = vec![0; 2048];
+        fs[43..54].copy_from_slice(format!("{:<11}", label).as_bytes());
+        fs[54..62].copy_from_slice(b"FAT16   ");
+        fs[510..512].copy_from_slice(&MBR_SIGNATURE);
+        fs
+    }
+
+    #[test]
+    fn gpt() {
+        let fake = Fake::new();
+        fake.add_disk(
+            "mmcblk0",
+            &gpt_disk(&["boot", "rootfs-a", "rootfs-b"]),
+            &[(1, &[]), (2, &[]), (3, &[])],
+        );
+
+        assert_eq!(
+            fake.devices.resolve(&Key::PartLabel("rootfs-b")).unwrap(),
+            fake.partition("mmcblk0p3")
+        );
+        assert_eq!(
+            fake.devices.resolve(&Key::GptPartitionName("boot")).unwrap(),
+            fake.partition("mmcblk0p1")
+        );
+        assert_eq!(
+            fake.devices.resolve(&Key::PartUuid("0FC63DAF-8483-4772-8E79-3D69D8477D01")).unwrap(),
+            fake.partition("mmcblk0p2")
+        );
+        match fake.devices.resolve(&Key::PartLabel("data")) {
+            Err(Error::NoPartition(name)) => assert_eq!(name, "data"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn cloned_disk() {
+        let fake = Fake::new();
+        fake.add_disk("mmcblk0", &gpt_disk(&["boot", "rootfs"]), &[(1, &[]), (2, &[])]);
+        fake.add_disk("mmcblk1", &gpt_disk(&["boot", "rootfs"]), &[(1, &[]), (2, &[])]);
+
+        match fake.devices.resolve(&Key::GptPartitionName("rootfs")) {
+            Err(Error::AmbiguousPartition(_, found)) => {
+                assert_eq!(found, vec![fake.partition("mmcblk0p2"), fake.partition("mmcblk1p2")])
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn mbr() {
+        let fake = Fake::new();
+        let mut disk = vec![0; 1024];
+        disk[440..444].copy_from_slice(&0x1234_abcdu32.to_le_bytes());
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE);
+        fake.add_disk("sda", &disk, &[(1, &vfat("BOOT")), (2, &ext4("data"))]);
+
+        assert_eq!(
+            fake.devices.resolve(&Key::PartUuid("1234abcd-02")).unwrap(),
+            fake.partition("sda2")
+        );
+        assert!(fake.devices.resolve(&Key::PartLabel("data")).is_err());
+        assert_eq!(fake.devices.resolve(&Key::FsLabel("data")).unwrap(), fake.partition("sda2"));
+        assert_eq!(fake.devices.resolve(&Key::FsLabel("BOOT")).unwrap(), fake.partition("sda1"));
+    }
+
+    #[test]
+    fn udev_links() {
+        let fake = Fake::new();
+        fake.add_disk("sda", &[0; 1024], &[(1, &[])]);
+        let links = fake.devices.dev.join("disk/by-label");
+        fs::create_dir_all(&links).unwrap();
+        symlink(fake.partition("sda1"), links.join("my\\x20data")).unwrap();
+
+        assert_eq!(fake.devices.resolve(&Key::FsLabel("my data")).unwrap(), fake.partition("sda1"));
+        assert!(fake.devices.resolve(&Key::GptPartitionName("my data")).is_err());
+    }
+}
@@ -0,0 +1,498 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use pkg_schema::definitions::Bmap;
+use std::{
+    cmp,
+    convert::TryInto,
+    io::{self, Seek, SeekFrom, Write},
+    ops::Range,
+};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// From https://android.googlesource.com/platform/system/core/+/master/libsparse/sparse_format.h
+const SPARSE_HEADER_MAGIC: u32 = 0xed26_ff3a;
+const SPARSE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+/// Writer expanding sparse images into the target, seeking over their holes
+/// instead of writing them. Android sparse images are recognized by their
+/// header while bmap described images need the block map, any other image
+/// is written as is.
+pub(crate) struct Writer<W> {
+    output: Output<W>,
+    format: Format,
+}
+
+/// Extent of the image written on the target, with the amount of bytes
+/// written and the holes which have been left untouched.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Image {
+    pub(crate) end: u64,
+    pub(crate) written: u64,
+    pub(crate) holes: Vec<Range<u64>>,
+}
+
+enum Format {
+    /// Header bytes collected until the format can be told.
+    Unknown(Vec<u8>),
+    Plain,
+    Android(Android),
+    Bmap(Mapped),
+}
+
+struct Output<W> {
+    inner: W,
+    base: u64,
+    /// Offset within the expanded image.
+    position: u64,
+    holes: Vec<Range<u64>>,
+    written: u64,
+    seek_pending: bool,
+}
+
+struct Android {
+    block_size: u64,
+    chunk_header_size: usize,
+    chunks_left: u32,
+    size: u64,
+    state: State,
+    pending: Vec<u8>,
+}
+
+enum State {
+    Header(usize),
+    Raw(u64),
+    Fill(u64),
+    Skip(u64),
+    Done,
+}
+
+struct Mapped {
+    ranges: Vec<Range<u64>>,
+    next: usize,
+    size: u64,
+}
+
+impl<W: Write + Seek> Writer<W> {
+    /// Creates a writer placing the image at `base` on the target.
+    pub(crate) fn new(inner: W, base: u64) -> io::Result<Self> {
+        Ok(Writer {
+            output: Output::new(inner, base)?,
+            format: Format::Unknown(Vec::with_capacity(SPARSE_HEADER_SIZE)),
+        })
+    }
+
+    /// Creates a writer placing the image at `base` on the target, only
+    /// writing the blocks mapped by `bmap`.
+    pub(crate) fn with_bmap(inner: W, base: u64, bmap: &Bmap) -> io::Result<Self> {
+        let ranges = bmap.mapped_bytes().filter(|r| !r.is_empty()).collect();
+        Ok(Writer {
+            output: Output::new(inner, base)?,
+            format: Format::Bmap(Mapped { ranges, next: 0, size: bmap.image_size }),
+        })
+    }
+
+    /// Flushes the remaining data, failing if the image is incomplete, and
+    /// returns the inner writer.
+    pub(crate) fn finish(self) -> io::Result<(Image, W)> {
+        let Writer { mut output, format } = self;
+        let size = match format {
+            // Images smaller than a sparse header
+            Format::Unknown(pending) => {
+                output.write(&pending)?;
+                output.position
+            }
+            Format::Plain => output.position,
+            Format::Android(Android { state: State::Done, size, .. }) => size,
+            Format::Android(_) => return Err(invalid_data("sparse image is truncated")),
+            Format::Bmap(Mapped { size, .. }) if output.position < size => {
+                return Err(invalid_data("image is smaller than its block map"));
+            }
+            Format::Bmap(Mapped { size, .. }) => size,
+        };
+        output.inner.flush()?;
+
+        let base = output.base;
+        let holes = output
+            .holes
+            .into_iter()
+            .filter(|hole| hole.start < size)
+            .map(|hole| base + hole.start..base + cmp::min(hole.end, size))
+            .collect();
+        Ok((Image { end: base + size, written: output.written, holes }, output.inner))
+    }
+
+    fn detect(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pending = match &mut self.format {
+            Format::Unknown(pending) => pending,
+            _ => return Ok(0),
+        };
+        let n = cmp::min(SPARSE_HEADER_SIZE - pending.len(), buf.len());
+        pending.extend_from_slice(&buf[..n]);
+        if pending.len() < SPARSE_HEADER_SIZE {
+            return Ok(n);
+        }
+
+        if read_u32(pending, 0) == SPARSE_HEADER_MAGIC {
+            self.format = Format::Android(Android::from_header(pending)?);
+        } else {
+            let pending = std::mem::take(pending);
+            self.output.write(&pending)?;
+            self.format = Format::Plain;
+        }
+        Ok(n)
+    }
+}
+
+impl<W: Write + Seek> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let n = match &mut self.format {
+                Format::Unknown(_) => self.detect(rest)?,
+                Format::Plain => {
+                    self.output.write(rest)?;
+                    rest.len()
+                }
+                Format::Android(android) => android.write(&mut self.output, rest)?,
+                Format::Bmap(mapped) => mapped.write(&mut self.output, rest)?,
+            };
+            rest = &rest[n..];
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Output<W> {
+    fn new(mut inner: W, base: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(base))?;
+        Ok(Output {
+            inner,
+            base,
+            position: 0,
+            holes: Vec::default(),
+            written: 0,
+            seek_pending: false,
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.seek_pending {
+            self.inner.seek(SeekFrom::Start(self.base + self.position))?;
+            self.seek_pending = false;
+        }
+        self.inner.write_all(data)?;
+        self.position += data.len() as u64;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) {
+        let end = self.position + len;
+        match self.holes.last_mut() {
+            Some(hole) if hole.end == self.position => hole.end = end,
+            _ => self.holes.push(self.position..end),
+        }
+        self.position = end;
+        self.seek_pending = true;
+    }
+}
+
+impl Android {
+    fn from_header(header: &[u8]) -> io::Result<Self> {
+        let major_version = read_u16(header, 4);
+        let file_header_size = usize::from(read_u16(header, 8));
+        let chunk_header_size = usize::from(read_u16(header, 10));
+        let block_size = u64::from(read_u32(header, 12));
+        let blocks = u64::from(read_u32(header, 16));
+        let chunks = read_u32(header, 20);
+
+        if major_version != 1
+            || file_header_size < SPARSE_HEADER_SIZE
+            || chunk_header_size < CHUNK_HEADER_SIZE
+            || block_size == 0
+            || block_size % 4 != 0
+        {
+            return Err(invalid_data("unsupported sparse image header"));
+        }
+
+        let mut android = Android {
+            block_size,
+            chunk_header_size,
+            chunks_left: chunks,
+            size: blocks * block_size,
+            state: State::Done,
+            pending: Vec::with_capacity(chunk_header_size),
+        };
+        android.state = match file_header_size - SPARSE_HEADER_SIZE {
+            0 => android.next_chunk(),
+            extra => State::Skip(extra as u64),
+        };
+        Ok(android)
+    }
+
+    fn next_chunk(&self) -> State {
+        if self.chunks_left == 0 {
+            State::Done
+        } else {
+            State::Header(self.chunk_header_size)
+        }
+    }
+
+    fn write<W: Write + Seek>(&mut self, output: &mut Output<W>, buf: &[u8]) -> io::Result<usize> {
+        match self.state {
+            State::Header(size) => {
+                let n = cmp::min(size - self.pending.len(), buf.len());
+                self.pending.extend_from_slice(&buf[..n]);
+                if self.pending.len() == size {
+                    self.state = self.chunk(output)?;
+                    self.pending.clear();
+                }
+                Ok(n)
+            }
+            State::Raw(remaining) => {
+                let n = cmp::min(remaining, buf.len() as u64) as usize;
+                output.write(&buf[..n])?;
+                self.state = match remaining - n as u64 {
+                    0 => self.next_chunk(),
+                    remaining => State::Raw(remaining),
+                };
+                Ok(n)
+            }
+            State::Fill(len) => {
+                let n = cmp::min(4 - self.pending.len(), buf.len());
+                self.pending.extend_from_slice(&buf[..n]);
+                if self.pending.len() == 4 {
+                    let pattern = self.pending.iter().copied().cycle().take(BUFFER_SIZE);
+                    let pattern = pattern.collect::<Vec<_>>();
+                    let mut left = len;
+                    while left > 0 {
+                        let len = cmp::min(left, BUFFER_SIZE as u64) as usize;
+                        output.write(&pattern[..len])?;
+                        left -= len as u64;
+                    }
+                    self.pending.clear();
+                    self.state = self.next_chunk();
+                }
+                Ok(n)
+            }
+            State::Skip(remaining) => {
+                let n = cmp::min(remaining, buf.len() as u64) as usize;
+                self.state = match remaining - n as u64 {
+                    0 => self.next_chunk(),
+                    remaining => State::Skip(remaining),
+                };
+                Ok(n)
+            }
+            // Trailing data isn't part of the image
+            State::Done => Ok(buf.len()),
+        }
+    }
+
+    /// Handles the chunk header on `pending`, returning the state to
+    /// process its data.
+    fn chunk<W: Write + Seek>(&mut self, output: &mut Output<W>) -> io::Result<State> {
+        let kind = read_u16(&self.pending, 0);
+        let len = u64::from(read_u32(&self.pending, 4)) * self.block_size;
+        let data_size = u64::from(read_u32(&self.pending, 8))
+            .checked_sub(self.chunk_header_size as u64)
+            .ok_or_else(|| invalid_data("sparse chunk is smaller than its header"))?;
+        self.chunks_left -= 1;
+
+        if output.position + len > self.size {
+            return Err(invalid_data("sparse chunk goes beyond the end of the image"));
+        }
+
+        Ok(match (kind, data_size) {
+            (CHUNK_TYPE_RAW, _) if data_size == len => match len {
+                0 => self.next_chunk(),
+                _ => State::Raw(len),
+            },
+            (CHUNK_TYPE_FILL, 4) => State::Fill(len),
+            (CHUNK_TYPE_DONT_CARE, 0) => {
+                output.skip(len);
+                self.next_chunk()
+            }
+            (CHUNK_TYPE_CRC32, 4) => State::Skip(4),
+            _ => {
+                return Err(invalid_data(format!(
+                    "invalid sparse chunk {:#x} with {} bytes",
+                    kind, data_size
+                )))
+            }
+        })
+    }
+}
+
+impl Mapped {
+    fn write<W: Write + Seek>(&mut self, output: &mut Output<W>, buf: &[u8]) -> io::Result<usize> {
+        let position = output.position;
+        while matches!(self.ranges.get(self.next), Some(range) if range.end <= position) {
+            self.next += 1;
+        }
+
+        match self.ranges.get(self.next) {
+            Some(range) if range.start <= position => {
+                let n = cmp::min(range.end - position, buf.len() as u64) as usize;
+                output.write(&buf[..n])?;
+                Ok(n)
+            }
+            range => {
+                let end = range.map_or(u64::MAX, |range| range.start);
+                let n = cmp::min(end - position, buf.len() as u64) as usize;
+                output.skip(n as u64);
+                Ok(n)
+            }
+        }
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use pkg_schema::definitions::BlockRange;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    pub(crate) enum Chunk<'a> {
+        Raw(&'a [u8]),
+        Fill(u32, [u8; 4]),
+        DontCare(u32),
+        Crc32,
+    }
+
+    /// Builds an Android sparse image with 4 bytes blocks.
+    pub(crate) fn fake_sparse_image(chunks: &[Chunk]) -> Vec<u8> {
+        let mut body = Vec::default();
+        let mut blocks = 0;
+        for chunk in chunks {
+            let (kind, len, data): (u16, u32, &[u8]) = match chunk {
+                Chunk::Raw(data) => (CHUNK_TYPE_RAW, data.len() as u32 / 4, data),
+                Chunk::Fill(len, pattern) => (CHUNK_TYPE_FILL, *len, pattern),
+                Chunk::DontCare(len) => (CHUNK_TYPE_DONT_CARE, *len, &[]),
+                Chunk::Crc32 => (CHUNK_TYPE_CRC32, 0, &[0; 4]),
+            };
+            body.extend_from_slice(&kind.to_le_bytes());
+            body.extend_from_slice(&[0; 2]);
+            body.extend_from_slice(&len.to_le_bytes());
+            body.extend_from_slice(&((CHUNK_HEADER_SIZE + data.len()) as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            blocks += len;
+        }
+
+        let mut image = Vec::default();
+        image.extend_from_slice(&SPARSE_HEADER_MAGIC.to_le_bytes());
+        image.extend_from_slice(&1u16.to_le_bytes());
+        image.extend_from_slice(&0u16.to_le_bytes());
+        image.extend_from_slice(&(SPARSE_HEADER_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&(CHUNK_HEADER_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&4u32.to_le_bytes());
+        image.extend_from_slice(&blocks.to_le_bytes());
+        image.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        image.extend_from_slice(&[0; 4]);
+        image.extend_from_slice(&body);
+        image
+    }
+
+    fn write_in_pieces(writer: &mut Writer<Cursor<&mut Vec<u8>>>, data: &[u8]) {
+        // Uneven pieces exercise the headers split across writes
+        for piece in data.chunks(5) {
+            writer.write_all(piece).unwrap();
+        }
+    }
+
+    #[test]
+    fn android_sparse_image() {
+        let image = fake_sparse_image(&[
+            Chunk::Raw(b"abcdefgh"),
+            Chunk::DontCare(2),
+            Chunk::Fill(2, *b"0123"),
+            Chunk::Crc32,
+            Chunk::DontCare(1),
+        ]);
+
+        let mut target = vec![b'.'; 32];
+        let mut writer = Writer::new(Cursor::new(&mut target), 2).unwrap();
+        write_in_pieces(&mut writer, &image);
+        let (image, _) = writer.finish().unwrap();
+
+        assert_eq!(image, Image { end: 30, written: 16, holes: vec![10..18, 26..30] });
+        assert_eq!(&target[..], &b"..abcdefgh........01230123......"[..]);
+    }
+
+    #[test]
+    fn invalid_sparse_image() {
+        let image = fake_sparse_image(&[Chunk::Raw(b"abcd"), Chunk::DontCare(1)]);
+
+        let mut target = Vec::default();
+        let mut writer = Writer::new(Cursor::new(&mut target), 0).unwrap();
+        writer.write_all(&image[..image.len() - 1]).unwrap();
+        assert_eq!(writer.finish().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut image = image;
+        image[SPARSE_HEADER_SIZE] = 0xff;
+        let mut writer = Writer::new(Cursor::new(&mut target), 0).unwrap();
+        assert_eq!(writer.write_all(&image).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn plain_image() {
+        let mut target = vec![b'.'; 8];
+        let mut writer = Writer::new(Cursor::new(&mut target), 1).unwrap();
+        write_in_pieces(&mut writer, b"abc");
+        assert_eq!(writer.finish().unwrap().0, Image { end: 4, written: 3, holes: vec![] });
+        assert_eq!(&target[..], &b".abc...."[..]);
+
+        let data = (0..64).collect::<Vec<u8>>();
+        let mut target = Vec::default();
+        let mut writer = Writer::new(Cursor::new(&mut target), 0).unwrap();
+        write_in_pieces(&mut writer, &data);
+        assert_eq!(writer.finish().unwrap().0, Image { end: 64, written: 64, holes: vec![] });
+        assert_eq!(target, data);
+    }
+
+    #[test]
+    fn bmap_image() {
+        let bmap = Bmap {
+            image_size: 14,
+            block_size: 4,
+            mapped_blocks: vec![BlockRange { first: 0, last: 0 }, BlockRange { first: 2, last: 3 }],
+        };
+
+        let mut target = vec![b'.'; 16];
+        let mut writer = Writer::with_bmap(Cursor::new(&mut target), 1, &bmap).unwrap();
+        write_in_pieces(&mut writer, b"abcdEFGHijklmnXX");
+        assert_eq!(
+            writer.finish().unwrap().0,
+            Image { end: 15, written: 10, holes: vec![Range { start: 5, end: 9 }] }
+        );
+        assert_eq!(&target[..], &b".abcd....ijklmn."[..]);
+
+        let mut writer = Writer::with_bmap(Cursor::new(&mut target), 0, &bmap).unwrap();
+        writer.write_all(b"abcd").unwrap();
+        assert_eq!(writer.finish().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,420 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Error, Result};
+use pkg_schema::definitions::{UbiVolume, UbiVolumeType};
+use slog_scope::info;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+const SYSFS_UBI: &str = "/sys/class/ubi";
+const DEFAULT_DEVICE: &str = "/dev/ubi0";
+
+/// Volume present on a UBI device.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Volume {
+    pub(crate) device: PathBuf,
+    pub(crate) id: i32,
+    pub(crate) name: String,
+    pub(crate) volume_type: UbiVolumeType,
+    /// Size of the logical erase blocks, the unit volumes are sized by.
+    pub(crate) leb_size: u64,
+    pub(crate) size: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Change {
+    Remove(Volume),
+    Resize(Volume, u64),
+    Rename(Volume, String),
+    Create { device: PathBuf, name: String, size: u64, volume_type: UbiVolumeType },
+}
+
+impl Change {
+    /// Removals and shrinks are made first, to free space for the others,
+    /// and creations last, so they can take the names renamed away.
+    fn order(&self) -> u8 {
+        match self {
+            Change::Remove(_) => 0,
+            Change::Resize(volume, size) if *size < volume.size => 1,
+            Change::Rename(..) => 2,
+            Change::Resize(..) => 3,
+            Change::Create { .. } => 4,
+        }
+    }
+}
+
+/// Volumes of all the UBI devices, none when UBI isn't available.
+pub(crate) fn volumes() -> Result<Vec<Volume>> {
+    volumes_in(Path::new(SYSFS_UBI))
+}
+
+fn volumes_in(sysfs: &Path) -> Result<Vec<Volume>> {
+    let entries = match fs::read_dir(sysfs) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::default()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut volumes = Vec::default();
+    for entry in entries {
+        let entry = entry?;
+        // Volumes are listed as `ubi0_1`, after their device and id, along
+        // with the devices themselves
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let (device, id) = match file_name.find('_') {
+            Some(i) if file_name.starts_with("ubi") => (&file_name[..i], &file_name[i + 1..]),
+            _ => continue,
+        };
+        let id = match id.parse() {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+
+        let path = entry.path();
+        let attribute = |name| -> Result<String> {
+            Ok(fs::read_to_string(path.join(name))?.trim_end().to_string())
+        };
+        let number = |name| -> Result<u64> {
+            attribute(name)?
+                .parse()
+                .map_err(|_| Error::InvalidUbiVolume(format!("invalid {} for {}", name, file_name)))
+        };
+        let volume_type = match attribute("type")?.as_str() {
+            "static" => UbiVolumeType::Static,
+            _ => UbiVolumeType::Dynamic,
+        };
+        let leb_size = number("usable_eb_size")?;
+
+        volumes.push(Volume {
+            device: Path::new("/dev").join(device),
+            id,
+            name: attribute("name")?,
+            volume_type,
+            leb_size,
+            size: number("reserved_ebs")? * leb_size,
+        });
+    }
+    volumes.sort_by(|a, b| (&a.device, a.id).cmp(&(&b.device, b.id)));
+
+    Ok(volumes)
+}
+
+/// Changes needed for the existing volumes to match the declared ones, in
+/// the order they are to be made.
+pub(crate) fn plan(declared: &[UbiVolume], existing: &[Volume]) -> Vec<Change> {
+    let mut existing = existing.to_vec();
+    let mut changes = Vec::default();
+
+    for volume in declared {
+        let find = |existing: &[Volume], name: &str| {
+            existing.iter().position(|v| {
+                v.name == name && volume.device.iter().all(|device| *device == v.device)
+            })
+        };
+        let position = find(&existing, &volume.name);
+
+        if volume.remove {
+            if let Some(i) = position {
+                changes.push(Change::Remove(existing.remove(i)));
+            }
+            continue;
+        }
+
+        let (current, renamed) = match position {
+            Some(i) => (Some(existing.remove(i)), false),
+            None => match volume.rename_from.as_ref().and_then(|from| find(&existing, from)) {
+                Some(i) => (Some(existing.remove(i)), true),
+                None => (None, false),
+            },
+        };
+        let create = |device| Change::Create {
+            device,
+            name: volume.name.clone(),
+            size: volume.size,
+            volume_type: volume.volume_type,
+        };
+
+        match current {
+            Some(current) if current.volume_type == volume.volume_type => {
+                let size = round_up(volume.size, current.leb_size);
+                if size != current.size {
+                    changes.push(Change::Resize(current.clone(), size));
+                }
+                if renamed {
+                    changes.push(Change::Rename(current, volume.name.clone()));
+                }
+            }
+            // The type of a volume can't be changed, so it is recreated
+            Some(current) => {
+                let device = current.device.clone();
+                changes.push(Change::Remove(current));
+                changes.push(create(device));
+            }
+            None => changes.push(create(
+                volume.device.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_DEVICE)),
+            )),
+        }
+    }
+
+    changes.sort_by_key(Change::order);
+    changes
+}
+
+/// Makes the changes, the renames of each device at once.
+pub(crate) fn apply(changes: Vec<Change>) -> Result<()> {
+    let mut renames = Vec::default();
+    for change in changes {
+        if let Change::Rename(volume, name) = change {
+            renames.push((volume, name));
+            continue;
+        }
+        rename(std::mem::take(&mut renames))?;
+
+        match change {
+            Change::Remove(volume) => {
+                info!("removing UBI volume {}", volume.name);
+                let device = fs::File::open(&volume.device)?;
+                unsafe { ffi::ubi_rmvol(device.as_raw_fd(), &volume.id)? };
+            }
+            Change::Resize(volume, size) => {
+                info!("resizing UBI volume {} to {} bytes", volume.name, size);
+                let device = fs::File::open(&volume.device)?;
+                let req = ffi::ubi_rsvol_req { bytes: size as i64, vol_id: volume.id };
+                unsafe { ffi::ubi_rsvol(device.as_raw_fd(), &req)? };
+            }
+            Change::Create { device, name, size, volume_type } => {
+                info!("creating UBI volume {} with {} bytes", name, size);
+                let device = fs::File::open(&device)?;
+                let (name, name_len) = name_field(&name)?;
+                let req = ffi::ubi_mkvol_req {
+                    vol_id: ffi::UBI_VOL_NUM_AUTO,
+                    alignment: 1,
+                    bytes: size as i64,
+                    vol_type: match volume_type {
+                        UbiVolumeType::Dynamic => ffi::UBI_DYNAMIC_VOLUME,
+                        UbiVolumeType::Static => ffi::UBI_STATIC_VOLUME,
+                    },
+                    flags: 0,
+                    name_len,
+                    padding2: [0; 4],
+                    name,
+                };
+                unsafe { ffi::ubi_mkvol(device.as_raw_fd(), &req)? };
+            }
+            Change::Rename(..) => unreachable!("renames are gathered above"),
+        }
+    }
+
+    rename(renames)
+}
+
+/// Renames the volumes atomically, either all of them are renamed or none
+/// is. A volume may take the name of another one renamed along with it, so
+/// names can be swapped.
+pub(crate) fn rename(renames: Vec<(Volume, String)>) -> Result<()> {
+    let mut by_device = BTreeMap::<_, Vec<_>>::default();
+    for (volume, name) in renames {
+        info!("renaming UBI volume {} to {}", volume.name, name);
+        by_device.entry(volume.device).or_default().push((volume.id, name));
+    }
+
+    for (device, renames) in by_device {
+        if renames.len() > ffi::UBI_MAX_RNVOL {
+            return Err(Error::InvalidUbiVolume(format!(
+                "at most {} volumes can be renamed at once",
+                ffi::UBI_MAX_RNVOL
+            )));
+        }
+
+        let mut req: ffi::ubi_rnvol_req = unsafe { std::mem::zeroed() };
+        req.count = renames.len() as i32;
+        for (ent, (id, name)) in req.ents.iter_mut().zip(renames) {
+            let (name, name_len) = name_field(&name)?;
+            *ent = ffi::ubi_rnvol_ent { vol_id: id, name_len, padding2: [0; 2], name };
+        }
+
+        let device = fs::File::open(&device)?;
+        unsafe { ffi::ubi_rnvol(device.as_raw_fd(), &req)? };
+    }
+
+    Ok(())
+}
+
+fn name_field(name: &str) -> Result<([u8; ffi::UBI_MAX_VOLUME_NAME + 1], i16)> {
+    let mut field = [0; ffi::UBI_MAX_VOLUME_NAME + 1];
+    if name.is_empty() || name.len() > ffi::UBI_MAX_VOLUME_NAME {
+        return Err(Error::InvalidUbiVolume(format!("'{}' is not a valid volume name", name)));
+    }
+    field[..name.len()].copy_from_slice(name.as_bytes());
+
+    Ok((field, name.len() as i16))
+}
+
+fn round_up(size: u64, unit: u64) -> u64 {
+    if unit == 0 {
+        return size;
+    }
+    size + (unit - size % unit) % unit
+}
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use nix::ioctl_write_ptr;
+
+    // From https://github.com/torvalds/linux/blob/master/include/uapi/mtd/ubi-user.h
+    const UBI_IOC_MAGIC: u8 = b'o';
+    const UBI_IOCMKVOL: u8 = 0;
+    const UBI_IOCRMVOL: u8 = 1;
+    const UBI_IOCRSVOL: u8 = 2;
+    const UBI_IOCRNVOL: u8 = 3;
+
+    pub const UBI_VOL_NUM_AUTO: i32 = -1;
+    pub const UBI_MAX_VOLUME_NAME: usize = 127;
+    pub const UBI_MAX_RNVOL: usize = 32;
+    pub const UBI_DYNAMIC_VOLUME: i8 = 3;
+    pub const UBI_STATIC_VOLUME: i8 = 4;
+
+    #[repr(C, packed)]
+    pub struct ubi_mkvol_req {
+        pub vol_id: i32,
+        pub alignment: i32,
+        pub bytes: i64,
+        pub vol_type: i8,
+        pub flags: u8,
+        pub name_len: i16,
+        pub padding2: [i8; 4],
+        pub name: [u8; UBI_MAX_VOLUME_NAME + 1],
+    }
+
+    #[repr(C, packed)]
+    pub struct ubi_rsvol_req {
+        pub bytes: i64,
+        pub vol_id: i32,
+    }
+
+    #[repr(C, packed)]
+    pub struct ubi_rnvol_ent {
+        pub vol_id: i32,
+        pub name_len: i16,
+        pub padding2: [i8; 2],
+        pub name: [u8; UBI_MAX_VOLUME_NAME + 1],
+    }
+
+    #[repr(C, packed)]
+    pub struct ubi_rnvol_req {
+        pub count: i32,
+        pub padding1: [i8; 12],
+        pub ents: [ubi_rnvol_ent; UBI_MAX_RNVOL],
+    }
+
+    ioctl_write_ptr!(ubi_mkvol, UBI_IOC_MAGIC, UBI_IOCMKVOL, ubi_mkvol_req);
+    ioctl_write_ptr!(ubi_rmvol, UBI_IOC_MAGIC, UBI_IOCRMVOL, i32);
+    ioctl_write_ptr!(ubi_rsvol, UBI_IOC_MAGIC, UBI_IOCRSVOL, ubi_rsvol_req);
+    ioctl_write_ptr!(ubi_rnvol, UBI_IOC_MAGIC, UBI_IOCRNVOL, ubi_rnvol_req);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    pub(crate) fn volume(id: i32, name: &str) -> Volume {
+        Volume {
+            device: PathBuf::from(DEFAULT_DEVICE),
+            id,
+            name: name.to_string(),
+            volume_type: UbiVolumeType::Dynamic,
+            leb_size: 1024,
+            size: 4 * 1024,
+        }
+    }
+
+    fn declared(name: &str, size: u64) -> UbiVolume {
+        UbiVolume {
+            name: name.to_string(),
+            device: None,
+            size,
+            volume_type: UbiVolumeType::Dynamic,
+            rename_from: None,
+            remove: false,
+        }
+    }
+
+    #[test]
+    fn read_volumes() {
+        let sysfs = tempfile::tempdir().unwrap();
+        for (dir, name, kind, ebs) in
+            &[("ubi0_1", "rootfs_b", "static", "8"), ("ubi0_0", "rootfs", "dynamic", "4")]
+        {
+            let dir = sysfs.path().join(dir);
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join("name"), format!("{}\n", name)).unwrap();
+            fs::write(dir.join("type"), format!("{}\n", kind)).unwrap();
+            fs::write(dir.join("usable_eb_size"), "1024\n").unwrap();
+            fs::write(dir.join("reserved_ebs"), format!("{}\n", ebs)).unwrap();
+        }
+        fs::create_dir(sysfs.path().join("ubi0")).unwrap();
+
+        let mut rootfs_b = volume(1, "rootfs_b");
+        rootfs_b.volume_type = UbiVolumeType::Static;
+        rootfs_b.size = 8 * 1024;
+        assert_eq!(volumes_in(sysfs.path()).unwrap(), vec![volume(0, "rootfs"), rootfs_b]);
+        assert_eq!(volumes_in(&sysfs.path().join("missing")).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn plan_changes() {
+        let existing = vec![volume(0, "rootfs"), volume(1, "system_b"), volume(2, "data")];
+
+        let mut rootfs = declared("rootfs", 3 * 1024 + 1);
+        let mut rootfs_b = declared("rootfs_b", 8 * 1024);
+        rootfs_b.rename_from = Some("system_b".to_string());
+        let mut data = declared("data", 0);
+        data.remove = true;
+        assert_eq!(
+            plan(&[rootfs.clone(), rootfs_b, data.clone(), declared("log", 1024)], &existing),
+            vec![
+                Change::Remove(volume(2, "data")),
+                Change::Rename(volume(1, "system_b"), "rootfs_b".to_string()),
+                Change::Resize(volume(1, "system_b"), 8 * 1024),
+                Change::Create {
+                    device: PathBuf::from(DEFAULT_DEVICE),
+                    name: "log".to_string(),
+                    size: 1024,
+                    volume_type: UbiVolumeType::Dynamic,
+                },
+            ]
+        );
+
+        // Shrinking comes before growing and the type is kept by recreating
+        let mut system_b = declared("system_b", 1024);
+        system_b.volume_type = UbiVolumeType::Static;
+        assert_eq!(
+            plan(&[declared("rootfs", 8 * 1024), declared("data", 1024), system_b], &existing),
+            vec![
+                Change::Remove(volume(1, "system_b")),
+                Change::Resize(volume(2, "data"), 1024),
+                Change::Resize(volume(0, "rootfs"), 8 * 1024),
+                Change::Create {
+                    device: PathBuf::from(DEFAULT_DEVICE),
+                    name: "system_b".to_string(),
+                    size: 1024,
+                    volume_type: UbiVolumeType::Static,
+                },
+            ]
+        );
+
+        // Nothing to change once the volumes match
+        rootfs.size = 4 * 1024;
+        rootfs.rename_from = Some("system_b".to_string());
+        assert_eq!(plan(&[rootfs, declared("data", 4 * 1024)], &existing), vec![]);
+        data.device = Some(PathBuf::from("/dev/ubi1"));
+        assert_eq!(plan(&[data], &existing), vec![]);
+    }
+}